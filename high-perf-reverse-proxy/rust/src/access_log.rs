@@ -0,0 +1,282 @@
+use anyhow::{Context, Result};
+use bytes::Buf;
+use hyper::body::{Body, Frame, SizeHint};
+use hyper::header;
+use hyper::Request;
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Lines waiting for the writer. Past this, lines are dropped rather than
+// holding up requests.
+const QUEUE_LINES: usize = 16 * 1024;
+
+/// Destination of the JSON access log, one object per line. Lines are
+/// written by a dedicated thread, so a slow disk or a stalled stdout never
+/// blocks the runtime's worker threads.
+pub struct AccessLog {
+    lines: SyncSender<String>,
+    dropped: AtomicU64,
+}
+
+impl AccessLog {
+    /// `-` logs to stdout; anything else is a file appended to.
+    pub fn open(target: &Path) -> Result<Self> {
+        let out: Box<dyn Write + Send> = if target == Path::new("-") {
+            Box::new(io::stdout())
+        } else {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(target)
+                .with_context(|| format!("opening access log {}", target.display()))?;
+            Box::new(file)
+        };
+        let (lines, queued) = mpsc::sync_channel(QUEUE_LINES);
+        thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || write_lines(queued, out))
+            .context("starting access log writer")?;
+        Ok(Self {
+            lines,
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// Lines lost because the writer fell behind.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn write(&self, line: String) {
+        if self.lines.try_send(line).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Writes queued lines until every sender is gone, flushing whenever the
+/// queue runs empty so a burst goes out in few writes.
+fn write_lines(queued: Receiver<String>, out: Box<dyn Write + Send>) {
+    let mut out = BufWriter::new(out);
+    while let Ok(line) = queued.recv() {
+        let _ = writeln!(out, "{}", line);
+        while let Ok(line) = queued.try_recv() {
+            let _ = writeln!(out, "{}", line);
+        }
+        let _ = out.flush();
+    }
+}
+
+/// What is known about one request so far. It is written out when dropped,
+/// which happens once the response body has been sent or the client has
+/// gone away, so the byte counts and duration cover the whole exchange.
+pub struct Entry {
+    log: Arc<AccessLog>,
+    at: SystemTime,
+    started: Instant,
+    client_ip: String,
+    tls: bool,
+    method: String,
+    host: Option<String>,
+    path: String,
+    pub status: u16,
+    /// Origin of the backend that served the last attempt.
+    pub upstream: Option<String>,
+    /// Time until that backend's response headers arrived.
+    pub upstream_latency: Option<Duration>,
+    pub attempts: u32,
+    /// Request body bytes, counted as the body is read.
+    pub bytes_in: Arc<AtomicU64>,
+    pub bytes_out: u64,
+}
+
+impl Entry {
+    pub fn new<B>(log: &Arc<AccessLog>, req: &Request<B>, peer: SocketAddr, tls: bool) -> Self {
+        let host = req
+            .headers()
+            .get(header::HOST)
+            .and_then(|h| h.to_str().ok())
+            .or_else(|| req.uri().authority().map(|a| a.as_str()));
+        Self {
+            log: Arc::clone(log),
+            at: SystemTime::now(),
+            started: Instant::now(),
+            client_ip: peer.ip().to_string(),
+            tls,
+            method: req.method().to_string(),
+            host: host.map(str::to_string),
+            path: req
+                .uri()
+                .path_and_query()
+                .map_or("/", |pq| pq.as_str())
+                .to_string(),
+            status: 0,
+            upstream: None,
+            upstream_latency: None,
+            attempts: 0,
+            bytes_in: Arc::default(),
+            bytes_out: 0,
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        serde_json::json!({
+            "ts": self.at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64(),
+            "client_ip": self.client_ip,
+            "proto": if self.tls { "https" } else { "http" },
+            "method": self.method,
+            "host": self.host,
+            "path": self.path,
+            "status": self.status,
+            "upstream": self.upstream,
+            "upstream_latency_ms": self.upstream_latency.map(ms),
+            "attempts": self.attempts,
+            "duration_ms": ms(self.started.elapsed()),
+            "bytes_in": self.bytes_in.load(Ordering::Relaxed),
+            "bytes_out": self.bytes_out,
+        })
+    }
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        self.log.write(self.to_json().to_string());
+    }
+}
+
+/// Passes a body through unchanged, calling `count` with the size of each
+/// data frame. Unlike `map_frame`, it keeps the inner body's size hint, which
+/// decides whether a request is buffered for retries and how it is framed.
+pub struct Counted<B, F> {
+    inner: B,
+    count: F,
+}
+
+impl<B, F> Counted<B, F> {
+    pub fn new(inner: B, count: F) -> Self {
+        Self { inner, count }
+    }
+}
+
+impl<B, F> Body for Counted<B, F>
+where
+    B: Body + Unpin,
+    F: FnMut(usize) + Unpin,
+{
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll {
+            if let Some(data) = frame.data_ref() {
+                (this.count)(data.remaining());
+            }
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use std::path::PathBuf;
+
+    fn temp_log(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("hprp-{}-{}.log", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    /// Waits for the writer thread to have written `n` lines to `path`.
+    fn read_lines(path: &Path, n: usize) -> Vec<Value> {
+        let mut lines = Vec::new();
+        for _ in 0..100 {
+            let text = std::fs::read_to_string(path).unwrap();
+            lines = text
+                .lines()
+                .map(|l| serde_json::from_str(l).unwrap())
+                .collect();
+            if lines.len() == n {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let _ = std::fs::remove_file(path);
+        lines
+    }
+
+    #[test]
+    fn test_entry_fields() {
+        let path = temp_log("fields");
+        let log = Arc::new(AccessLog::open(&path).unwrap());
+        let req = Request::post("/upload?x=1")
+            .header(header::HOST, "example.com")
+            .body(())
+            .unwrap();
+        let mut entry = Entry::new(&log, &req, "10.1.2.3:5555".parse().unwrap(), true);
+        entry.status = 201;
+        entry.upstream = Some("http://10.0.0.1:80".to_string());
+        entry.upstream_latency = Some(Duration::from_millis(12));
+        entry.attempts = 2;
+        entry.bytes_in.fetch_add(100, Ordering::Relaxed);
+        entry.bytes_out = 7;
+        drop(entry);
+
+        let lines = read_lines(&path, 1);
+        assert_eq!(lines.len(), 1);
+        let json = &lines[0];
+        assert_eq!(json["client_ip"], "10.1.2.3");
+        assert_eq!(json["proto"], "https");
+        assert_eq!(json["method"], "POST");
+        assert_eq!(json["host"], "example.com");
+        assert_eq!(json["path"], "/upload?x=1");
+        assert_eq!(json["status"], 201);
+        assert_eq!(json["upstream"], "http://10.0.0.1:80");
+        assert_eq!(json["upstream_latency_ms"], 12.0);
+        assert_eq!(json["attempts"], 2);
+        assert_eq!(json["bytes_in"], 100);
+        assert_eq!(json["bytes_out"], 7);
+    }
+
+    #[test]
+    fn test_lines_are_written_by_the_writer_thread() {
+        let path = temp_log("access");
+        let log = Arc::new(AccessLog::open(&path).unwrap());
+        let req = Request::get("/").body(()).unwrap();
+        for status in [200, 404, 502] {
+            let mut entry = Entry::new(&log, &req, "10.1.2.3:5555".parse().unwrap(), false);
+            entry.status = status;
+        }
+
+        let statuses: Vec<Value> = read_lines(&path, 3)
+            .iter()
+            .map(|l| l["status"].clone())
+            .collect();
+        assert_eq!(statuses, [200, 404, 502]);
+        assert_eq!(log.dropped(), 0);
+    }
+}
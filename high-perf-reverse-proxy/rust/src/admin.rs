@@ -0,0 +1,212 @@
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::body::Incoming;
+use hyper::header::{self, HeaderValue};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+use crate::balancer::{AdminState, Backend, LoadBalancer};
+use crate::breaker::State;
+use crate::health::now_ms;
+use crate::metrics;
+use crate::proxy::{accept, Proxy};
+
+const PROMETHEUS_TEXT: &str = "text/plain; version=0.0.4";
+
+/// Serves the admin API. It has its own listener, so it stays reachable
+/// while the proxy port is busy and can be bound to a private address.
+pub async fn serve(listener: TcpListener, proxy: Arc<Proxy>) {
+    loop {
+        let (stream, _) = accept(&listener).await;
+        let proxy = Arc::clone(&proxy);
+        tokio::spawn(async move {
+            let service = service_fn(move |req| {
+                let proxy = Arc::clone(&proxy);
+                async move { Ok::<_, Infallible>(handle(req, &proxy)) }
+            });
+            let _ = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await;
+        });
+    }
+}
+
+/// Routes:
+///
+/// - `GET /metrics`: Prometheus metrics.
+/// - `GET /pools`: every backend with its admin, health and circuit state.
+/// - `POST /pools/<pool>/backends/<host:port>/<enable|drain|disable>`:
+///   changes a backend's admin state and returns its new status.
+fn handle(req: Request<Incoming>, proxy: &Proxy) -> Response<Full<Bytes>> {
+    let segments: Vec<&str> = req.uri().path().split('/').skip(1).collect();
+    match (req.method(), segments.as_slice()) {
+        (&Method::GET, ["metrics"]) => {
+            respond(StatusCode::OK, PROMETHEUS_TEXT, metrics::render(proxy))
+        }
+        (&Method::GET, ["pools"]) => {
+            let router = proxy.router();
+            let pools: serde_json::Map<String, serde_json::Value> = router
+                .pools()
+                .map(|(name, lb)| {
+                    let backends = lb.backends().iter().map(|b| status(lb, b)).collect();
+                    (name.to_string(), serde_json::Value::Array(backends))
+                })
+                .collect();
+            json(StatusCode::OK, serde_json::Value::Object(pools))
+        }
+        (&Method::POST, ["pools", pool, "backends", addr, action]) => {
+            let state = match *action {
+                "enable" => AdminState::Enabled,
+                "drain" => AdminState::Draining,
+                "disable" => AdminState::Disabled,
+                _ => return not_found(),
+            };
+            let router = proxy.router();
+            let Some(lb) = router.pool(pool) else {
+                return not_found();
+            };
+            let Some(backend) = lb.find(addr) else {
+                return not_found();
+            };
+            let was = backend.set_admin_state(state);
+            if was != state {
+                println!("Backend {} in pool {}: {} -> {}", addr, pool, was, state);
+            }
+            json(StatusCode::OK, status(lb, backend))
+        }
+        (_, ["metrics" | "pools", ..]) => respond(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "text/plain",
+            "Method Not Allowed\n".to_string(),
+        ),
+        _ => not_found(),
+    }
+}
+
+fn status(lb: &LoadBalancer, backend: &Backend) -> serde_json::Value {
+    let circuit = match lb.circuit_state(backend) {
+        State::Closed => "closed",
+        State::Open => "open",
+        State::HalfOpen => "half-open",
+    };
+    serde_json::json!({
+        "backend": backend.origin(),
+        "state": backend.admin_state().as_str(),
+        "up": backend.health.is_up(now_ms()),
+        "in_flight": backend.in_flight(),
+        "circuit": circuit,
+    })
+}
+
+fn json(status: StatusCode, value: serde_json::Value) -> Response<Full<Bytes>> {
+    respond(status, "application/json", format!("{}\n", value))
+}
+
+fn not_found() -> Response<Full<Bytes>> {
+    respond(
+        StatusCode::NOT_FOUND,
+        "text/plain",
+        "Not Found\n".to_string(),
+    )
+}
+
+fn respond(status: StatusCode, content_type: &'static str, body: String) -> Response<Full<Bytes>> {
+    let mut resp = Response::new(Full::new(Bytes::from(body)));
+    *resp.status_mut() = status;
+    resp.headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balancer::Strategy;
+    use crate::config::Config;
+    use crate::router::{Defaults, Router};
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    async fn start_admin() -> (SocketAddr, Arc<Proxy>) {
+        let config = Config::from_backends("10.0.0.1:80,10.0.0.2:80", &Strategy::RoundRobin);
+        let defaults = Defaults {
+            policy: Default::default(),
+            outlier: Default::default(),
+            breaker: Default::default(),
+            backend_ca: None,
+        };
+        let (router, _) = Router::build(&config, None, &defaults).unwrap();
+        let proxy = Arc::new(Proxy::new(router, None));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, Arc::clone(&proxy)));
+        (addr, proxy)
+    }
+
+    /// Sends one request and returns the status line and the body.
+    async fn request(addr: SocketAddr, method: &str, path: &str) -> (String, String) {
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        let req = format!(
+            "{} {} HTTP/1.1\r\nHost: admin\r\nConnection: close\r\n\r\n",
+            method, path
+        );
+        conn.write_all(req.as_bytes()).await.unwrap();
+        let mut resp = String::new();
+        conn.read_to_string(&mut resp).await.unwrap();
+        let (head, body) = resp.split_once("\r\n\r\n").unwrap();
+        (head.lines().next().unwrap().to_string(), body.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_metrics_and_pools() {
+        let (addr, _) = start_admin().await;
+        let (status, body) = request(addr, "GET", "/metrics").await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(body.contains("# TYPE hprp_requests_total counter\n"));
+        assert!(
+            body.contains("hprp_backend_up{pool=\"default\",backend=\"http://10.0.0.1:80\"} 1\n")
+        );
+
+        let (status, body) = request(addr, "GET", "/pools").await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let pools: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let backends = pools["default"].as_array().unwrap();
+        assert_eq!(backends.len(), 2);
+        assert_eq!(backends[0]["backend"], "http://10.0.0.1:80");
+        assert_eq!(backends[0]["state"], "enabled");
+        assert_eq!(backends[0]["circuit"], "closed");
+
+        let (status, _) = request(addr, "POST", "/metrics").await;
+        assert_eq!(status, "HTTP/1.1 405 Method Not Allowed");
+        let (status, _) = request(addr, "GET", "/nope").await;
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+    }
+
+    #[tokio::test]
+    async fn test_change_admin_state() {
+        let (addr, proxy) = start_admin().await;
+        let (status, body) =
+            request(addr, "POST", "/pools/default/backends/10.0.0.2:80/drain").await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let backend: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(backend["state"], "draining");
+        let router = proxy.router();
+        let lb = router.pool("default").unwrap();
+        assert_eq!(lb.backends()[1].admin_state(), AdminState::Draining);
+
+        for path in [
+            "/pools/default/backends/10.0.0.2:80/pause",
+            "/pools/other/backends/10.0.0.2:80/drain",
+            "/pools/default/backends/10.0.0.9:80/drain",
+        ] {
+            let (status, _) = request(addr, "POST", path).await;
+            assert_eq!(status, "HTTP/1.1 404 Not Found", "{}", path);
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, Ordering};
+use std::time::Duration;
+
+/// `failures` consecutive failed requests open the circuit; after `open_for`
+/// it goes half-open and lets a single trial request through, whose outcome
+/// closes or re-opens it.
+#[derive(Debug, Clone)]
+pub struct BreakerConfig {
+    pub failures: u32,
+    pub open_for: Duration,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            failures: 5,
+            open_for: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+const CLOSED: u8 = 0;
+const OPEN: u8 = 1;
+
+#[derive(Default)]
+pub struct CircuitBreaker {
+    // Only Closed and Open are stored; Open turns into HalfOpen by time.
+    state: AtomicU8,
+    failures: AtomicU32,
+    opened_at: AtomicU64,
+    trial_in_flight: AtomicBool,
+}
+
+impl CircuitBreaker {
+    pub fn state(&self, now: u64, cfg: &BreakerConfig) -> State {
+        if self.state.load(Ordering::Relaxed) == CLOSED {
+            State::Closed
+        } else if now >= self.opened_at.load(Ordering::Relaxed) + cfg.open_for.as_millis() as u64 {
+            State::HalfOpen
+        } else {
+            State::Open
+        }
+    }
+
+    /// Whether a request may be sent. A half-open circuit allows one trial
+    /// at a time.
+    pub fn allows(&self, now: u64, cfg: &BreakerConfig) -> bool {
+        match self.state(now, cfg) {
+            State::Closed => true,
+            State::Open => false,
+            State::HalfOpen => !self.trial_in_flight.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Called once a request has been routed here. Returns `None` if the
+    /// request may not be sent after all, e.g. because a concurrent pick
+    /// claimed the half-open trial first, and `Some(true)` if this request
+    /// is the trial.
+    pub fn on_send(&self, now: u64, cfg: &BreakerConfig) -> Option<bool> {
+        match self.state(now, cfg) {
+            State::Closed => Some(false),
+            State::Open => None,
+            State::HalfOpen => self
+                .trial_in_flight
+                .compare_exchange(false, true, Ordering::Relaxed, Ordering::Relaxed)
+                .ok()
+                .map(|_| true),
+        }
+    }
+
+    /// Gives back a trial whose outcome was never recorded, e.g. because the
+    /// client went away, so the next request can try again.
+    pub fn release_trial(&self) {
+        self.trial_in_flight.store(false, Ordering::Relaxed);
+    }
+
+    /// Records a request's outcome and returns the new state if it changed.
+    pub fn record(&self, ok: bool, now: u64, cfg: &BreakerConfig) -> Option<State> {
+        if cfg.failures == 0 {
+            return None;
+        }
+        match (self.state(now, cfg), ok) {
+            (State::Closed, true) => {
+                self.failures.store(0, Ordering::Relaxed);
+                None
+            }
+            (State::Closed, false) => {
+                let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+                (failures >= cfg.failures).then(|| self.open(now))
+            }
+            (State::HalfOpen, true) => {
+                self.failures.store(0, Ordering::Relaxed);
+                self.trial_in_flight.store(false, Ordering::Relaxed);
+                self.state.store(CLOSED, Ordering::Relaxed);
+                Some(State::Closed)
+            }
+            (State::HalfOpen, false) => Some(self.open(now)),
+            // Stragglers sent before the circuit opened.
+            (State::Open, _) => None,
+        }
+    }
+
+    fn open(&self, now: u64) -> State {
+        self.opened_at.store(now, Ordering::Relaxed);
+        self.trial_in_flight.store(false, Ordering::Relaxed);
+        self.state.store(OPEN, Ordering::Relaxed);
+        State::Open
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cfg() -> BreakerConfig {
+        BreakerConfig {
+            failures: 3,
+            open_for: Duration::from_millis(100),
+        }
+    }
+
+    #[test]
+    fn test_opens_after_consecutive_failures() {
+        let (b, cfg) = (CircuitBreaker::default(), cfg());
+        assert_eq!(b.record(false, 0, &cfg), None);
+        assert_eq!(b.record(true, 0, &cfg), None);
+        assert_eq!(b.record(false, 0, &cfg), None);
+        assert_eq!(b.record(false, 0, &cfg), None);
+        assert_eq!(b.record(false, 10, &cfg), Some(State::Open));
+        assert!(!b.allows(50, &cfg));
+        assert_eq!(b.state(109, &cfg), State::Open);
+    }
+
+    #[test]
+    fn test_half_open_admits_one_trial() {
+        let (b, cfg) = (CircuitBreaker::default(), cfg());
+        for _ in 0..3 {
+            b.record(false, 0, &cfg);
+        }
+        assert_eq!(b.state(100, &cfg), State::HalfOpen);
+        assert!(b.allows(100, &cfg));
+        assert_eq!(b.on_send(100, &cfg), Some(true));
+        assert!(!b.allows(101, &cfg));
+        assert_eq!(b.on_send(101, &cfg), None);
+
+        // A failed trial re-opens for another full period.
+        assert_eq!(b.record(false, 120, &cfg), Some(State::Open));
+        assert!(!b.allows(219, &cfg));
+        assert!(b.allows(220, &cfg));
+        b.on_send(220, &cfg);
+        assert_eq!(b.record(true, 230, &cfg), Some(State::Closed));
+        assert!(b.allows(230, &cfg));
+    }
+}
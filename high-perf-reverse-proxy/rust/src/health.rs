@@ -0,0 +1,296 @@
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use http_body_util::Empty;
+use hyper::{Request, Uri};
+use hyper_rustls::HttpsConnector;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::balancer::{AdminState, Backend, LoadBalancer};
+use crate::tls;
+
+// Repeated ejections back off linearly, up to this many base periods.
+const MAX_EJECTION_MULTIPLIER: u64 = 10;
+
+/// Milliseconds on a monotonic clock that starts with the process.
+pub fn now_ms() -> u64 {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    EPOCH.get_or_init(Instant::now).elapsed().as_millis() as u64
+}
+
+/// Parses `200` or `200-399`.
+pub fn parse_status_range(s: &str) -> Result<RangeInclusive<u16>> {
+    let (lo, hi) = s.split_once('-').unwrap_or((s, s));
+    let (lo, hi): (u16, u16) = (lo.trim().parse()?, hi.trim().parse()?);
+    if !(100..=599).contains(&lo) || !(lo..=599).contains(&hi) {
+        bail!("invalid status range '{}'", s);
+    }
+    Ok(lo..=hi)
+}
+
+/// Active probing: `GET path` every `interval`, expecting a status in
+/// `expect`. A backend goes down after `fall` failed probes in a row and
+/// comes back after `rise` good ones.
+#[derive(Debug, Clone)]
+pub struct HealthConfig {
+    pub path: String,
+    pub expect: RangeInclusive<u16>,
+    pub timeout: Duration,
+    pub interval: Duration,
+    pub rise: u32,
+    pub fall: u32,
+}
+
+/// Passive outlier detection on live traffic: `failures` consecutive 5xx or
+/// connect errors eject a backend for `ejection` (times the number of
+/// ejections in a row), after which it ramps back up over `slow_start`.
+#[derive(Debug, Clone)]
+pub struct OutlierConfig {
+    pub failures: u32,
+    pub ejection: Duration,
+    pub slow_start: Duration,
+}
+
+impl Default for OutlierConfig {
+    fn default() -> Self {
+        Self {
+            failures: 5,
+            ejection: Duration::from_secs(30),
+            slow_start: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Health state of one backend, shared between the prober, the proxy
+/// reporting outcomes and the balancer reading it.
+pub struct BackendHealth {
+    probe_up: AtomicBool,
+    failures: AtomicU32,
+    ejections: AtomicU32,
+    ejected_until: AtomicU64,
+    // When the backend last became available again; 0 means it has been at
+    // full weight all along.
+    ramp_from: AtomicU64,
+}
+
+impl Default for BackendHealth {
+    fn default() -> Self {
+        Self {
+            probe_up: AtomicBool::new(true),
+            failures: AtomicU32::new(0),
+            ejections: AtomicU32::new(0),
+            ejected_until: AtomicU64::new(0),
+            ramp_from: AtomicU64::new(0),
+        }
+    }
+}
+
+impl BackendHealth {
+    pub fn is_ejected(&self, now: u64) -> bool {
+        now < self.ejected_until.load(Ordering::Relaxed)
+    }
+
+    /// Passing health checks and not ejected, ignoring any slow start.
+    pub fn is_up(&self, now: u64) -> bool {
+        self.probe_up.load(Ordering::Relaxed) && !self.is_ejected(now)
+    }
+
+    /// Records the result of an active probe that crossed a rise/fall
+    /// threshold.
+    pub fn set_probe_up(&self, up: bool) {
+        let was = self.probe_up.swap(up, Ordering::Relaxed);
+        if up && !was {
+            self.start_ramp();
+        }
+    }
+
+    /// Restarts slow start from now.
+    pub fn start_ramp(&self) {
+        self.ramp_from.store(now_ms().max(1), Ordering::Relaxed);
+    }
+
+    /// Whether this backend should take a request right now. While ramping
+    /// back up it takes a share of traffic that grows linearly from 0 to
+    /// 100% over `slow_start`; `roll` supplies the randomness for that.
+    pub fn admits(&self, now: u64, slow_start: Duration, roll: impl FnOnce() -> u64) -> bool {
+        if !self.probe_up.load(Ordering::Relaxed) || self.is_ejected(now) {
+            return false;
+        }
+        let from = self.ramp_from.load(Ordering::Relaxed);
+        let window = slow_start.as_millis() as u64;
+        if from == 0 || window == 0 {
+            return true;
+        }
+        let elapsed = now.saturating_sub(from);
+        if elapsed >= window {
+            let _ = self
+                .ramp_from
+                .compare_exchange(from, 0, Ordering::Relaxed, Ordering::Relaxed);
+            return true;
+        }
+        roll() % window < elapsed
+    }
+
+    /// Feeds the outcome of a proxied request into outlier detection.
+    /// Returns true if this failure ejected the backend.
+    pub fn record(&self, ok: bool, cfg: &OutlierConfig) -> bool {
+        if ok {
+            self.failures.store(0, Ordering::Relaxed);
+            if self.ramp_from.load(Ordering::Relaxed) == 0 {
+                self.ejections.store(0, Ordering::Relaxed);
+            }
+            return false;
+        }
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if cfg.failures == 0 || failures < cfg.failures {
+            return false;
+        }
+        let now = now_ms();
+        if self.is_ejected(now) {
+            return false;
+        }
+        let n = self.ejections.fetch_add(1, Ordering::Relaxed) as u64 + 1;
+        let until = now + cfg.ejection.as_millis() as u64 * n.min(MAX_EJECTION_MULTIPLIER);
+        self.failures.store(0, Ordering::Relaxed);
+        self.ejected_until.store(until, Ordering::Relaxed);
+        self.ramp_from.store(until, Ordering::Relaxed);
+        true
+    }
+}
+
+type ProbeClient = Client<HttpsConnector<HttpConnector>, Empty<Bytes>>;
+
+/// Starts one probe loop per backend. `backend_ca` is trusted for `https://`
+/// backends, as in the proxy itself. A loop ends once its backend is gone,
+/// i.e. its pool was replaced by a reload and the last request finished.
+pub fn spawn_checker(
+    lb: &Arc<LoadBalancer>,
+    cfg: HealthConfig,
+    backend_ca: Option<&Path>,
+) -> Result<()> {
+    let connector = tls::backend_connector(HttpConnector::new(), backend_ca)?;
+    let client: ProbeClient = Client::builder(TokioExecutor::new()).build(connector);
+    for backend in lb.backends() {
+        let backend = Arc::downgrade(backend);
+        let client = client.clone();
+        let cfg = cfg.clone();
+        tokio::spawn(async move {
+            let (mut good, mut bad) = (0u32, 0u32);
+            loop {
+                tokio::time::sleep(cfg.interval).await;
+                let Some(backend) = backend.upgrade() else {
+                    break;
+                };
+                if backend.admin_state() == AdminState::Disabled {
+                    continue;
+                }
+                if probe(&client, &backend, &cfg).await {
+                    good += 1;
+                    bad = 0;
+                    if good >= cfg.rise && !backend.health.probe_up.load(Ordering::Relaxed) {
+                        println!("Backend {} is up", backend.addr());
+                        backend.health.set_probe_up(true);
+                    }
+                } else {
+                    bad += 1;
+                    good = 0;
+                    if bad >= cfg.fall && backend.health.probe_up.load(Ordering::Relaxed) {
+                        println!("Backend {} is down", backend.addr());
+                        backend.health.set_probe_up(false);
+                    }
+                }
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn probe(client: &ProbeClient, backend: &Backend, cfg: &HealthConfig) -> bool {
+    let Ok(uri) = format!("{}{}", backend.origin(), cfg.path).parse::<Uri>() else {
+        return false;
+    };
+    let mut req = Request::new(Empty::new());
+    *req.uri_mut() = uri;
+    match tokio::time::timeout(cfg.timeout, client.request(req)).await {
+        Ok(Ok(resp)) => cfg.expect.contains(&resp.status().as_u16()),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cfg() -> OutlierConfig {
+        OutlierConfig {
+            failures: 3,
+            ejection: Duration::from_millis(50),
+            slow_start: Duration::from_millis(1000),
+        }
+    }
+
+    #[test]
+    fn test_consecutive_failures_eject() {
+        let h = BackendHealth::default();
+        let cfg = cfg();
+        assert!(!h.record(false, &cfg));
+        assert!(!h.record(false, &cfg));
+        assert!(!h.record(true, &cfg));
+        assert!(!h.record(false, &cfg));
+        assert!(!h.record(false, &cfg));
+        assert!(!h.is_ejected(now_ms()));
+        assert!(h.record(false, &cfg));
+        assert!(h.is_ejected(now_ms()));
+        assert!(!h.admits(now_ms(), cfg.slow_start, || 0));
+    }
+
+    #[test]
+    fn test_ejected_backend_ramps_back_up() {
+        let h = BackendHealth::default();
+        let cfg = cfg();
+        for _ in 0..3 {
+            h.record(false, &cfg);
+        }
+        let until = h.ejected_until.load(Ordering::Relaxed);
+        assert!(!h.admits(until - 1, cfg.slow_start, || 0));
+
+        // A quarter of the way through slow start, about a quarter of rolls pass.
+        let admitted = (0..1000u64)
+            .filter(|&r| h.admits(until + 250, cfg.slow_start, || r))
+            .count();
+        assert_eq!(admitted, 250);
+        assert!(h.admits(until + 1000, cfg.slow_start, || 999));
+        assert_eq!(h.ramp_from.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_repeated_ejections_back_off() {
+        let h = BackendHealth::default();
+        let cfg = cfg();
+        for _ in 0..3 {
+            h.record(false, &cfg);
+        }
+        let first = h.ejected_until.load(Ordering::Relaxed) - now_ms();
+        // Pretend the ejection expired without a success in between.
+        h.ejected_until.store(0, Ordering::Relaxed);
+        for _ in 0..3 {
+            h.record(false, &cfg);
+        }
+        let second = h.ejected_until.load(Ordering::Relaxed) - now_ms();
+        assert!(second > first + 40, "{} vs {}", second, first);
+    }
+
+    #[test]
+    fn test_parse_status_range() {
+        assert_eq!(parse_status_range("200").unwrap(), 200..=200);
+        assert_eq!(parse_status_range("200-399").unwrap(), 200..=399);
+        assert!(parse_status_range("399-200").is_err());
+        assert!(parse_status_range("abc").is_err());
+    }
+}
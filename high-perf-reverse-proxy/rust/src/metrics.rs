@@ -0,0 +1,358 @@
+use hyper::StatusCode;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::balancer::AdminState;
+use crate::breaker::State;
+use crate::health::now_ms;
+use crate::proxy::Proxy;
+
+/// Upper bounds, in seconds, of the latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0,
+];
+
+/// A Prometheus-style histogram. Bucket counts are stored per bucket and
+/// made cumulative when rendered.
+#[derive(Default)]
+pub struct Histogram {
+    // One extra slot for observations above the last bound.
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let slot = LATENCY_BUCKETS
+            .iter()
+            .position(|&le| secs <= le)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[slot].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut count = 0;
+        for (i, slot) in self.buckets.iter().enumerate() {
+            count += slot.load(Ordering::Relaxed);
+            let le = LATENCY_BUCKETS
+                .get(i)
+                .map_or("+Inf".to_string(), |le| le.to_string());
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, le, count);
+        }
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+    }
+}
+
+const CLASSES: [&str; 6] = ["1xx", "2xx", "3xx", "4xx", "5xx", "error"];
+
+/// Traffic counters for one backend, kept for as long as its pool is.
+#[derive(Default)]
+pub struct BackendStats {
+    // Indexed like `CLASSES`; "error" is a request that got no response.
+    responses: [AtomicU64; CLASSES.len()],
+    latency: Histogram,
+}
+
+impl BackendStats {
+    /// Records a response's status and how long its headers took to arrive.
+    pub fn record_response(&self, status: StatusCode, latency: Duration) {
+        let class = (status.as_u16() / 100).clamp(1, 5) as usize - 1;
+        self.responses[class].fetch_add(1, Ordering::Relaxed);
+        self.latency.observe(latency);
+    }
+
+    /// Records a connect error, timeout or broken connection.
+    pub fn record_error(&self, latency: Duration) {
+        self.responses[CLASSES.len() - 1].fetch_add(1, Ordering::Relaxed);
+        self.latency.observe(latency);
+    }
+}
+
+/// Renders every metric in the Prometheus text exposition format.
+pub fn render(proxy: &Proxy) -> String {
+    let mut out = String::new();
+    let counters = proxy.counters();
+    let proxy_counters = [
+        ("requests", "Requests received.", &counters.requests),
+        (
+            "retries",
+            "Attempts retried on another backend.",
+            &counters.retries,
+        ),
+        (
+            "connect_errors",
+            "Failed backend connects.",
+            &counters.connect_errors,
+        ),
+        ("timeouts", "Backend response timeouts.", &counters.timeouts),
+        (
+            "upstream_errors",
+            "Backend connections broken mid-request.",
+            &counters.upstream_errors,
+        ),
+        (
+            "upstream_5xx",
+            "5xx responses from backends.",
+            &counters.upstream_5xx,
+        ),
+        (
+            "circuit_open",
+            "Requests refused with every circuit open.",
+            &counters.circuit_open,
+        ),
+        (
+            "no_backend",
+            "Requests refused with no healthy backend.",
+            &counters.no_backend,
+        ),
+        (
+            "no_route",
+            "Requests matching no route.",
+            &counters.no_route,
+        ),
+    ];
+    for (name, help, counter) in proxy_counters {
+        let name = format!("hprp_{}_total", name);
+        header(&mut out, &name, help, "counter");
+        let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+    }
+    header(
+        &mut out,
+        "hprp_access_log_dropped_total",
+        "Access log lines dropped because the writer fell behind.",
+        "counter",
+    );
+    let _ = writeln!(
+        out,
+        "hprp_access_log_dropped_total {}",
+        proxy.access_log_dropped()
+    );
+
+    header(
+        &mut out,
+        "hprp_active_connections",
+        "Open client connections.",
+        "gauge",
+    );
+    for (listener, active) in proxy.active_connections() {
+        let _ = writeln!(
+            out,
+            "hprp_active_connections{{listener=\"{}\"}} {}",
+            listener, active
+        );
+    }
+
+    let router = proxy.router();
+    let now = now_ms();
+    let backends: Vec<_> = router
+        .pools()
+        .flat_map(|(pool, lb)| {
+            lb.backends().iter().map(move |b| {
+                let labels = format!(
+                    "pool=\"{}\",backend=\"{}\"",
+                    escape(pool),
+                    escape(&b.origin())
+                );
+                (labels, lb, b)
+            })
+        })
+        .collect();
+
+    header(
+        &mut out,
+        "hprp_backend_responses_total",
+        "Backend responses by status class; \"error\" means none arrived.",
+        "counter",
+    );
+    for (labels, _, b) in &backends {
+        for (class, count) in CLASSES.iter().zip(&b.stats.responses) {
+            let _ = writeln!(
+                out,
+                "hprp_backend_responses_total{{{},class=\"{}\"}} {}",
+                labels,
+                class,
+                count.load(Ordering::Relaxed)
+            );
+        }
+    }
+    header(
+        &mut out,
+        "hprp_backend_latency_seconds",
+        "Time until backend response headers arrived.",
+        "histogram",
+    );
+    for (labels, _, b) in &backends {
+        b.stats
+            .latency
+            .render(&mut out, "hprp_backend_latency_seconds", labels);
+    }
+    header(
+        &mut out,
+        "hprp_backend_active_requests",
+        "Requests in flight to the backend, including response streaming.",
+        "gauge",
+    );
+    for (labels, _, b) in &backends {
+        let _ = writeln!(
+            out,
+            "hprp_backend_active_requests{{{}}} {}",
+            labels,
+            b.in_flight()
+        );
+    }
+    header(
+        &mut out,
+        "hprp_backend_up",
+        "1 if the backend passes health checks and is not ejected.",
+        "gauge",
+    );
+    for (labels, _, b) in &backends {
+        let _ = writeln!(
+            out,
+            "hprp_backend_up{{{}}} {}",
+            labels,
+            b.health.is_up(now) as u8
+        );
+    }
+    header(
+        &mut out,
+        "hprp_backend_circuit_state",
+        "0 closed, 1 open, 2 half-open.",
+        "gauge",
+    );
+    for (labels, lb, b) in &backends {
+        let state = match lb.circuit_state(b) {
+            State::Closed => 0,
+            State::Open => 1,
+            State::HalfOpen => 2,
+        };
+        let _ = writeln!(out, "hprp_backend_circuit_state{{{}}} {}", labels, state);
+    }
+    header(
+        &mut out,
+        "hprp_backend_admin_state",
+        "0 enabled, 1 draining, 2 disabled.",
+        "gauge",
+    );
+    for (labels, _, b) in &backends {
+        let state = match b.admin_state() {
+            AdminState::Enabled => 0,
+            AdminState::Draining => 1,
+            AdminState::Disabled => 2,
+        };
+        let _ = writeln!(out, "hprp_backend_admin_state{{{}}} {}", labels, state);
+    }
+    out
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balancer::Strategy;
+    use crate::config::Config;
+    use crate::router::{Defaults, Router};
+
+    #[test]
+    fn test_histogram_buckets_are_cumulative() {
+        let h = Histogram::default();
+        h.observe(Duration::from_micros(500));
+        h.observe(Duration::from_millis(30));
+        h.observe(Duration::from_secs(20));
+        let mut out = String::new();
+        h.render(&mut out, "x", "pool=\"p\"");
+        assert!(out.contains("x_bucket{pool=\"p\",le=\"0.001\"} 1\n"));
+        assert!(out.contains("x_bucket{pool=\"p\",le=\"0.025\"} 1\n"));
+        assert!(out.contains("x_bucket{pool=\"p\",le=\"0.05\"} 2\n"));
+        assert!(out.contains("x_bucket{pool=\"p\",le=\"10\"} 2\n"));
+        assert!(out.contains("x_bucket{pool=\"p\",le=\"+Inf\"} 3\n"));
+        assert!(out.contains("x_sum{pool=\"p\"} 20.0305\n"));
+        assert!(out.contains("x_count{pool=\"p\"} 3\n"));
+    }
+
+    #[test]
+    fn test_status_classes() {
+        let stats = BackendStats::default();
+        stats.record_response(StatusCode::OK, Duration::ZERO);
+        stats.record_response(StatusCode::NO_CONTENT, Duration::ZERO);
+        stats.record_response(StatusCode::BAD_GATEWAY, Duration::ZERO);
+        stats.record_error(Duration::ZERO);
+        let counts: Vec<u64> = stats
+            .responses
+            .iter()
+            .map(|c| c.load(Ordering::Relaxed))
+            .collect();
+        assert_eq!(counts, [0, 2, 0, 0, 1, 1]);
+    }
+
+    #[test]
+    fn test_render() {
+        let config = Config::from_backends("10.0.0.1:80,10.0.0.2:80", &Strategy::RoundRobin);
+        let defaults = Defaults {
+            policy: Default::default(),
+            outlier: Default::default(),
+            breaker: Default::default(),
+            backend_ca: None,
+        };
+        let (router, _) = Router::build(&config, None, &defaults).unwrap();
+        let proxy = Proxy::new(router, None);
+        proxy.counters().requests.fetch_add(3, Ordering::Relaxed);
+        let router = proxy.router();
+        let (_, lb) = router.pools().next().unwrap();
+        let backend = &lb.backends()[1];
+        backend
+            .stats
+            .record_response(StatusCode::OK, Duration::from_millis(3));
+        backend.set_admin_state(AdminState::Draining);
+
+        let out = render(&proxy);
+        let labels = "pool=\"default\",backend=\"http://10.0.0.2:80\"";
+        for line in [
+            "# TYPE hprp_requests_total counter".to_string(),
+            "hprp_requests_total 3".to_string(),
+            "hprp_access_log_dropped_total 0".to_string(),
+            "hprp_active_connections{listener=\"https\"} 0".to_string(),
+            format!("hprp_backend_responses_total{{{},class=\"2xx\"}} 1", labels),
+            format!(
+                "hprp_backend_latency_seconds_bucket{{{},le=\"0.005\"}} 1",
+                labels
+            ),
+            format!("hprp_backend_latency_seconds_count{{{}}} 1", labels),
+            format!("hprp_backend_active_requests{{{}}} 0", labels),
+            format!("hprp_backend_circuit_state{{{}}} 0", labels),
+            format!("hprp_backend_admin_state{{{}}} 1", labels),
+        ] {
+            assert!(out.lines().any(|l| l == line), "missing {}", line);
+        }
+        // Every sample belongs to a declared metric.
+        for line in out.lines().filter(|l| !l.starts_with('#')) {
+            let name = line.split(['{', ' ']).next().unwrap();
+            let family = ["_bucket", "_sum", "_count"]
+                .iter()
+                .find_map(|s| name.strip_suffix(s))
+                .unwrap_or(name);
+            assert!(
+                out.contains(&format!("# TYPE {} ", family)),
+                "undeclared {}",
+                name
+            );
+        }
+    }
+}
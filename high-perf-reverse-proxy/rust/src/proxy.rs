@@ -0,0 +1,1015 @@
+use anyhow::Result;
+use bytes::Bytes;
+use http_body_util::{combinators::BoxBody, BodyExt, Full};
+use hyper::body::Incoming;
+use hyper::body::{Body, Frame, SizeHint};
+use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
+use hyper::http::request;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode, Uri, Version};
+use hyper_rustls::HttpsConnector;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::graceful::Watcher;
+use std::convert::Infallible;
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Sleep;
+
+use crate::access_log::{AccessLog, Counted, Entry};
+use crate::balancer::{Backend, Lease, LoadBalancer, RequestInfo};
+use crate::router::Router;
+use crate::tls;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+pub type ProxyBody = BoxBody<Bytes, BoxError>;
+
+const VIA: &str = "hprp";
+const X_PROXY_ERROR: HeaderName = HeaderName::from_static("x-proxy-error");
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+const POOL_MAX_IDLE_PER_HOST: usize = 256;
+const MAX_REPLAY_BODY: u64 = 64 * 1024;
+// Pause after a failed accept, e.g. on EMFILE, rather than spinning on it.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+// Headers that describe a single hop and must not be forwarded (RFC 9110
+// §7.6.1). `Proxy-Connection` is non-standard but still sent by old clients.
+const HOP_BY_HOP: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Timeouts and retry budget applied to each proxied request.
+#[derive(Debug, Clone)]
+pub struct RoutePolicy {
+    /// Time allowed for the TCP connect to a backend.
+    pub connect_timeout: Duration,
+    /// Time allowed from sending the request until response headers arrive.
+    pub response_timeout: Duration,
+    /// Longest a backend may go quiet while streaming the response body.
+    pub read_timeout: Duration,
+    /// Extra attempts, each on a backend not yet tried. Only idempotent
+    /// requests with a body of at most `MAX_REPLAY_BODY` bytes are retried.
+    pub retries: u32,
+}
+
+impl Default for RoutePolicy {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(2),
+            response_timeout: Duration::from_secs(30),
+            read_timeout: Duration::from_secs(30),
+            retries: 2,
+        }
+    }
+}
+
+/// Outcome counters since startup.
+#[derive(Default)]
+pub struct Counters {
+    pub requests: AtomicU64,
+    pub retries: AtomicU64,
+    pub connect_errors: AtomicU64,
+    pub timeouts: AtomicU64,
+    pub upstream_errors: AtomicU64,
+    pub upstream_5xx: AtomicU64,
+    pub circuit_open: AtomicU64,
+    pub no_backend: AtomicU64,
+    pub no_route: AtomicU64,
+}
+
+impl fmt::Display for Counters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let get = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        write!(
+            f,
+            "requests={} retries={} connect_errors={} timeouts={} upstream_errors={} \
+             upstream_5xx={} circuit_open={} no_backend={} no_route={}",
+            get(&self.requests),
+            get(&self.retries),
+            get(&self.connect_errors),
+            get(&self.timeouts),
+            get(&self.upstream_errors),
+            get(&self.upstream_5xx),
+            get(&self.circuit_open),
+            get(&self.no_backend),
+            get(&self.no_route),
+        )
+    }
+}
+
+fn bump(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+enum Failure {
+    Connect,
+    Timeout,
+    Upstream,
+}
+
+pub type UpstreamClient = Client<HttpsConnector<HttpConnector>, ProxyBody>;
+
+/// Builds a backend client. It keeps idle keep-alive connections per
+/// backend, so steady traffic pays for a TCP handshake only when the pool
+/// runs dry. `backend_ca` is trusted, on top of the public roots, when
+/// re-encrypting to `https://` backends.
+pub fn build_client(
+    connect_timeout: Duration,
+    backend_ca: Option<&Path>,
+) -> Result<UpstreamClient> {
+    let mut connector = HttpConnector::new();
+    connector.set_nodelay(true);
+    connector.set_keepalive(Some(POOL_IDLE_TIMEOUT));
+    connector.set_connect_timeout(Some(connect_timeout));
+    Ok(Client::builder(TokioExecutor::new())
+        .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+        .pool_max_idle_per_host(POOL_MAX_IDLE_PER_HOST)
+        .build(tls::backend_connector(connector, backend_ca)?))
+}
+
+pub struct Proxy {
+    // Swapped wholesale on reload. Requests hold their own `Arc`, so the ones
+    // in flight finish against the router they started with.
+    router: RwLock<Arc<Router>>,
+    counters: Counters,
+    // Open client connections, plaintext and TLS.
+    connections: [AtomicU64; 2],
+    access_log: Option<Arc<AccessLog>>,
+}
+
+impl Proxy {
+    pub fn new(router: Router, access_log: Option<AccessLog>) -> Self {
+        Self {
+            router: RwLock::new(Arc::new(router)),
+            counters: Counters::default(),
+            connections: Default::default(),
+            access_log: access_log.map(Arc::new),
+        }
+    }
+
+    pub fn router(&self) -> Arc<Router> {
+        Arc::clone(&self.router.read().unwrap())
+    }
+
+    pub fn set_router(&self, router: Router) {
+        *self.router.write().unwrap() = Arc::new(router);
+    }
+
+    pub fn counters(&self) -> &Counters {
+        &self.counters
+    }
+
+    /// Open client connections per listener.
+    pub fn active_connections(&self) -> [(&'static str, u64); 2] {
+        let get = |i: usize| self.connections[i].load(Ordering::Relaxed);
+        [("http", get(0)), ("https", get(1))]
+    }
+
+    /// Access log lines dropped because the writer fell behind.
+    pub fn access_log_dropped(&self) -> u64 {
+        self.access_log.as_ref().map_or(0, |log| log.dropped())
+    }
+
+    async fn handle(
+        &self,
+        req: Request<Incoming>,
+        peer: SocketAddr,
+        tls: bool,
+    ) -> Response<ProxyBody> {
+        let Some(log) = &self.access_log else {
+            let req = req.map(|body| body.map_err(Into::into).boxed());
+            return self.forward(req, peer, tls, None).await;
+        };
+        // Both bodies are wrapped to count their bytes; the entry rides along
+        // with the response body and is written out when that is dropped.
+        let mut entry = Entry::new(log, &req, peer, tls);
+        let bytes_in = Arc::clone(&entry.bytes_in);
+        let req = req.map(|body| {
+            Counted::new(body, move |n| {
+                bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+            })
+            .map_err(Into::into)
+            .boxed()
+        });
+        let resp = self.forward(req, peer, tls, Some(&mut entry)).await;
+        entry.status = resp.status().as_u16();
+        resp.map(|body| {
+            Counted::new(body, move |n| {
+                // Borrow the whole entry so the closure owns it, not just the
+                // one field it updates.
+                let entry = &mut entry;
+                entry.bytes_out += n as u64;
+            })
+            .boxed()
+        })
+    }
+
+    /// Routes and proxies one request, noting each attempt in `entry`.
+    async fn forward(
+        &self,
+        req: Request<ProxyBody>,
+        peer: SocketAddr,
+        tls: bool,
+        mut entry: Option<&mut Entry>,
+    ) -> Response<ProxyBody> {
+        bump(&self.counters.requests);
+        let router = self.router();
+        let host = req
+            .headers()
+            .get(header::HOST)
+            .and_then(|h| h.to_str().ok())
+            .or_else(|| req.uri().authority().map(|a| a.as_str()));
+        let path_and_query = req.uri().path_and_query().map_or("/", |pq| pq.as_str());
+        let Some((route, path)) = router.route(host, path_and_query) else {
+            bump(&self.counters.no_route);
+            return error_response(StatusCode::NOT_FOUND, "no-route", "No route");
+        };
+        let (lb, policy) = (&route.pool, &route.policy);
+
+        let (mut head, body) = req.into_parts();
+        if prepare_head(&mut head, peer, tls).is_err() {
+            return error_response(StatusCode::BAD_REQUEST, "bad-request", "Bad Request");
+        }
+
+        // A retry has to send the body again, so it is buffered up front when
+        // the request qualifies; anything else is streamed through once.
+        let replayable = policy.retries > 0
+            && is_idempotent(&head.method)
+            && body
+                .size_hint()
+                .exact()
+                .is_some_and(|n| n <= MAX_REPLAY_BODY);
+        let (replay, mut stream) = if replayable {
+            match body.collect().await {
+                Ok(collected) => (Some(collected.to_bytes()), None),
+                Err(_) => {
+                    return error_response(StatusCode::BAD_REQUEST, "bad-request", "Bad Request")
+                }
+            }
+        } else {
+            (None, Some(body))
+        };
+
+        let mut tried = Vec::new();
+        let Some(mut lease) = pick(lb, &head, peer, &tried) else {
+            return self.unavailable(lb);
+        };
+        let mut attempt = 0;
+        loop {
+            let body = match (&replay, stream.take()) {
+                (Some(bytes), _) => full_body(bytes.clone()),
+                (None, Some(body)) => body,
+                (None, None) => unreachable!("a streamed body is never retried"),
+            };
+            let req = match upstream_request(&head, &path, lease.backend(), body) {
+                Ok(req) => req,
+                Err(_) => {
+                    return error_response(StatusCode::BAD_REQUEST, "bad-request", "Bad Request")
+                }
+            };
+            let sent = Instant::now();
+            let outcome = send(&route.client, policy.response_timeout, req).await;
+            let latency = sent.elapsed();
+            if let Some(entry) = entry.as_mut() {
+                entry.upstream = Some(lease.backend().origin());
+                entry.upstream_latency = Some(latency);
+                entry.attempts = attempt + 1;
+            }
+            let stats = &lease.backend().stats;
+            let retryable = match &outcome {
+                Ok(resp) => {
+                    let status = resp.status();
+                    stats.record_response(status, latency);
+                    if status.is_server_error() {
+                        bump(&self.counters.upstream_5xx);
+                    }
+                    lb.report(&lease, !status.is_server_error());
+                    matches!(status.as_u16(), 502..=504)
+                }
+                Err(failure) => {
+                    stats.record_error(latency);
+                    bump(match failure {
+                        Failure::Connect => &self.counters.connect_errors,
+                        Failure::Timeout => &self.counters.timeouts,
+                        Failure::Upstream => &self.counters.upstream_errors,
+                    });
+                    lb.report(&lease, false);
+                    true
+                }
+            };
+
+            if retryable && replay.is_some() && attempt < policy.retries {
+                tried.push(lease.index());
+                if let Some(next) = pick(lb, &head, peer, &tried) {
+                    bump(&self.counters.retries);
+                    lease = next;
+                    attempt += 1;
+                    continue;
+                }
+            }
+            return match outcome {
+                Ok(resp) => prepare_response(resp, lease, policy.read_timeout),
+                Err(Failure::Connect) => error_response(
+                    StatusCode::BAD_GATEWAY,
+                    "connect-error",
+                    "Upstream connect error",
+                ),
+                Err(Failure::Timeout) => {
+                    error_response(StatusCode::GATEWAY_TIMEOUT, "timeout", "Gateway Timeout")
+                }
+                Err(Failure::Upstream) => {
+                    error_response(StatusCode::BAD_GATEWAY, "upstream-error", "Bad Gateway")
+                }
+            };
+        }
+    }
+
+    fn unavailable(&self, lb: &LoadBalancer) -> Response<ProxyBody> {
+        if lb.any_circuit_open() {
+            bump(&self.counters.circuit_open);
+            error_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "circuit-open",
+                "Circuit open",
+            )
+        } else {
+            bump(&self.counters.no_backend);
+            error_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "no-backend",
+                "No healthy backends",
+            )
+        }
+    }
+}
+
+fn pick(
+    lb: &LoadBalancer,
+    head: &request::Parts,
+    peer: SocketAddr,
+    tried: &[usize],
+) -> Option<Lease> {
+    lb.pick(&RequestInfo {
+        client_ip: peer.ip(),
+        headers: &head.headers,
+        exclude: tried,
+    })
+}
+
+async fn send(
+    client: &UpstreamClient,
+    timeout: Duration,
+    req: Request<ProxyBody>,
+) -> Result<Response<Incoming>, Failure> {
+    match tokio::time::timeout(timeout, client.request(req)).await {
+        Ok(Ok(resp)) => Ok(resp),
+        Ok(Err(e)) if e.is_connect() => Err(Failure::Connect),
+        Ok(Err(_)) => Err(Failure::Upstream),
+        Err(_) => Err(Failure::Timeout),
+    }
+}
+
+/// Accepts the next connection. Errors such as running out of file
+/// descriptors are logged and retried after a pause instead of ending the
+/// accept loop.
+pub async fn accept(listener: &TcpListener) -> (TcpStream, SocketAddr) {
+    loop {
+        match listener.accept().await {
+            Ok(accepted) => return accepted,
+            Err(e) => {
+                eprintln!("Accept failed: {}", e);
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+            }
+        }
+    }
+}
+
+/// Serves one client connection, answering requests until either side
+/// closes it. Bodies are streamed in both directions, so their size is not
+/// limited by any buffer here. `tls` says whether `io` is a terminated TLS
+/// stream rather than plain TCP. On shutdown, `watcher` lets the request in
+/// progress finish and then closes the connection.
+pub async fn serve_connection<I>(
+    io: I,
+    peer: SocketAddr,
+    tls: bool,
+    proxy: Arc<Proxy>,
+    watcher: Watcher,
+) where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let active = Arc::clone(&proxy);
+    let _open = OpenConnection::new(&active.connections[tls as usize]);
+    let service = service_fn(move |req| {
+        let proxy = Arc::clone(&proxy);
+        async move { Ok::<_, Infallible>(proxy.handle(req, peer, tls).await) }
+    });
+    let conn = http1::Builder::new()
+        .keep_alive(true)
+        .serve_connection(TokioIo::new(io), service);
+    let _ = watcher.watch(conn).await;
+}
+
+/// Counts a client connection as open until dropped, which also covers a
+/// connection future that is cancelled rather than run to the end.
+struct OpenConnection<'a>(&'a AtomicU64);
+
+impl<'a> OpenConnection<'a> {
+    fn new(gauge: &'a AtomicU64) -> Self {
+        gauge.fetch_add(1, Ordering::Relaxed);
+        Self(gauge)
+    }
+}
+
+impl Drop for OpenConnection<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A response the proxy generates itself. `error` goes in `X-Proxy-Error`,
+/// so clients and monitoring can tell causes apart that share a status.
+fn error_response(
+    status: StatusCode,
+    error: &'static str,
+    body: &'static str,
+) -> Response<ProxyBody> {
+    let body = Full::new(Bytes::from_static(body.as_bytes()))
+        .map_err(|never| match never {})
+        .boxed();
+    let mut resp = Response::new(body);
+    *resp.status_mut() = status;
+    resp.headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+    resp.headers_mut()
+        .insert(X_PROXY_ERROR, HeaderValue::from_static(error));
+    resp
+}
+
+fn version_tag(version: Version) -> &'static str {
+    match version {
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        _ => "1.1",
+    }
+}
+
+/// Removes hop-by-hop headers, including any the sender listed in `Connection`.
+/// `TE: trailers` survives: it says the client accepts trailers, which holds
+/// for the next hop too (RFC 9110 §7.6.1).
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let trailers = headers
+        .get_all(header::TE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|t| t.split(';').next().unwrap_or("").trim())
+        .any(|t| t.eq_ignore_ascii_case("trailers"));
+    let listed: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(name);
+    }
+    if trailers {
+        headers.insert(header::TE, HeaderValue::from_static("trailers"));
+    }
+}
+
+/// Appends this hop to a list-valued header such as `Via` or `X-Forwarded-For`.
+fn append_list(headers: &mut HeaderMap, name: HeaderName, item: &str) -> Result<()> {
+    let previous: Vec<&str> = headers
+        .get_all(&name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect();
+    let value = if previous.is_empty() {
+        item.to_string()
+    } else {
+        format!("{}, {}", previous.join(", "), item)
+    };
+    headers.insert(name, HeaderValue::from_str(&value)?);
+    Ok(())
+}
+
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    )
+}
+
+fn full_body(bytes: Bytes) -> ProxyBody {
+    Full::new(bytes).map_err(|never| match never {}).boxed()
+}
+
+/// Rewrites the client's headers for forwarding. Done once per request;
+/// every attempt then copies the result.
+fn prepare_head(head: &mut request::Parts, peer: SocketAddr, tls: bool) -> Result<()> {
+    let via = format!("{} {}", version_tag(head.version), VIA);
+    let headers = &mut head.headers;
+    strip_hop_by_hop(headers);
+    append_list(
+        headers,
+        HeaderName::from_static("x-forwarded-for"),
+        &peer.ip().to_string(),
+    )?;
+    append_list(headers, header::VIA, &via)?;
+    // Overwritten rather than kept: a plaintext client could otherwise
+    // claim to have connected over TLS.
+    let proto = if tls { "https" } else { "http" };
+    headers.insert("x-forwarded-proto", HeaderValue::from_static(proto));
+    Ok(())
+}
+
+/// `path` is the path and query after any route rewriting.
+fn upstream_request(
+    head: &request::Parts,
+    path: &str,
+    backend: &Backend,
+    body: ProxyBody,
+) -> Result<Request<ProxyBody>> {
+    let uri: Uri = format!("{}{}", backend.origin(), path).parse()?;
+    let mut req = Request::new(body);
+    *req.method_mut() = head.method.clone();
+    *req.uri_mut() = uri;
+    // The backend connection is always HTTP/1.1 regardless of what the
+    // client spoke, so it can be pooled.
+    *req.version_mut() = Version::HTTP_11;
+    *req.headers_mut() = head.headers.clone();
+    Ok(req)
+}
+
+/// The lease rides along with the body, so the backend counts the request
+/// as in flight until the response has been streamed out (or abandoned).
+/// A body that stalls for `read_timeout` is cut off, which releases both.
+fn prepare_response(
+    mut resp: Response<Incoming>,
+    lease: Lease,
+    read_timeout: Duration,
+) -> Response<ProxyBody> {
+    let via = format!("{} {}", version_tag(resp.version()), VIA);
+    let headers = resp.headers_mut();
+    strip_hop_by_hop(headers);
+    let _ = append_list(headers, header::VIA, &via);
+    resp.map(|body| {
+        let body = body.map_frame(move |frame| {
+            let _ = &lease;
+            frame
+        });
+        ReadTimeout::new(body, read_timeout).boxed()
+    })
+}
+
+/// Fails a body once the inner one has been pending for `limit` without
+/// yielding a frame. The clock only runs while a frame is being waited
+/// for, so a client that is slow to read does not count against the
+/// backend.
+struct ReadTimeout<B> {
+    inner: B,
+    limit: Duration,
+    deadline: Pin<Box<Sleep>>,
+    waiting: bool,
+}
+
+impl<B> ReadTimeout<B> {
+    fn new(inner: B, limit: Duration) -> Self {
+        Self {
+            inner,
+            limit,
+            deadline: Box::pin(tokio::time::sleep(limit)),
+            waiting: false,
+        }
+    }
+}
+
+impl<B> Body for ReadTimeout<B>
+where
+    B: Body + Unpin,
+    B::Error: Into<BoxError>,
+{
+    type Data = B::Data;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = &mut *self;
+        if let Poll::Ready(frame) = Pin::new(&mut this.inner).poll_frame(cx) {
+            this.waiting = false;
+            return Poll::Ready(frame.map(|f| f.map_err(Into::into)));
+        }
+        if !this.waiting {
+            this.waiting = true;
+            let deadline = tokio::time::Instant::now() + this.limit;
+            this.deadline.as_mut().reset(deadline);
+        }
+        match this.deadline.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Some(Err("backend stalled mid-body".into()))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balancer::{AdminState, Strategy};
+    use crate::breaker::BreakerConfig;
+    use crate::config::Config;
+    use crate::router::{Defaults, Router};
+    use hyper_util::server::graceful::GracefulShutdown;
+    use serde_json::{json, Value};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Answers with what it received: the URI, headers and body as JSON.
+    /// Its responses carry hop-by-hop headers the proxy has to drop.
+    async fn echo(req: Request<Incoming>) -> Result<Response<ProxyBody>, hyper::Error> {
+        let mut headers = serde_json::Map::new();
+        for name in req.headers().keys() {
+            let values: Vec<&str> = req
+                .headers()
+                .get_all(name)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .collect();
+            headers.insert(name.to_string(), json!(values.join(", ")));
+        }
+        let uri = req.uri().to_string();
+        let body = req.into_body().collect().await?.to_bytes();
+        let echoed = json!({
+            "uri": uri,
+            "headers": headers,
+            "body": String::from_utf8_lossy(&body),
+        });
+        let mut resp = Response::new(full_body(Bytes::from(echoed.to_string())));
+        let headers = resp.headers_mut();
+        headers.insert("connection", HeaderValue::from_static("x-backend-hop"));
+        headers.insert("x-backend-hop", HeaderValue::from_static("1"));
+        headers.insert("keep-alive", HeaderValue::from_static("timeout=5"));
+        headers.insert("x-backend", HeaderValue::from_static("kept"));
+        Ok(resp)
+    }
+
+    async fn echo_backend() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let conn =
+                    http1::Builder::new().serve_connection(TokioIo::new(stream), service_fn(echo));
+                tokio::spawn(conn);
+            }
+        });
+        addr
+    }
+
+    /// A backend that accepts connections and never answers.
+    async fn silent_backend() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            loop {
+                held.push(listener.accept().await.unwrap().0);
+            }
+        });
+        addr
+    }
+
+    /// A backend that sends response headers and part of the body, then
+    /// stalls with the connection still open.
+    async fn stalling_backend() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).await.unwrap();
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\npartial")
+                    .await
+                    .unwrap();
+                held.push(stream);
+            }
+        });
+        addr
+    }
+
+    /// An address nothing listens on.
+    fn closed_port() -> SocketAddr {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    /// Runs a proxy for `backends` on a local port, as `main` does.
+    async fn start_proxy(backends: &str, policy: RoutePolicy) -> (SocketAddr, Arc<Proxy>) {
+        let config = Config::from_backends(backends, &Strategy::RoundRobin);
+        let defaults = Defaults {
+            policy,
+            outlier: Default::default(),
+            breaker: Default::default(),
+            backend_ca: None,
+        };
+        let (router, _) = Router::build(&config, None, &defaults).unwrap();
+        let proxy = Arc::new(Proxy::new(router, None));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let serving = Arc::clone(&proxy);
+        tokio::spawn(async move {
+            let graceful = GracefulShutdown::new();
+            loop {
+                let (stream, peer) = listener.accept().await.unwrap();
+                let proxy = Arc::clone(&serving);
+                tokio::spawn(serve_connection(
+                    stream,
+                    peer,
+                    false,
+                    proxy,
+                    graceful.watcher(),
+                ));
+            }
+        });
+        (addr, proxy)
+    }
+
+    /// Sends `request` as written and reads one response: the lowercased
+    /// head, and a body framed by `Content-Length`.
+    async fn exchange(conn: &mut BufReader<TcpStream>, request: &str) -> (String, String) {
+        conn.get_mut().write_all(request.as_bytes()).await.unwrap();
+        let mut head = String::new();
+        loop {
+            let mut line = String::new();
+            assert!(
+                conn.read_line(&mut line).await.unwrap() > 0,
+                "connection closed"
+            );
+            if line == "\r\n" {
+                break;
+            }
+            head.push_str(&line);
+        }
+        let length = head
+            .lines()
+            .find_map(|l| {
+                l.to_ascii_lowercase()
+                    .strip_prefix("content-length:")
+                    .map(|n| n.trim().parse().unwrap())
+            })
+            .unwrap_or(0);
+        let mut body = vec![0; length];
+        conn.read_exact(&mut body).await.unwrap();
+        (head.to_ascii_lowercase(), String::from_utf8(body).unwrap())
+    }
+
+    async fn connect(addr: SocketAddr) -> BufReader<TcpStream> {
+        BufReader::new(TcpStream::connect(addr).await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_and_hop_by_hop_headers() {
+        let (addr, _) =
+            start_proxy(&echo_backend().await.to_string(), RoutePolicy::default()).await;
+        let mut conn = connect(addr).await;
+        let (head, body) = exchange(
+            &mut conn,
+            "GET /a?b=1 HTTP/1.1\r\nHost: example.com\r\n\
+             X-Forwarded-For: 203.0.113.9\r\nX-Forwarded-Proto: https\r\n\
+             Connection: keep-alive, x-secret\r\nX-Secret: s\r\nKeep-Alive: timeout=5\r\n\
+             Proxy-Authorization: Basic eA==\r\nTE: trailers, deflate;q=0.5\r\n\r\n",
+        )
+        .await;
+
+        let seen: Value = serde_json::from_str(&body).unwrap();
+        let headers = &seen["headers"];
+        assert_eq!(seen["uri"], "/a?b=1");
+        assert_eq!(headers["host"], "example.com");
+        assert_eq!(headers["x-forwarded-for"], "203.0.113.9, 127.0.0.1");
+        // A plaintext client cannot claim TLS.
+        assert_eq!(headers["x-forwarded-proto"], "http");
+        assert_eq!(headers["via"], "1.1 hprp");
+        assert_eq!(headers["te"], "trailers");
+        for name in ["x-secret", "keep-alive", "proxy-authorization"] {
+            assert!(headers.get(name).is_none(), "{name} was forwarded");
+        }
+
+        assert!(head.starts_with("http/1.1 200"));
+        assert!(head.contains("x-backend: kept"));
+        assert!(head.contains("via: 1.1 hprp"));
+        assert!(!head.contains("x-backend-hop"));
+        assert!(!head.contains("keep-alive"));
+
+        // `TE` without `trailers` is dropped altogether.
+        let (_, body) = exchange(&mut conn, "GET / HTTP/1.1\r\nHost: x\r\nTE: gzip\r\n\r\n").await;
+        let seen: Value = serde_json::from_str(&body).unwrap();
+        assert!(seen["headers"].get("te").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_request_framing_and_keep_alive() {
+        let (addr, proxy) =
+            start_proxy(&echo_backend().await.to_string(), RoutePolicy::default()).await;
+        let mut conn = connect(addr).await;
+
+        let (_, body) = exchange(
+            &mut conn,
+            "POST /len HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhello",
+        )
+        .await;
+        let seen: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(seen["body"], "hello");
+        assert_eq!(seen["headers"]["content-length"], "5");
+
+        // A chunked body is streamed on, still chunked.
+        let (_, body) = exchange(
+            &mut conn,
+            "POST /chunked HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n\
+             5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n",
+        )
+        .await;
+        let seen: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(seen["body"], "hello world");
+        assert_eq!(seen["headers"]["transfer-encoding"], "chunked");
+        assert!(seen["headers"].get("content-length").is_none());
+
+        // All of the above went over one client connection.
+        assert_eq!(proxy.active_connections()[0], ("http", 1));
+        let (head, _) = exchange(
+            &mut conn,
+            "GET / HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(head.contains("connection: close"));
+        let mut rest = Vec::new();
+        assert_eq!(conn.read_to_end(&mut rest).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_connection_leaves_the_gauge() {
+        let (_, proxy) = start_proxy(&closed_port().to_string(), RoutePolicy::default()).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, peer) = listener.accept().await.unwrap();
+        let graceful = GracefulShutdown::new();
+        let task = tokio::spawn(serve_connection(
+            stream,
+            peer,
+            true,
+            Arc::clone(&proxy),
+            graceful.watcher(),
+        ));
+        tokio::task::yield_now().await;
+        assert_eq!(proxy.active_connections()[1], ("https", 1));
+
+        task.abort();
+        let _ = task.await;
+        assert_eq!(proxy.active_connections()[1], ("https", 0));
+    }
+
+    #[tokio::test]
+    async fn test_retry_on_another_backend() {
+        let backends = format!("{},{}", closed_port(), echo_backend().await);
+        let (addr, proxy) = start_proxy(&backends, RoutePolicy::default()).await;
+        let mut conn = connect(addr).await;
+        // Round-robin sends at least one of these to the dead backend first.
+        for _ in 0..2 {
+            let (head, _) = exchange(&mut conn, "GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+            assert!(head.starts_with("http/1.1 200"), "{head}");
+        }
+        let counters = proxy.counters();
+        let retries = counters.retries.load(Ordering::Relaxed);
+        assert!(retries >= 1);
+        assert_eq!(counters.connect_errors.load(Ordering::Relaxed), retries);
+    }
+
+    #[tokio::test]
+    async fn test_upstream_failures_map_to_status() {
+        let (addr, _) = start_proxy(&closed_port().to_string(), RoutePolicy::default()).await;
+        let (head, body) = exchange(
+            &mut connect(addr).await,
+            "GET / HTTP/1.1\r\nHost: x\r\n\r\n",
+        )
+        .await;
+        assert!(head.starts_with("http/1.1 502"), "{head}");
+        assert_eq!(body, "Upstream connect error");
+        assert!(head.contains("x-proxy-error: connect-error\r\n"), "{head}");
+
+        let policy = RoutePolicy {
+            response_timeout: Duration::from_millis(100),
+            retries: 1,
+            ..RoutePolicy::default()
+        };
+        let backends = format!("{},{}", silent_backend().await, silent_backend().await);
+        let (addr, proxy) = start_proxy(&backends, policy).await;
+        let (head, body) = exchange(
+            &mut connect(addr).await,
+            "GET / HTTP/1.1\r\nHost: x\r\n\r\n",
+        )
+        .await;
+        assert!(head.starts_with("http/1.1 504"), "{head}");
+        assert_eq!(body, "Gateway Timeout");
+        assert!(head.contains("x-proxy-error: timeout\r\n"), "{head}");
+        let counters = proxy.counters();
+        assert_eq!(counters.timeouts.load(Ordering::Relaxed), 2);
+        assert_eq!(counters.retries.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_unavailable_causes_are_tagged() {
+        // Each refused connect counts towards the breaker; once it opens,
+        // the backend is not tried at all.
+        let (addr, _) = start_proxy(&closed_port().to_string(), RoutePolicy::default()).await;
+        let mut conn = connect(addr).await;
+        for _ in 0..BreakerConfig::default().failures {
+            let (head, _) = exchange(&mut conn, "GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+            assert!(head.starts_with("http/1.1 502"), "{head}");
+        }
+        let (head, body) = exchange(&mut conn, "GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        assert!(head.starts_with("http/1.1 503"), "{head}");
+        assert!(head.contains("x-proxy-error: circuit-open\r\n"), "{head}");
+        assert_eq!(body, "Circuit open");
+
+        let (addr, proxy) =
+            start_proxy(&echo_backend().await.to_string(), RoutePolicy::default()).await;
+        let router = proxy.router();
+        let (route, _) = router.route(Some("x"), "/").unwrap();
+        route.pool.backends()[0].set_admin_state(AdminState::Disabled);
+        let (head, body) = exchange(
+            &mut connect(addr).await,
+            "GET / HTTP/1.1\r\nHost: x\r\n\r\n",
+        )
+        .await;
+        assert!(head.starts_with("http/1.1 503"), "{head}");
+        assert!(head.contains("x-proxy-error: no-backend\r\n"), "{head}");
+        assert_eq!(body, "No healthy backends");
+    }
+
+    #[tokio::test]
+    async fn test_stalled_body_is_cut_off() {
+        let policy = RoutePolicy {
+            read_timeout: Duration::from_millis(100),
+            ..RoutePolicy::default()
+        };
+        let (addr, proxy) = start_proxy(&stalling_backend().await.to_string(), policy).await;
+        let mut conn = connect(addr).await;
+        conn.get_mut()
+            .write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), conn.read_to_end(&mut response))
+            .await
+            .expect("connection left open")
+            .unwrap();
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(response.ends_with("\r\n\r\npartial"), "{response}");
+
+        let router = proxy.router();
+        let (route, _) = router.route(Some("x"), "/").unwrap();
+        assert_eq!(route.pool.backends()[0].in_flight(), 0);
+    }
+}
@@ -0,0 +1,402 @@
+use anyhow::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::balancer::{LoadBalancer, Strategy};
+use crate::breaker::BreakerConfig;
+use crate::config::{Config, PoolConfig, RouteConfig};
+use crate::health::OutlierConfig;
+use crate::proxy::{build_client, RoutePolicy, UpstreamClient};
+
+/// Settings from the command line that apply to every pool and route unless
+/// the config file overrides them.
+#[derive(Debug, Clone)]
+pub struct Defaults {
+    pub policy: RoutePolicy,
+    pub outlier: OutlierConfig,
+    pub breaker: BreakerConfig,
+    pub backend_ca: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum HostMatch {
+    Any,
+    Exact(String),
+    // `*.example.com`, stored as `.example.com`.
+    Subdomain(String),
+}
+
+impl HostMatch {
+    fn parse(host: Option<&str>) -> Self {
+        match host.map(|h| h.to_ascii_lowercase()) {
+            None => Self::Any,
+            Some(h) => match h.strip_prefix("*.") {
+                Some(suffix) => Self::Subdomain(format!(".{}", suffix)),
+                None => Self::Exact(h),
+            },
+        }
+    }
+
+    fn matches(&self, host: Option<&str>) -> bool {
+        match (self, host) {
+            (Self::Any, _) => true,
+            (Self::Exact(h), Some(host)) => h == host,
+            (Self::Subdomain(suffix), Some(host)) => host.ends_with(suffix.as_str()),
+            (_, None) => false,
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            Self::Exact(_) => 2,
+            Self::Subdomain(_) => 1,
+            Self::Any => 0,
+        }
+    }
+}
+
+pub struct Route {
+    host: HostMatch,
+    prefix: String,
+    replacement: Option<String>,
+    pub pool: Arc<LoadBalancer>,
+    pub policy: RoutePolicy,
+    pub client: UpstreamClient,
+}
+
+impl Route {
+    /// Whether `path` falls under the prefix on a segment boundary, so
+    /// `/api` matches `/api` and `/api/users` but not `/apiary`.
+    fn matches_path(&self, path: &str) -> bool {
+        match path.strip_prefix(self.prefix.as_str()) {
+            Some(rest) => self.prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+
+    /// Applies `strip_prefix`/`rewrite_prefix` to a matched path and query.
+    fn rewrite(&self, path_and_query: &str) -> String {
+        let Some(replacement) = &self.replacement else {
+            return path_and_query.to_string();
+        };
+        let rest = &path_and_query[self.prefix.len()..];
+        let mut out = replacement.trim_end_matches('/').to_string();
+        if !rest.is_empty() && !rest.starts_with('/') && !rest.starts_with('?') {
+            out.push('/');
+        }
+        out.push_str(rest);
+        if !out.starts_with('/') {
+            out.insert(0, '/');
+        }
+        out
+    }
+}
+
+pub struct Router {
+    // Most specific first: exact host, then subdomain, then any host; within
+    // each, longest prefix first. The default pool, if any, comes last.
+    routes: Vec<Route>,
+    pools: BTreeMap<String, (PoolConfig, Arc<LoadBalancer>)>,
+    clients: HashMap<Duration, UpstreamClient>,
+}
+
+impl Router {
+    /// Builds a router for `config`. Pools whose definition is unchanged since
+    /// `previous` are carried over with their health and circuit state, as
+    /// are upstream clients with their pooled connections; rebuilt pools keep
+    /// the admin state of the backends they still have. Also returns the
+    /// newly created pools, which still need health checkers.
+    pub fn build(
+        config: &Config,
+        previous: Option<&Router>,
+        defaults: &Defaults,
+    ) -> Result<(Self, Vec<Arc<LoadBalancer>>)> {
+        let mut pools = BTreeMap::new();
+        let mut fresh = Vec::new();
+        for (name, pool) in &config.pools {
+            let reused = previous
+                .and_then(|p| p.pools.get(name))
+                .filter(|(old, _)| old == pool)
+                .map(|(_, lb)| Arc::clone(lb));
+            let lb = match reused {
+                Some(lb) => lb,
+                None => {
+                    let lb = Arc::new(LoadBalancer::new(
+                        pool.specs()?,
+                        Strategy::parse(&pool.strategy)?,
+                        defaults.outlier.clone(),
+                        defaults.breaker.clone(),
+                    ));
+                    if let Some((_, old)) = previous.and_then(|p| p.pools.get(name)) {
+                        lb.inherit_admin_states(old);
+                    }
+                    fresh.push(Arc::clone(&lb));
+                    lb
+                }
+            };
+            pools.insert(name.clone(), (pool.clone(), lb));
+        }
+
+        let mut router = Router {
+            routes: Vec::new(),
+            pools,
+            clients: HashMap::new(),
+        };
+        for rc in &config.routes {
+            let route = router.route_for(rc, previous, defaults)?;
+            router.routes.push(route);
+        }
+        router.routes.sort_by_key(|r| {
+            (
+                std::cmp::Reverse(r.host.rank()),
+                std::cmp::Reverse(r.prefix.len()),
+            )
+        });
+        if let Some(pool) = &config.default_pool {
+            let rc = RouteConfig {
+                host: None,
+                path_prefix: "/".to_string(),
+                pool: pool.clone(),
+                strip_prefix: false,
+                rewrite_prefix: None,
+                connect_timeout_ms: None,
+                response_timeout_ms: None,
+                read_timeout_ms: None,
+                retries: None,
+            };
+            let route = router.route_for(&rc, previous, defaults)?;
+            router.routes.push(route);
+        }
+        Ok((router, fresh))
+    }
+
+    /// Looks up a pool by name.
+    pub fn pool(&self, name: &str) -> Option<&Arc<LoadBalancer>> {
+        self.pools.get(name).map(|(_, lb)| lb)
+    }
+
+    fn route_for(
+        &mut self,
+        rc: &RouteConfig,
+        previous: Option<&Router>,
+        defaults: &Defaults,
+    ) -> Result<Route> {
+        let mut policy = defaults.policy.clone();
+        if let Some(ms) = rc.connect_timeout_ms {
+            policy.connect_timeout = Duration::from_millis(ms);
+        }
+        if let Some(ms) = rc.response_timeout_ms {
+            policy.response_timeout = Duration::from_millis(ms);
+        }
+        if let Some(ms) = rc.read_timeout_ms {
+            policy.read_timeout = Duration::from_millis(ms);
+        }
+        if let Some(retries) = rc.retries {
+            policy.retries = retries;
+        }
+        let client = match self.clients.get(&policy.connect_timeout) {
+            Some(client) => client.clone(),
+            None => {
+                let client = match previous.and_then(|p| p.clients.get(&policy.connect_timeout)) {
+                    Some(client) => client.clone(),
+                    None => build_client(policy.connect_timeout, defaults.backend_ca.as_deref())?,
+                };
+                self.clients.insert(policy.connect_timeout, client.clone());
+                client
+            }
+        };
+        let replacement = if rc.strip_prefix {
+            Some("/".to_string())
+        } else {
+            rc.rewrite_prefix.clone()
+        };
+        Ok(Route {
+            host: HostMatch::parse(rc.host.as_deref()),
+            prefix: rc.path_prefix.clone(),
+            replacement,
+            pool: Arc::clone(&self.pools[&rc.pool].1),
+            policy,
+            client,
+        })
+    }
+
+    /// Finds the route for a request and the path and query to forward.
+    /// `host` is the Host header, with or without a port.
+    pub fn route(&self, host: Option<&str>, path_and_query: &str) -> Option<(&Route, String)> {
+        let host = host.map(|h| strip_port(h).to_ascii_lowercase());
+        let path = path_and_query.split('?').next().unwrap_or("/");
+        let route = self
+            .routes
+            .iter()
+            .find(|r| r.host.matches(host.as_deref()) && r.matches_path(path))?;
+        Some((route, route.rewrite(path_and_query)))
+    }
+
+    pub fn pools(&self) -> impl Iterator<Item = (&str, &Arc<LoadBalancer>)> {
+        self.pools.iter().map(|(name, (_, lb))| (name.as_str(), lb))
+    }
+}
+
+fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        // IPv6 literal: keep through the closing bracket.
+        return host.find(']').map_or(host, |i| &host[..=i]);
+    }
+    host.rsplit_once(':').map_or(host, |(h, _)| h)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn defaults() -> Defaults {
+        Defaults {
+            policy: RoutePolicy::default(),
+            outlier: OutlierConfig::default(),
+            breaker: BreakerConfig::default(),
+            backend_ca: None,
+        }
+    }
+
+    const CONFIG: &str = r#"
+        default_pool = "web"
+
+        [pools.web]
+        backends = ["10.0.0.1:80"]
+
+        [pools.api]
+        backends = ["10.0.0.2:80"]
+
+        [pools.admin]
+        backends = ["10.0.0.3:80"]
+
+        [[routes]]
+        path_prefix = "/api"
+        pool = "api"
+        strip_prefix = true
+
+        [[routes]]
+        path_prefix = "/api/v2"
+        pool = "api"
+        rewrite_prefix = "/v2-beta"
+        response_timeout_ms = 1000
+        read_timeout_ms = 2000
+
+        [[routes]]
+        host = "admin.example.com"
+        pool = "admin"
+
+        [[routes]]
+        host = "*.tenants.example.com"
+        path_prefix = "/static/"
+        pool = "web"
+    "#;
+
+    fn resolve(router: &Router, host: &str, path: &str) -> Option<(String, String)> {
+        let (route, path) = router.route(Some(host), path)?;
+        Some((route.pool.backends()[0].host.clone(), path))
+    }
+
+    #[test]
+    fn test_routes_by_host_and_longest_prefix() {
+        let (router, fresh) =
+            Router::build(&Config::parse(CONFIG).unwrap(), None, &defaults()).unwrap();
+        assert_eq!(fresh.len(), 3);
+
+        assert_eq!(
+            resolve(&router, "example.com", "/"),
+            Some(("10.0.0.1".into(), "/".into()))
+        );
+        assert_eq!(
+            resolve(&router, "example.com", "/apiary"),
+            Some(("10.0.0.1".into(), "/apiary".into()))
+        );
+        assert_eq!(
+            resolve(&router, "example.com", "/api"),
+            Some(("10.0.0.2".into(), "/".into()))
+        );
+        assert_eq!(
+            resolve(&router, "example.com:8080", "/api/users?x=1"),
+            Some(("10.0.0.2".into(), "/users?x=1".into()))
+        );
+        assert_eq!(
+            resolve(&router, "example.com", "/api?x=1"),
+            Some(("10.0.0.2".into(), "/?x=1".into()))
+        );
+        assert_eq!(
+            resolve(&router, "example.com", "/api/v2/items"),
+            Some(("10.0.0.2".into(), "/v2-beta/items".into()))
+        );
+        assert_eq!(
+            resolve(&router, "Admin.Example.com", "/api/users"),
+            Some(("10.0.0.3".into(), "/api/users".into()))
+        );
+        assert_eq!(
+            resolve(&router, "a.tenants.example.com", "/static/app.js"),
+            Some(("10.0.0.1".into(), "/static/app.js".into()))
+        );
+
+        let (route, _) = router.route(Some("x"), "/api/v2").unwrap();
+        assert_eq!(route.policy.response_timeout, Duration::from_millis(1000));
+        assert_eq!(route.policy.read_timeout, Duration::from_millis(2000));
+        let (route, _) = router.route(Some("x"), "/api").unwrap();
+        assert_eq!(
+            route.policy.response_timeout,
+            defaults().policy.response_timeout
+        );
+    }
+
+    #[test]
+    fn test_without_default_pool_unmatched_requests_have_no_route() {
+        let config = Config::parse(
+            "[pools.a]\nbackends = [\"h:1\"]\n[[routes]]\nhost = \"a.test\"\npool = \"a\"\n",
+        )
+        .unwrap();
+        let (router, _) = Router::build(&config, None, &defaults()).unwrap();
+        assert!(router.route(Some("a.test"), "/x").is_some());
+        assert!(router.route(Some("b.test"), "/x").is_none());
+        assert!(router.route(None, "/x").is_none());
+    }
+
+    #[test]
+    fn test_rebuild_keeps_unchanged_pools() {
+        let config = Config::parse(CONFIG).unwrap();
+        let (old, _) = Router::build(&config, None, &defaults()).unwrap();
+
+        let mut changed = config.clone();
+        changed
+            .pools
+            .get_mut("api")
+            .unwrap()
+            .backends
+            .push("10.0.0.9:80".to_string());
+        let (new, fresh) = Router::build(&changed, Some(&old), &defaults()).unwrap();
+        assert_eq!(fresh.len(), 1);
+        assert_eq!(fresh[0].backends().len(), 2);
+
+        let find =
+            |r: &Router, name: &str| Arc::clone(r.pools().find(|(n, _)| *n == name).unwrap().1);
+        assert!(Arc::ptr_eq(&find(&old, "web"), &find(&new, "web")));
+        assert!(!Arc::ptr_eq(&find(&old, "api"), &find(&new, "api")));
+    }
+
+    #[test]
+    fn test_wildcard_host_matches_whole_labels() {
+        let wildcard = HostMatch::parse(Some("*.Example.com"));
+        assert!(wildcard.matches(Some("a.example.com")));
+        assert!(wildcard.matches(Some("a.b.example.com")));
+        assert!(!wildcard.matches(Some("example.com")));
+        assert!(!wildcard.matches(Some("evilexample.com")));
+        // Anything else with a `*` is taken literally, never as a suffix.
+        assert!(!HostMatch::parse(Some("*foo.com")).matches(Some("evilfoo.com")));
+    }
+
+    #[test]
+    fn test_strip_port() {
+        assert_eq!(strip_port("example.com:8080"), "example.com");
+        assert_eq!(strip_port("example.com"), "example.com");
+        assert_eq!(strip_port("[::1]:8080"), "[::1]");
+    }
+}
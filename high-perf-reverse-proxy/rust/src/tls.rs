@@ -0,0 +1,238 @@
+use anyhow::{bail, Context, Result};
+use hyper_rustls::HttpsConnector;
+use hyper_util::client::legacy::connect::HttpConnector;
+use rustls::crypto::ring::sign::any_supported_type;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::TlsAcceptor;
+
+/// Picks the certificate whose names match the SNI the client sent, in the
+/// order they were loaded. Clients without SNI, or asking for a name no
+/// certificate covers, get the first one.
+pub struct SniResolver {
+    certs: Vec<Arc<CertifiedKey>>,
+}
+
+impl fmt::Debug for SniResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SniResolver({} certificates)", self.certs.len())
+    }
+}
+
+impl SniResolver {
+    pub fn new(certs: Vec<Arc<CertifiedKey>>) -> Result<Self> {
+        if certs.is_empty() {
+            bail!("no TLS certificates");
+        }
+        Ok(Self { certs })
+    }
+
+    fn find(&self, sni: &str) -> Option<&Arc<CertifiedKey>> {
+        let name = ServerName::try_from(sni).ok()?;
+        self.certs.iter().find(|ck| {
+            let Some(leaf) = ck.cert.first() else {
+                return false;
+            };
+            webpki::EndEntityCert::try_from(leaf)
+                .and_then(|cert| cert.verify_is_valid_for_subject_name(&name))
+                .is_ok()
+        })
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let found = hello.server_name().and_then(|sni| self.find(sni));
+        Some(Arc::clone(found.unwrap_or(&self.certs[0])))
+    }
+}
+
+/// Loads a PEM certificate chain and its private key.
+pub fn load_cert(cert: &Path, key: &Path) -> Result<Arc<CertifiedKey>> {
+    let chain = CertificateDer::pem_file_iter(cert)
+        .with_context(|| format!("reading {}", cert.display()))?
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("parsing {}", cert.display()))?;
+    if chain.is_empty() {
+        bail!("no certificates in {}", cert.display());
+    }
+    let key = PrivateKeyDer::from_pem_file(key)
+        .with_context(|| format!("reading private key {}", key.display()))?;
+    let signing_key = any_supported_type(&key)?;
+    Ok(Arc::new(CertifiedKey::new(chain, signing_key)))
+}
+
+/// Builds the acceptor for the TLS listener from `(cert, key)` PEM pairs.
+pub fn acceptor<P: AsRef<Path>>(pairs: &[(P, P)]) -> Result<TlsAcceptor> {
+    let certs = pairs
+        .iter()
+        .map(|(cert, key)| load_cert(cert.as_ref(), key.as_ref()))
+        .collect::<Result<Vec<_>>>()?;
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(SniResolver::new(certs)?));
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Connector for backend connections: plain TCP for `http://` backends and
+/// TLS for `https://` ones, trusting the public web PKI plus `extra_ca`.
+pub fn backend_connector(
+    http: HttpConnector,
+    extra_ca: Option<&Path>,
+) -> Result<HttpsConnector<HttpConnector>> {
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    if let Some(path) = extra_ca {
+        for cert in CertificateDer::pem_file_iter(path)
+            .with_context(|| format!("reading {}", path.display()))?
+        {
+            roots.add(cert?)?;
+        }
+    }
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let mut http = http;
+    http.enforce_http(false);
+    Ok(hyper_rustls::HttpsConnectorBuilder::new()
+        .with_tls_config(config)
+        .https_or_http()
+        .enable_http1()
+        .wrap_connector(http))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use http_body_util::{BodyExt, Empty, Full};
+    use hyper::service::service_fn;
+    use hyper::{Request, Response};
+    use hyper_util::client::legacy::Client;
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use std::convert::Infallible;
+    use std::path::PathBuf;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::TlsConnector;
+
+    struct TestCert {
+        cert: PathBuf,
+        key: PathBuf,
+        der: CertificateDer<'static>,
+    }
+
+    // Writes a fresh self-signed certificate for `names` to the temp dir.
+    fn self_signed(tag: &str, names: &[&str]) -> TestCert {
+        let names: Vec<String> = names.iter().map(|s| s.to_string()).collect();
+        let generated = rcgen::generate_simple_self_signed(names).unwrap();
+        let dir = std::env::temp_dir().join(format!("hprp-tls-{}-{}", std::process::id(), tag));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        std::fs::write(&cert, generated.cert.pem()).unwrap();
+        std::fs::write(&key, generated.key_pair.serialize_pem()).unwrap();
+        TestCert {
+            cert,
+            key,
+            der: generated.cert.der().clone(),
+        }
+    }
+
+    // Handshakes with `sni`, trusting every test cert, and returns the leaf
+    // certificate the server presented.
+    async fn presented_cert(
+        addr: std::net::SocketAddr,
+        sni: &str,
+        trusted: &[&TestCert],
+    ) -> CertificateDer<'static> {
+        let mut roots = RootCertStore::empty();
+        for c in trusted {
+            roots.add(c.der.clone()).unwrap();
+        }
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let tls = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from(sni.to_string()).unwrap(), tcp)
+            .await
+            .unwrap();
+        tls.get_ref().1.peer_certificates().unwrap()[0].clone()
+    }
+
+    #[tokio::test]
+    async fn test_sni_selects_matching_certificate() {
+        let a = self_signed("sni-a", &["a.test"]);
+        let b = self_signed("sni-b", &["b.test", "*.b.test"]);
+        let acceptor = acceptor(&[
+            (a.cert.clone(), a.key.clone()),
+            (b.cert.clone(), b.key.clone()),
+        ])
+        .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let _ = acceptor.accept(stream).await;
+                });
+            }
+        });
+
+        assert_eq!(presented_cert(addr, "a.test", &[&a, &b]).await, a.der);
+        assert_eq!(presented_cert(addr, "b.test", &[&a, &b]).await, b.der);
+        assert_eq!(presented_cert(addr, "api.b.test", &[&a, &b]).await, b.der);
+        // Unknown names fall back to the first certificate, which the client
+        // then rejects, so check the resolver directly.
+        let resolver = SniResolver::new(vec![
+            load_cert(&a.cert, &a.key).unwrap(),
+            load_cert(&b.cert, &b.key).unwrap(),
+        ])
+        .unwrap();
+        assert!(resolver.find("c.test").is_none());
+        assert_eq!(resolver.find("b.test").unwrap().cert[0], b.der);
+    }
+
+    #[tokio::test]
+    async fn test_reencrypts_to_tls_backend() {
+        let backend = self_signed("backend", &["localhost"]);
+        let acceptor = acceptor(&[(backend.cert.clone(), backend.key.clone())]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let tls = acceptor.accept(stream).await.unwrap();
+            let service = service_fn(|_req: Request<hyper::body::Incoming>| async {
+                Ok::<_, Infallible>(Response::new(Full::new(Bytes::from_static(b"secure"))))
+            });
+            let _ = hyper::server::conn::http1::Builder::new()
+                .serve_connection(TokioIo::new(tls), service)
+                .await;
+        });
+
+        let connector = backend_connector(HttpConnector::new(), Some(&backend.cert)).unwrap();
+        let client: Client<_, Empty<Bytes>> =
+            Client::builder(TokioExecutor::new()).build(connector);
+        let uri = format!("https://localhost:{}/", port).parse().unwrap();
+        let resp = client.get(uri).await.unwrap();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"secure");
+    }
+
+    #[test]
+    fn test_missing_files_are_reported() {
+        let Err(err) = acceptor(&[("/nonexistent/cert.pem", "/nonexistent/key.pem")]) else {
+            panic!("loaded a missing certificate");
+        };
+        assert!(err.to_string().contains("/nonexistent/cert.pem"));
+    }
+}
@@ -0,0 +1,383 @@
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use crate::pubsub::{Inbox, KeyspaceEvents, Subscriber};
+use crate::replication::Role;
+use crate::resp::Frame;
+use crate::server::ServerStats;
+use crate::store::{KVStore, StoreError, Ttl};
+
+fn wrong_args(name: &str) -> Frame {
+    Frame::error(format!("ERR wrong number of arguments for '{}' command", name))
+}
+
+fn store_error(e: StoreError) -> Frame {
+    Frame::error(e.to_string())
+}
+
+fn to_string(arg: &[u8]) -> Result<String, Frame> {
+    String::from_utf8(arg.to_vec()).map_err(|_| Frame::error("ERR value is not valid UTF-8"))
+}
+
+fn to_int(arg: &[u8]) -> Result<i64, Frame> {
+    std::str::from_utf8(arg).ok()
+        .and_then(|s| s.parse::<i64>().ok())
+        .ok_or_else(|| Frame::error("ERR value is not an integer or out of range"))
+}
+
+fn expire_time(arg: &[u8], unit_ms: u64, name: &str) -> Result<Duration, Frame> {
+    let n = to_int(arg)?;
+    if n <= 0 { return Err(Frame::error(format!("ERR invalid expire time in '{}' command", name))); }
+    Ok(Duration::from_millis((n as u64).saturating_mul(unit_ms)))
+}
+
+fn to_strings(args: &[Vec<u8>]) -> Result<Vec<String>, Frame> {
+    args.iter().map(|a| to_string(a)).collect()
+}
+
+fn bulk_array(items: impl IntoIterator<Item = String>) -> Frame {
+    Frame::Array(Some(items.into_iter().map(Frame::bulk).collect()))
+}
+
+/// Per-connection state that outlives a single command.
+#[derive(Default)]
+pub struct Session {
+    subscriber: Option<Subscriber>,
+    // Handed to the connection once, when its first subscription is made.
+    receiver: Option<Inbox>,
+}
+
+impl Session {
+    /// In RESP2 a connection with active subscriptions may only manage
+    /// them, ping or quit.
+    pub fn is_subscribed(&self) -> bool {
+        self.subscriber.as_ref().is_some_and(|s| s.count() > 0)
+    }
+
+    /// The queue of published messages, the first time there is one.
+    pub fn take_receiver(&mut self) -> Option<Inbox> {
+        self.receiver.take()
+    }
+
+    fn subscriber(&mut self, store: &KVStore) -> &mut Subscriber {
+        if self.subscriber.is_none() {
+            let (sub, rx) = store.pubsub().subscriber();
+            self.subscriber = Some(sub);
+            self.receiver = Some(rx);
+        }
+        self.subscriber.as_mut().unwrap()
+    }
+}
+
+/// Runs one command against the store and appends its reply, or replies,
+/// to `out`. `args[0]` is the command name (case-insensitive).
+pub fn execute(store: &KVStore, stats: &ServerStats, role: &Role, session: &mut Session, args: &[Vec<u8>], out: &mut Vec<u8>) {
+    let Some(name) = args.first() else { return Frame::error("ERR empty command").encode(out) };
+    let name = String::from_utf8_lossy(name).to_ascii_lowercase();
+    let args = &args[1..];
+    let frames = match name.as_str() {
+        "subscribe" | "unsubscribe" | "psubscribe" | "punsubscribe" => subscription(store, session, &name, args),
+        "ping" if session.is_subscribed() => match args {
+            [] => Ok(vec![Frame::Array(Some(vec![Frame::bulk("pong"), Frame::bulk("")]))]),
+            [msg] => Ok(vec![Frame::Array(Some(vec![Frame::bulk("pong"), Frame::bulk(msg.clone())]))]),
+            _ => Err(wrong_args(&name)),
+        },
+        _ if session.is_subscribed() => Err(Frame::error(format!(
+            "ERR Can't execute '{}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT are allowed in this context",
+            name))),
+        _ if role.is_replica() && is_write(&name) => {
+            Err(Frame::error("READONLY You can't write against a read only replica."))
+        }
+        _ if role.is_loading() && !matches!(name.as_str(), "ping" | "info") => {
+            Err(Frame::error("LOADING Redis is loading the dataset in memory"))
+        }
+        // A write is only acknowledged once it is in the log: refused while
+        // the log is failing, and reported if this very write broke it.
+        _ if is_write(&name) => store.check_log().map_err(store_error).and_then(|_| {
+            let reply = dispatch(store, stats, role, &name, args);
+            store.check_log().map_err(store_error)?;
+            reply.map(|frame| vec![frame])
+        }),
+        _ => dispatch(store, stats, role, &name, args).map(|frame| vec![frame]),
+    };
+    match frames {
+        Ok(frames) => frames.iter().for_each(|f| f.encode(out)),
+        Err(frame) => frame.encode(out),
+    }
+}
+
+/// Each (un)subscribed channel gets its own reply carrying the
+/// connection's remaining subscription count.
+fn subscription(store: &KVStore, session: &mut Session, name: &str, args: &[Vec<u8>]) -> Result<Vec<Frame>, Frame> {
+    let reply = |kind: &str, target: Option<String>, count: usize| Frame::Array(Some(vec![
+        Frame::bulk(kind),
+        target.map_or_else(Frame::null, Frame::bulk),
+        Frame::Integer(count as i64),
+    ]));
+    let names = to_strings(args)?;
+    let sub = session.subscriber(store);
+    let frames = match name {
+        "subscribe" | "psubscribe" => {
+            if names.is_empty() { return Err(wrong_args(name)); }
+            names.into_iter().map(|n| {
+                let count = if name == "subscribe" { sub.subscribe(&n) } else { sub.psubscribe(&n) };
+                reply(name, Some(n), count)
+            }).collect()
+        }
+        _ => {
+            let pattern = name == "punsubscribe";
+            // With no arguments, drop every subscription of that kind.
+            let names = match names.is_empty() {
+                true if pattern => sub.patterns(),
+                true => sub.channels(),
+                false => names,
+            };
+            if names.is_empty() { return Ok(vec![reply(name, None, sub.count())]); }
+            names.into_iter().map(|n| {
+                let count = if pattern { sub.punsubscribe(&n) } else { sub.unsubscribe(&n) };
+                reply(name, Some(n), count)
+            }).collect()
+        }
+    };
+    Ok(frames)
+}
+
+/// Commands that mutate the keyspace; a replica only takes these from its
+/// primary.
+fn is_write(name: &str) -> bool {
+    matches!(name,
+        "set" | "setex" | "psetex" | "del" | "mset" | "expire" | "pexpire" | "persist"
+        | "incr" | "decr" | "incrby" | "decrby" | "lpush" | "rpush" | "lpop" | "rpop"
+        | "hset" | "hdel" | "sadd" | "srem")
+}
+
+fn dispatch(store: &KVStore, stats: &ServerStats, role: &Role, name: &str, args: &[Vec<u8>]) -> Result<Frame, Frame> {
+    let frame = match name {
+        "ping" => match args {
+            [] => Frame::Simple("PONG".to_string()),
+            [msg] => Frame::bulk(msg.clone()),
+            _ => return Err(wrong_args(name)),
+        },
+        "get" => {
+            let [key] = args else { return Err(wrong_args(name)) };
+            store.get(&to_string(key)?).map_err(store_error)?.map_or_else(Frame::null, Frame::bulk)
+        }
+        "set" => {
+            let [key, value, opts @ ..] = args else { return Err(wrong_args(name)) };
+            let ttl = match opts {
+                [] => None,
+                [unit, n] if unit.eq_ignore_ascii_case(b"ex") => Some(expire_time(n, 1000, name)?),
+                [unit, n] if unit.eq_ignore_ascii_case(b"px") => Some(expire_time(n, 1, name)?),
+                _ => return Err(Frame::error("ERR syntax error")),
+            };
+            let (key, value) = (to_string(key)?, to_string(value)?);
+            match ttl {
+                Some(ttl) => store.set_with_ttl(key, value, ttl),
+                None => store.set(key, value),
+            }.map_err(store_error)?;
+            Frame::ok()
+        }
+        "setex" | "psetex" => {
+            let [key, n, value] = args else { return Err(wrong_args(name)) };
+            let ttl = expire_time(n, if name == "setex" { 1000 } else { 1 }, name)?;
+            store.set_with_ttl(to_string(key)?, to_string(value)?, ttl).map_err(store_error)?;
+            Frame::ok()
+        }
+        "expire" | "pexpire" => {
+            let [key, n] = args else { return Err(wrong_args(name)) };
+            let key = to_string(key)?;
+            let n = to_int(n)?;
+            // A non-positive TTL expires the key immediately, as in Redis.
+            let applied = if n <= 0 {
+                store.delete(&key)
+            } else {
+                let unit_ms = if name == "expire" { 1000 } else { 1 };
+                store.expire(&key, Duration::from_millis((n as u64).saturating_mul(unit_ms)))
+            };
+            Frame::Integer(applied as i64)
+        }
+        "ttl" | "pttl" => {
+            let [key] = args else { return Err(wrong_args(name)) };
+            let reply = match store.ttl(&to_string(key)?) {
+                Ttl::Missing => -2,
+                Ttl::Persistent => -1,
+                // Round up so a key with 300ms left still reports TTL 1.
+                Ttl::Expires(d) if name == "ttl" => d.as_millis().div_ceil(1000) as i64,
+                Ttl::Expires(d) => d.as_millis() as i64,
+            };
+            Frame::Integer(reply)
+        }
+        "persist" => {
+            let [key] = args else { return Err(wrong_args(name)) };
+            Frame::Integer(store.persist(&to_string(key)?) as i64)
+        }
+        "del" => {
+            if args.is_empty() { return Err(wrong_args(name)); }
+            let removed = to_strings(args)?.iter().filter(|k| store.delete(k)).count();
+            Frame::Integer(removed as i64)
+        }
+        "exists" => {
+            if args.is_empty() { return Err(wrong_args(name)); }
+            let found = to_strings(args)?.iter().filter(|k| store.exists(k)).count();
+            Frame::Integer(found as i64)
+        }
+        "mget" => {
+            if args.is_empty() { return Err(wrong_args(name)); }
+            let values = store.get_many(&to_strings(args)?);
+            Frame::Array(Some(values.into_iter()
+                .map(|v| v.map_or_else(Frame::null, Frame::bulk))
+                .collect()))
+        }
+        "mset" => {
+            if args.is_empty() || !args.len().is_multiple_of(2) { return Err(wrong_args(name)); }
+            let strings = to_strings(args)?;
+            let pairs = strings.chunks(2).map(|kv| (kv[0].clone(), kv[1].clone())).collect();
+            store.set_many(pairs).map_err(store_error)?;
+            Frame::ok()
+        }
+        "incr" | "decr" => {
+            let [key] = args else { return Err(wrong_args(name)) };
+            let delta = if name == "incr" { 1 } else { -1 };
+            Frame::Integer(store.incr_by(&to_string(key)?, delta).map_err(store_error)?)
+        }
+        "incrby" | "decrby" => {
+            let [key, n] = args else { return Err(wrong_args(name)) };
+            let n = to_int(n)?;
+            let delta = if name == "incrby" { Some(n) } else { n.checked_neg() };
+            let delta = delta.ok_or_else(|| store_error(StoreError::Overflow))?;
+            Frame::Integer(store.incr_by(&to_string(key)?, delta).map_err(store_error)?)
+        }
+        "lpush" | "rpush" => {
+            let [key, values @ ..] = args else { return Err(wrong_args(name)) };
+            if values.is_empty() { return Err(wrong_args(name)); }
+            let len = store.push(&to_string(key)?, to_strings(values)?, name == "lpush").map_err(store_error)?;
+            Frame::Integer(len as i64)
+        }
+        "lpop" | "rpop" => {
+            let left = name == "lpop";
+            match args {
+                [key] => {
+                    let mut popped = store.pop(&to_string(key)?, 1, left).map_err(store_error)?;
+                    popped.pop().map_or_else(Frame::null, Frame::bulk)
+                }
+                [key, count] => {
+                    let count = to_int(count)?;
+                    if count < 0 { return Err(Frame::error("ERR value is out of range, must be positive")); }
+                    let key = to_string(key)?;
+                    // A missing key is a nil reply even when a count is given.
+                    if !store.exists(&key) { return Ok(Frame::Array(None)); }
+                    bulk_array(store.pop(&key, count as usize, left).map_err(store_error)?)
+                }
+                _ => return Err(wrong_args(name)),
+            }
+        }
+        "lrange" => {
+            let [key, start, stop] = args else { return Err(wrong_args(name)) };
+            bulk_array(store.range(&to_string(key)?, to_int(start)?, to_int(stop)?).map_err(store_error)?)
+        }
+        "llen" => {
+            let [key] = args else { return Err(wrong_args(name)) };
+            Frame::Integer(store.list_len(&to_string(key)?).map_err(store_error)? as i64)
+        }
+        "hset" => {
+            let [key, pairs @ ..] = args else { return Err(wrong_args(name)) };
+            if pairs.is_empty() || !pairs.len().is_multiple_of(2) { return Err(wrong_args(name)); }
+            let strings = to_strings(pairs)?;
+            let pairs = strings.chunks(2).map(|fv| (fv[0].clone(), fv[1].clone())).collect();
+            Frame::Integer(store.hash_set(&to_string(key)?, pairs).map_err(store_error)? as i64)
+        }
+        "hget" => {
+            let [key, field] = args else { return Err(wrong_args(name)) };
+            store.hash_get(&to_string(key)?, &to_string(field)?).map_err(store_error)?
+                .map_or_else(Frame::null, Frame::bulk)
+        }
+        "hgetall" => {
+            let [key] = args else { return Err(wrong_args(name)) };
+            let pairs = store.hash_get_all(&to_string(key)?).map_err(store_error)?;
+            bulk_array(pairs.into_iter().flat_map(|(f, v)| [f, v]))
+        }
+        "hdel" => {
+            let [key, fields @ ..] = args else { return Err(wrong_args(name)) };
+            if fields.is_empty() { return Err(wrong_args(name)); }
+            Frame::Integer(store.hash_del(&to_string(key)?, &to_strings(fields)?).map_err(store_error)? as i64)
+        }
+        "sadd" => {
+            let [key, members @ ..] = args else { return Err(wrong_args(name)) };
+            if members.is_empty() { return Err(wrong_args(name)); }
+            Frame::Integer(store.set_add(&to_string(key)?, to_strings(members)?).map_err(store_error)? as i64)
+        }
+        "srem" => {
+            let [key, members @ ..] = args else { return Err(wrong_args(name)) };
+            if members.is_empty() { return Err(wrong_args(name)); }
+            Frame::Integer(store.set_remove(&to_string(key)?, &to_strings(members)?).map_err(store_error)? as i64)
+        }
+        "sismember" => {
+            let [key, member] = args else { return Err(wrong_args(name)) };
+            Frame::Integer(store.set_is_member(&to_string(key)?, &to_string(member)?).map_err(store_error)? as i64)
+        }
+        "smembers" => {
+            let [key] = args else { return Err(wrong_args(name)) };
+            bulk_array(store.set_members(&to_string(key)?).map_err(store_error)?)
+        }
+        "type" => {
+            let [key] = args else { return Err(wrong_args(name)) };
+            Frame::Simple(store.type_of(&to_string(key)?).unwrap_or("none").to_string())
+        }
+        "publish" => {
+            let [channel, message] = args else { return Err(wrong_args(name)) };
+            Frame::Integer(store.pubsub().publish(&to_string(channel)?, message) as i64)
+        }
+        "config" => {
+            let [sub, param, rest @ ..] = args else { return Err(wrong_args(name)) };
+            if !param.eq_ignore_ascii_case(b"notify-keyspace-events") {
+                return Err(Frame::error(format!("ERR Unsupported CONFIG parameter: {}", String::from_utf8_lossy(param))));
+            }
+            match rest {
+                [] if sub.eq_ignore_ascii_case(b"get") => Frame::Array(Some(vec![
+                    Frame::bulk("notify-keyspace-events"),
+                    Frame::bulk(store.pubsub().keyspace_events().to_string()),
+                ])),
+                [flags] if sub.eq_ignore_ascii_case(b"set") => {
+                    let events = KeyspaceEvents::parse(&to_string(flags)?)
+                        .map_err(|e| Frame::error(format!("ERR {}", e)))?;
+                    store.pubsub().set_keyspace_events(events);
+                    Frame::ok()
+                }
+                _ => return Err(Frame::error("ERR syntax error")),
+            }
+        }
+        "info" => Frame::bulk(info(store, stats, role)),
+        // redis-cli probes COMMAND DOCS on connect; an empty reply is enough.
+        "command" => Frame::Array(Some(Vec::new())),
+        _ => return Err(Frame::error(format!("ERR unknown command '{}'", name))),
+    };
+    Ok(frame)
+}
+
+fn info(store: &KVStore, stats: &ServerStats, role: &Role) -> String {
+    let mut s = String::new();
+    s.push_str("# Server\r\n");
+    s.push_str("redis_version:7.0.0-compat\r\n");
+    s.push_str(&format!("uptime_in_seconds:{}\r\n", stats.start.elapsed().as_secs()));
+    s.push_str(&format!("shards:{}\r\n", store.shard_count()));
+    s.push_str("\r\n# Clients\r\n");
+    s.push_str(&format!("connected_clients:{}\r\n", stats.connected_clients.load(Ordering::Relaxed)));
+    let mem = store.memory_stats();
+    s.push_str("\r\n# Memory\r\n");
+    s.push_str(&format!("used_memory:{}\r\n", mem.used_memory));
+    s.push_str(&format!("maxmemory:{}\r\n", mem.max_memory));
+    s.push_str(&format!("maxmemory_policy:{}\r\n", mem.policy));
+    s.push_str("\r\n# Stats\r\n");
+    s.push_str(&format!("total_connections_received:{}\r\n", stats.total_connections.load(Ordering::Relaxed)));
+    s.push_str(&format!("total_commands_processed:{}\r\n", stats.total_commands.load(Ordering::Relaxed)));
+    s.push_str(&format!("expired_keys:{}\r\n", mem.expired_keys));
+    s.push_str(&format!("evicted_keys:{}\r\n", mem.evicted_keys));
+    let (channels, patterns) = store.pubsub().counts();
+    s.push_str(&format!("pubsub_channels:{}\r\n", channels));
+    s.push_str(&format!("pubsub_patterns:{}\r\n", patterns));
+    s.push_str("\r\n# Replication\r\n");
+    s.push_str(&role.info());
+    s.push_str("\r\n# Keyspace\r\n");
+    s.push_str(&format!("db0:keys={},expires={}\r\n", store.len(), store.expires_len()));
+    s
+}
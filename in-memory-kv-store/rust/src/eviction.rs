@@ -0,0 +1,144 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EvictionPolicy {
+    #[default]
+    NoEviction,
+    AllKeysLru,
+    AllKeysLfu,
+    VolatileTtl,
+}
+
+impl EvictionPolicy {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "noeviction" => Ok(Self::NoEviction),
+            "allkeys-lru" => Ok(Self::AllKeysLru),
+            "allkeys-lfu" => Ok(Self::AllKeysLfu),
+            "volatile-ttl" => Ok(Self::VolatileTtl),
+            _ => Err(format!("invalid eviction policy '{}'", s)),
+        }
+    }
+
+    /// LRU and LFU need per-key access metadata kept up to date on reads.
+    pub fn tracks_access(self) -> bool {
+        matches!(self, Self::AllKeysLru | Self::AllKeysLfu)
+    }
+}
+
+impl fmt::Display for EvictionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::NoEviction => "noeviction",
+            Self::AllKeysLru => "allkeys-lru",
+            Self::AllKeysLfu => "allkeys-lfu",
+            Self::VolatileTtl => "volatile-ttl",
+        };
+        f.write_str(s)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MemoryConfig {
+    /// Budget in key + value bytes; 0 means unlimited.
+    pub max_memory: usize,
+    pub policy: EvictionPolicy,
+}
+
+/// Parses a byte size such as `1048576`, `512kb`, `64mb` or `1gb`.
+pub fn parse_memory(s: &str) -> Result<usize, String> {
+    let lower = s.to_ascii_lowercase();
+    let (num, mult) = [("gb", 1usize << 30), ("mb", 1 << 20), ("kb", 1 << 10), ("b", 1)]
+        .iter()
+        .find_map(|(suffix, mult)| lower.strip_suffix(suffix).map(|n| (n.to_string(), *mult)))
+        .unwrap_or((lower.clone(), 1));
+    num.trim().parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(mult))
+        .ok_or_else(|| format!("invalid memory size '{}'", s))
+}
+
+// LFU counts halve for every period a key goes untouched, like Redis's
+// `lfu-decay-time` (default one minute), so keys that were hot once do not
+// outrank ones in use now forever.
+const LFU_DECAY_PERIOD: Duration = Duration::from_secs(60);
+
+fn decay_period() -> u64 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_secs() / LFU_DECAY_PERIOD.as_secs()
+}
+
+// Reads only hold the read lock, so access metadata is atomic.
+#[derive(Default)]
+pub struct Access {
+    last_used: AtomicU64,
+    hits: AtomicU64,
+    // Decay period `hits` was last brought up to date in.
+    decayed_at: AtomicU64,
+}
+
+impl Access {
+    pub fn touch(&self, tick: u64) {
+        self.touch_at(tick, decay_period());
+    }
+
+    fn touch_at(&self, tick: u64, period: u64) {
+        self.last_used.store(tick, Ordering::Relaxed);
+        let hits = self.hits_at(period);
+        self.decayed_at.store(period, Ordering::Relaxed);
+        self.hits.store(hits + 1, Ordering::Relaxed);
+    }
+
+    fn hits_at(&self, period: u64) -> u64 {
+        let idle = period.saturating_sub(self.decayed_at.load(Ordering::Relaxed));
+        self.hits.load(Ordering::Relaxed).checked_shr(idle.min(64) as u32).unwrap_or(0)
+    }
+
+    /// Lower scores are evicted first.
+    pub fn score(&self, policy: EvictionPolicy) -> u64 {
+        self.score_at(policy, decay_period())
+    }
+
+    fn score_at(&self, policy: EvictionPolicy, period: u64) -> u64 {
+        match policy {
+            EvictionPolicy::AllKeysLfu => self.hits_at(period),
+            _ => self.last_used.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_memory_and_policy() {
+        assert_eq!(parse_memory("4096").unwrap(), 4096);
+        assert_eq!(parse_memory("512kb").unwrap(), 512 * 1024);
+        assert_eq!(parse_memory("64MB").unwrap(), 64 << 20);
+        assert!(parse_memory("lots").is_err());
+
+        assert_eq!(EvictionPolicy::parse("allkeys-lru").unwrap(), EvictionPolicy::AllKeysLru);
+        assert_eq!(EvictionPolicy::VolatileTtl.to_string(), "volatile-ttl");
+        assert!(EvictionPolicy::parse("random").is_err());
+    }
+
+    #[test]
+    fn test_lfu_counts_decay_while_idle() {
+        let (old, new) = (Access::default(), Access::default());
+        for tick in 0..16 { old.touch_at(tick, 0); }
+        assert_eq!(old.score_at(EvictionPolicy::AllKeysLfu, 0), 16);
+        assert_eq!(old.score_at(EvictionPolicy::AllKeysLfu, 2), 4);
+        assert_eq!(old.score_at(EvictionPolicy::AllKeysLfu, 100), 0);
+        assert_eq!(old.score_at(EvictionPolicy::AllKeysLru, 100), 15);
+
+        for tick in 16..21 { new.touch_at(tick, 2); }
+        assert!(new.score_at(EvictionPolicy::AllKeysLfu, 2) > old.score_at(EvictionPolicy::AllKeysLfu, 2));
+        old.touch_at(21, 2);
+        assert_eq!(old.score_at(EvictionPolicy::AllKeysLfu, 2), 5);
+        assert_eq!(old.score_at(EvictionPolicy::AllKeysLfu, 3), 2);
+    }
+}
@@ -1,32 +1,42 @@
-use std::collections::HashMap;
-use std::sync::RwLock;
-use std::time::Instant;
+mod command;
+mod eviction;
+mod persistence;
+mod pubsub;
+mod replication;
+mod resp;
+mod server;
+mod store;
+mod value;
 
-struct KVStore {
-    data: RwLock<HashMap<String, String>>,
-}
-
-impl KVStore {
-    fn new() -> Self {
-        Self { data: RwLock::new(HashMap::new()) }
-    }
-
-    fn set(&self, key: String, value: String) {
-        self.data.write().unwrap().insert(key, value);
-    }
-
-    fn get(&self, key: &str) -> Option<String> {
-        self.data.read().unwrap().get(key).cloned()
-    }
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
 
-    fn delete(&self, key: &str) -> bool {
-        self.data.write().unwrap().remove(key).is_some()
-    }
-}
+use eviction::{EvictionPolicy, MemoryConfig};
+use persistence::{FsyncPolicy, Persistence, PersistenceConfig};
+use server::Server;
+use store::{KVStore, MemoryStats, DEFAULT_SHARDS};
 
 struct Stats {
     total_ops: usize,
     processing_ns: u128,
+    rejected_writes: usize,
+    memory: MemoryStats,
+}
+
+// Per-operation latencies from the concurrent benchmark, in nanoseconds.
+struct Latencies {
+    sorted_ns: Vec<u64>,
+    reads: usize,
+    writes: usize,
+}
+
+impl Latencies {
+    fn percentile_us(&self, p: f64) -> f64 {
+        if self.sorted_ns.is_empty() { return 0.0; }
+        let idx = ((self.sorted_ns.len() - 1) as f64 * p / 100.0).round() as usize;
+        self.sorted_ns[idx] as f64 / 1000.0
+    }
 }
 
 impl Stats {
@@ -41,20 +51,79 @@ impl Stats {
     }
 }
 
-fn parse_args() -> Result<usize, String> {
+enum Mode {
+    Benchmark(usize, MemoryConfig),
+    Concurrent { num_ops: usize, threads: usize, shards: usize, read_pct: u32 },
+    Serve { addr: String, memory: MemoryConfig, persistence: Option<PersistenceConfig> },
+    Replica { addr: String, primary: String, memory: MemoryConfig },
+}
+
+// Trailing `[maxmemory] [policy]` shared by both modes, e.g. `64mb allkeys-lru`.
+fn parse_memory_args(args: &[String]) -> Result<MemoryConfig, String> {
+    let max_memory = args.first().map(|v| eviction::parse_memory(v)).transpose()?.unwrap_or(0);
+    let policy = args.get(1).map(|v| EvictionPolicy::parse(v)).transpose()?.unwrap_or_default();
+    Ok(MemoryConfig { max_memory, policy })
+}
+
+// Server-only `[data-dir] [fsync] [snapshot-secs]`; no data dir means no persistence.
+fn parse_persistence_args(args: &[String]) -> Result<Option<PersistenceConfig>, String> {
+    let Some(dir) = args.first() else { return Ok(None) };
+    let mut config = PersistenceConfig::new(dir);
+    if let Some(v) = args.get(1) { config.fsync = FsyncPolicy::parse(v)?; }
+    if let Some(v) = args.get(2) {
+        let secs = v.parse::<u64>().map_err(|_| "invalid snapshot interval".to_string())?;
+        config.snapshot_interval = Duration::from_secs(secs);
+    }
+    Ok(Some(config))
+}
+
+fn parse_args() -> Result<Mode, String> {
     let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("serve") {
+        let addr = args.get(2).cloned().unwrap_or_else(|| "127.0.0.1:6379".to_string());
+        let memory = parse_memory_args(args.get(3..).unwrap_or(&[]))?;
+        let persistence = parse_persistence_args(args.get(5..).unwrap_or(&[]))?;
+        return Ok(Mode::Serve { addr, memory, persistence });
+    }
+    if args.get(1).map(String::as_str) == Some("replica") {
+        let addr = args.get(2).cloned().unwrap_or_else(|| "127.0.0.1:6380".to_string());
+        let primary = args.get(3).cloned().unwrap_or_else(|| "127.0.0.1:6379".to_string());
+        let memory = parse_memory_args(args.get(4..).unwrap_or(&[]))?;
+        return Ok(Mode::Replica { addr, primary, memory });
+    }
+    if args.get(1).map(String::as_str) == Some("concurrent") {
+        let num = |i: usize, name: &str, default: usize| -> Result<usize, String> {
+            let v = args.get(i).map(|v| v.parse::<usize>()).transpose()
+                .map_err(|_| format!("invalid {}", name))?.unwrap_or(default);
+            if v == 0 { return Err(format!("invalid {}", name)); }
+            Ok(v)
+        };
+        let cpus = thread::available_parallelism().map(|n| n.get()).unwrap_or(4);
+        let num_ops = num(2, "operations", 1_000_000)?;
+        let threads = num(3, "threads", cpus)?;
+        let shards = num(4, "shards", DEFAULT_SHARDS)?;
+        let read_pct = args.get(5).map(|v| v.parse::<u32>()).transpose()
+            .map_err(|_| "invalid read percentage".to_string())?.unwrap_or(80);
+        if read_pct > 100 { return Err("invalid read percentage".to_string()); }
+        return Ok(Mode::Concurrent { num_ops, threads, shards, read_pct });
+    }
     let num_ops = args.get(1)
         .map(|v| v.parse::<usize>().map_err(|_| "invalid operations".to_string()))
         .transpose()?
         .unwrap_or(100000);
     if num_ops == 0 { return Err("invalid operations".to_string()); }
-    Ok(num_ops)
+    let memory = parse_memory_args(args.get(2..).unwrap_or(&[]))?;
+    Ok(Mode::Benchmark(num_ops, memory))
 }
 
-fn print_config(num_ops: usize) {
+fn print_config(num_ops: usize, memory: &MemoryConfig) {
     println!("Configuration:");
     println!("  Operations: {}", num_ops);
     println!("  Store type: In-memory KV Store");
+    if memory.max_memory > 0 {
+        println!("  Max memory: {} bytes", memory.max_memory);
+        println!("  Eviction policy: {}", memory.policy);
+    }
     println!();
 }
 
@@ -64,6 +133,20 @@ fn print_stats(s: &Stats) {
     println!("Processing time: {:.3}s", s.processing_ns as f64 / 1_000_000_000.0);
     println!("Average latency: {:.6}ms", s.avg_latency_ms());
     println!("Throughput: {:.0} ops/sec", s.throughput());
+    if s.memory.max_memory > 0 {
+        println!("Used memory: {} bytes", s.memory.used_memory);
+        println!("Evicted keys: {}", s.memory.evicted_keys);
+        println!("Rejected writes: {}", s.rejected_writes);
+    }
+}
+
+fn print_latencies(l: &Latencies) {
+    println!("Reads: {}", l.reads);
+    println!("Writes: {}", l.writes);
+    println!("Latency p50: {:.3}us", l.percentile_us(50.0));
+    println!("Latency p95: {:.3}us", l.percentile_us(95.0));
+    println!("Latency p99: {:.3}us", l.percentile_us(99.0));
+    println!("Latency max: {:.3}us", l.percentile_us(100.0));
 }
 
 fn generate_test_data(num_ops: usize) -> (Vec<String>, Vec<String>) {
@@ -102,25 +185,139 @@ fn generate_test_data(num_ops: usize) -> (Vec<String>, Vec<String>) {
     (keys, values)
 }
 
-fn run_benchmark(num_ops: usize) -> Stats {
-    let kv = KVStore::new();
+// Loops kept as in the Go/Zig versions of this benchmark.
+#[allow(clippy::needless_range_loop)]
+fn run_benchmark(num_ops: usize, memory: MemoryConfig) -> Stats {
+    let kv = KVStore::with_config(memory);
     let (keys, values) = generate_test_data(num_ops);
 
     let start = Instant::now();
 
-    for i in 0..num_ops { kv.set(keys[i].clone(), values[i].clone()); }
-    for i in 0..num_ops { kv.get(&keys[i]); }
+    let mut rejected_writes = 0;
+    for i in 0..num_ops {
+        if kv.set(keys[i].clone(), values[i].clone()).is_err() { rejected_writes += 1; }
+    }
+    for i in 0..num_ops { let _ = kv.get(&keys[i]); }
     for i in 0..num_ops / 2 { kv.delete(&keys[i]); }
 
     Stats {
         total_ops: num_ops * 2 + num_ops / 2,
         processing_ns: start.elapsed().as_nanos(),
+        rejected_writes,
+        memory: kv.memory_stats(),
     }
 }
 
+// xorshift64: cheap per-thread randomness for picking keys and read/write mix.
+fn next_rand(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+/// Mixed GET/SET workload from `threads` threads against a pre-populated
+/// store, timing every operation individually. Run with `shards = 1` to see
+/// the cost of a single global lock.
+fn run_concurrent_benchmark(num_ops: usize, threads: usize, shards: usize, read_pct: u32) -> (Stats, Latencies) {
+    let kv = Arc::new(KVStore::with_shards(shards, MemoryConfig::default()));
+    let (keys, values) = generate_test_data(num_ops);
+    for (k, v) in keys.iter().zip(&values) { let _ = kv.set(k.clone(), v.clone()); }
+    let data = Arc::new((keys, values));
+    let barrier = Arc::new(Barrier::new(threads + 1));
+
+    let ops_per_thread = num_ops / threads;
+    let handles: Vec<_> = (0..threads).map(|t| {
+        let (kv, data, barrier) = (Arc::clone(&kv), Arc::clone(&data), Arc::clone(&barrier));
+        thread::spawn(move || {
+            let (keys, values) = &*data;
+            let mut rng = 0x9E37_79B9_7F4A_7C15u64 ^ (t as u64 + 1);
+            let mut lat = Vec::with_capacity(ops_per_thread);
+            let mut reads = 0;
+            barrier.wait();
+            for _ in 0..ops_per_thread {
+                let r = next_rand(&mut rng);
+                let i = (r >> 8) as usize % keys.len();
+                let is_read = (r % 100) < read_pct as u64;
+                let start = Instant::now();
+                if is_read {
+                    let _ = kv.get(&keys[i]);
+                } else {
+                    let _ = kv.set(keys[i].clone(), values[i].clone());
+                }
+                lat.push(start.elapsed().as_nanos() as u64);
+                reads += is_read as usize;
+            }
+            (lat, reads)
+        })
+    }).collect();
+
+    barrier.wait();
+    let start = Instant::now();
+    let mut all = Vec::with_capacity(ops_per_thread * threads);
+    let mut reads = 0;
+    for h in handles {
+        let (lat, r) = h.join().unwrap();
+        all.extend(lat);
+        reads += r;
+    }
+    let processing_ns = start.elapsed().as_nanos();
+    all.sort_unstable();
+
+    let total_ops = all.len();
+    let stats = Stats { total_ops, processing_ns, rejected_writes: 0, memory: kv.memory_stats() };
+    (stats, Latencies { sorted_ns: all, reads, writes: total_ops - reads })
+}
+
+fn serve(addr: &str, memory: MemoryConfig, persistence: Option<PersistenceConfig>) {
+    let store = Arc::new(KVStore::with_shards(DEFAULT_SHARDS, memory));
+    if let Some(config) = persistence {
+        let dir = config.dir.clone();
+        let started = Instant::now();
+        let p = Persistence::restore(config, &store)
+            .unwrap_or_else(|e| { eprintln!("Error: {e}"); std::process::exit(1); });
+        println!("Loaded {} keys from {} in {:.3}s", store.len(), dir.display(), started.elapsed().as_secs_f64());
+        p.start_background(Arc::clone(&store));
+    }
+    store.start_sweeper(store::SWEEP_INTERVAL);
+    let server = Server::bind(addr, store)
+        .unwrap_or_else(|e| { eprintln!("Error: {e}"); std::process::exit(1); });
+    let local = server.local_addr().map(|a| a.to_string()).unwrap_or_else(|_| addr.to_string());
+    println!("Listening on {} (RESP2)", local);
+    server.run();
+}
+
+fn serve_replica(addr: &str, primary: &str, memory: MemoryConfig) {
+    let store = Arc::new(KVStore::with_shards(DEFAULT_SHARDS, memory));
+    store.start_sweeper(store::SWEEP_INTERVAL);
+    let server = Server::bind_replica(addr, store, primary)
+        .unwrap_or_else(|e| { eprintln!("Error: {e}"); std::process::exit(1); });
+    let local = server.local_addr().map(|a| a.to_string()).unwrap_or_else(|_| addr.to_string());
+    println!("Listening on {} (RESP2, read-only replica of {})", local, primary);
+    server.run();
+}
+
 fn main() {
-    let num_ops = parse_args().unwrap_or_else(|e| { eprintln!("Error: {e}"); std::process::exit(1); });
-    print_config(num_ops);
-    let stats = run_benchmark(num_ops);
-    print_stats(&stats);
+    let mode = parse_args().unwrap_or_else(|e| { eprintln!("Error: {e}"); std::process::exit(1); });
+    match mode {
+        Mode::Benchmark(num_ops, memory) => {
+            print_config(num_ops, &memory);
+            let stats = run_benchmark(num_ops, memory);
+            print_stats(&stats);
+        }
+        Mode::Concurrent { num_ops, threads, shards, read_pct } => {
+            println!("Configuration:");
+            println!("  Operations: {}", num_ops);
+            println!("  Store type: In-memory KV Store (concurrent)");
+            println!("  Threads: {}", threads);
+            println!("  Shards: {}", shards);
+            println!("  Read ratio: {}%", read_pct);
+            println!();
+            let (stats, latencies) = run_concurrent_benchmark(num_ops, threads, shards, read_pct);
+            print_stats(&stats);
+            print_latencies(&latencies);
+        }
+        Mode::Serve { addr, memory, persistence } => serve(&addr, memory, persistence),
+        Mode::Replica { addr, primary, memory } => serve_replica(&addr, &primary, memory),
+    }
 }
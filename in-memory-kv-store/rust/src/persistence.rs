@@ -0,0 +1,669 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::resp::{self, Frame};
+use crate::store::{KVStore, SnapshotEntry};
+use crate::value::Value;
+
+// On-disk layout inside the data directory:
+//
+//   dump.kvs               snapshot; its header names the first AOF
+//                          generation that is NOT already folded into it
+//   appendonly.<gen>.aof   mutation logs, replayed in generation order
+//
+// A rewrite rotates to a fresh AOF generation while writers are blocked,
+// writes the snapshot from the captured copy, and only then deletes the
+// older generations. A crash at any point leaves a loadable directory.
+const SNAPSHOT_FILE: &str = "dump.kvs";
+const SNAPSHOT_MAGIC: &str = "KVSNAP";
+const SNAPSHOT_VERSION: &str = "1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FsyncPolicy {
+    Always,
+    #[default]
+    EverySec,
+    Never,
+}
+
+impl FsyncPolicy {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "always" => Ok(Self::Always),
+            "everysec" => Ok(Self::EverySec),
+            "no" | "never" => Ok(Self::Never),
+            _ => Err(format!("invalid fsync policy '{}'", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PersistenceConfig {
+    pub dir: PathBuf,
+    pub fsync: FsyncPolicy,
+    /// How often to take a snapshot and compact the log; zero disables the timer.
+    pub snapshot_interval: Duration,
+    /// The log is also compacted once it exceeds this size and has doubled
+    /// since the last rewrite.
+    pub rewrite_min_size: u64,
+}
+
+impl PersistenceConfig {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            fsync: FsyncPolicy::default(),
+            snapshot_interval: Duration::from_secs(300),
+            rewrite_min_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+pub enum Mutation<'a> {
+    Set { key: &'a str, value: &'a str, expires_at: Option<Instant> },
+    Del { key: &'a str },
+    ExpireAt { key: &'a str, at: Instant },
+    Persist { key: &'a str },
+    /// A typed-value command replayed verbatim, e.g. `RPUSH key a b`.
+    Op { name: &'static str, key: &'a str, args: &'a [&'a str] },
+}
+
+fn to_unix_ms(t: Instant) -> u64 {
+    let now = Instant::now();
+    let wall = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let wall = if t >= now { wall + (t - now) } else { wall.saturating_sub(now - t) };
+    wall.as_millis() as u64
+}
+
+/// Converts a wall-clock deadline back to an `Instant`; `None` if it has passed.
+fn from_unix_ms(ms: u64) -> Option<Instant> {
+    let wall = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let at = Duration::from_millis(ms);
+    (at > wall).then(|| Instant::now() + (at - wall))
+}
+
+fn encode_command(args: &[&[u8]], out: &mut Vec<u8>) {
+    Frame::Array(Some(args.iter().map(|a| Frame::bulk(*a)).collect())).encode(out);
+}
+
+/// A consumer of the store's mutation stream (the AOF, the replication
+/// backlog). `append` is called while the key's shard is write-locked.
+pub trait MutationLog: Send + Sync {
+    fn append(&self, m: &Mutation);
+
+    /// Whether records are currently failing to reach the log.
+    fn failed(&self) -> bool { false }
+}
+
+pub fn encode_mutation(m: &Mutation, out: &mut Vec<u8>) {
+    match m {
+        Mutation::Set { key, value, expires_at: None } => {
+            encode_command(&[b"SET", key.as_bytes(), value.as_bytes()], out)
+        }
+        Mutation::Set { key, value, expires_at: Some(t) } => {
+            let ms = to_unix_ms(*t).to_string();
+            encode_command(&[b"SET", key.as_bytes(), value.as_bytes(), b"PXAT", ms.as_bytes()], out)
+        }
+        Mutation::Del { key } => encode_command(&[b"DEL", key.as_bytes()], out),
+        Mutation::ExpireAt { key, at } => {
+            let ms = to_unix_ms(*at).to_string();
+            encode_command(&[b"PEXPIREAT", key.as_bytes(), ms.as_bytes()], out)
+        }
+        Mutation::Persist { key } => encode_command(&[b"PERSIST", key.as_bytes()], out),
+        Mutation::Op { name, key, args } => {
+            let mut all: Vec<&[u8]> = Vec::with_capacity(args.len() + 2);
+            all.push(name.as_bytes());
+            all.push(key.as_bytes());
+            all.extend(args.iter().map(|a| a.as_bytes()));
+            encode_command(&all, out)
+        }
+    }
+}
+
+fn aof_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("appendonly.{}.aof", gen))
+}
+
+fn list_generations(dir: &Path) -> io::Result<Vec<u64>> {
+    let mut gens = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let gen = name.to_str()
+            .and_then(|n| n.strip_prefix("appendonly."))
+            .and_then(|n| n.strip_suffix(".aof"))
+            .and_then(|n| n.parse::<u64>().ok());
+        if let Some(g) = gen { gens.push(g); }
+    }
+    gens.sort_unstable();
+    Ok(gens)
+}
+
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+struct AofFile {
+    // Shared so the everysec fsync can run without holding the lock.
+    file: Arc<File>,
+    gen: u64,
+    // Bytes of complete records; a failed append is cut back to this.
+    size: u64,
+    dirty: bool,
+}
+
+pub struct Aof {
+    dir: PathBuf,
+    fsync: FsyncPolicy,
+    file: Mutex<AofFile>,
+    // Set by a failed write or fsync; writes are refused until `recover`.
+    failed: AtomicBool,
+}
+
+impl Aof {
+    fn open(dir: &Path, gen: u64, fsync: FsyncPolicy) -> io::Result<Self> {
+        let path = aof_path(dir, gen);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            fsync,
+            file: Mutex::new(AofFile { file: Arc::new(file), gen, size, dirty: false }),
+            failed: AtomicBool::new(false),
+        })
+    }
+
+    /// Flushes appends to disk. Appends carry on meanwhile: only picking
+    /// the file happens under the lock.
+    fn sync(&self) -> io::Result<()> {
+        let file = {
+            let mut f = self.file.lock().unwrap();
+            if !f.dirty { return Ok(()); }
+            f.dirty = false;
+            Arc::clone(&f.file)
+        };
+        file.sync_data()
+    }
+
+    fn fail(&self, e: &io::Error) {
+        if !self.failed.swap(true, Ordering::Relaxed) {
+            eprintln!("aof write error, refusing writes: {e}");
+        }
+    }
+
+    /// After a failure, drops any partial record and checks that the file
+    /// can be synced again; if so, writes are accepted again.
+    fn recover(&self) -> io::Result<()> {
+        let f = self.file.lock().unwrap();
+        f.file.set_len(f.size)?;
+        f.file.sync_data()?;
+        self.failed.store(false, Ordering::Relaxed);
+        eprintln!("aof writable again, accepting writes");
+        Ok(())
+    }
+
+    /// Switches appends to a new, empty generation and returns its number.
+    fn rotate(&self) -> io::Result<u64> {
+        let mut f = self.file.lock().unwrap();
+        f.file.sync_data()?;
+        let gen = f.gen + 1;
+        let file = OpenOptions::new().create(true).append(true).open(aof_path(&self.dir, gen))?;
+        sync_dir(&self.dir)?;
+        *f = AofFile { file: Arc::new(file), gen, size: 0, dirty: false };
+        Ok(gen)
+    }
+
+    fn size(&self) -> u64 {
+        self.file.lock().unwrap().size
+    }
+}
+
+impl MutationLog for Aof {
+    /// Called by the store while it holds its write lock, so log order always
+    /// matches the order mutations were applied. Every record reaches the OS
+    /// immediately; only the fsync is governed by the policy. A failure is
+    /// not returned here but through `failed`, which makes the command layer
+    /// reply with an error and refuse further writes.
+    fn append(&self, m: &Mutation) {
+        let mut buf = Vec::with_capacity(64);
+        encode_mutation(m, &mut buf);
+        let mut f = self.file.lock().unwrap();
+        let res = (&*f.file).write_all(&buf).and_then(|_| match self.fsync {
+            FsyncPolicy::Always => f.file.sync_data(),
+            _ => Ok(()),
+        });
+        match res {
+            Ok(()) => { f.size += buf.len() as u64; f.dirty = self.fsync != FsyncPolicy::Always; }
+            Err(e) => {
+                // Cut off whatever part of the record made it, so later
+                // appends still start on a record boundary.
+                let _ = f.file.set_len(f.size);
+                self.fail(&e);
+            }
+        }
+    }
+
+    fn failed(&self) -> bool {
+        self.failed.load(Ordering::Relaxed)
+    }
+}
+
+fn parse_ms(arg: &[u8]) -> Result<u64, String> {
+    std::str::from_utf8(arg).ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .ok_or_else(|| "invalid timestamp".to_string())
+}
+
+fn utf8(arg: &[u8]) -> Result<String, String> {
+    String::from_utf8(arg.to_vec()).map_err(|_| "invalid UTF-8".to_string())
+}
+
+fn utf8_all(args: &[Vec<u8>]) -> Result<Vec<String>, String> {
+    args.iter().map(|a| utf8(a)).collect()
+}
+
+// Records are replayed straight into the store before the log is attached,
+// so nothing here is written back out. Replicas apply the primary's stream
+// through the same path.
+pub fn apply(store: &KVStore, args: &[Vec<u8>]) -> Result<(), String> {
+    let name = args.first().map(|n| n.to_ascii_uppercase()).unwrap_or_default();
+    match (name.as_slice(), &args[1..]) {
+        (b"SET", [key, value]) => { store.set(utf8(key)?, utf8(value)?).map_err(|e| e.to_string())?; }
+        (b"SET", [key, value, opt, ms]) if opt.eq_ignore_ascii_case(b"PXAT") => {
+            match from_unix_ms(parse_ms(ms)?) {
+                Some(at) => store.set_with_ttl(utf8(key)?, utf8(value)?, at - Instant::now())
+                    .map_err(|e| e.to_string())?,
+                None => { store.delete(&utf8(key)?); }
+            }
+        }
+        (b"DEL", [key]) => { store.delete(&utf8(key)?); }
+        (b"PEXPIREAT", [key, ms]) => match from_unix_ms(parse_ms(ms)?) {
+            Some(at) => { store.expire(&utf8(key)?, at - Instant::now()); }
+            None => { store.delete(&utf8(key)?); }
+        },
+        (b"PERSIST", [key]) => { store.persist(&utf8(key)?); }
+        (b"INCRBY", [key, delta]) => {
+            let delta = utf8(delta)?.parse::<i64>().map_err(|_| "invalid delta".to_string())?;
+            store.incr_by(&utf8(key)?, delta).map_err(|e| e.to_string())?;
+        }
+        (b"LPUSH" | b"RPUSH", [key, values @ ..]) => {
+            store.push(&utf8(key)?, utf8_all(values)?, name == b"LPUSH").map_err(|e| e.to_string())?;
+        }
+        (b"LPOP" | b"RPOP", [key, count]) => {
+            let count = utf8(count)?.parse::<usize>().map_err(|_| "invalid count".to_string())?;
+            store.pop(&utf8(key)?, count, name == b"LPOP").map_err(|e| e.to_string())?;
+        }
+        (b"HSET", [key, pairs @ ..]) if pairs.len() % 2 == 0 => {
+            let pairs = utf8_all(pairs)?;
+            let pairs = pairs.chunks(2).map(|p| (p[0].clone(), p[1].clone())).collect();
+            store.hash_set(&utf8(key)?, pairs).map_err(|e| e.to_string())?;
+        }
+        (b"HDEL", [key, fields @ ..]) => {
+            store.hash_del(&utf8(key)?, &utf8_all(fields)?).map_err(|e| e.to_string())?;
+        }
+        (b"SADD", [key, members @ ..]) => {
+            store.set_add(&utf8(key)?, utf8_all(members)?).map_err(|e| e.to_string())?;
+        }
+        (b"SREM", [key, members @ ..]) => {
+            store.set_remove(&utf8(key)?, &utf8_all(members)?).map_err(|e| e.to_string())?;
+        }
+        _ => return Err(format!("unknown record '{}'", String::from_utf8_lossy(&name))),
+    }
+    Ok(())
+}
+
+/// Replays one log file. A record cut off at the end of the file (a crash
+/// mid-append) is dropped and the file truncated back to the last complete
+/// record; corruption anywhere else is an error.
+fn replay_aof(path: &Path, store: &KVStore) -> io::Result<usize> {
+    let data = fs::read(path)?;
+    let mut pos = 0;
+    let mut count = 0;
+    while pos < data.len() {
+        match resp::parse_command(&data[pos..]) {
+            Ok(Some((args, used))) => {
+                apply(store, &args).map_err(|e| io::Error::new(io::ErrorKind::InvalidData,
+                    format!("{}: {}", path.display(), e)))?;
+                pos += used;
+                count += 1;
+            }
+            Ok(None) => {
+                eprintln!("{}: dropping truncated final record ({} bytes)", path.display(), data.len() - pos);
+                OpenOptions::new().write(true).open(path)?.set_len(pos as u64)?;
+                break;
+            }
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("{}: {} at offset {}", path.display(), e, pos))),
+        }
+    }
+    Ok(count)
+}
+
+/// Loads the snapshot and returns the first AOF generation not contained in it.
+fn load_snapshot(path: &Path, store: &KVStore) -> io::Result<u64> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), msg));
+    let data = fs::read(path)?;
+    let mut records = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let (args, used) = resp::parse_command(&data[pos..])
+            .map_err(|e| invalid(&e))?
+            .ok_or_else(|| invalid("truncated snapshot"))?;
+        records.push(args);
+        pos += used;
+    }
+    let (Some(header), Some(trailer)) = (records.first(), records.last()) else { return Err(invalid("empty snapshot")) };
+    if header.len() != 3 || header[0] != SNAPSHOT_MAGIC.as_bytes() || header[1] != SNAPSHOT_VERSION.as_bytes() {
+        return Err(invalid("bad snapshot header"));
+    }
+    let gen = parse_ms(&header[2]).map_err(|_| invalid("bad snapshot generation"))?;
+    let expected = records.len().saturating_sub(2).to_string();
+    if records.len() < 2 || trailer.len() != 2 || trailer[0] != b"EOF" || trailer[1] != expected.as_bytes() {
+        return Err(invalid("missing snapshot trailer"));
+    }
+    for args in &records[1..records.len() - 1] {
+        apply(store, args).map_err(|e| invalid(&e))?;
+    }
+    Ok(gen)
+}
+
+/// Encodes one key as the commands that rebuild it and returns how many
+/// records were written.
+pub fn encode_entry(e: &SnapshotEntry, out: &mut Vec<u8>) -> usize {
+    let key = e.key.as_str();
+    let (name, args): (&'static str, Vec<&str>) = match &e.value {
+        Value::Str(s) => {
+            encode_mutation(&Mutation::Set { key, value: s, expires_at: e.expires_at }, out);
+            return 1;
+        }
+        Value::List(l) => ("RPUSH", l.iter().map(String::as_str).collect()),
+        Value::Hash(h) => ("HSET", h.iter().flat_map(|(f, v)| [f.as_str(), v.as_str()]).collect()),
+        Value::Set(s) => ("SADD", s.iter().map(String::as_str).collect()),
+    };
+    encode_mutation(&Mutation::Op { name, key, args: &args }, out);
+    match e.expires_at {
+        Some(at) => { encode_mutation(&Mutation::ExpireAt { key, at }, out); 2 }
+        None => 1,
+    }
+}
+
+fn write_snapshot(dir: &Path, gen: u64, entries: &[SnapshotEntry]) -> io::Result<()> {
+    let tmp = dir.join(format!("{}.tmp", SNAPSHOT_FILE));
+    let mut w = BufWriter::new(File::create(&tmp)?);
+    let mut buf = Vec::with_capacity(64 * 1024);
+    let gen_str = gen.to_string();
+    encode_command(&[SNAPSHOT_MAGIC.as_bytes(), SNAPSHOT_VERSION.as_bytes(), gen_str.as_bytes()], &mut buf);
+    let mut records = 0usize;
+    for e in entries {
+        records += encode_entry(e, &mut buf);
+        if buf.len() >= 64 * 1024 { w.write_all(&buf)?; buf.clear(); }
+    }
+    encode_command(&[b"EOF", records.to_string().as_bytes()], &mut buf);
+    w.write_all(&buf)?;
+    w.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(&tmp, dir.join(SNAPSHOT_FILE))?;
+    sync_dir(dir)
+}
+
+pub struct Persistence {
+    config: PersistenceConfig,
+    aof: Arc<Aof>,
+    // (time of last rewrite, log size right after it)
+    last_rewrite: Mutex<(Instant, u64)>,
+}
+
+impl Persistence {
+    /// Restores `store` from the data directory (snapshot, then every newer
+    /// log generation) and attaches the log so later mutations are recorded.
+    pub fn restore(config: PersistenceConfig, store: &KVStore) -> io::Result<Arc<Self>> {
+        fs::create_dir_all(&config.dir)?;
+        let (base, gens) = store.load(|| -> io::Result<_> {
+            let snapshot = config.dir.join(SNAPSHOT_FILE);
+            let base = if snapshot.exists() { load_snapshot(&snapshot, store)? } else { 0 };
+
+            let gens = list_generations(&config.dir)?;
+            for &gen in &gens {
+                let path = aof_path(&config.dir, gen);
+                if gen < base {
+                    // Already folded into the snapshot; left over from a crash mid-rewrite.
+                    fs::remove_file(&path)?;
+                    continue;
+                }
+                replay_aof(&path, store)?;
+            }
+            Ok((base, gens))
+        })?;
+
+        let current = gens.last().copied().unwrap_or(base).max(base);
+        let aof = Arc::new(Aof::open(&config.dir, current, config.fsync)?);
+        let size = aof.size();
+        store.attach_log(aof.clone());
+        Ok(Arc::new(Self { config, aof, last_rewrite: Mutex::new((Instant::now(), size)) }))
+    }
+
+    /// Takes a point-in-time snapshot and drops the log generations it covers.
+    pub fn rewrite(&self, store: &KVStore) -> io::Result<()> {
+        let (entries, gen) = store.snapshot(|| self.aof.rotate());
+        let gen = gen?;
+        write_snapshot(&self.config.dir, gen, &entries)?;
+        for old in list_generations(&self.config.dir)?.into_iter().filter(|&g| g < gen) {
+            fs::remove_file(aof_path(&self.config.dir, old))?;
+        }
+        *self.last_rewrite.lock().unwrap() = (Instant::now(), self.aof.size());
+        Ok(())
+    }
+
+    fn rewrite_due(&self) -> bool {
+        let (at, base_size) = *self.last_rewrite.lock().unwrap();
+        let interval = self.config.snapshot_interval;
+        let size = self.aof.size();
+        (!interval.is_zero() && at.elapsed() >= interval)
+            || (size >= self.config.rewrite_min_size && size >= base_size.saturating_mul(2))
+    }
+
+    /// Background thread for the `everysec` fsync, retrying a failed log
+    /// and periodic rewrites.
+    pub fn start_background(self: &Arc<Self>, store: Arc<KVStore>) -> thread::JoinHandle<()> {
+        let this = Arc::clone(self);
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(1));
+            if this.aof.failed() {
+                if let Err(e) = this.aof.recover() { eprintln!("aof still failing: {e}"); }
+                continue;
+            }
+            if this.config.fsync == FsyncPolicy::EverySec {
+                if let Err(e) = this.aof.sync() { this.aof.fail(&e); }
+            }
+            if this.rewrite_due() {
+                if let Err(e) = this.rewrite(&store) { eprintln!("snapshot error: {e}"); }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn temp_dir() -> PathBuf {
+        static N: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "ikvs-test-{}-{}", std::process::id(), N.fetch_add(1, Ordering::Relaxed)));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn config(dir: &Path) -> PersistenceConfig {
+        PersistenceConfig { fsync: FsyncPolicy::Always, ..PersistenceConfig::new(dir) }
+    }
+
+    #[test]
+    fn test_aof_replay_after_restart() {
+        let dir = temp_dir();
+        {
+            let kv = KVStore::default();
+            Persistence::restore(config(&dir), &kv).unwrap();
+            kv.set("user:1:name".to_string(), "John Doe".to_string()).unwrap();
+            kv.set("user:2:name".to_string(), "Jane".to_string()).unwrap();
+            kv.set_with_ttl("session:1:token".to_string(), "abc".to_string(), Duration::from_secs(60)).unwrap();
+            kv.set_with_ttl("temp:calc:1".to_string(), "x".to_string(), Duration::from_millis(1)).unwrap();
+            kv.delete("user:2:name");
+        }
+        thread::sleep(Duration::from_millis(5));
+
+        let kv = KVStore::default();
+        Persistence::restore(config(&dir), &kv).unwrap();
+        assert_eq!(kv.get("user:1:name").unwrap().as_deref(), Some("John Doe"));
+        assert_eq!(kv.get("user:2:name").unwrap(), None);
+        assert!(matches!(kv.ttl("session:1:token"), crate::store::Ttl::Expires(d) if d > Duration::from_secs(50)));
+        assert_eq!(kv.get("temp:calc:1").unwrap(), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_truncated_final_record_is_dropped() {
+        let dir = temp_dir();
+        {
+            let kv = KVStore::default();
+            Persistence::restore(config(&dir), &kv).unwrap();
+            kv.set("a".to_string(), "1".to_string()).unwrap();
+            kv.set("b".to_string(), "2".to_string()).unwrap();
+        }
+        let path = aof_path(&dir, 0);
+        let full = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(full - 4).unwrap();
+
+        let kv = KVStore::default();
+        Persistence::restore(config(&dir), &kv).unwrap();
+        assert_eq!(kv.get("a").unwrap().as_deref(), Some("1"));
+        assert_eq!(kv.get("b").unwrap(), None);
+
+        // The torn tail was cut off, so new appends land on a clean boundary.
+        kv.set("c".to_string(), "3".to_string()).unwrap();
+        let kv = KVStore::default();
+        Persistence::restore(config(&dir), &kv).unwrap();
+        assert_eq!(kv.get("c").unwrap().as_deref(), Some("3"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_error_refuses_writes_until_recovered() {
+        use crate::command::{self, Session};
+        use crate::replication::{Primary, Role};
+        use crate::server::ServerStats;
+
+        let dir = temp_dir();
+        let kv = KVStore::default();
+        let p = Persistence::restore(config(&dir), &kv).unwrap();
+        let (stats, role) = (ServerStats::new(), Role::Primary(Primary::attach(&kv)));
+        let run = |args: &[&str]| {
+            let args: Vec<Vec<u8>> = args.iter().map(|a| a.as_bytes().to_vec()).collect();
+            let mut out = Vec::new();
+            command::execute(&kv, &stats, &role, &mut Session::default(), &args, &mut out);
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(run(&["SET", "a", "1"]), "+OK\r\n");
+
+        // A full disk: the write that hits it and every later one is refused.
+        let set_file = |file: File| p.aof.file.lock().unwrap().file = Arc::new(file);
+        set_file(OpenOptions::new().append(true).open("/dev/full").unwrap());
+        assert!(run(&["SET", "b", "2"]).starts_with("-MISCONF"));
+        assert!(run(&["DEL", "a"]).starts_with("-MISCONF"));
+        assert_eq!(run(&["GET", "a"]), "$1\r\n1\r\n");
+        assert!(p.aof.recover().is_err());
+
+        set_file(OpenOptions::new().append(true).open(aof_path(&dir, 0)).unwrap());
+        p.aof.recover().unwrap();
+        assert_eq!(run(&["SET", "c", "3"]), "+OK\r\n");
+
+        let kv = KVStore::default();
+        Persistence::restore(config(&dir), &kv).unwrap();
+        assert_eq!(kv.get("a").unwrap().as_deref(), Some("1"));
+        assert_eq!(kv.get("c").unwrap().as_deref(), Some("3"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_replay_ignores_memory_limit() {
+        use crate::eviction::{EvictionPolicy, MemoryConfig};
+
+        let dir = temp_dir();
+        {
+            let kv = KVStore::default();
+            Persistence::restore(config(&dir), &kv).unwrap();
+            for i in 0..10 { kv.set(format!("k{}", i), "12345678".to_string()).unwrap(); }
+            kv.push("queue", vec!["12345678".to_string()], false).unwrap();
+        }
+        for policy in [EvictionPolicy::NoEviction, EvictionPolicy::AllKeysLru] {
+            let kv = KVStore::with_config(MemoryConfig { max_memory: 20, policy });
+            Persistence::restore(config(&dir), &kv).unwrap();
+            assert_eq!(kv.len(), 11);
+            assert_eq!(kv.memory_stats().evicted_keys, 0);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rewrite_compacts_log() {
+        let dir = temp_dir();
+        {
+            let kv = KVStore::default();
+            let p = Persistence::restore(config(&dir), &kv).unwrap();
+            for i in 0..100 { kv.set("counter".to_string(), i.to_string()).unwrap(); }
+            kv.set_with_ttl("cache:page:1".to_string(), "<html>".to_string(), Duration::from_secs(60)).unwrap();
+            p.rewrite(&kv).unwrap();
+            kv.set("after".to_string(), "rewrite".to_string()).unwrap();
+            assert_eq!(list_generations(&dir).unwrap(), vec![1]);
+        }
+
+        let kv = KVStore::default();
+        Persistence::restore(config(&dir), &kv).unwrap();
+        assert_eq!(kv.get("counter").unwrap().as_deref(), Some("99"));
+        assert_eq!(kv.get("after").unwrap().as_deref(), Some("rewrite"));
+        assert!(matches!(kv.ttl("cache:page:1"), crate::store::Ttl::Expires(_)));
+        assert_eq!(kv.len(), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_typed_values_survive_replay_and_rewrite() {
+        let dir = temp_dir();
+        let fill = |kv: &KVStore| {
+            kv.incr_by("hits", 5).unwrap();
+            kv.push("queue", vec!["a".into(), "b".into(), "c".into()], false).unwrap();
+            kv.push("queue", vec!["z".into()], true).unwrap();
+            kv.pop("queue", 1, false).unwrap();
+            kv.hash_set("user:1", vec![("name".into(), "John".into()), ("age".into(), "30".into())]).unwrap();
+            kv.hash_del("user:1", &["age".to_string()]).unwrap();
+            kv.set_add("tags", vec!["x".into(), "y".into()]).unwrap();
+            kv.set_remove("tags", &["x".to_string()]).unwrap();
+            kv.expire("tags", Duration::from_secs(60));
+        };
+        let check = |kv: &KVStore| {
+            assert_eq!(kv.get("hits").unwrap().as_deref(), Some("5"));
+            assert_eq!(kv.range("queue", 0, -1).unwrap(), vec!["z", "a", "b"]);
+            assert_eq!(kv.hash_get_all("user:1").unwrap(), vec![("name".to_string(), "John".to_string())]);
+            assert_eq!(kv.set_members("tags").unwrap(), vec!["y"]);
+            assert!(matches!(kv.ttl("tags"), crate::store::Ttl::Expires(_)));
+        };
+        {
+            let kv = KVStore::default();
+            Persistence::restore(config(&dir), &kv).unwrap();
+            fill(&kv);
+        }
+        let kv = KVStore::default();
+        let p = Persistence::restore(config(&dir), &kv).unwrap();
+        check(&kv);
+
+        p.rewrite(&kv).unwrap();
+        let kv = KVStore::default();
+        Persistence::restore(config(&dir), &kv).unwrap();
+        check(&kv);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,510 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, RwLock};
+
+use crate::resp::Frame;
+
+/// Bytes of undelivered messages a subscriber may have queued, like Redis's
+/// default `client-output-buffer-limit pubsub` hard limit. A subscriber that
+/// falls further behind is disconnected.
+pub const QUEUE_LIMIT: usize = 32 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Message { channel: String, payload: Vec<u8> },
+    PMessage { pattern: String, channel: String, payload: Vec<u8> },
+}
+
+impl Message {
+    pub fn to_frame(&self) -> Frame {
+        let parts: Vec<&[u8]> = match self {
+            Message::Message { channel, payload } => vec![b"message", channel.as_bytes(), payload],
+            Message::PMessage { pattern, channel, payload } => {
+                vec![b"pmessage", pattern.as_bytes(), channel.as_bytes(), payload]
+            }
+        };
+        Frame::Array(Some(parts.into_iter().map(Frame::bulk).collect()))
+    }
+
+    // What the message holds in a subscriber's queue.
+    fn size(&self) -> usize {
+        match self {
+            Message::Message { channel, payload } => channel.len() + payload.len(),
+            Message::PMessage { pattern, channel, payload } => pattern.len() + channel.len() + payload.len(),
+        }
+    }
+}
+
+/// A keyspace change, published when notifications for its class are on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyEvent {
+    Set,
+    Del,
+    Expire,
+    Persist,
+    Expired,
+    Evicted,
+    IncrBy,
+    LPush,
+    RPush,
+    LPop,
+    RPop,
+    HSet,
+    HDel,
+    SAdd,
+    SRem,
+}
+
+impl KeyEvent {
+    pub fn name(self) -> &'static str {
+        match self {
+            KeyEvent::Set => "set",
+            KeyEvent::Del => "del",
+            KeyEvent::Expire => "expire",
+            KeyEvent::Persist => "persist",
+            KeyEvent::Expired => "expired",
+            KeyEvent::Evicted => "evicted",
+            KeyEvent::IncrBy => "incrby",
+            KeyEvent::LPush => "lpush",
+            KeyEvent::RPush => "rpush",
+            KeyEvent::LPop => "lpop",
+            KeyEvent::RPop => "rpop",
+            KeyEvent::HSet => "hset",
+            KeyEvent::HDel => "hdel",
+            KeyEvent::SAdd => "sadd",
+            KeyEvent::SRem => "srem",
+        }
+    }
+
+    fn class(self) -> u32 {
+        match self {
+            KeyEvent::Del | KeyEvent::Expire | KeyEvent::Persist => KeyspaceEvents::GENERIC,
+            KeyEvent::Set | KeyEvent::IncrBy => KeyspaceEvents::STRING,
+            KeyEvent::LPush | KeyEvent::RPush | KeyEvent::LPop | KeyEvent::RPop => KeyspaceEvents::LIST,
+            KeyEvent::HSet | KeyEvent::HDel => KeyspaceEvents::HASH,
+            KeyEvent::SAdd | KeyEvent::SRem => KeyspaceEvents::SET,
+            KeyEvent::Expired => KeyspaceEvents::EXPIRED,
+            KeyEvent::Evicted => KeyspaceEvents::EVICTED,
+        }
+    }
+}
+
+/// Which notifications to publish, in the `notify-keyspace-events` flag
+/// syntax: `K`/`E` pick the channel kind and the rest pick event classes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct KeyspaceEvents(u32);
+
+impl KeyspaceEvents {
+    const KEYSPACE: u32 = 1 << 0;
+    const KEYEVENT: u32 = 1 << 1;
+    const GENERIC: u32 = 1 << 2;
+    const STRING: u32 = 1 << 3;
+    const LIST: u32 = 1 << 4;
+    const HASH: u32 = 1 << 5;
+    const SET: u32 = 1 << 6;
+    const EXPIRED: u32 = 1 << 7;
+    const EVICTED: u32 = 1 << 8;
+    const ALL: u32 = Self::GENERIC | Self::STRING | Self::LIST | Self::HASH | Self::SET
+        | Self::EXPIRED | Self::EVICTED;
+
+    const FLAGS: [(char, u32); 10] = [
+        ('K', Self::KEYSPACE), ('E', Self::KEYEVENT), ('g', Self::GENERIC), ('$', Self::STRING),
+        ('l', Self::LIST), ('h', Self::HASH), ('s', Self::SET), ('x', Self::EXPIRED),
+        ('e', Self::EVICTED), ('A', Self::ALL),
+    ];
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut bits = 0;
+        for c in s.chars() {
+            let (_, bit) = Self::FLAGS.iter().find(|(f, _)| *f == c)
+                .ok_or_else(|| format!("invalid keyspace event flag '{}'", c))?;
+            bits |= bit;
+        }
+        Ok(Self(bits))
+    }
+
+    fn publishes(self, event: KeyEvent) -> bool {
+        self.0 & (Self::KEYSPACE | Self::KEYEVENT) != 0 && self.0 & event.class() != 0
+    }
+}
+
+impl fmt::Display for KeyspaceEvents {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let all = self.0 & Self::ALL == Self::ALL;
+        for (c, bit) in Self::FLAGS {
+            let shown = match c {
+                'A' => all,
+                'K' | 'E' => self.0 & bit != 0,
+                _ => !all && self.0 & bit != 0,
+            };
+            if shown { write!(f, "{}", c)?; }
+        }
+        Ok(())
+    }
+}
+
+/// Redis glob matching: `*`, `?`, `[abc]`, `[a-z]`, `[^x]` and `\` escapes.
+/// On a mismatch only the most recent `*` is retried one character further,
+/// which keeps the worst case at O(pattern × text).
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    fn class(p: &[char], c: char) -> Option<(bool, usize)> {
+        // Returns whether `c` matched and how many pattern chars the class used.
+        let mut i = 1;
+        let negate = p.get(i) == Some(&'^');
+        if negate { i += 1; }
+        let mut matched = false;
+        while i < p.len() && p[i] != ']' {
+            if p[i] == '\\' && i + 1 < p.len() {
+                matched |= p[i + 1] == c;
+                i += 2;
+            } else if i + 2 < p.len() && p[i + 1] == '-' && p[i + 2] != ']' {
+                let (lo, hi) = if p[i] <= p[i + 2] { (p[i], p[i + 2]) } else { (p[i + 2], p[i]) };
+                matched |= (lo..=hi).contains(&c);
+                i += 3;
+            } else {
+                matched |= p[i] == c;
+                i += 1;
+            }
+        }
+        if i >= p.len() { return None; }
+        Some((matched != negate, i + 1))
+    }
+
+    // Matches the single-character token at the front of `p` against `c`,
+    // returning how many pattern chars it used.
+    fn token(p: &[char], c: char) -> Option<usize> {
+        match p[0] {
+            '?' => Some(1),
+            '[' => match class(p, c) {
+                Some((matched, used)) => matched.then_some(used),
+                // An unterminated class matches a literal '['.
+                None => (c == '[').then_some(1),
+            },
+            '\\' if p.len() > 1 => (p[1] == c).then_some(2),
+            literal => (literal == c).then_some(1),
+        }
+    }
+
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = text.chars().collect();
+    let (mut pi, mut ti) = (0, 0);
+    // Pattern position after the last `*` and the text position it resumes at.
+    let mut star = None;
+    while ti < t.len() {
+        if p.get(pi) == Some(&'*') {
+            pi += 1;
+            star = Some((pi, ti));
+            continue;
+        }
+        if let Some(used) = p.get(pi).and_then(|_| token(&p[pi..], t[ti])) {
+            pi += used;
+            ti += 1;
+            continue;
+        }
+        let Some((after, at)) = star else { return false };
+        star = Some((after, at + 1));
+        pi = after;
+        ti = at + 1;
+    }
+    p[pi..].iter().all(|&c| c == '*')
+}
+
+type Subscribers = HashMap<u64, Queue>;
+
+/// The sending side of one subscriber's queue, with its byte count.
+#[derive(Clone)]
+struct Queue {
+    tx: Sender<Message>,
+    state: Arc<QueueState>,
+}
+
+#[derive(Default)]
+struct QueueState {
+    bytes: AtomicUsize,
+    overflowed: AtomicBool,
+}
+
+impl Queue {
+    fn send(&self, msg: Message) -> bool {
+        if self.state.overflowed.load(Ordering::Relaxed) { return false; }
+        let size = msg.size();
+        if self.state.bytes.fetch_add(size, Ordering::Relaxed) + size > QUEUE_LIMIT {
+            self.state.bytes.fetch_sub(size, Ordering::Relaxed);
+            self.state.overflowed.store(true, Ordering::Relaxed);
+            return false;
+        }
+        self.tx.send(msg).is_ok()
+    }
+}
+
+/// The receiving end of a subscriber's queue.
+pub struct Inbox {
+    rx: Receiver<Message>,
+    state: Arc<QueueState>,
+}
+
+impl Inbox {
+    /// Waits for the next message. `None` once the subscriber is dropped or
+    /// its queue has overflowed.
+    pub fn recv(&self) -> Option<Message> {
+        if self.overflowed() { return None; }
+        let msg = self.rx.recv().ok()?;
+        self.state.bytes.fetch_sub(msg.size(), Ordering::Relaxed);
+        Some(msg)
+    }
+
+    /// The next message if one is already queued.
+    pub fn try_recv(&self) -> Option<Message> {
+        let msg = self.rx.try_recv().ok()?;
+        self.state.bytes.fetch_sub(msg.size(), Ordering::Relaxed);
+        Some(msg)
+    }
+
+    /// Whether messages were dropped because more than `QUEUE_LIMIT` bytes
+    /// were waiting. The subscriber's connection should then be closed, as
+    /// it has missed messages.
+    pub fn overflowed(&self) -> bool {
+        self.state.overflowed.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default)]
+struct Registry {
+    channels: HashMap<String, Subscribers>,
+    patterns: HashMap<String, Subscribers>,
+}
+
+/// Channel-based publish/subscribe hub. Publishing never blocks: every
+/// subscriber has its own queue, drained at its own pace and capped at
+/// `QUEUE_LIMIT` bytes.
+#[derive(Default)]
+pub struct PubSub {
+    registry: RwLock<Registry>,
+    next_id: AtomicU64,
+    events: AtomicU32,
+}
+
+impl PubSub {
+    /// Creates a subscriber with no subscriptions and the receiving end of
+    /// its message queue.
+    pub fn subscriber(self: &Arc<Self>) -> (Subscriber, Inbox) {
+        let (tx, rx) = mpsc::channel();
+        let state = Arc::new(QueueState::default());
+        let sub = Subscriber {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            hub: Arc::clone(self),
+            queue: Queue { tx, state: Arc::clone(&state) },
+            channels: HashSet::new(),
+            patterns: HashSet::new(),
+        };
+        (sub, Inbox { rx, state })
+    }
+
+    /// Delivers `payload` to every subscriber of `channel` and of any
+    /// matching pattern. Returns how many deliveries were made.
+    /// A subscriber whose queue is full misses the message and is counted
+    /// out.
+    pub fn publish(&self, channel: &str, payload: &[u8]) -> usize {
+        let registry = self.registry.read().unwrap();
+        let mut delivered = 0;
+        if let Some(subs) = registry.channels.get(channel) {
+            for queue in subs.values() {
+                let msg = Message::Message { channel: channel.to_string(), payload: payload.to_vec() };
+                if queue.send(msg) { delivered += 1; }
+            }
+        }
+        for (pattern, subs) in &registry.patterns {
+            if !glob_match(pattern, channel) { continue; }
+            for queue in subs.values() {
+                let msg = Message::PMessage {
+                    pattern: pattern.clone(),
+                    channel: channel.to_string(),
+                    payload: payload.to_vec(),
+                };
+                if queue.send(msg) { delivered += 1; }
+            }
+        }
+        delivered
+    }
+
+    pub fn keyspace_events(&self) -> KeyspaceEvents {
+        KeyspaceEvents(self.events.load(Ordering::Relaxed))
+    }
+
+    pub fn set_keyspace_events(&self, events: KeyspaceEvents) {
+        self.events.store(events.0, Ordering::Relaxed);
+    }
+
+    /// Publishes `event` on `__keyspace@0__:<key>` and/or
+    /// `__keyevent@0__:<event>`, as configured. Costs one atomic load when
+    /// notifications are off.
+    pub fn notify(&self, event: KeyEvent, key: &str) {
+        let events = self.keyspace_events();
+        if !events.publishes(event) { return; }
+        if events.0 & KeyspaceEvents::KEYSPACE != 0 {
+            self.publish(&format!("__keyspace@0__:{}", key), event.name().as_bytes());
+        }
+        if events.0 & KeyspaceEvents::KEYEVENT != 0 {
+            self.publish(&format!("__keyevent@0__:{}", event.name()), key.as_bytes());
+        }
+    }
+
+    /// Number of channels and patterns with at least one subscriber.
+    pub fn counts(&self) -> (usize, usize) {
+        let registry = self.registry.read().unwrap();
+        (registry.channels.len(), registry.patterns.len())
+    }
+}
+
+/// One subscriber's set of channels and patterns. Dropping it removes all
+/// of its subscriptions, which in turn closes its receiver.
+pub struct Subscriber {
+    id: u64,
+    hub: Arc<PubSub>,
+    queue: Queue,
+    channels: HashSet<String>,
+    patterns: HashSet<String>,
+}
+
+impl Subscriber {
+    /// Subscribes to `channel` and returns the total subscription count.
+    pub fn subscribe(&mut self, channel: &str) -> usize {
+        if self.channels.insert(channel.to_string()) {
+            let mut registry = self.hub.registry.write().unwrap();
+            registry.channels.entry(channel.to_string()).or_default().insert(self.id, self.queue.clone());
+        }
+        self.count()
+    }
+
+    pub fn unsubscribe(&mut self, channel: &str) -> usize {
+        if self.channels.remove(channel) {
+            remove(&mut self.hub.registry.write().unwrap().channels, channel, self.id);
+        }
+        self.count()
+    }
+
+    /// Subscribes to every channel matching the glob `pattern`.
+    pub fn psubscribe(&mut self, pattern: &str) -> usize {
+        if self.patterns.insert(pattern.to_string()) {
+            let mut registry = self.hub.registry.write().unwrap();
+            registry.patterns.entry(pattern.to_string()).or_default().insert(self.id, self.queue.clone());
+        }
+        self.count()
+    }
+
+    pub fn punsubscribe(&mut self, pattern: &str) -> usize {
+        if self.patterns.remove(pattern) {
+            remove(&mut self.hub.registry.write().unwrap().patterns, pattern, self.id);
+        }
+        self.count()
+    }
+
+    pub fn channels(&self) -> Vec<String> {
+        self.channels.iter().cloned().collect()
+    }
+
+    pub fn patterns(&self) -> Vec<String> {
+        self.patterns.iter().cloned().collect()
+    }
+
+    pub fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        let mut registry = self.hub.registry.write().unwrap();
+        for channel in &self.channels { remove(&mut registry.channels, channel, self.id); }
+        for pattern in &self.patterns { remove(&mut registry.patterns, pattern, self.id); }
+    }
+}
+
+fn remove(map: &mut HashMap<String, Subscribers>, name: &str, id: u64) {
+    if let Some(subs) = map.get_mut(name) {
+        subs.remove(&id);
+        if subs.is_empty() { map.remove(name); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("user:*", "user:1:name"));
+        assert!(!glob_match("user:*", "cart:item:1"));
+        assert!(glob_match("cart:item:?", "cart:item:7"));
+        assert!(glob_match("h[ae]llo", "hello") && !glob_match("h[ae]llo", "hillo"));
+        assert!(glob_match("h[^e]llo", "hallo") && !glob_match("h[^e]llo", "hello"));
+        assert!(glob_match("k[0-9]", "k5"));
+        assert!(glob_match("a\\*", "a*") && !glob_match("a\\*", "ab"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("a*b*c", "axxbyyc") && !glob_match("a*b*c", "axxbyy"));
+        assert!(glob_match("*[", "x[") && glob_match("*?", "x") && !glob_match("?*", ""));
+        assert!(glob_match("[é]*", "été"));
+    }
+
+    #[test]
+    fn test_glob_match_many_stars_is_fast() {
+        // Exponential with naive backtracking; a handful of passes here.
+        let pattern = format!("{}b", "a*".repeat(30));
+        let text = "a".repeat(200);
+        let started = std::time::Instant::now();
+        assert!(!glob_match(&pattern, &text));
+        assert!(glob_match(&pattern, &format!("{}b", text)));
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_publish_and_pattern_delivery() {
+        let hub = Arc::new(PubSub::default());
+        let (mut a, rx_a) = hub.subscriber();
+        let (mut b, rx_b) = hub.subscriber();
+        assert_eq!(a.subscribe("news"), 1);
+        assert_eq!(b.psubscribe("n*"), 1);
+
+        assert_eq!(hub.publish("news", b"hi\xff"), 2);
+        assert_eq!(rx_a.try_recv().unwrap(), Message::Message { channel: "news".into(), payload: b"hi\xff".to_vec() });
+        assert!(matches!(rx_b.try_recv().unwrap(), Message::PMessage { pattern, .. } if pattern == "n*"));
+        assert_eq!(hub.publish("other", b"x"), 0);
+
+        assert_eq!(a.unsubscribe("news"), 0);
+        drop(b);
+        assert_eq!(hub.counts(), (0, 0));
+        assert_eq!(hub.publish("news", b"gone"), 0);
+    }
+
+    #[test]
+    fn test_full_queue_overflows() {
+        let hub = Arc::new(PubSub::default());
+        let (mut slow, inbox) = hub.subscriber();
+        let (mut fast, fast_inbox) = hub.subscriber();
+        slow.subscribe("c");
+        fast.subscribe("c");
+        let payload = vec![0u8; QUEUE_LIMIT / 4];
+        for _ in 0..3 {
+            assert_eq!(hub.publish("c", &payload), 2);
+            assert!(fast_inbox.recv().is_some());
+        }
+        // The slow subscriber misses this one and is cut off.
+        assert_eq!(hub.publish("c", &payload), 1);
+        assert!(inbox.overflowed() && !fast_inbox.overflowed());
+        assert!(inbox.recv().is_none());
+        assert_eq!(hub.publish("c", b"small"), 1);
+    }
+
+    #[test]
+    fn test_keyspace_event_flags() {
+        let events = KeyspaceEvents::parse("Kx").unwrap();
+        assert!(events.publishes(KeyEvent::Expired));
+        assert!(!events.publishes(KeyEvent::Set));
+        assert!(!KeyspaceEvents::parse("g$").unwrap().publishes(KeyEvent::Set));
+        assert_eq!(KeyspaceEvents::parse("KEA").unwrap().to_string(), "KEA");
+        assert_eq!(KeyspaceEvents::parse("Eg$").unwrap().to_string(), "Eg$");
+        assert!(KeyspaceEvents::parse("Z").is_err());
+    }
+}
@@ -0,0 +1,615 @@
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, RandomState};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::persistence::{self, encode_entry, encode_mutation, Mutation, MutationLog};
+use crate::resp::{self, CommandParser, Frame};
+use crate::store::KVStore;
+
+// Protocol, modelled on Redis PSYNC:
+//
+//   replica → PSYNC <replid> <offset>      ("? -1" on first contact)
+//   primary → +CONTINUE                    offset still in the backlog
+//           | +FULLRESYNC <replid> <offset> followed by a bulk string of
+//             snapshot records, which the replica applies as they arrive
+//   primary → mutation records, forever
+//
+// Offsets count bytes of the mutation stream, so a replica that applied N
+// bytes can ask to resume at exactly N after a reconnect. A record the
+// replica cannot apply means its copy has diverged: it drops the link and
+// asks for a full sync instead.
+const BACKLOG_SIZE: usize = 1 << 20;
+const MAX_CHUNK: usize = 64 * 1024;
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const RECONNECT_DELAY: Duration = Duration::from_millis(200);
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+struct BacklogState {
+    buf: VecDeque<u8>,
+    // Stream offset of `buf[0]`.
+    start: u64,
+}
+
+impl BacklogState {
+    fn end(&self) -> u64 {
+        self.start + self.buf.len() as u64
+    }
+}
+
+/// The most recent stretch of the mutation stream, kept so replicas can
+/// resume without a full sync. Nothing is recorded until the first replica
+/// asks for it.
+pub struct Backlog {
+    active: AtomicBool,
+    state: Mutex<BacklogState>,
+    grew: Condvar,
+    capacity: usize,
+}
+
+impl Backlog {
+    fn new(capacity: usize) -> Self {
+        Self {
+            active: AtomicBool::new(false),
+            state: Mutex::new(BacklogState { buf: VecDeque::new(), start: 0 }),
+            grew: Condvar::new(),
+            capacity,
+        }
+    }
+
+    /// Starts recording and returns the current offset. Called with every
+    /// shard read-locked, so no mutation can straddle the switch.
+    fn activate(&self) -> u64 {
+        self.active.store(true, Ordering::Relaxed);
+        self.state.lock().unwrap().end()
+    }
+
+    fn offset(&self) -> u64 {
+        self.state.lock().unwrap().end()
+    }
+
+    fn contains(&self, offset: u64) -> bool {
+        let s = self.state.lock().unwrap();
+        self.active.load(Ordering::Relaxed) && s.start <= offset && offset <= s.end()
+    }
+
+    /// Waits up to `timeout` for bytes past `offset` and returns them (empty
+    /// on timeout). `None` means `offset` has already been overwritten.
+    fn read_from(&self, offset: u64, timeout: Duration) -> Option<Vec<u8>> {
+        let s = self.state.lock().unwrap();
+        let (s, _) = self.grew.wait_timeout_while(s, timeout, |s| s.end() == offset).unwrap();
+        if offset < s.start || offset > s.end() { return None; }
+        let skip = (offset - s.start) as usize;
+        Some(s.buf.range(skip..).take(MAX_CHUNK).copied().collect())
+    }
+}
+
+impl MutationLog for Backlog {
+    fn append(&self, m: &Mutation) {
+        // `active` only flips while all shards are read-locked, and we run
+        // under a shard write lock, so this unlocked check cannot race it.
+        if !self.active.load(Ordering::Relaxed) { return; }
+        let mut bytes = Vec::with_capacity(64);
+        encode_mutation(m, &mut bytes);
+        let mut s = self.state.lock().unwrap();
+        s.buf.extend(bytes);
+        let excess = s.buf.len().saturating_sub(self.capacity);
+        if excess > 0 {
+            s.buf.drain(..excess);
+            s.start += excess as u64;
+        }
+        self.grew.notify_all();
+    }
+}
+
+fn new_replid() -> String {
+    let seed = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_nanos();
+    let (a, b) = (RandomState::new().hash_one(seed), RandomState::new().hash_one(seed));
+    format!("{:016x}{:016x}", a, b)
+}
+
+pub struct Primary {
+    replid: String,
+    backlog: Arc<Backlog>,
+    connected: AtomicUsize,
+    full_syncs: AtomicU64,
+    partial_syncs: AtomicU64,
+}
+
+impl Primary {
+    /// Attaches a replication backlog to `store`.
+    pub fn attach(store: &KVStore) -> Arc<Self> {
+        let backlog = Arc::new(Backlog::new(BACKLOG_SIZE));
+        store.attach_log(backlog.clone());
+        Arc::new(Self {
+            replid: new_replid(),
+            backlog,
+            connected: AtomicUsize::new(0),
+            full_syncs: AtomicU64::new(0),
+            partial_syncs: AtomicU64::new(0),
+        })
+    }
+
+    /// Takes over a connection that sent `PSYNC` and streams mutations to it
+    /// until the replica goes away or falls behind the backlog.
+    pub fn serve_replica(&self, store: &KVStore, mut stream: TcpStream, args: &[Vec<u8>]) -> io::Result<()> {
+        let [_, replid, offset] = args else {
+            stream.write_all(b"-ERR wrong number of arguments for 'psync' command\r\n")?;
+            return Ok(());
+        };
+        let offset = std::str::from_utf8(offset).ok().and_then(|o| o.parse::<u64>().ok());
+        let mut offset = match offset {
+            Some(o) if replid.as_slice() == self.replid.as_bytes() && self.backlog.contains(o) => {
+                stream.write_all(b"+CONTINUE\r\n")?;
+                self.partial_syncs.fetch_add(1, Ordering::Relaxed);
+                o
+            }
+            _ => {
+                let (entries, offset) = store.snapshot(|| self.backlog.activate());
+                let mut payload = Vec::new();
+                for e in &entries { encode_entry(e, &mut payload); }
+                drop(entries);
+                let header = format!("+FULLRESYNC {} {}\r\n${}\r\n", self.replid, offset, payload.len());
+                stream.write_all(header.as_bytes())?;
+                stream.write_all(&payload)?;
+                stream.write_all(b"\r\n")?;
+                self.full_syncs.fetch_add(1, Ordering::Relaxed);
+                offset
+            }
+        };
+
+        // The replica never sends anything after PSYNC, so EOF on the read
+        // side is how we notice it has gone while the stream is idle.
+        let closed = Arc::new(AtomicBool::new(false));
+        let mut reader = stream.try_clone()?;
+        let flag = Arc::clone(&closed);
+        thread::spawn(move || {
+            let mut buf = [0u8; 64];
+            while matches!(reader.read(&mut buf), Ok(n) if n > 0) {}
+            flag.store(true, Ordering::Relaxed);
+        });
+
+        self.connected.fetch_add(1, Ordering::Relaxed);
+        let result = loop {
+            if closed.load(Ordering::Relaxed) { break Ok(()); }
+            let Some(bytes) = self.backlog.read_from(offset, POLL_INTERVAL) else {
+                break Err(invalid("replica fell behind the backlog"));
+            };
+            if bytes.is_empty() { continue; }
+            if let Err(e) = stream.write_all(&bytes) { break Err(e); }
+            offset += bytes.len() as u64;
+        };
+        self.connected.fetch_sub(1, Ordering::Relaxed);
+        let _ = stream.shutdown(Shutdown::Both);
+        result
+    }
+
+    pub fn info(&self) -> String {
+        format!(
+            "role:master\r\nconnected_slaves:{}\r\nmaster_replid:{}\r\nmaster_repl_offset:{}\r\nsync_full:{}\r\nsync_partial_ok:{}\r\n",
+            self.connected.load(Ordering::Relaxed),
+            self.replid,
+            self.backlog.offset(),
+            self.full_syncs.load(Ordering::Relaxed),
+            self.partial_syncs.load(Ordering::Relaxed),
+        )
+    }
+}
+
+struct Link {
+    replid: Option<String>,
+    offset: u64,
+    stream: Option<TcpStream>,
+}
+
+/// Follows a primary: one full sync, then the mutation stream, reconnecting
+/// and resuming from the last applied offset whenever the link drops.
+pub struct Replica {
+    primary: String,
+    link: Mutex<Link>,
+    link_up: AtomicBool,
+    // Set during a full sync, while the store holds a partial copy.
+    loading: AtomicBool,
+}
+
+impl Replica {
+    /// Starts the sync thread. It holds only a weak reference to `store`, so
+    /// it exits once the store is dropped.
+    pub fn start(store: &Arc<KVStore>, primary: impl Into<String>) -> Arc<Self> {
+        let replica = Arc::new(Self {
+            primary: primary.into(),
+            link: Mutex::new(Link { replid: None, offset: 0, stream: None }),
+            link_up: AtomicBool::new(false),
+            loading: AtomicBool::new(false),
+        });
+        let weak = Arc::downgrade(store);
+        let this = Arc::clone(&replica);
+        thread::spawn(move || loop {
+            if weak.strong_count() == 0 { return; }
+            let result = this.sync(&weak);
+            if this.link_up.swap(false, Ordering::Relaxed) {
+                if let Err(e) = result { eprintln!("replication: lost link to {}: {e}", this.primary); }
+            }
+            this.link.lock().unwrap().stream = None;
+            thread::sleep(RECONNECT_DELAY);
+        });
+        replica
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.link.lock().unwrap().offset
+    }
+
+    pub fn is_linked(&self) -> bool {
+        self.link_up.load(Ordering::Relaxed)
+    }
+
+    pub fn is_loading(&self) -> bool {
+        self.loading.load(Ordering::Relaxed)
+    }
+
+    /// Drops the current connection; the sync thread reconnects on its own.
+    #[cfg(test)]
+    fn disconnect(&self) {
+        if let Some(stream) = &self.link.lock().unwrap().stream {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    pub fn info(&self) -> String {
+        format!(
+            "role:slave\r\nmaster_host:{}\r\nmaster_link_status:{}\r\nslave_repl_offset:{}\r\n",
+            self.primary,
+            if self.is_linked() { "up" } else { "down" },
+            self.offset(),
+        )
+    }
+
+    fn sync(&self, store: &Weak<KVStore>) -> io::Result<()> {
+        let mut stream = TcpStream::connect(&self.primary)?;
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        let (replid, offset) = {
+            let mut link = self.link.lock().unwrap();
+            link.stream = Some(stream.try_clone()?);
+            match &link.replid {
+                Some(id) => (id.clone(), link.offset.to_string()),
+                None => ("?".to_string(), "-1".to_string()),
+            }
+        };
+        let mut req = Vec::new();
+        Frame::Array(Some(["PSYNC", &replid, &offset].into_iter().map(Frame::bulk).collect())).encode(&mut req);
+        stream.write_all(&req)?;
+
+        let mut buf = Vec::new();
+        match read_frame(&mut stream, &mut buf, store)? {
+            Frame::Simple(s) if s == "CONTINUE" => {}
+            Frame::Simple(s) if s.starts_with("FULLRESYNC ") => {
+                let mut parts = s.split(' ').skip(1);
+                let (Some(id), Some(offset)) = (parts.next(), parts.next().and_then(|o| o.parse::<u64>().ok())) else {
+                    return Err(invalid(format!("bad FULLRESYNC reply '{}'", s)));
+                };
+                let len = read_snapshot_len(&mut stream, &mut buf, store)?;
+                let Some(strong) = store.upgrade() else { return Ok(()) };
+                self.link.lock().unwrap().replid = None;
+                self.loading.store(true, Ordering::Relaxed);
+                let loaded = strong.load(|| {
+                    strong.clear();
+                    self.load_snapshot(&mut stream, &mut buf, len, &strong, store)
+                });
+                self.loading.store(false, Ordering::Relaxed);
+                loaded?;
+                let mut link = self.link.lock().unwrap();
+                link.replid = Some(id.to_string());
+                link.offset = offset;
+            }
+            Frame::Error(e) => return Err(io::Error::other(e)),
+            other => return Err(invalid(format!("unexpected PSYNC reply {:?}", other))),
+        }
+        self.link_up.store(true, Ordering::Relaxed);
+
+        // The offset only moves past whole commands, so a resync after a
+        // drop mid-command starts that command again.
+        let mut parser = CommandParser::default();
+        let mut partial = 0;
+        let mut chunk = [0u8; 16 * 1024];
+        loop {
+            let (mut consumed, mut applied) = (0, 0);
+            {
+                let Some(store) = store.upgrade() else { return Ok(()) };
+                loop {
+                    let (command, used) = parser.parse(&buf[consumed..]).map_err(invalid)?;
+                    consumed += used;
+                    partial += used;
+                    let Some(args) = command else { break };
+                    self.apply(&store, &args)?;
+                    applied += std::mem::take(&mut partial);
+                }
+            }
+            buf.drain(..consumed);
+            self.link.lock().unwrap().offset += applied as u64;
+            read_more(&mut stream, &mut chunk, &mut buf, store)?;
+        }
+    }
+}
+
+impl Replica {
+    /// Applies the `len` bytes of snapshot records that follow the
+    /// FULLRESYNC reply as they arrive, so the snapshot is never held whole.
+    fn load_snapshot(
+        &self,
+        stream: &mut TcpStream,
+        buf: &mut Vec<u8>,
+        len: u64,
+        store: &KVStore,
+        weak: &Weak<KVStore>,
+    ) -> io::Result<()> {
+        let mut parser = CommandParser::default();
+        let mut remaining = len;
+        let mut chunk = [0u8; 16 * 1024];
+        while remaining > 0 {
+            let available = buf.len().min(usize::try_from(remaining).unwrap_or(usize::MAX));
+            let mut consumed = 0;
+            loop {
+                let (command, used) = parser.parse(&buf[consumed..available]).map_err(invalid)?;
+                consumed += used;
+                let Some(args) = command else { break };
+                self.apply(store, &args)?;
+            }
+            buf.drain(..consumed);
+            remaining -= consumed as u64;
+            if remaining > 0 {
+                if consumed == 0 && available as u64 == remaining { return Err(invalid("truncated snapshot")); }
+                read_more(stream, &mut chunk, buf, weak)?;
+            }
+        }
+        while buf.len() < 2 { read_more(stream, &mut chunk, buf, weak)?; }
+        if &buf[..2] != b"\r\n" { return Err(invalid("expected CRLF after snapshot")); }
+        buf.drain(..2);
+        Ok(())
+    }
+
+    /// A record that fails to apply means this copy no longer matches the
+    /// primary's, so the next connection asks for a full sync.
+    fn apply(&self, store: &KVStore, args: &[Vec<u8>]) -> io::Result<()> {
+        persistence::apply(store, args).map_err(|e| {
+            self.link.lock().unwrap().replid = None;
+            invalid(format!("failed to apply {}: {e}", String::from_utf8_lossy(&args[0])))
+        })
+    }
+}
+
+/// Reads the `$<len>` header of the snapshot that follows FULLRESYNC.
+fn read_snapshot_len(stream: &mut TcpStream, buf: &mut Vec<u8>, store: &Weak<KVStore>) -> io::Result<u64> {
+    let mut chunk = [0u8; 64];
+    loop {
+        if let Some(end) = buf.windows(2).position(|w| w == b"\r\n") {
+            let len = std::str::from_utf8(&buf[..end]).ok()
+                .and_then(|line| line.strip_prefix('$'))
+                .and_then(|n| n.parse::<u64>().ok())
+                .ok_or_else(|| invalid("expected snapshot payload"))?;
+            buf.drain(..end + 2);
+            return Ok(len);
+        }
+        read_more(stream, &mut chunk, buf, store)?;
+    }
+}
+
+/// Reads at least one byte into `buf`, waking up periodically to check the
+/// store still exists.
+fn read_more(stream: &mut TcpStream, chunk: &mut [u8], buf: &mut Vec<u8>, store: &Weak<KVStore>) -> io::Result<()> {
+    loop {
+        match stream.read(chunk) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => { buf.extend_from_slice(&chunk[..n]); return Ok(()); }
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                if store.strong_count() == 0 { return Err(io::ErrorKind::NotConnected.into()); }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn read_frame(stream: &mut TcpStream, buf: &mut Vec<u8>, store: &Weak<KVStore>) -> io::Result<Frame> {
+    let mut chunk = [0u8; 16 * 1024];
+    loop {
+        if let Some((frame, used)) = resp::parse_frame(buf).map_err(invalid)? {
+            buf.drain(..used);
+            return Ok(frame);
+        }
+        read_more(stream, &mut chunk, buf, store)?;
+    }
+}
+
+/// What this server is in a replication setup.
+pub enum Role {
+    Primary(Arc<Primary>),
+    Replica(Arc<Replica>),
+}
+
+impl Role {
+    pub fn is_replica(&self) -> bool {
+        matches!(self, Role::Replica(_))
+    }
+
+    /// True while a replica is loading a full sync and its keyspace is partial.
+    pub fn is_loading(&self) -> bool {
+        matches!(self, Role::Replica(r) if r.is_loading())
+    }
+
+    pub fn info(&self) -> String {
+        match self {
+            Role::Primary(p) => p.info(),
+            Role::Replica(r) => r.info(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::time::Instant;
+
+    fn wait_for(what: &str, cond: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !cond() {
+            assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    // A bare PSYNC listener, without the command server in front of it.
+    fn start_primary(store: &Arc<KVStore>) -> (Arc<Primary>, String) {
+        let primary = Primary::attach(store);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (p, store) = (Arc::clone(&primary), Arc::clone(store));
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let (p, store) = (Arc::clone(&p), Arc::clone(&store));
+                thread::spawn(move || {
+                    let mut buf = Vec::new();
+                    let mut chunk = [0u8; 1024];
+                    let args = loop {
+                        if let Some((args, _)) = resp::parse_command(&buf).unwrap() { break args; }
+                        let n = stream.read(&mut chunk).unwrap();
+                        buf.extend_from_slice(&chunk[..n]);
+                    };
+                    let _ = p.serve_replica(&store, stream, &args);
+                });
+            }
+        });
+        (primary, addr)
+    }
+
+    #[test]
+    fn test_full_sync_then_stream() {
+        let primary_store = Arc::new(KVStore::with_shards(4, Default::default()));
+        primary_store.set("user:1:name".to_string(), "John".to_string()).unwrap();
+        primary_store.push("queue", vec!["a".to_string(), "b".to_string()], false).unwrap();
+        let (_primary, addr) = start_primary(&primary_store);
+
+        let replica_store = Arc::new(KVStore::default());
+        replica_store.set("stale".to_string(), "x".to_string()).unwrap();
+        let replica = Replica::start(&replica_store, addr);
+        wait_for("full sync", || replica.is_linked());
+        assert!(!replica_store.exists("stale"));
+        assert_eq!(replica_store.get("user:1:name").unwrap().as_deref(), Some("John"));
+        assert_eq!(replica_store.range("queue", 0, -1).unwrap(), vec!["a", "b"]);
+
+        primary_store.set_with_ttl("session:1".to_string(), "t".to_string(), Duration::from_secs(60)).unwrap();
+        primary_store.delete("user:1:name");
+        primary_store.incr_by("hits", 3).unwrap();
+        wait_for("stream", || replica_store.get("hits").unwrap().is_some());
+        assert!(!replica_store.exists("user:1:name"));
+        assert!(matches!(replica_store.ttl("session:1"), crate::store::Ttl::Expires(_)));
+    }
+
+    #[test]
+    fn test_replica_resumes_from_offset() {
+        let primary_store = Arc::new(KVStore::default());
+        let (primary, addr) = start_primary(&primary_store);
+        let replica_store = Arc::new(KVStore::default());
+        let replica = Replica::start(&replica_store, addr);
+        wait_for("full sync", || replica.is_linked());
+
+        primary_store.set("a".to_string(), "1".to_string()).unwrap();
+        wait_for("first write", || replica_store.exists("a"));
+
+        replica.disconnect();
+        wait_for("link down", || !replica.is_linked());
+        primary_store.set("b".to_string(), "2".to_string()).unwrap();
+        primary_store.delete("a");
+
+        wait_for("catch-up", || replica_store.exists("b") && !replica_store.exists("a"));
+        assert_eq!(primary.full_syncs.load(Ordering::Relaxed), 1);
+        assert_eq!(primary.partial_syncs.load(Ordering::Relaxed), 1);
+        assert_eq!(replica.offset(), primary.backlog.offset());
+    }
+
+    #[test]
+    fn test_apply_failure_forces_full_sync() {
+        let primary_store = Arc::new(KVStore::default());
+        primary_store.set("hits".to_string(), "1".to_string()).unwrap();
+        let (primary, addr) = start_primary(&primary_store);
+        let replica_store = Arc::new(KVStore::default());
+        let replica = Replica::start(&replica_store, addr);
+        wait_for("full sync", || replica.is_linked());
+
+        // The replica's copy diverges, so the next INCRBY cannot apply.
+        replica_store.set("hits".to_string(), "x".to_string()).unwrap();
+        primary_store.incr_by("hits", 1).unwrap();
+        wait_for("resync", || replica_store.get("hits").unwrap().as_deref() == Some("2"));
+        assert_eq!(primary.full_syncs.load(Ordering::Relaxed), 2);
+        assert_eq!(primary.partial_syncs.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_snapshot_is_applied_as_it_arrives() {
+        use crate::command::{self, Session};
+        use crate::server::ServerStats;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let replica_store = Arc::new(KVStore::default());
+        replica_store.set("stale".to_string(), "x".to_string()).unwrap();
+        let replica = Replica::start(&replica_store, addr);
+        let (mut conn, _) = listener.accept().unwrap();
+
+        let mut payload = Vec::new();
+        for i in 0..2000 {
+            let entry = crate::store::SnapshotEntry {
+                key: format!("key:{}", i), value: crate::value::Value::Str("v".repeat(100)), expires_at: None,
+            };
+            encode_entry(&entry, &mut payload);
+        }
+        let (head, tail) = payload.split_at(payload.len() / 2);
+        write!(conn, "+FULLRESYNC {} 0\r\n${}\r\n", "a".repeat(32), payload.len()).unwrap();
+        conn.write_all(head).unwrap();
+
+        // Half the snapshot is in: reads are refused rather than answered
+        // from a partial keyspace.
+        let role = Role::Replica(Arc::clone(&replica));
+        let run = |args: &[&str]| {
+            let args: Vec<Vec<u8>> = args.iter().map(|a| a.as_bytes().to_vec()).collect();
+            let mut out = Vec::new();
+            command::execute(&replica_store, &ServerStats::new(), &role, &mut Session::default(), &args, &mut out);
+            String::from_utf8(out).unwrap()
+        };
+        wait_for("partial load", || replica_store.exists("key:0"));
+        assert!(replica.is_loading());
+        assert!(run(&["GET", "key:0"]).starts_with("-LOADING"));
+        assert_eq!(run(&["PING"]), "+PONG\r\n");
+
+        conn.write_all(tail).unwrap();
+        conn.write_all(b"\r\n").unwrap();
+        wait_for("full sync", || replica.is_linked());
+        assert!(!replica.is_loading());
+        assert_eq!(replica_store.len(), 2000);
+        assert!(!replica_store.exists("stale"));
+        assert_eq!(run(&["GET", "key:1999"]), format!("$100\r\n{}\r\n", "v".repeat(100)));
+    }
+
+    #[test]
+    fn test_backlog_trims_and_reports_lag() {
+        let backlog = Backlog::new(64);
+        backlog.append(&Mutation::Del { key: "ignored" });
+        assert_eq!(backlog.activate(), 0);
+        for i in 0..10 { backlog.append(&Mutation::Del { key: &format!("key:{}", i) }); }
+        let end = backlog.offset();
+        assert!(end > 64);
+        assert!(!backlog.contains(0));
+        assert!(backlog.contains(end - 10));
+        assert_eq!(backlog.read_from(0, Duration::ZERO), None);
+        assert_eq!(backlog.read_from(end - 10, Duration::ZERO).unwrap().len(), 10);
+        assert!(backlog.read_from(end, Duration::ZERO).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,252 @@
+// RESP2 wire format: https://redis.io/docs/reference/protocol-spec/
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Frame>>),
+}
+
+impl Frame {
+    pub fn ok() -> Self { Frame::Simple("OK".to_string()) }
+    pub fn null() -> Self { Frame::Bulk(None) }
+    pub fn error(msg: impl Into<String>) -> Self { Frame::Error(msg.into()) }
+    pub fn bulk(data: impl Into<Vec<u8>>) -> Self { Frame::Bulk(Some(data.into())) }
+
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Frame::Simple(s) => { out.push(b'+'); out.extend_from_slice(s.as_bytes()); out.extend_from_slice(b"\r\n"); }
+            Frame::Error(s) => { out.push(b'-'); out.extend_from_slice(s.as_bytes()); out.extend_from_slice(b"\r\n"); }
+            Frame::Integer(n) => { out.extend_from_slice(format!(":{}\r\n", n).as_bytes()); }
+            Frame::Bulk(None) => out.extend_from_slice(b"$-1\r\n"),
+            Frame::Bulk(Some(data)) => {
+                out.extend_from_slice(format!("${}\r\n", data.len()).as_bytes());
+                out.extend_from_slice(data);
+                out.extend_from_slice(b"\r\n");
+            }
+            Frame::Array(None) => out.extend_from_slice(b"*-1\r\n"),
+            Frame::Array(Some(items)) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items { item.encode(out); }
+            }
+        }
+    }
+}
+
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+// Limits from Redis: a line (inline command or a length header) must end
+// within 64 KB, a command has at most 1M arguments, and replies nest at
+// most this deep, so a hostile peer cannot grow the buffer or the stack
+// without bound.
+const MAX_INLINE_LEN: usize = 64 * 1024;
+const MAX_ARGS: usize = 1024 * 1024;
+const MAX_DEPTH: usize = 32;
+
+fn find_crlf(buf: &[u8]) -> Option<usize> {
+    buf.windows(2).position(|w| w == b"\r\n")
+}
+
+/// Finds the CRLF ending the line at the front of `buf`; an error once the
+/// line is longer than `MAX_INLINE_LEN` without one.
+fn line_end(buf: &[u8]) -> Result<Option<usize>, String> {
+    let window = &buf[..buf.len().min(MAX_INLINE_LEN + 2)];
+    match find_crlf(window) {
+        None if window.len() > MAX_INLINE_LEN => Err("Protocol error: too big inline request".to_string()),
+        end => Ok(end),
+    }
+}
+
+fn parse_int(line: &[u8]) -> Result<i64, String> {
+    std::str::from_utf8(line).ok()
+        .and_then(|s| s.parse::<i64>().ok())
+        .ok_or_else(|| "Protocol error: invalid integer".to_string())
+}
+
+/// Parses one frame from the front of `buf`.
+/// Returns `Ok(None)` when more bytes are needed, otherwise the frame and the
+/// number of bytes it consumed.
+pub fn parse_frame(buf: &[u8]) -> Result<Option<(Frame, usize)>, String> {
+    parse_nested(buf, 0)
+}
+
+fn parse_nested(buf: &[u8], depth: usize) -> Result<Option<(Frame, usize)>, String> {
+    let Some(&tag) = buf.first() else { return Ok(None) };
+    let Some(end) = line_end(buf)? else { return Ok(None) };
+    let line = &buf[1..end];
+    let mut pos = end + 2;
+    let frame = match tag {
+        b'+' => Frame::Simple(String::from_utf8_lossy(line).into_owned()),
+        b'-' => Frame::Error(String::from_utf8_lossy(line).into_owned()),
+        b':' => Frame::Integer(parse_int(line)?),
+        b'$' => {
+            let len = parse_int(line)?;
+            if len < 0 { return Ok(Some((Frame::Bulk(None), pos))); }
+            let len = len as usize;
+            if len > MAX_BULK_LEN { return Err("Protocol error: invalid bulk length".to_string()); }
+            if buf.len() < pos + len + 2 { return Ok(None); }
+            if &buf[pos + len..pos + len + 2] != b"\r\n" {
+                return Err("Protocol error: expected CRLF after bulk".to_string());
+            }
+            let data = buf[pos..pos + len].to_vec();
+            pos += len + 2;
+            Frame::Bulk(Some(data))
+        }
+        b'*' => {
+            let count = parse_int(line)?;
+            if count < 0 { return Ok(Some((Frame::Array(None), pos))); }
+            if depth >= MAX_DEPTH { return Err("Protocol error: too deeply nested reply".to_string()); }
+            let mut items = Vec::with_capacity((count as usize).min(1024));
+            for _ in 0..count {
+                match parse_nested(&buf[pos..], depth + 1)? {
+                    Some((item, used)) => { items.push(item); pos += used; }
+                    None => return Ok(None),
+                }
+            }
+            Frame::Array(Some(items))
+        }
+        _ => return Err(format!("Protocol error: unexpected byte '{}'", tag as char)),
+    };
+    Ok(Some((frame, pos)))
+}
+
+pub type Args = Vec<Vec<u8>>;
+
+/// Parses one client command from a buffer holding it whole, e.g. a log
+/// file or a snapshot. See `CommandParser` for input arriving in pieces.
+pub fn parse_command(buf: &[u8]) -> Result<Option<(Args, usize)>, String> {
+    match CommandParser::default().parse(buf)? {
+        (Some(args), used) => Ok(Some((args, used))),
+        (None, _) => Ok(None),
+    }
+}
+
+/// Parses client commands from a connection: either a RESP array of bulk
+/// strings (what client libraries send) or an inline whitespace-separated
+/// line (telnet). Arguments are taken out of the buffer as soon as they are
+/// complete, so a command arriving over many reads is parsed once rather
+/// than again from its start after every read.
+#[derive(Default)]
+pub struct CommandParser {
+    // Arguments of the array command in progress, and how many are missing.
+    args: Args,
+    missing: usize,
+}
+
+impl CommandParser {
+    /// Parses from the front of `buf`. Returns the command once it is
+    /// complete, and the bytes consumed, which the caller must drop from
+    /// `buf` either way.
+    pub fn parse(&mut self, buf: &[u8]) -> Result<(Option<Args>, usize), String> {
+        let mut pos = 0;
+        if self.missing == 0 {
+            if buf.first() != Some(&b'*') { return parse_inline(buf); }
+            let Some(end) = line_end(buf)? else { return Ok((None, 0)) };
+            let count = parse_int(&buf[1..end])?;
+            if count < 0 { return Err("Protocol error: expected array of bulk strings".to_string()); }
+            if count as u64 > MAX_ARGS as u64 { return Err("Protocol error: invalid multibulk length".to_string()); }
+            pos = end + 2;
+            if count == 0 { return Ok((Some(Vec::new()), pos)); }
+            self.missing = count as usize;
+            self.args = Vec::with_capacity(self.missing.min(1024));
+        }
+        while self.missing > 0 {
+            let rest = &buf[pos..];
+            let Some(&tag) = rest.first() else { return Ok((None, pos)) };
+            if tag != b'$' { return Err("Protocol error: expected bulk string".to_string()); }
+            let Some(end) = line_end(rest)? else { return Ok((None, pos)) };
+            let len = parse_int(&rest[1..end])?;
+            if len < 0 || len as u64 > MAX_BULK_LEN as u64 {
+                return Err("Protocol error: invalid bulk length".to_string());
+            }
+            let (start, len) = (end + 2, len as usize);
+            if rest.len() < start + len + 2 { return Ok((None, pos)); }
+            if &rest[start + len..start + len + 2] != b"\r\n" {
+                return Err("Protocol error: expected CRLF after bulk".to_string());
+            }
+            self.args.push(rest[start..start + len].to_vec());
+            self.missing -= 1;
+            pos += start + len + 2;
+        }
+        Ok((Some(std::mem::take(&mut self.args)), pos))
+    }
+}
+
+fn parse_inline(buf: &[u8]) -> Result<(Option<Args>, usize), String> {
+    let window = &buf[..buf.len().min(MAX_INLINE_LEN + 1)];
+    let Some(end) = window.iter().position(|&b| b == b'\n') else {
+        if window.len() > MAX_INLINE_LEN { return Err("Protocol error: too big inline request".to_string()); }
+        return Ok((None, 0));
+    };
+    let line = buf[..end].strip_suffix(b"\r").unwrap_or(&buf[..end]);
+    let args = line.split(|b| b.is_ascii_whitespace())
+        .filter(|a| !a.is_empty())
+        .map(|a| a.to_vec())
+        .collect();
+    Ok((Some(args), end + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_array_command() {
+        let buf = b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n";
+        let (args, used) = parse_command(buf).unwrap().unwrap();
+        assert_eq!(args, vec![b"SET".to_vec(), b"foo".to_vec(), b"bar".to_vec()]);
+        assert_eq!(used, buf.len());
+    }
+
+    #[test]
+    fn test_parse_incomplete_and_inline() {
+        let buf = b"*2\r\n$3\r\nGET\r\n$3\r\nfo";
+        assert_eq!(parse_command(buf).unwrap(), None);
+
+        let (args, used) = parse_command(b"PING  hello\r\nrest").unwrap().unwrap();
+        assert_eq!(args, vec![b"PING".to_vec(), b"hello".to_vec()]);
+        assert_eq!(used, 13);
+    }
+
+    #[test]
+    fn test_parser_keeps_its_place_between_reads() {
+        let buf = b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\nPING\r\n";
+        let mut parser = CommandParser::default();
+        assert_eq!(parser.parse(&buf[..2]).unwrap(), (None, 0));
+        // The header and the first argument are consumed while the rest is
+        // still on its way.
+        assert_eq!(parser.parse(&buf[..20]).unwrap(), (None, 13));
+        assert_eq!(parser.parse(&buf[13..24]).unwrap(), (None, 9));
+        let (args, used) = parser.parse(&buf[22..]).unwrap();
+        assert_eq!(args.unwrap(), vec![b"SET".to_vec(), b"foo".to_vec(), b"bar".to_vec()]);
+        assert_eq!(parser.parse(&buf[22 + used..]).unwrap(), (Some(vec![b"PING".to_vec()]), 6));
+    }
+
+    #[test]
+    fn test_limits() {
+        let long = vec![b'a'; MAX_INLINE_LEN + 1];
+        assert!(parse_command(&long).is_err());
+        assert_eq!(parse_command(&long[..MAX_INLINE_LEN]).unwrap(), None);
+
+        let mut header = b"*1\r\n$".to_vec();
+        header.extend(vec![b'1'; MAX_INLINE_LEN + 1]);
+        assert!(parse_command(&header).is_err());
+        assert!(parse_command(b"*2000000\r\n").is_err());
+
+        let nested = "*1\r\n".repeat(MAX_DEPTH + 1) + ":1\r\n";
+        assert!(parse_frame(nested.as_bytes()).is_err());
+        let nested = "*1\r\n".repeat(MAX_DEPTH) + ":1\r\n";
+        assert!(parse_frame(nested.as_bytes()).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_encode_roundtrip() {
+        let frame = Frame::Array(Some(vec![
+            Frame::bulk("a"), Frame::null(), Frame::Integer(-7), Frame::ok(), Frame::error("ERR x"),
+        ]));
+        let mut out = Vec::new();
+        frame.encode(&mut out);
+        assert_eq!(parse_frame(&out).unwrap(), Some((frame, out.len())));
+    }
+}
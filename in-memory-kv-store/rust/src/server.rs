@@ -0,0 +1,370 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use crate::command::{self, Session};
+use crate::pubsub::Inbox;
+use crate::replication::{Primary, Replica, Role};
+use crate::resp::{CommandParser, Frame};
+use crate::store::KVStore;
+
+pub struct ServerStats {
+    pub connected_clients: AtomicU64,
+    pub total_connections: AtomicU64,
+    pub total_commands: AtomicU64,
+    pub start: Instant,
+}
+
+impl ServerStats {
+    pub fn new() -> Self {
+        Self {
+            connected_clients: AtomicU64::new(0),
+            total_connections: AtomicU64::new(0),
+            total_commands: AtomicU64::new(0),
+            start: Instant::now(),
+        }
+    }
+}
+
+pub struct Server {
+    listener: TcpListener,
+    store: Arc<KVStore>,
+    stats: Arc<ServerStats>,
+    role: Arc<Role>,
+}
+
+impl Server {
+    /// Binds a primary, which replicas can sync from with PSYNC.
+    pub fn bind(addr: &str, store: Arc<KVStore>) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let role = Role::Primary(Primary::attach(&store));
+        Ok(Self::new(listener, store, role))
+    }
+
+    /// Binds a read-only replica that follows the primary at `primary`.
+    pub fn bind_replica(addr: &str, store: Arc<KVStore>, primary: &str) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let role = Role::Replica(Replica::start(&store, primary));
+        Ok(Self::new(listener, store, role))
+    }
+
+    fn new(listener: TcpListener, store: Arc<KVStore>, role: Role) -> Self {
+        Self { listener, store, stats: Arc::new(ServerStats::new()), role: Arc::new(role) }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts connections forever, one thread per client.
+    pub fn run(&self) {
+        for conn in self.listener.incoming() {
+            let stream = match conn {
+                Ok(s) => s,
+                Err(e) => { eprintln!("accept error: {e}"); continue; }
+            };
+            let store = Arc::clone(&self.store);
+            let stats = Arc::clone(&self.stats);
+            let role = Arc::clone(&self.role);
+            thread::spawn(move || {
+                stats.total_connections.fetch_add(1, Ordering::Relaxed);
+                stats.connected_clients.fetch_add(1, Ordering::Relaxed);
+                if let Err(e) = handle_client(stream, &store, &stats, &role) {
+                    if e.kind() != io::ErrorKind::ConnectionReset { eprintln!("client error: {e}"); }
+                }
+                stats.connected_clients.fetch_sub(1, Ordering::Relaxed);
+            });
+        }
+    }
+}
+
+// Every complete command already in the read buffer is executed before the
+// replies are flushed in one write, so pipelined clients pay one syscall per
+// batch rather than per command.
+//
+// Published messages are written by a separate forwarder thread. Both sides
+// hold `writer` while they produce output, so a SUBSCRIBE reply always
+// reaches the client before the first message on that channel.
+fn handle_client(mut stream: TcpStream, store: &KVStore, stats: &ServerStats, role: &Role) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    let mut session = Session::default();
+    let mut parser = CommandParser::default();
+    let mut buf: Vec<u8> = Vec::with_capacity(16 * 1024);
+    let mut chunk = [0u8; 16 * 1024];
+    let mut out: Vec<u8> = Vec::with_capacity(16 * 1024);
+    loop {
+        let n = stream.read(&mut chunk)?;
+        if n == 0 { return Ok(()); }
+        buf.extend_from_slice(&chunk[..n]);
+
+        let mut w = writer.lock().unwrap();
+        let mut consumed = 0;
+        let mut quit = false;
+        let mut psync = None;
+        loop {
+            match parser.parse(&buf[consumed..]) {
+                Ok((Some(args), used)) => {
+                    consumed += used;
+                    if args.is_empty() { continue; }
+                    stats.total_commands.fetch_add(1, Ordering::Relaxed);
+                    if args[0].eq_ignore_ascii_case(b"quit") {
+                        Frame::ok().encode(&mut out);
+                        quit = true;
+                        break;
+                    }
+                    // A replica's PSYNC turns this connection into a
+                    // replication stream once pending replies are flushed.
+                    if args[0].eq_ignore_ascii_case(b"psync") {
+                        psync = Some(args);
+                        break;
+                    }
+                    command::execute(store, stats, role, &mut session, &args, &mut out);
+                }
+                Ok((None, used)) => { consumed += used; break; }
+                Err(e) => {
+                    Frame::error(format!("ERR {e}")).encode(&mut out);
+                    quit = true;
+                    break;
+                }
+            }
+        }
+        buf.drain(..consumed);
+
+        if !out.is_empty() {
+            w.write_all(&out)?;
+            out.clear();
+        }
+        drop(w);
+        if let Some(inbox) = session.take_receiver() {
+            let writer = Arc::clone(&writer);
+            thread::spawn(move || forward_messages(inbox, &writer));
+        }
+        if quit { return Ok(()); }
+        if let Some(args) = psync {
+            return match role {
+                Role::Primary(primary) => primary.serve_replica(store, stream, &args),
+                Role::Replica(_) => stream.write_all(b"-ERR PSYNC is not supported on a replica\r\n"),
+            };
+        }
+    }
+}
+
+// Runs until the session's subscriber is dropped or the client goes away.
+// A client that let its queue overflow has missed messages, so it is
+// disconnected rather than handed a stream with gaps, as Redis does.
+fn forward_messages(inbox: Inbox, writer: &Mutex<TcpStream>) {
+    let mut out = Vec::new();
+    while let Some(msg) = inbox.recv() {
+        msg.to_frame().encode(&mut out);
+        // Coalesce whatever else is already queued into the same write.
+        for msg in std::iter::from_fn(|| inbox.try_recv()).take(1024) { msg.to_frame().encode(&mut out); }
+        if inbox.overflowed() { break; }
+        if writer.lock().unwrap().write_all(&out).is_err() { return; }
+        out.clear();
+    }
+    if inbox.overflowed() {
+        let _ = writer.lock().unwrap().shutdown(Shutdown::Both);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resp;
+    use std::time::Duration;
+
+    fn start_server() -> SocketAddr {
+        let server = Server::bind("127.0.0.1:0", Arc::new(KVStore::default())).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+        addr
+    }
+
+    fn read_reply(stream: &mut TcpStream, buf: &mut Vec<u8>) -> Frame {
+        let mut chunk = [0u8; 4096];
+        loop {
+            if let Some((frame, used)) = resp::parse_frame(buf).unwrap() {
+                buf.drain(..used);
+                return frame;
+            }
+            let n = stream.read(&mut chunk).unwrap();
+            assert!(n > 0, "server closed connection");
+            buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    fn command(args: &[&str]) -> Vec<u8> {
+        let mut out = Vec::new();
+        Frame::Array(Some(args.iter().map(|a| Frame::bulk(a.as_bytes())).collect())).encode(&mut out);
+        out
+    }
+
+    #[test]
+    fn test_server_pipelined_commands() {
+        let mut stream = TcpStream::connect(start_server()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let mut batch = Vec::new();
+        batch.extend(command(&["PING"]));
+        batch.extend(command(&["SET", "user:1:name", "John Doe"]));
+        batch.extend(command(&["MSET", "a", "1", "b", "2"]));
+        batch.extend(command(&["GET", "user:1:name"]));
+        batch.extend(command(&["MGET", "a", "missing", "b"]));
+        batch.extend(command(&["EXISTS", "a", "b", "missing"]));
+        batch.extend(command(&["DEL", "a", "missing"]));
+        batch.extend(command(&["GET", "a"]));
+        batch.extend(command(&["NOPE"]));
+        stream.write_all(&batch).unwrap();
+
+        let mut buf = Vec::new();
+        assert_eq!(read_reply(&mut stream, &mut buf), Frame::Simple("PONG".to_string()));
+        assert_eq!(read_reply(&mut stream, &mut buf), Frame::ok());
+        assert_eq!(read_reply(&mut stream, &mut buf), Frame::ok());
+        assert_eq!(read_reply(&mut stream, &mut buf), Frame::bulk("John Doe"));
+        assert_eq!(read_reply(&mut stream, &mut buf),
+                   Frame::Array(Some(vec![Frame::bulk("1"), Frame::null(), Frame::bulk("2")])));
+        assert_eq!(read_reply(&mut stream, &mut buf), Frame::Integer(2));
+        assert_eq!(read_reply(&mut stream, &mut buf), Frame::Integer(1));
+        assert_eq!(read_reply(&mut stream, &mut buf), Frame::null());
+        assert!(matches!(read_reply(&mut stream, &mut buf), Frame::Error(e) if e.starts_with("ERR unknown command")));
+    }
+
+    #[test]
+    fn test_server_split_writes_and_info() {
+        let mut stream = TcpStream::connect(start_server()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        // A command split across two writes must wait for the rest.
+        let cmd = command(&["SET", "k", "v"]);
+        stream.write_all(&cmd[..7]).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        stream.write_all(&cmd[7..]).unwrap();
+
+        let mut buf = Vec::new();
+        assert_eq!(read_reply(&mut stream, &mut buf), Frame::ok());
+
+        stream.write_all(&command(&["INFO"])).unwrap();
+        let Frame::Bulk(Some(info)) = read_reply(&mut stream, &mut buf) else { panic!("expected bulk") };
+        let info = String::from_utf8(info).unwrap();
+        assert!(info.contains("db0:keys=1,expires=0"));
+        assert!(info.contains("connected_clients:1"));
+    }
+
+    #[test]
+    fn test_server_typed_commands() {
+        let mut stream = TcpStream::connect(start_server()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let mut batch = Vec::new();
+        batch.extend(command(&["INCRBY", "hits", "5"]));
+        batch.extend(command(&["DECR", "hits"]));
+        batch.extend(command(&["RPUSH", "queue", "a", "b", "c"]));
+        batch.extend(command(&["LPOP", "queue", "2"]));
+        batch.extend(command(&["LRANGE", "queue", "0", "-1"]));
+        batch.extend(command(&["HSET", "user:1", "name", "John"]));
+        batch.extend(command(&["HGETALL", "user:1"]));
+        batch.extend(command(&["SADD", "tags", "x", "x"]));
+        batch.extend(command(&["SISMEMBER", "tags", "x"]));
+        batch.extend(command(&["TYPE", "tags"]));
+        batch.extend(command(&["TYPE", "missing"]));
+        batch.extend(command(&["GET", "queue"]));
+        stream.write_all(&batch).unwrap();
+
+        let mut buf = Vec::new();
+        let bulks = |items: &[&str]| Frame::Array(Some(items.iter().map(|s| Frame::bulk(s.as_bytes())).collect()));
+        assert_eq!(read_reply(&mut stream, &mut buf), Frame::Integer(5));
+        assert_eq!(read_reply(&mut stream, &mut buf), Frame::Integer(4));
+        assert_eq!(read_reply(&mut stream, &mut buf), Frame::Integer(3));
+        assert_eq!(read_reply(&mut stream, &mut buf), bulks(&["a", "b"]));
+        assert_eq!(read_reply(&mut stream, &mut buf), bulks(&["c"]));
+        assert_eq!(read_reply(&mut stream, &mut buf), Frame::Integer(1));
+        assert_eq!(read_reply(&mut stream, &mut buf), bulks(&["name", "John"]));
+        assert_eq!(read_reply(&mut stream, &mut buf), Frame::Integer(1));
+        assert_eq!(read_reply(&mut stream, &mut buf), Frame::Integer(1));
+        assert_eq!(read_reply(&mut stream, &mut buf), Frame::Simple("set".to_string()));
+        assert_eq!(read_reply(&mut stream, &mut buf), Frame::Simple("none".to_string()));
+        assert!(matches!(read_reply(&mut stream, &mut buf), Frame::Error(e) if e.starts_with("WRONGTYPE")));
+    }
+
+    #[test]
+    fn test_server_pubsub_and_keyspace_events() {
+        let addr = start_server();
+        let mut sub = TcpStream::connect(addr).unwrap();
+        let mut client = TcpStream::connect(addr).unwrap();
+        sub.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let (mut sub_buf, mut buf) = (Vec::new(), Vec::new());
+        let bulks = |items: &[&str]| Frame::Array(Some(items.iter().map(|s| Frame::bulk(s.as_bytes())).collect()));
+        let confirm = |kind: &str, name: &str, n: i64| Frame::Array(Some(vec![
+            Frame::bulk(kind), Frame::bulk(name), Frame::Integer(n),
+        ]));
+
+        client.write_all(&command(&["CONFIG", "SET", "notify-keyspace-events", "Kg$"])).unwrap();
+        assert_eq!(read_reply(&mut client, &mut buf), Frame::ok());
+
+        let mut batch = command(&["SUBSCRIBE", "news"]);
+        batch.extend(command(&["PSUBSCRIBE", "__keyspace@0__:user:*"]));
+        batch.extend(command(&["GET", "k"]));
+        sub.write_all(&batch).unwrap();
+        assert_eq!(read_reply(&mut sub, &mut sub_buf), confirm("subscribe", "news", 1));
+        assert_eq!(read_reply(&mut sub, &mut sub_buf), confirm("psubscribe", "__keyspace@0__:user:*", 2));
+        assert!(matches!(read_reply(&mut sub, &mut sub_buf), Frame::Error(e) if e.contains("only (P)SUBSCRIBE")));
+
+        client.write_all(&command(&["PUBLISH", "news", "hello"])).unwrap();
+        assert_eq!(read_reply(&mut client, &mut buf), Frame::Integer(1));
+        assert_eq!(read_reply(&mut sub, &mut sub_buf), bulks(&["message", "news", "hello"]));
+
+        client.write_all(&command(&["SET", "cart:item:1", "x"])).unwrap();
+        client.write_all(&command(&["SET", "user:1:name", "John"])).unwrap();
+        assert_eq!(read_reply(&mut client, &mut buf), Frame::ok());
+        assert_eq!(read_reply(&mut client, &mut buf), Frame::ok());
+        assert_eq!(read_reply(&mut sub, &mut sub_buf),
+                   bulks(&["pmessage", "__keyspace@0__:user:*", "__keyspace@0__:user:1:name", "set"]));
+
+        sub.write_all(&command(&["UNSUBSCRIBE"])).unwrap();
+        assert_eq!(read_reply(&mut sub, &mut sub_buf), confirm("unsubscribe", "news", 1));
+        sub.write_all(&command(&["PUNSUBSCRIBE"])).unwrap();
+        assert_eq!(read_reply(&mut sub, &mut sub_buf), confirm("punsubscribe", "__keyspace@0__:user:*", 0));
+        sub.write_all(&command(&["GET", "user:1:name"])).unwrap();
+        assert_eq!(read_reply(&mut sub, &mut sub_buf), Frame::bulk("John"));
+    }
+
+    #[test]
+    fn test_server_replica_is_read_only() {
+        let primary = start_server();
+        let server = Server::bind_replica("127.0.0.1:0", Arc::new(KVStore::default()), &primary.to_string()).unwrap();
+        let replica = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        let mut p = TcpStream::connect(primary).unwrap();
+        let mut r = TcpStream::connect(replica).unwrap();
+        p.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        r.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let (mut pbuf, mut rbuf) = (Vec::new(), Vec::new());
+
+        p.write_all(&command(&["SET", "user:1:name", "John"])).unwrap();
+        assert_eq!(read_reply(&mut p, &mut pbuf), Frame::ok());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            r.write_all(&command(&["GET", "user:1:name"])).unwrap();
+            if read_reply(&mut r, &mut rbuf) == Frame::bulk("John") { break; }
+            assert!(Instant::now() < deadline, "replica never caught up");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        r.write_all(&command(&["SET", "user:1:name", "Jane"])).unwrap();
+        assert!(matches!(read_reply(&mut r, &mut rbuf), Frame::Error(e) if e.starts_with("READONLY")));
+        r.write_all(&command(&["INFO"])).unwrap();
+        let Frame::Bulk(Some(info)) = read_reply(&mut r, &mut rbuf) else { panic!("expected bulk") };
+        let info = String::from_utf8(info).unwrap();
+        assert!(info.contains("role:slave") && info.contains("master_link_status:up"));
+
+        p.write_all(&command(&["INFO"])).unwrap();
+        let Frame::Bulk(Some(info)) = read_reply(&mut p, &mut pbuf) else { panic!("expected bulk") };
+        assert!(String::from_utf8(info).unwrap().contains("connected_slaves:1"));
+    }
+}
@@ -0,0 +1,1191 @@
+use std::collections::hash_map::Entry as MapEntry;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::ops::Bound;
+use std::hash::{BuildHasher, RandomState};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::eviction::{Access, EvictionPolicy, MemoryConfig};
+use crate::persistence::{Mutation, MutationLog};
+use crate::pubsub::{KeyEvent, PubSub};
+use crate::value::{resolve_range, Value};
+
+// Active expiry: every tick the sweeper takes the write lock for at most
+// SWEEP_BATCH keys. A full batch means more keys are probably due, so it
+// goes again immediately instead of waiting for the next tick.
+pub const SWEEP_INTERVAL: Duration = Duration::from_millis(100);
+const SWEEP_BATCH: usize = 20;
+
+// How often a write that cannot make room yields and tries again while
+// shards it could evict from are locked by other writers, before giving up
+// with OOM.
+const BUSY_RETRIES: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ttl {
+    Missing,
+    Persistent,
+    Expires(Duration),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreError {
+    OutOfMemory,
+    WrongType,
+    NotInteger,
+    Overflow,
+    /// An attached log could not be written, so writes are refused.
+    LogFailed,
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::OutOfMemory => f.write_str("OOM command not allowed when used memory > 'maxmemory'"),
+            StoreError::WrongType => f.write_str("WRONGTYPE Operation against a key holding the wrong kind of value"),
+            StoreError::NotInteger => f.write_str("ERR value is not an integer or out of range"),
+            StoreError::Overflow => f.write_str("ERR increment or decrement would overflow"),
+            StoreError::LogFailed => f.write_str(
+                "MISCONF Errors writing to the AOF file; write commands are refused until it can be written again"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MemoryStats {
+    pub used_memory: usize,
+    pub max_memory: usize,
+    pub policy: EvictionPolicy,
+    pub evicted_keys: u64,
+    pub expired_keys: u64,
+}
+
+pub struct SnapshotEntry {
+    pub key: String,
+    pub value: Value,
+    pub expires_at: Option<Instant>,
+}
+
+struct Entry {
+    value: Value,
+    expires_at: Option<Instant>,
+    access: Access,
+    // Score this key is filed under in `Shard::ranked`; may lag behind
+    // `access.score()` until the evictor looks at it.
+    rank: u64,
+}
+
+impl Entry {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|t| t <= now)
+    }
+}
+
+fn entry_size(key: &str, value: &Value) -> usize {
+    key.len() + value.size()
+}
+
+/// A `check` for `Shard::modify` that only accepts values of type `kind`.
+fn of_type(kind: &'static str) -> impl FnOnce(&Value) -> Result<(), StoreError> {
+    move |v| if v.type_name() == kind { Ok(()) } else { Err(StoreError::WrongType) }
+}
+
+#[derive(Default)]
+struct Shard {
+    data: HashMap<String, Entry>,
+    // Keys with a TTL ordered by deadline, so the sweeper only ever looks
+    // at keys that can actually be expired.
+    expiry: BTreeSet<(Instant, String)>,
+    // LRU/LFU candidates ordered by score. Reads only bump the atomic
+    // score, so a key's rank here is a lower bound (except that LFU counts
+    // decay while a key sits idle, which makes LFU approximate, as in Redis);
+    // the evictor re-files stale ranks lazily and evicts keys whose rank is
+    // current.
+    ranked: BTreeSet<(u64, String)>,
+    clock: AtomicU64,
+    memory: MemoryConfig,
+    // Bytes held by this shard, and by the whole store: the budget is
+    // global, so a write may evict keys from any shard to fit.
+    used_memory: usize,
+    total_used: Arc<AtomicUsize>,
+    siblings: Weak<Vec<RwLock<Shard>>>,
+    index: usize,
+    evicted_keys: u64,
+    expired_keys: u64,
+    // Every mutation below is logged while the write lock is held.
+    logs: Vec<Arc<dyn MutationLog>>,
+    // Keyspace notifications are published under the same lock, so
+    // subscribers see a key's events in the order they were applied.
+    events: Arc<PubSub>,
+    // Set while a dataset is loaded: no eviction and no notifications.
+    loading: bool,
+}
+
+impl Shard {
+    fn new(
+        index: usize,
+        memory: MemoryConfig,
+        events: Arc<PubSub>,
+        total_used: Arc<AtomicUsize>,
+        siblings: Weak<Vec<RwLock<Shard>>>,
+    ) -> Self {
+        Self { index, memory, events, total_used, siblings, ..Self::default() }
+    }
+
+    fn grow(&mut self, bytes: usize) {
+        self.used_memory += bytes;
+        self.total_used.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn shrink(&mut self, bytes: usize) {
+        self.used_memory -= bytes;
+        self.total_used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    fn tracks_access(&self) -> bool {
+        self.memory.max_memory > 0 && self.memory.policy.tracks_access()
+    }
+
+    fn touch(&self, entry: &Entry) {
+        if self.tracks_access() {
+            entry.access.touch(self.clock.fetch_add(1, Ordering::Relaxed));
+        }
+    }
+
+    fn log(&self, m: Mutation) {
+        for log in &self.logs { log.append(&m); }
+    }
+
+    fn notify(&self, event: KeyEvent, key: &str) {
+        if !self.loading { self.events.notify(event, key); }
+    }
+
+    fn insert(&mut self, key: String, value: String, expires_at: Option<Instant>) -> Result<(), StoreError> {
+        self.make_room(&key, key.len() + value.len())?;
+        self.set_str(key, value, expires_at);
+        Ok(())
+    }
+
+    /// Writes a string once room has been made for it.
+    fn set_str(&mut self, key: String, value: String, expires_at: Option<Instant>) {
+        self.log(Mutation::Set { key: &key, value: &value, expires_at });
+        self.notify(KeyEvent::Set, &key);
+        self.store_entry(key, Value::Str(value), expires_at);
+    }
+
+    fn store_entry(&mut self, key: String, value: Value, expires_at: Option<Instant>) {
+        self.grow(entry_size(&key, &value));
+        let tracks_access = self.tracks_access();
+        // Single hash lookup on the hot SET path; the indexes are only
+        // touched when TTLs or eviction tracking are in play.
+        match self.data.entry(key) {
+            MapEntry::Occupied(mut o) => {
+                let old_size = entry_size(o.key(), &o.get().value);
+                self.used_memory -= old_size;
+                self.total_used.fetch_sub(old_size, Ordering::Relaxed);
+                if let Some(t) = o.get().expires_at { self.expiry.remove(&(t, o.key().clone())); }
+                if let Some(t) = expires_at { self.expiry.insert((t, o.key().clone())); }
+                let old = o.get_mut();
+                old.value = value;
+                old.expires_at = expires_at;
+                if tracks_access {
+                    old.access.touch(self.clock.fetch_add(1, Ordering::Relaxed));
+                }
+            }
+            MapEntry::Vacant(v) => {
+                if let Some(t) = expires_at { self.expiry.insert((t, v.key().clone())); }
+                let entry = Entry { value, expires_at, access: Access::default(), rank: 0 };
+                if tracks_access {
+                    entry.access.touch(self.clock.fetch_add(1, Ordering::Relaxed));
+                    let rank = entry.access.score(self.memory.policy);
+                    self.ranked.insert((rank, v.key().clone()));
+                    v.insert(Entry { rank, ..entry });
+                } else {
+                    v.insert(entry);
+                }
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        if !self.data.contains_key(key) { return None; }
+        self.log(Mutation::Del { key });
+        self.unlink(key)
+    }
+
+    /// Removes a key whose deadline has passed.
+    fn expire_key(&mut self, key: &str) {
+        self.remove(key);
+        self.expired_keys += 1;
+        self.notify(KeyEvent::Expired, key);
+    }
+
+    fn unlink(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.data.remove(key)?;
+        self.shrink(entry_size(key, &entry.value));
+        if let Some(t) = entry.expires_at { self.expiry.remove(&(t, key.to_string())); }
+        if self.tracks_access() { self.ranked.remove(&(entry.rank, key.to_string())); }
+        Some(entry)
+    }
+
+    fn set_expiry(&mut self, key: &str, expires_at: Option<Instant>) -> bool {
+        let Some(entry) = self.data.get_mut(key) else { return false };
+        let old = std::mem::replace(&mut entry.expires_at, expires_at);
+        match expires_at {
+            Some(at) => { self.log(Mutation::ExpireAt { key, at }); self.notify(KeyEvent::Expire, key); }
+            None => { self.log(Mutation::Persist { key }); self.notify(KeyEvent::Persist, key); }
+        }
+        if let Some(t) = old { self.expiry.remove(&(t, key.to_string())); }
+        if let Some(t) = expires_at { self.expiry.insert((t, key.to_string())); }
+        true
+    }
+
+    /// Looks up a live entry, treating an expired one as absent.
+    fn live(&self, key: &str, now: Instant) -> Option<&Entry> {
+        self.data.get(key).filter(|e| !e.is_expired(now))
+    }
+
+    /// Runs `f` on the live value at `key`, first creating it with `create`
+    /// if the key is absent (or returning `Ok(None)` when `create` yields
+    /// nothing). `growth` bounds the bytes `f` may add and is reserved up
+    /// front, so the budget holds even though `f` mutates in place. `check`
+    /// vets an existing value before that, so a write refused for its type
+    /// or contents has not evicted anything. Collections left empty are
+    /// deleted. Logging is up to the caller.
+    fn modify<T>(
+        &mut self,
+        key: &str,
+        now: Instant,
+        growth: usize,
+        create: impl FnOnce() -> Option<Value>,
+        check: impl FnOnce(&Value) -> Result<(), StoreError>,
+        f: impl FnOnce(&mut Value) -> Result<T, StoreError>,
+    ) -> Result<Option<T>, StoreError> {
+        if self.data.get(key).is_some_and(|e| e.is_expired(now)) {
+            self.expire_key(key);
+        }
+        if let Some(e) = self.data.get(key) { check(&e.value)?; }
+        let existing = self.data.get(key).map(|e| e.value.size());
+        if growth > 0 {
+            self.make_room(key, key.len() + existing.unwrap_or(0) + growth)?;
+        }
+        if existing.is_none() {
+            let Some(value) = create() else { return Ok(None) };
+            self.store_entry(key.to_string(), value, None);
+        }
+        let tick = self.tracks_access().then(|| self.clock.fetch_add(1, Ordering::Relaxed));
+        let entry = self.data.get_mut(key).expect("entry present");
+        let before = entry.value.size();
+        let result = f(&mut entry.value);
+        let after = entry.value.size();
+        if let Some(tick) = tick { entry.access.touch(tick); }
+        let empty = entry.value.is_empty_collection();
+        if after > before { self.grow(after - before) } else { self.shrink(before - after) }
+        if empty { self.unlink(key); }
+        result.map(Some)
+    }
+
+    /// Evicts keys until writing `incoming` bytes for `key` fits the budget.
+    /// The key being written is never chosen as a victim.
+    fn make_room(&mut self, key: &str, incoming: usize) -> Result<(), StoreError> {
+        let existing = self.data.get(key).map_or(0, |e| entry_size(key, &e.value));
+        let index = self.index;
+        reserve(&mut [self], &[index], &|k| k == key, existing, incoming)
+    }
+
+    /// Chooses victims in eviction order, skipping `protect`ed keys, until
+    /// they add up to `need` bytes or run out. Nothing is evicted yet.
+    fn pick_victims(&mut self, protect: &dyn Fn(&str) -> bool, need: usize) -> (Vec<String>, usize) {
+        let policy = self.memory.policy;
+        let (mut victims, mut freed) = (Vec::new(), 0);
+        match policy {
+            EvictionPolicy::NoEviction => {}
+            EvictionPolicy::VolatileTtl => {
+                for (_, key) in &self.expiry {
+                    if freed >= need { break; }
+                    if protect(key) { continue; }
+                    freed += entry_size(key, &self.data[key].value);
+                    victims.push(key.clone());
+                }
+            }
+            EvictionPolicy::AllKeysLru | EvictionPolicy::AllKeysLfu => {
+                let mut from = Bound::Unbounded;
+                while freed < need {
+                    let Some((rank, key)) = self.ranked.range((from.clone(), Bound::Unbounded))
+                        .find(|(_, k)| !protect(k)).cloned() else { break };
+                    let entry = self.data.get_mut(&key).expect("ranked key present");
+                    let score = entry.access.score(policy);
+                    if score != rank {
+                        self.ranked.remove(&(rank, key.clone()));
+                        entry.rank = score;
+                        self.ranked.insert((score, key));
+                        continue;
+                    }
+                    freed += entry_size(&key, &entry.value);
+                    from = Bound::Excluded((rank, key.clone()));
+                    victims.push(key);
+                }
+            }
+        }
+        (victims, freed)
+    }
+
+    fn evict(&mut self, victims: Vec<String>) {
+        for victim in victims {
+            self.remove(&victim);
+            self.evicted_keys += 1;
+            self.notify(KeyEvent::Evicted, &victim);
+        }
+    }
+}
+
+/// Makes room under the global budget for `incoming` bytes that replace
+/// `existing` ones. `held` are the shards the caller has locked, at indices
+/// `held_idx`; the others are only tried with `try_write`, as blocking on
+/// them could deadlock with a writer there doing the same. Victims are all
+/// chosen before any is evicted, so a write that cannot fit fails with OOM
+/// without having evicted anything. They come from the held shards while
+/// those hold at least their share of the data, otherwise from the largest
+/// other shards first. `protect`ed keys are never chosen.
+fn reserve(
+    held: &mut [&mut Shard],
+    held_idx: &[usize],
+    protect: &dyn Fn(&str) -> bool,
+    existing: usize,
+    incoming: usize,
+) -> Result<(), StoreError> {
+    let max = held[0].memory.max_memory;
+    if max == 0 || held[0].loading { return Ok(()); }
+    if incoming > max { return Err(StoreError::OutOfMemory); }
+    let (total_used, siblings) = (Arc::clone(&held[0].total_used), held[0].siblings.upgrade());
+    let shards = siblings.as_ref().map_or(1, |s| s.len());
+    let mut retries = 0;
+    loop {
+        let total = total_used.load(Ordering::Relaxed);
+        let need = (total - existing + incoming).saturating_sub(max);
+        if need == 0 { return Ok(()); }
+        let mut busy = false;
+        let mut others: Vec<_> = siblings.iter()
+            .flat_map(|s| s.iter().enumerate())
+            .filter(|(i, _)| !held_idx.contains(i))
+            .filter_map(|(_, s)| s.try_write().map_err(|_| busy = true).ok())
+            .collect();
+        others.sort_by_key(|s| std::cmp::Reverse(s.used_memory));
+        let held_used: usize = held.iter().map(|s| s.used_memory).sum();
+        let mut order: Vec<&mut Shard> = others.iter_mut().map(|s| &mut **s).collect();
+        if held_used * shards >= total * held.len() {
+            order.splice(0..0, held.iter_mut().map(|s| &mut **s));
+        } else {
+            order.extend(held.iter_mut().map(|s| &mut **s));
+        }
+        let (mut plans, mut freed) = (Vec::new(), 0);
+        for shard in order.iter_mut() {
+            if freed >= need { break; }
+            let (victims, bytes) = shard.pick_victims(protect, need - freed);
+            plans.push(victims);
+            freed += bytes;
+        }
+        if freed >= need {
+            for (shard, victims) in order.iter_mut().zip(plans) { shard.evict(victims); }
+            continue;
+        }
+        // Victims may be waiting in shards other writers hold right now.
+        if !busy || retries == BUSY_RETRIES { return Err(StoreError::OutOfMemory); }
+        retries += 1;
+        drop(order);
+        drop(others);
+        thread::yield_now();
+    }
+}
+
+pub const DEFAULT_SHARDS: usize = 16;
+
+/// Keys are spread over independently locked shards by hash, so writers to
+/// different shards never contend. The memory budget is shared: used bytes
+/// are counted store-wide and a write may evict keys from any shard.
+pub struct KVStore {
+    shards: Arc<Vec<RwLock<Shard>>>,
+    hasher: RandomState,
+    memory: MemoryConfig,
+    pubsub: Arc<PubSub>,
+}
+
+impl Default for KVStore {
+    fn default() -> Self {
+        Self::with_config(MemoryConfig::default())
+    }
+}
+
+impl KVStore {
+    /// Single-shard store: one global lock, as in the original benchmark.
+    pub fn with_config(memory: MemoryConfig) -> Self {
+        Self::with_shards(1, memory)
+    }
+
+    pub fn with_shards(shards: usize, memory: MemoryConfig) -> Self {
+        let shards = shards.max(1);
+        let pubsub = Arc::new(PubSub::default());
+        let total_used = Arc::new(AtomicUsize::new(0));
+        Self {
+            shards: Arc::new_cyclic(|siblings| (0..shards)
+                .map(|i| RwLock::new(Shard::new(i, memory, Arc::clone(&pubsub), Arc::clone(&total_used), siblings.clone())))
+                .collect()),
+            hasher: RandomState::new(),
+            memory,
+            pubsub,
+        }
+    }
+
+    /// Publish/subscribe hub, which also carries keyspace notifications.
+    pub fn pubsub(&self) -> &Arc<PubSub> {
+        &self.pubsub
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn shard_index(&self, key: &str) -> usize {
+        if self.shards.len() == 1 { return 0; }
+        (self.hasher.hash_one(key) % self.shards.len() as u64) as usize
+    }
+
+    fn shard(&self, key: &str) -> &RwLock<Shard> {
+        &self.shards[self.shard_index(key)]
+    }
+
+    pub fn set(&self, key: String, value: String) -> Result<(), StoreError> {
+        self.shard(&key).write().unwrap().insert(key, value, None)
+    }
+
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<(), StoreError> {
+        self.shard(&key).write().unwrap().insert(key, value, Some(Instant::now() + ttl))
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<String>, StoreError> {
+        let now = Instant::now();
+        let lock = self.shard(key);
+        {
+            let shard = lock.read().unwrap();
+            match shard.data.get(key) {
+                None => return Ok(None),
+                Some(e) if !e.is_expired(now) => {
+                    shard.touch(e);
+                    return match &e.value {
+                        Value::Str(s) => Ok(Some(s.clone())),
+                        _ => Err(StoreError::WrongType),
+                    };
+                }
+                Some(_) => {}
+            }
+        }
+        let mut shard = lock.write().unwrap();
+        if shard.data.get(key).is_some_and(|e| e.is_expired(now)) {
+            shard.expire_key(key);
+        }
+        Ok(None)
+    }
+
+    pub fn delete(&self, key: &str) -> bool {
+        let now = Instant::now();
+        let mut shard = self.shard(key).write().unwrap();
+        let removed = shard.remove(key).is_some_and(|e| !e.is_expired(now));
+        if removed { shard.notify(KeyEvent::Del, key); }
+        removed
+    }
+
+    pub fn exists(&self, key: &str) -> bool {
+        self.shard(key).read().unwrap().live(key, Instant::now()).is_some()
+    }
+
+    /// Distinct shard indices for `keys`, ascending. Multi-key operations
+    /// lock shards in this order so they cannot deadlock each other.
+    fn shards_for<'a>(&self, keys: impl Iterator<Item = &'a str>) -> Vec<usize> {
+        let mut idx: Vec<usize> = keys.map(|k| self.shard_index(k)).collect();
+        idx.sort_unstable();
+        idx.dedup();
+        idx
+    }
+
+    // MGET/MSET hold every shard they touch at once so the batch is atomic.
+    pub fn get_many(&self, keys: &[String]) -> Vec<Option<String>> {
+        let now = Instant::now();
+        let idx = self.shards_for(keys.iter().map(String::as_str));
+        let guards: Vec<_> = idx.iter().map(|&i| self.shards[i].read().unwrap()).collect();
+        keys.iter().map(|k| {
+            let shard = &guards[idx.binary_search(&self.shard_index(k)).unwrap()];
+            // Like Redis, MGET reports non-string keys as missing.
+            shard.live(k, now).and_then(|e| match &e.value {
+                Value::Str(s) => { shard.touch(e); Some(s.clone()) }
+                _ => None,
+            })
+        }).collect()
+    }
+
+    /// Room is made for the whole batch before any key is written, so MSET
+    /// either sets every key or fails with nothing changed.
+    pub fn set_many(&self, pairs: Vec<(String, String)>) -> Result<(), StoreError> {
+        let idx = self.shards_for(pairs.iter().map(|(k, _)| k.as_str()));
+        let mut guards: Vec<_> = idx.iter().map(|&i| self.shards[i].write().unwrap()).collect();
+        let guard = |k: &str| idx.binary_search(&self.shard_index(k)).unwrap();
+        // A key given twice ends up with its last value.
+        let sizes: HashMap<&str, usize> = pairs.iter().map(|(k, v)| (k.as_str(), k.len() + v.len())).collect();
+        let existing = sizes.keys()
+            .map(|k| guards[guard(k)].data.get(*k).map_or(0, |e| entry_size(k, &e.value)))
+            .sum();
+        let mut held: Vec<&mut Shard> = guards.iter_mut().map(|g| &mut **g).collect();
+        reserve(&mut held, &idx, &|k| sizes.contains_key(k), existing, sizes.values().sum())?;
+        for (k, v) in pairs {
+            let g = guard(&k);
+            guards[g].set_str(k, v, None);
+        }
+        Ok(())
+    }
+
+    /// Sets a TTL on an existing key. Returns false if the key does not exist.
+    pub fn expire(&self, key: &str, ttl: Duration) -> bool {
+        let now = Instant::now();
+        let mut shard = self.shard(key).write().unwrap();
+        if shard.live(key, now).is_none() { return false; }
+        shard.set_expiry(key, Some(now + ttl))
+    }
+
+    /// Removes the TTL from a key. Returns false if the key does not exist or
+    /// had no TTL.
+    pub fn persist(&self, key: &str) -> bool {
+        let now = Instant::now();
+        let mut shard = self.shard(key).write().unwrap();
+        match shard.live(key, now) {
+            Some(e) if e.expires_at.is_some() => shard.set_expiry(key, None),
+            _ => false,
+        }
+    }
+
+    pub fn ttl(&self, key: &str) -> Ttl {
+        let now = Instant::now();
+        match self.shard(key).read().unwrap().live(key, now) {
+            None => Ttl::Missing,
+            Some(Entry { expires_at: None, .. }) => Ttl::Persistent,
+            Some(Entry { expires_at: Some(t), .. }) => Ttl::Expires(t.saturating_duration_since(now)),
+        }
+    }
+
+    pub fn type_of(&self, key: &str) -> Option<&'static str> {
+        self.shard(key).read().unwrap().live(key, Instant::now()).map(|e| e.value.type_name())
+    }
+
+    /// Runs `f` on the live value at `key` under the read lock.
+    fn read<T>(&self, key: &str, f: impl FnOnce(&Value) -> Result<T, StoreError>) -> Result<Option<T>, StoreError> {
+        let shard = self.shard(key).read().unwrap();
+        let Some(e) = shard.live(key, Instant::now()) else { return Ok(None) };
+        shard.touch(e);
+        f(&e.value).map(Some)
+    }
+
+    /// Adds `delta` to the integer stored as a string at `key` (0 if absent)
+    /// and returns the new value. The TTL, if any, is kept.
+    pub fn incr_by(&self, key: &str, delta: i64) -> Result<i64, StoreError> {
+        let mut shard = self.shard(key).write().unwrap();
+        let incremented = |v: &Value| {
+            let Value::Str(s) = v else { return Err(StoreError::WrongType) };
+            let n = s.parse::<i64>().map_err(|_| StoreError::NotInteger)?;
+            n.checked_add(delta).ok_or(StoreError::Overflow)
+        };
+        let create = || Some(Value::Str("0".to_string()));
+        let result = shard.modify(key, Instant::now(), 20, create, |v| incremented(v).map(drop), |v| {
+            let n = incremented(v)?;
+            *v = Value::Str(n.to_string());
+            Ok(n)
+        })?.expect("created if absent");
+        shard.log(Mutation::Op { name: "INCRBY", key, args: &[&delta.to_string()] });
+        shard.notify(KeyEvent::IncrBy, key);
+        Ok(result)
+    }
+
+    /// Pushes `values` onto the head (`left`) or tail of the list at `key`
+    /// and returns the new length.
+    pub fn push(&self, key: &str, values: Vec<String>, left: bool) -> Result<usize, StoreError> {
+        let growth = values.iter().map(String::len).sum();
+        let mut shard = self.shard(key).write().unwrap();
+        let args: Vec<&str> = values.iter().map(String::as_str).collect();
+        let (name, event) = if left { ("LPUSH", KeyEvent::LPush) } else { ("RPUSH", KeyEvent::RPush) };
+        let len = shard.modify(key, Instant::now(), growth, || Some(Value::List(VecDeque::new())), of_type("list"), |v| {
+            let Value::List(list) = v else { return Err(StoreError::WrongType) };
+            for value in &args {
+                if left { list.push_front(value.to_string()) } else { list.push_back(value.to_string()) }
+            }
+            Ok(list.len())
+        })?.expect("created if absent");
+        shard.log(Mutation::Op { name, key, args: &args });
+        shard.notify(event, key);
+        Ok(len)
+    }
+
+    /// Removes up to `count` elements from the head (`left`) or tail of the
+    /// list at `key`.
+    pub fn pop(&self, key: &str, count: usize, left: bool) -> Result<Vec<String>, StoreError> {
+        let mut shard = self.shard(key).write().unwrap();
+        let popped = shard.modify(key, Instant::now(), 0, || None, of_type("list"), |v| {
+            let Value::List(list) = v else { return Err(StoreError::WrongType) };
+            let n = count.min(list.len());
+            Ok(if left { list.drain(..n).collect() } else { list.drain(list.len() - n..).rev().collect::<Vec<_>>() })
+        })?.unwrap_or_default();
+        if !popped.is_empty() {
+            let (name, event) = if left { ("LPOP", KeyEvent::LPop) } else { ("RPOP", KeyEvent::RPop) };
+            shard.log(Mutation::Op { name, key, args: &[&popped.len().to_string()] });
+            shard.notify(event, key);
+        }
+        Ok(popped)
+    }
+
+    pub fn range(&self, key: &str, start: i64, stop: i64) -> Result<Vec<String>, StoreError> {
+        Ok(self.read(key, |v| {
+            let Value::List(list) = v else { return Err(StoreError::WrongType) };
+            Ok(list.range(resolve_range(start, stop, list.len())).cloned().collect())
+        })?.unwrap_or_default())
+    }
+
+    pub fn list_len(&self, key: &str) -> Result<usize, StoreError> {
+        Ok(self.read(key, |v| match v {
+            Value::List(list) => Ok(list.len()),
+            _ => Err(StoreError::WrongType),
+        })?.unwrap_or(0))
+    }
+
+    /// Sets hash fields and returns how many were newly added.
+    pub fn hash_set(&self, key: &str, pairs: Vec<(String, String)>) -> Result<usize, StoreError> {
+        let growth = pairs.iter().map(|(f, v)| f.len() + v.len()).sum();
+        let mut shard = self.shard(key).write().unwrap();
+        let args: Vec<&str> = pairs.iter().flat_map(|(f, v)| [f.as_str(), v.as_str()]).collect();
+        let added = shard.modify(key, Instant::now(), growth, || Some(Value::Hash(HashMap::new())), of_type("hash"), |v| {
+            let Value::Hash(hash) = v else { return Err(StoreError::WrongType) };
+            Ok(pairs.iter().filter(|(f, v)| hash.insert(f.clone(), v.clone()).is_none()).count())
+        })?.expect("created if absent");
+        shard.log(Mutation::Op { name: "HSET", key, args: &args });
+        shard.notify(KeyEvent::HSet, key);
+        Ok(added)
+    }
+
+    pub fn hash_get(&self, key: &str, field: &str) -> Result<Option<String>, StoreError> {
+        Ok(self.read(key, |v| match v {
+            Value::Hash(hash) => Ok(hash.get(field).cloned()),
+            _ => Err(StoreError::WrongType),
+        })?.flatten())
+    }
+
+    pub fn hash_get_all(&self, key: &str) -> Result<Vec<(String, String)>, StoreError> {
+        Ok(self.read(key, |v| match v {
+            Value::Hash(hash) => Ok(hash.iter().map(|(f, v)| (f.clone(), v.clone())).collect()),
+            _ => Err(StoreError::WrongType),
+        })?.unwrap_or_default())
+    }
+
+    /// Deletes hash fields and returns how many existed.
+    pub fn hash_del(&self, key: &str, fields: &[String]) -> Result<usize, StoreError> {
+        let mut shard = self.shard(key).write().unwrap();
+        let removed = shard.modify(key, Instant::now(), 0, || None, of_type("hash"), |v| {
+            let Value::Hash(hash) = v else { return Err(StoreError::WrongType) };
+            Ok(fields.iter().filter(|f| hash.remove(*f).is_some()).count())
+        })?.unwrap_or(0);
+        if removed > 0 {
+            let args: Vec<&str> = fields.iter().map(String::as_str).collect();
+            shard.log(Mutation::Op { name: "HDEL", key, args: &args });
+            shard.notify(KeyEvent::HDel, key);
+        }
+        Ok(removed)
+    }
+
+    /// Adds set members and returns how many were new.
+    pub fn set_add(&self, key: &str, members: Vec<String>) -> Result<usize, StoreError> {
+        let growth = members.iter().map(String::len).sum();
+        let mut shard = self.shard(key).write().unwrap();
+        let args: Vec<&str> = members.iter().map(String::as_str).collect();
+        let added = shard.modify(key, Instant::now(), growth, || Some(Value::Set(HashSet::new())), of_type("set"), |v| {
+            let Value::Set(set) = v else { return Err(StoreError::WrongType) };
+            Ok(args.iter().filter(|m| set.insert(m.to_string())).count())
+        })?.expect("created if absent");
+        shard.log(Mutation::Op { name: "SADD", key, args: &args });
+        shard.notify(KeyEvent::SAdd, key);
+        Ok(added)
+    }
+
+    /// Removes set members and returns how many existed.
+    pub fn set_remove(&self, key: &str, members: &[String]) -> Result<usize, StoreError> {
+        let mut shard = self.shard(key).write().unwrap();
+        let removed = shard.modify(key, Instant::now(), 0, || None, of_type("set"), |v| {
+            let Value::Set(set) = v else { return Err(StoreError::WrongType) };
+            Ok(members.iter().filter(|m| set.remove(*m)).count())
+        })?.unwrap_or(0);
+        if removed > 0 {
+            let args: Vec<&str> = members.iter().map(String::as_str).collect();
+            shard.log(Mutation::Op { name: "SREM", key, args: &args });
+            shard.notify(KeyEvent::SRem, key);
+        }
+        Ok(removed)
+    }
+
+    pub fn set_is_member(&self, key: &str, member: &str) -> Result<bool, StoreError> {
+        Ok(self.read(key, |v| match v {
+            Value::Set(set) => Ok(set.contains(member)),
+            _ => Err(StoreError::WrongType),
+        })?.unwrap_or(false))
+    }
+
+    pub fn set_members(&self, key: &str) -> Result<Vec<String>, StoreError> {
+        Ok(self.read(key, |v| match v {
+            Value::Set(set) => Ok(set.iter().cloned().collect()),
+            _ => Err(StoreError::WrongType),
+        })?.unwrap_or_default())
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.read().unwrap().data.len()).sum()
+    }
+
+    pub fn expires_len(&self) -> usize {
+        self.shards.iter().map(|s| s.read().unwrap().expiry.len()).sum()
+    }
+
+    pub fn memory_stats(&self) -> MemoryStats {
+        let mut stats = MemoryStats {
+            max_memory: self.memory.max_memory,
+            policy: self.memory.policy,
+            ..MemoryStats::default()
+        };
+        for shard in self.shards.iter() {
+            let shard = shard.read().unwrap();
+            stats.used_memory += shard.used_memory;
+            stats.evicted_keys += shard.evicted_keys;
+            stats.expired_keys += shard.expired_keys;
+        }
+        stats
+    }
+
+    /// Starts recording mutations to `log`, alongside any already attached.
+    pub fn attach_log(&self, log: Arc<dyn MutationLog>) {
+        for shard in self.shards.iter() {
+            shard.write().unwrap().logs.push(Arc::clone(&log));
+        }
+    }
+
+    /// `Err(StoreError::LogFailed)` while an attached log cannot be written.
+    /// Write commands check this so they are not acknowledged unrecorded.
+    pub fn check_log(&self) -> Result<(), StoreError> {
+        let shard = self.shards[0].read().unwrap();
+        if shard.logs.iter().any(|log| log.failed()) { return Err(StoreError::LogFailed); }
+        Ok(())
+    }
+
+    /// Runs `f` with eviction and keyspace notifications off, for loading a
+    /// dataset from disk or a primary. Records go in as they were written,
+    /// even if the data is over budget; later writes evict as usual.
+    pub fn load<R>(&self, f: impl FnOnce() -> R) -> R {
+        let set = |loading| for shard in self.shards.iter() { shard.write().unwrap().loading = loading; };
+        set(true);
+        let result = f();
+        set(false);
+        result
+    }
+
+    /// Drops every key without logging or notifying; used by a replica
+    /// before it loads a full copy from its primary.
+    pub fn clear(&self) {
+        for shard in self.shards.iter() {
+            let mut shard = shard.write().unwrap();
+            shard.data.clear();
+            shard.expiry.clear();
+            shard.ranked.clear();
+            let used = shard.used_memory;
+            shard.shrink(used);
+        }
+    }
+
+    /// Copies the keyspace while writers are blocked and runs `during` at
+    /// that same instant, so the copy lines up exactly with a log rotation.
+    pub fn snapshot<R>(&self, during: impl FnOnce() -> R) -> (Vec<SnapshotEntry>, R) {
+        let now = Instant::now();
+        let guards: Vec<_> = self.shards.iter().map(|s| s.read().unwrap()).collect();
+        let result = during();
+        let entries = guards.iter()
+            .flat_map(|shard| shard.data.iter())
+            .filter(|(_, e)| !e.is_expired(now))
+            .map(|(k, e)| SnapshotEntry { key: k.clone(), value: e.value.clone(), expires_at: e.expires_at })
+            .collect();
+        (entries, result)
+    }
+
+    /// Evicts up to `max` keys in shard `i` whose deadline has passed and
+    /// returns how many were removed.
+    fn purge_shard(&self, i: usize, max: usize) -> usize {
+        let now = Instant::now();
+        let mut shard = self.shards[i].write().unwrap();
+        let mut removed = 0;
+        while removed < max {
+            let Some((t, key)) = shard.expiry.first().cloned() else { break };
+            if t > now { break; }
+            shard.expire_key(&key);
+            removed += 1;
+        }
+        removed
+    }
+
+    /// Starts the background expiry thread. It holds only a weak reference,
+    /// so it exits once the store is dropped.
+    pub fn start_sweeper(self: &Arc<Self>, interval: Duration) -> thread::JoinHandle<()> {
+        let weak = Arc::downgrade(self);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let Some(store) = weak.upgrade() else { return };
+            for i in 0..store.shards.len() {
+                while store.purge_shard(i, SWEEP_BATCH) == SWEEP_BATCH {}
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lazy_expiry_on_read() {
+        let kv = KVStore::default();
+        kv.set_with_ttl("session:1:token".to_string(), "abc".to_string(), Duration::from_millis(20)).unwrap();
+        kv.set("user:1:name".to_string(), "John Doe".to_string()).unwrap();
+        assert_eq!(kv.get("session:1:token").unwrap().as_deref(), Some("abc"));
+        assert!(matches!(kv.ttl("session:1:token"), Ttl::Expires(_)));
+        assert_eq!(kv.ttl("user:1:name"), Ttl::Persistent);
+
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(kv.get("session:1:token").unwrap(), None);
+        assert_eq!(kv.ttl("session:1:token"), Ttl::Missing);
+        assert_eq!(kv.len(), 1);
+        assert_eq!(kv.expires_len(), 0);
+    }
+
+    #[test]
+    fn test_expire_persist_and_overwrite() {
+        let kv = KVStore::default();
+        assert!(!kv.expire("missing", Duration::from_secs(1)));
+
+        kv.set("cache:page:1".to_string(), "<html>".to_string()).unwrap();
+        assert!(!kv.persist("cache:page:1"));
+        assert!(kv.expire("cache:page:1", Duration::from_secs(10)));
+        assert_eq!(kv.expires_len(), 1);
+        assert!(kv.persist("cache:page:1"));
+        assert_eq!(kv.ttl("cache:page:1"), Ttl::Persistent);
+        assert_eq!(kv.expires_len(), 0);
+
+        // A plain SET clears any TTL on the key.
+        kv.set_with_ttl("temp:calc:1".to_string(), "1".to_string(), Duration::from_secs(10)).unwrap();
+        kv.set("temp:calc:1".to_string(), "2".to_string()).unwrap();
+        assert_eq!(kv.ttl("temp:calc:1"), Ttl::Persistent);
+        assert_eq!(kv.expires_len(), 0);
+    }
+
+    #[test]
+    fn test_sweeper_evicts_in_batches() {
+        let kv = Arc::new(KVStore::default());
+        for i in 0..100 {
+            kv.set_with_ttl(format!("temp:calc:{}", i), "x".to_string(), Duration::from_millis(10)).unwrap();
+        }
+        kv.set("config:app:1".to_string(), "keep".to_string()).unwrap();
+
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(kv.purge_shard(0, SWEEP_BATCH), SWEEP_BATCH);
+        assert_eq!(kv.len(), 100 - SWEEP_BATCH + 1);
+
+        kv.start_sweeper(Duration::from_millis(10));
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while kv.len() > 1 && Instant::now() < deadline { thread::sleep(Duration::from_millis(5)); }
+        assert_eq!(kv.len(), 1);
+        assert_eq!(kv.get("config:app:1").unwrap().as_deref(), Some("keep"));
+    }
+
+    fn limited(max_memory: usize, policy: EvictionPolicy) -> KVStore {
+        KVStore::with_config(MemoryConfig { max_memory, policy })
+    }
+
+    #[test]
+    fn test_memory_accounting() {
+        let kv = KVStore::default();
+        kv.set("a".to_string(), "1234".to_string()).unwrap();
+        kv.set("bb".to_string(), "12".to_string()).unwrap();
+        assert_eq!(kv.memory_stats().used_memory, 9);
+        kv.set("a".to_string(), "1".to_string()).unwrap();
+        assert_eq!(kv.memory_stats().used_memory, 6);
+        kv.delete("bb");
+        assert_eq!(kv.memory_stats().used_memory, 2);
+    }
+
+    #[test]
+    fn test_noeviction_rejects_writes() {
+        let kv = limited(20, EvictionPolicy::NoEviction);
+        kv.set("k1".to_string(), "12345678".to_string()).unwrap();
+        kv.set("k2".to_string(), "12345678".to_string()).unwrap();
+        assert_eq!(kv.set("k3".to_string(), "x".to_string()), Err(StoreError::OutOfMemory));
+        // Shrinking an existing key still fits.
+        kv.set("k1".to_string(), "1".to_string()).unwrap();
+        assert_eq!(kv.len(), 2);
+        assert_eq!(kv.memory_stats().evicted_keys, 0);
+    }
+
+    #[test]
+    fn test_allkeys_lru_evicts_least_recently_used() {
+        let kv = limited(30, EvictionPolicy::AllKeysLru);
+        for k in ["k1", "k2", "k3"] { kv.set(k.to_string(), "12345678".to_string()).unwrap(); }
+        let _ = kv.get("k1");
+        kv.set("k4".to_string(), "12345678".to_string()).unwrap();
+        assert!(kv.exists("k1"));
+        assert!(!kv.exists("k2"));
+        assert!(kv.exists("k3") && kv.exists("k4"));
+        assert_eq!(kv.memory_stats().evicted_keys, 1);
+        assert!(kv.memory_stats().used_memory <= 30);
+    }
+
+    #[test]
+    fn test_allkeys_lfu_evicts_least_frequently_used() {
+        let kv = limited(30, EvictionPolicy::AllKeysLfu);
+        for k in ["k1", "k2", "k3"] { kv.set(k.to_string(), "12345678".to_string()).unwrap(); }
+        for _ in 0..3 { let _ = kv.get("k1"); let _ = kv.get("k3"); }
+        let _ = kv.get("k2");
+        kv.set("k4".to_string(), "12345678".to_string()).unwrap();
+        assert!(!kv.exists("k2"));
+        assert!(kv.exists("k1") && kv.exists("k3") && kv.exists("k4"));
+    }
+
+    #[test]
+    fn test_failed_write_evicts_nothing() {
+        let kv = limited(30, EvictionPolicy::VolatileTtl);
+        kv.set("config:1".to_string(), "12".to_string()).unwrap();
+        kv.set_with_ttl("temp:1".to_string(), "1234".to_string(), Duration::from_secs(10)).unwrap();
+        // Evicting temp:1 frees 10 bytes, not enough for 25 more.
+        assert_eq!(kv.set("big".to_string(), "x".repeat(22)), Err(StoreError::OutOfMemory));
+        assert!(kv.exists("temp:1"));
+        assert_eq!(kv.memory_stats().evicted_keys, 0);
+        kv.set("big".to_string(), "x".repeat(12)).unwrap();
+        assert!(!kv.exists("temp:1"));
+    }
+
+    #[test]
+    fn test_refused_typed_write_evicts_nothing() {
+        // 43 of 45 bytes used: every write below would have to evict.
+        let kv = limited(45, EvictionPolicy::AllKeysLru);
+        kv.set("name".to_string(), "John".to_string()).unwrap();
+        kv.push("queue", vec!["1234567890".to_string()], false).unwrap();
+        kv.set("n".to_string(), i64::MAX.to_string()).unwrap();
+        assert_eq!(kv.push("name", vec!["x".repeat(10)], false), Err(StoreError::WrongType));
+        assert_eq!(kv.hash_set("queue", vec![("f".to_string(), "x".repeat(10))]), Err(StoreError::WrongType));
+        assert_eq!(kv.set_add("name", vec!["x".repeat(10)]), Err(StoreError::WrongType));
+        assert_eq!(kv.incr_by("name", 1), Err(StoreError::NotInteger));
+        assert_eq!(kv.incr_by("n", 1), Err(StoreError::Overflow));
+        assert_eq!(kv.len(), 3);
+        assert_eq!(kv.memory_stats().evicted_keys, 0);
+    }
+
+    #[test]
+    fn test_mset_checks_the_whole_batch() {
+        let kv = limited(30, EvictionPolicy::NoEviction);
+        kv.set("a".to_string(), "1234".to_string()).unwrap();
+        let batch = |n: usize| (0..n).map(|i| (format!("k{}", i), "12345678".to_string())).collect::<Vec<_>>();
+        assert_eq!(kv.set_many(batch(3)), Err(StoreError::OutOfMemory));
+        assert_eq!(kv.len(), 1);
+        // Overwriting `a` in the same batch frees its old bytes.
+        let mut pairs = batch(2);
+        pairs.push(("a".to_string(), "1".to_string()));
+        kv.set_many(pairs).unwrap();
+        assert_eq!(kv.memory_stats().used_memory, 22);
+
+        // Under LRU the batch's own keys are never the victims.
+        let kv = KVStore::with_shards(4, MemoryConfig { max_memory: 40, policy: EvictionPolicy::AllKeysLru });
+        for i in 0..4 { kv.set(format!("old{}", i), "1234567".to_string()).unwrap(); }
+        kv.set_many(batch(4)).unwrap();
+        assert_eq!(kv.len(), 4);
+        assert!((0..4).all(|i| kv.exists(&format!("k{}", i))));
+        assert_eq!(kv.memory_stats().evicted_keys, 4);
+    }
+
+    #[test]
+    fn test_volatile_ttl_evicts_nearest_deadline() {
+        let kv = limited(45, EvictionPolicy::VolatileTtl);
+        kv.set("config:1".to_string(), "12345678".to_string()).unwrap();
+        kv.set_with_ttl("temp:1".to_string(), "1234567".to_string(), Duration::from_secs(100)).unwrap();
+        kv.set_with_ttl("temp:2".to_string(), "1234567".to_string(), Duration::from_secs(10)).unwrap();
+        kv.set("config:2".to_string(), "12345678".to_string()).unwrap();
+        assert!(!kv.exists("temp:2"));
+        assert!(kv.exists("temp:1"));
+
+        // Evicting temp:1 is not enough and no volatile keys remain.
+        assert_eq!(kv.set("config:3".to_string(), "12345678".to_string()), Err(StoreError::OutOfMemory));
+        assert!(kv.exists("config:1") && kv.exists("config:2"));
+    }
+
+    #[test]
+    fn test_sharded_store_concurrent_writers() {
+        let kv = Arc::new(KVStore::with_shards(8, MemoryConfig::default()));
+        let handles: Vec<_> = (0..4).map(|t| {
+            let kv = Arc::clone(&kv);
+            thread::spawn(move || {
+                for i in 0..500 {
+                    kv.set(format!("user:{}:{}", t, i), i.to_string()).unwrap();
+                    if i % 2 == 0 { kv.delete(&format!("user:{}:{}", t, i)); }
+                }
+            })
+        }).collect();
+        for h in handles { h.join().unwrap(); }
+
+        assert_eq!(kv.shard_count(), 8);
+        assert_eq!(kv.len(), 4 * 250);
+        assert_eq!(kv.get("user:3:499").unwrap().as_deref(), Some("499"));
+        assert_eq!(kv.get("user:3:498").unwrap(), None);
+
+        let keys: Vec<String> = (0..20).map(|i| format!("cart:item:{}", i)).collect();
+        kv.set_many(keys.iter().map(|k| (k.clone(), k.to_uppercase())).collect()).unwrap();
+        let values = kv.get_many(&keys);
+        assert!(values.iter().zip(&keys).all(|(v, k)| v.as_deref() == Some(k.to_uppercase().as_str())));
+    }
+
+    #[test]
+    fn test_sharded_memory_budget() {
+        let kv = KVStore::with_shards(4, MemoryConfig { max_memory: 400, policy: EvictionPolicy::AllKeysLru });
+        for i in 0..200 { kv.set(format!("k{:03}", i), "12345".to_string()).unwrap(); }
+        let stats = kv.memory_stats();
+        assert!(stats.used_memory <= 400);
+        assert_eq!(stats.max_memory, 400);
+        assert_eq!(stats.evicted_keys as usize + kv.len(), 200);
+    }
+
+    #[test]
+    fn test_memory_budget_is_shared_by_shards() {
+        let kv = KVStore::with_shards(4, MemoryConfig { max_memory: 400, policy: EvictionPolicy::NoEviction });
+        kv.set("big".to_string(), "x".repeat(300)).unwrap();
+        assert_eq!(kv.set("more".to_string(), "x".repeat(100)), Err(StoreError::OutOfMemory));
+        kv.set("more".to_string(), "x".repeat(90)).unwrap();
+        assert_eq!(kv.memory_stats().used_memory, 397);
+
+        // A write evicts from whichever shards hold the data.
+        let kv = KVStore::with_shards(4, MemoryConfig { max_memory: 400, policy: EvictionPolicy::AllKeysLru });
+        for i in 0..40 { kv.set(format!("k{:03}", i), "123456".to_string()).unwrap(); }
+        assert_eq!(kv.len(), 40);
+        kv.set("big".to_string(), "x".repeat(300)).unwrap();
+        assert!(kv.memory_stats().used_memory <= 400);
+        assert_eq!(kv.get("big").unwrap().map(|v| v.len()), Some(300));
+        assert!(kv.len() <= 11);
+        kv.clear();
+        assert_eq!(kv.memory_stats().used_memory, 0);
+    }
+
+    #[test]
+    fn test_concurrent_writers_share_the_budget() {
+        let kv = Arc::new(KVStore::with_shards(4, MemoryConfig { max_memory: 2000, policy: EvictionPolicy::AllKeysLru }));
+        let writers: Vec<_> = (0..4).map(|t| {
+            let kv = Arc::clone(&kv);
+            thread::spawn(move || for i in 0..2000 { kv.set(format!("{}-{}", t, i), "12345678".to_string()).unwrap(); })
+        }).collect();
+        for w in writers { w.join().unwrap(); }
+        let stats = kv.memory_stats();
+        assert!(stats.used_memory <= 2000);
+        assert_eq!(stats.evicted_keys as usize + kv.len(), 8000);
+    }
+
+    #[test]
+    fn test_counters_and_wrong_type() {
+        let kv = KVStore::default();
+        assert_eq!(kv.incr_by("stats:hits", 1).unwrap(), 1);
+        assert_eq!(kv.incr_by("stats:hits", 10).unwrap(), 11);
+        assert_eq!(kv.incr_by("stats:hits", -12).unwrap(), -1);
+        assert_eq!(kv.type_of("stats:hits"), Some("string"));
+
+        kv.set("name".to_string(), "John".to_string()).unwrap();
+        assert_eq!(kv.incr_by("name", 1), Err(StoreError::NotInteger));
+        kv.set("max".to_string(), i64::MAX.to_string()).unwrap();
+        assert_eq!(kv.incr_by("max", 1), Err(StoreError::Overflow));
+
+        kv.push("queue", vec!["a".to_string()], false).unwrap();
+        assert_eq!(kv.get("queue"), Err(StoreError::WrongType));
+        assert_eq!(kv.incr_by("queue", 1), Err(StoreError::WrongType));
+        assert_eq!(kv.hash_get("queue", "f"), Err(StoreError::WrongType));
+        assert_eq!(kv.push("name", vec!["x".to_string()], true), Err(StoreError::WrongType));
+        assert_eq!(kv.get_many(&["queue".to_string(), "name".to_string()]), vec![None, Some("John".to_string())]);
+    }
+
+    #[test]
+    fn test_lists_hashes_and_sets() {
+        let kv = KVStore::default();
+        let strings = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(kv.push("queue", strings(&["a", "b"]), false).unwrap(), 2);
+        assert_eq!(kv.push("queue", strings(&["y", "z"]), true).unwrap(), 4);
+        assert_eq!(kv.range("queue", 0, -1).unwrap(), strings(&["z", "y", "a", "b"]));
+        assert_eq!(kv.range("queue", -2, 10).unwrap(), strings(&["a", "b"]));
+        assert_eq!(kv.pop("queue", 1, true).unwrap(), strings(&["z"]));
+        assert_eq!(kv.pop("queue", 2, false).unwrap(), strings(&["b", "a"]));
+        assert_eq!(kv.list_len("queue").unwrap(), 1);
+        // Popping the last element removes the key.
+        kv.pop("queue", 5, true).unwrap();
+        assert!(!kv.exists("queue"));
+
+        let pairs = vec![("name".to_string(), "John".to_string()), ("age".to_string(), "30".to_string())];
+        assert_eq!(kv.hash_set("user:1", pairs).unwrap(), 2);
+        assert_eq!(kv.hash_set("user:1", vec![("age".to_string(), "31".to_string())]).unwrap(), 0);
+        assert_eq!(kv.hash_get("user:1", "age").unwrap().as_deref(), Some("31"));
+        assert_eq!(kv.hash_get_all("user:1").unwrap().len(), 2);
+        assert_eq!(kv.hash_del("user:1", &strings(&["age", "missing"])).unwrap(), 1);
+        assert_eq!(kv.type_of("user:1"), Some("hash"));
+        kv.hash_del("user:1", &strings(&["name"])).unwrap();
+        assert_eq!(kv.type_of("user:1"), None);
+
+        assert_eq!(kv.set_add("tags", strings(&["rust", "redis", "rust"])).unwrap(), 2);
+        assert!(kv.set_is_member("tags", "redis").unwrap());
+        assert_eq!(kv.set_remove("tags", &strings(&["redis", "go"])).unwrap(), 1);
+        assert_eq!(kv.set_members("tags").unwrap(), strings(&["rust"]));
+        assert_eq!(kv.len(), 1);
+        assert_eq!(kv.memory_stats().used_memory, "tags".len() + "rust".len());
+    }
+
+    #[test]
+    fn test_collection_growth_respects_budget() {
+        let kv = limited(20, EvictionPolicy::NoEviction);
+        kv.push("queue", vec!["12345678".to_string()], false).unwrap();
+        assert_eq!(kv.push("queue", vec!["12345678".to_string()], false), Err(StoreError::OutOfMemory));
+        assert_eq!(kv.list_len("queue").unwrap(), 1);
+        assert_eq!(kv.memory_stats().used_memory, 13);
+    }
+
+    #[test]
+    fn test_keyspace_notifications_by_prefix() {
+        use crate::pubsub::{KeyspaceEvents, Message};
+
+        let kv = KVStore::with_shards(4, MemoryConfig::default());
+        let (mut sub, rx) = kv.pubsub().subscriber();
+        sub.psubscribe("__keyspace@0__:user:*");
+
+        // Notifications are off by default.
+        kv.set("user:1:name".to_string(), "John".to_string()).unwrap();
+        assert!(rx.try_recv().is_none());
+
+        kv.pubsub().set_keyspace_events(KeyspaceEvents::parse("KA").unwrap());
+        kv.set("user:1:name".to_string(), "Jane".to_string()).unwrap();
+        kv.set("cart:item:1".to_string(), "x".to_string()).unwrap();
+        kv.set_with_ttl("user:1:token".to_string(), "t".to_string(), Duration::from_millis(5)).unwrap();
+        kv.delete("user:1:name");
+        kv.push("user:1:queue", vec!["a".to_string()], false).unwrap();
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(kv.get("user:1:token").unwrap(), None);
+
+        let events: Vec<(String, String)> = std::iter::from_fn(|| rx.try_recv()).map(|m| match m {
+            Message::PMessage { channel, payload, .. } => (channel, String::from_utf8(payload).unwrap()),
+            other => panic!("unexpected {:?}", other),
+        }).collect();
+        let expected = [
+            ("user:1:name", "set"), ("user:1:token", "set"), ("user:1:name", "del"),
+            ("user:1:queue", "rpush"), ("user:1:token", "expired"),
+        ];
+        assert_eq!(events, expected.iter()
+            .map(|(k, e)| (format!("__keyspace@0__:{}", k), e.to_string()))
+            .collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,65 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Str(String),
+    List(VecDeque<String>),
+    Hash(HashMap<String, String>),
+    Set(HashSet<String>),
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Str(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+        }
+    }
+
+    /// Payload bytes counted against the memory budget.
+    pub fn size(&self) -> usize {
+        match self {
+            Value::Str(s) => s.len(),
+            Value::List(l) => l.iter().map(String::len).sum(),
+            Value::Hash(h) => h.iter().map(|(f, v)| f.len() + v.len()).sum(),
+            Value::Set(s) => s.iter().map(String::len).sum(),
+        }
+    }
+
+    /// Collections that become empty are deleted, as in Redis.
+    pub fn is_empty_collection(&self) -> bool {
+        match self {
+            Value::Str(_) => false,
+            Value::List(l) => l.is_empty(),
+            Value::Hash(h) => h.is_empty(),
+            Value::Set(s) => s.is_empty(),
+        }
+    }
+}
+
+/// Resolves Redis-style inclusive `start..=stop` indices, where negative
+/// values count from the end, to a half-open range within `len`.
+pub fn resolve_range(start: i64, stop: i64, len: usize) -> std::ops::Range<usize> {
+    let len = len as i64;
+    let norm = |i: i64| if i < 0 { (len + i).max(0) } else { i };
+    let (start, stop) = (norm(start), norm(stop).min(len - 1));
+    if start > stop || start >= len { return 0..0; }
+    start as usize..stop as usize + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_range() {
+        assert_eq!(resolve_range(0, -1, 5), 0..5);
+        assert_eq!(resolve_range(1, 2, 5), 1..3);
+        assert_eq!(resolve_range(-2, -1, 5), 3..5);
+        assert_eq!(resolve_range(-100, 100, 5), 0..5);
+        assert_eq!(resolve_range(3, 1, 5), 0..0);
+        assert_eq!(resolve_range(0, -1, 0), 0..0);
+    }
+}
@@ -0,0 +1,1018 @@
+use axum::body::{Body, Bytes};
+use axum::extract::Request;
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use axum::response::Response;
+use http_body::{Body as HttpBody, Frame, SizeHint};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::config::{CacheConfig, RouteCacheConfig};
+
+// Statuses that may be stored (RFC 9110 §15.1, heuristically cacheable).
+const CACHEABLE: [u16; 10] = [200, 203, 204, 300, 301, 404, 405, 410, 414, 501];
+
+// What a 304 from the cache carries over from the stored response
+// (RFC 9110 §15.4.5).
+const NOT_MODIFIED_HEADERS: [HeaderName; 5] = [
+    header::CACHE_CONTROL,
+    header::CONTENT_LOCATION,
+    header::ETAG,
+    header::EXPIRES,
+    header::VARY,
+];
+
+/// How one route uses the cache.
+#[derive(Debug, Clone)]
+pub struct CachePolicy {
+    // Keeps this route's entries apart from other routes'.
+    scope: String,
+    ttl: Option<Duration>,
+    stale_while_revalidate: Option<Duration>,
+    // Every request on the route is authenticated. Such responses, and any
+    // to a request with `Authorization`, are shared between callers only
+    // if the upstream marks them `public`, `must-revalidate` or gives them
+    // `s-maxage` (RFC 9111 §3.5).
+    authenticated: bool,
+}
+
+impl CachePolicy {
+    pub fn new(scope: String, config: &RouteCacheConfig, authenticated: bool) -> Self {
+        Self {
+            scope,
+            ttl: config.ttl_secs.map(Duration::from_secs),
+            stale_while_revalidate: config.stale_while_revalidate_secs.map(Duration::from_secs),
+            authenticated,
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct CacheStats {
+    /// Served fresh from the cache.
+    pub hits: u64,
+    /// Served stale while being refreshed in the background.
+    pub stale_hits: u64,
+    /// Stale entries the upstream confirmed with a 304.
+    pub revalidations: u64,
+    /// Fetched from the upstream in full.
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+/// In-memory HTTP cache of upstream responses, bounded in size and evicting
+/// the least recently used entries first.
+pub struct Cache {
+    max_bytes: usize,
+    max_entry_bytes: usize,
+    inner: Mutex<Inner>,
+    hits: AtomicU64,
+    stale_hits: AtomicU64,
+    revalidations: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+#[derive(Default)]
+struct Inner {
+    // The variants stored for each URL, one per combination of the request
+    // headers their `Vary` names.
+    entries: HashMap<String, Vec<Variant>>,
+    // Last use of each variant, oldest first.
+    lru: BTreeMap<u64, String>,
+    tick: u64,
+    bytes: usize,
+}
+
+struct Variant {
+    vary: Vec<(HeaderName, Vec<HeaderValue>)>,
+    stored: Arc<Stored>,
+    tick: u64,
+    revalidating: bool,
+}
+
+struct Stored {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    // When the response was generated: received, less the `Age` it came with.
+    born: Instant,
+    lifetime: Duration,
+    stale_while_revalidate: Duration,
+    size: usize,
+}
+
+/// How long a response may be served: fresh, and then stale while it is
+/// revalidated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Freshness {
+    lifetime: Duration,
+    stale_while_revalidate: Duration,
+}
+
+#[derive(Debug, Default)]
+struct Directives {
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    public: bool,
+    must_revalidate: bool,
+    max_age: Option<u64>,
+    s_maxage: Option<u64>,
+    stale_while_revalidate: Option<u64>,
+}
+
+impl Cache {
+    pub fn new(config: &CacheConfig) -> Self {
+        Self {
+            max_bytes: config.max_bytes as usize,
+            max_entry_bytes: config.max_entry_bytes as usize,
+            inner: Mutex::new(Inner::default()),
+            hits: AtomicU64::new(0),
+            stale_hits: AtomicU64::new(0),
+            revalidations: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    /// Answers `request` from the cache, or with `fetch`, which forwards a
+    /// request upstream. Only GETs without `Range` are cached.
+    pub async fn handle<F, Fut>(
+        self: &Arc<Self>,
+        policy: &CachePolicy,
+        request: Request,
+        fetch: F,
+    ) -> Response
+    where
+        F: FnOnce(Request) -> Fut + Send + 'static,
+        Fut: Future<Output = Response> + Send,
+    {
+        let directives = Directives::parse(request.headers());
+        if request.method() != Method::GET
+            || request.headers().contains_key(header::RANGE)
+            || directives.no_store
+        {
+            return fetch(request).await;
+        }
+
+        let key = format!("{}|{}", policy.scope, request.uri());
+        // `no-cache` from the client skips the stored copy but still updates it.
+        let cached = if directives.no_cache || directives.max_age == Some(0) {
+            None
+        } else {
+            self.lookup(&key, request.headers())
+        };
+        let mut stale = None;
+        if let Some(stored) = cached {
+            let age = stored.born.elapsed();
+            if age < stored.lifetime {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return respond(&stored, request.headers(), "HIT");
+            }
+            if age < stored.lifetime + stored.stale_while_revalidate {
+                self.stale_hits.fetch_add(1, Ordering::Relaxed);
+                let response = respond(&stored, request.headers(), "STALE");
+                if self.claim(&key, &stored) {
+                    self.spawn_revalidation(key, policy.clone(), request, stored, fetch);
+                }
+                return response;
+            }
+            stale = Some(stored);
+        }
+
+        let (parts, body) = request.into_parts();
+        let request_headers = parts.headers.clone();
+        let mut request = Request::from_parts(parts, body);
+        if let Some(stored) = &stale {
+            add_validators(request.headers_mut(), stored);
+        }
+        let response = fetch(request).await;
+        if let Some(stored) = stale.filter(|_| response.status() == StatusCode::NOT_MODIFIED) {
+            self.revalidations.fetch_add(1, Ordering::Relaxed);
+            let stored = self.refresh(&key, policy, &request_headers, &stored, response.headers());
+            return respond(&stored, &request_headers, "REVALIDATED");
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        self.store_streaming(key, policy, request_headers, response)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let inner = self.inner.lock().unwrap();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            stale_hits: self.stale_hits.load(Ordering::Relaxed),
+            revalidations: self.revalidations.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            entries: inner.lru.len(),
+            bytes: inner.bytes,
+        }
+    }
+
+    fn lookup(&self, key: &str, request_headers: &HeaderMap) -> Option<Arc<Stored>> {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let variant = inner
+            .entries
+            .get_mut(key)?
+            .iter_mut()
+            .find(|v| v.matches(request_headers))?;
+        inner.lru.remove(&variant.tick);
+        inner.tick += 1;
+        variant.tick = inner.tick;
+        inner.lru.insert(variant.tick, key.to_string());
+        Some(Arc::clone(&variant.stored))
+    }
+
+    /// Whether the caller should revalidate `stored`: only one request does
+    /// at a time.
+    fn claim(&self, key: &str, stored: &Arc<Stored>) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let variant = inner
+            .entries
+            .get_mut(key)
+            .and_then(|variants| variants.iter_mut().find(|v| Arc::ptr_eq(&v.stored, stored)));
+        match variant {
+            Some(variant) if !variant.revalidating => {
+                variant.revalidating = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn release(&self, key: &str, stored: &Arc<Stored>) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(variant) = inner
+            .entries
+            .get_mut(key)
+            .and_then(|variants| variants.iter_mut().find(|v| Arc::ptr_eq(&v.stored, stored)))
+        {
+            variant.revalidating = false;
+        }
+    }
+
+    fn spawn_revalidation<F, Fut>(
+        self: &Arc<Self>,
+        key: String,
+        policy: CachePolicy,
+        request: Request,
+        stored: Arc<Stored>,
+        fetch: F,
+    ) where
+        F: FnOnce(Request) -> Fut + Send + 'static,
+        Fut: Future<Output = Response> + Send,
+    {
+        let cache = Arc::clone(self);
+        let (parts, _) = request.into_parts();
+        tokio::spawn(async move {
+            let request_headers = parts.headers.clone();
+            let mut request = Request::from_parts(parts, Body::empty());
+            add_validators(request.headers_mut(), &stored);
+            let response = fetch(request).await;
+            if response.status() == StatusCode::NOT_MODIFIED {
+                cache.revalidations.fetch_add(1, Ordering::Relaxed);
+                cache.refresh(&key, &policy, &request_headers, &stored, response.headers());
+            } else if let Some(freshness) = freshness(
+                &policy,
+                &request_headers,
+                response.status(),
+                response.headers(),
+            ) {
+                let (parts, body) = response.into_parts();
+                if let Ok(body) = axum::body::to_bytes(body, cache.max_entry_bytes).await {
+                    let stored = Stored::new(parts.status, parts.headers, body, freshness, &key);
+                    cache.insert(key.clone(), &request_headers, stored);
+                }
+            }
+            // Anything else keeps the stale entry until it expires.
+            cache.release(&key, &stored);
+        });
+    }
+
+    /// Stores `old` again with the headers of the 304 that confirmed it.
+    fn refresh(
+        &self,
+        key: &str,
+        policy: &CachePolicy,
+        request_headers: &HeaderMap,
+        old: &Stored,
+        update: &HeaderMap,
+    ) -> Arc<Stored> {
+        let mut headers = old.headers.clone();
+        for name in update.keys() {
+            if *name == header::CONTENT_LENGTH {
+                continue;
+            }
+            headers.remove(name);
+            for value in update.get_all(name) {
+                headers.append(name.clone(), value.clone());
+            }
+        }
+        match freshness(policy, request_headers, old.status, &headers) {
+            Some(freshness) => {
+                let stored = Stored::new(old.status, headers, old.body.clone(), freshness, key);
+                self.insert(key.to_string(), request_headers, stored)
+            }
+            // No longer storable: serve it this once, and the old entry stays
+            // stale until evicted.
+            None => Arc::new(Stored::new(
+                old.status,
+                headers,
+                old.body.clone(),
+                Freshness::default(),
+                key,
+            )),
+        }
+    }
+
+    /// Passes `response` on, storing its body once it has been streamed
+    /// to the client, if the response may be stored and fits.
+    fn store_streaming(
+        self: &Arc<Self>,
+        key: String,
+        policy: &CachePolicy,
+        request_headers: HeaderMap,
+        response: Response,
+    ) -> Response {
+        let (mut parts, body) = response.into_parts();
+        let fits = content_length(&parts.headers).is_none_or(|len| len <= self.max_entry_bytes);
+        let freshness = freshness(policy, &request_headers, parts.status, &parts.headers);
+        let body = match freshness.filter(|_| fits) {
+            Some(freshness) => {
+                let cache = Arc::clone(self);
+                let (status, headers) = (parts.status, parts.headers.clone());
+                let store = move |body: Bytes| {
+                    let stored = Stored::new(status, headers, body, freshness, &key);
+                    cache.insert(key, &request_headers, stored);
+                };
+                // An empty body may never be polled at all.
+                if body.is_end_stream() {
+                    store(Bytes::new());
+                    body
+                } else {
+                    Body::new(Tee {
+                        inner: body,
+                        buffer: Some(Vec::new()),
+                        max: self.max_entry_bytes,
+                        store: Some(Box::new(store)),
+                    })
+                }
+            }
+            None => body,
+        };
+        parts
+            .headers
+            .insert("x-cache", HeaderValue::from_static("MISS"));
+        Response::from_parts(parts, body)
+    }
+
+    fn insert(&self, key: String, request_headers: &HeaderMap, stored: Stored) -> Arc<Stored> {
+        let stored = Arc::new(stored);
+        if stored.size > self.max_entry_bytes {
+            return stored;
+        }
+        let vary: Vec<(HeaderName, Vec<HeaderValue>)> = vary_names(&stored.headers)
+            .into_iter()
+            .map(|name| {
+                let values = request_headers.get_all(&name).iter().cloned().collect();
+                (name, values)
+            })
+            .collect();
+
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        // Replaces the variant for the same request headers.
+        if let Some(variants) = inner.entries.get_mut(&key) {
+            if let Some(i) = variants.iter().position(|v| v.vary == vary) {
+                let old = variants.remove(i);
+                inner.lru.remove(&old.tick);
+                inner.bytes -= old.stored.size;
+            }
+        }
+        while inner.bytes + stored.size > self.max_bytes {
+            let Some((tick, oldest)) = inner.lru.pop_first() else {
+                break;
+            };
+            if let Some(variants) = inner.entries.get_mut(&oldest) {
+                if let Some(i) = variants.iter().position(|v| v.tick == tick) {
+                    inner.bytes -= variants.remove(i).stored.size;
+                    self.evictions.fetch_add(1, Ordering::Relaxed);
+                }
+                if variants.is_empty() {
+                    inner.entries.remove(&oldest);
+                }
+            }
+        }
+        inner.tick += 1;
+        inner.bytes += stored.size;
+        inner.lru.insert(inner.tick, key.clone());
+        inner.entries.entry(key).or_default().push(Variant {
+            vary,
+            stored: Arc::clone(&stored),
+            tick: inner.tick,
+            revalidating: false,
+        });
+        stored
+    }
+}
+
+impl Variant {
+    fn matches(&self, request_headers: &HeaderMap) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, values)| request_headers.get_all(name).iter().eq(values.iter()))
+    }
+}
+
+impl Stored {
+    fn new(
+        status: StatusCode,
+        headers: HeaderMap,
+        body: Bytes,
+        freshness: Freshness,
+        key: &str,
+    ) -> Self {
+        let now = Instant::now();
+        let age = headers
+            .get(header::AGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .map_or(Duration::ZERO, Duration::from_secs);
+        let header_bytes: usize = headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        Self {
+            size: key.len() + header_bytes + body.len(),
+            status,
+            headers,
+            body,
+            born: now.checked_sub(age).unwrap_or(now),
+            lifetime: freshness.lifetime,
+            stale_while_revalidate: freshness.stale_while_revalidate,
+        }
+    }
+}
+
+impl Default for Freshness {
+    fn default() -> Self {
+        Self {
+            lifetime: Duration::ZERO,
+            stale_while_revalidate: Duration::ZERO,
+        }
+    }
+}
+
+impl Directives {
+    fn parse(headers: &HeaderMap) -> Self {
+        let mut directives = Self::default();
+        let values = headers
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','));
+        for directive in values {
+            let (name, value) = directive.split_once('=').unwrap_or((directive, ""));
+            let secs = value.trim().trim_matches('"').parse().ok();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "no-store" => directives.no_store = true,
+                "no-cache" => directives.no_cache = true,
+                "private" => directives.private = true,
+                "public" => directives.public = true,
+                "must-revalidate" | "proxy-revalidate" => directives.must_revalidate = true,
+                "max-age" => directives.max_age = secs,
+                "s-maxage" => directives.s_maxage = secs,
+                "stale-while-revalidate" => directives.stale_while_revalidate = secs,
+                _ => {}
+            }
+        }
+        directives
+    }
+}
+
+/// How long a response to a request with `request_headers` may be stored
+/// and served, or `None` if it must not be stored at all.
+fn freshness(
+    policy: &CachePolicy,
+    request_headers: &HeaderMap,
+    status: StatusCode,
+    headers: &HeaderMap,
+) -> Option<Freshness> {
+    if !CACHEABLE.contains(&status.as_u16())
+        || headers.contains_key(header::SET_COOKIE)
+        || vary_names(headers).iter().any(|name| name == "*")
+    {
+        return None;
+    }
+    let cc = Directives::parse(headers);
+    let authenticated = policy.authenticated || request_headers.contains_key(header::AUTHORIZATION);
+    let shareable = cc.public || cc.must_revalidate || cc.s_maxage.is_some();
+    if cc.no_store || cc.private || (authenticated && !shareable) {
+        return None;
+    }
+    if cc.no_cache {
+        // Stored only to be revalidated on every use.
+        let validated =
+            headers.contains_key(header::ETAG) || headers.contains_key(header::LAST_MODIFIED);
+        return validated.then(Freshness::default);
+    }
+    let lifetime = policy
+        .ttl
+        .or(cc.s_maxage.or(cc.max_age).map(Duration::from_secs))
+        .or_else(|| expires(headers))?;
+    let stale_while_revalidate = if cc.must_revalidate {
+        Duration::ZERO
+    } else {
+        policy
+            .stale_while_revalidate
+            .or(cc.stale_while_revalidate.map(Duration::from_secs))
+            .unwrap_or(Duration::ZERO)
+    };
+    Some(Freshness {
+        lifetime,
+        stale_while_revalidate,
+    })
+}
+
+/// The lifetime `Expires` gives, measured from `Date`. An invalid date
+/// means already expired.
+fn expires(headers: &HeaderMap) -> Option<Duration> {
+    let date = |name| {
+        headers
+            .get(name)
+            .and_then(|v: &HeaderValue| v.to_str().ok())
+            .map(|v| httpdate::parse_http_date(v).ok())
+    };
+    let expires = date(header::EXPIRES)?;
+    let now = date(header::DATE).flatten().unwrap_or_else(SystemTime::now);
+    Some(
+        expires
+            .and_then(|e| e.duration_since(now).ok())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+fn vary_names(headers: &HeaderMap) -> Vec<HeaderName> {
+    headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect()
+}
+
+fn content_length(headers: &HeaderMap) -> Option<usize> {
+    headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+}
+
+/// Makes the upstream request conditional on the stored copy, replacing
+/// the client's own conditions.
+fn add_validators(headers: &mut HeaderMap, stored: &Stored) {
+    headers.remove(header::IF_NONE_MATCH);
+    headers.remove(header::IF_MODIFIED_SINCE);
+    if let Some(etag) = stored.headers.get(header::ETAG) {
+        headers.insert(header::IF_NONE_MATCH, etag.clone());
+    }
+    if let Some(modified) = stored.headers.get(header::LAST_MODIFIED) {
+        headers.insert(header::IF_MODIFIED_SINCE, modified.clone());
+    }
+}
+
+/// A response from the cache: 304 if the client's `If-None-Match` matches.
+fn respond(stored: &Stored, request_headers: &HeaderMap, outcome: &'static str) -> Response {
+    let not_modified = stored
+        .headers
+        .get(header::ETAG)
+        .is_some_and(|etag| etag_matches(request_headers, etag));
+    let mut response = if not_modified {
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::NOT_MODIFIED;
+        for name in NOT_MODIFIED_HEADERS {
+            for value in stored.headers.get_all(&name) {
+                response.headers_mut().append(name.clone(), value.clone());
+            }
+        }
+        response
+    } else {
+        let mut response = Response::new(Body::from(stored.body.clone()));
+        *response.status_mut() = stored.status;
+        *response.headers_mut() = stored.headers.clone();
+        response
+    };
+    let headers = response.headers_mut();
+    headers.insert(
+        header::AGE,
+        HeaderValue::from(stored.born.elapsed().as_secs()),
+    );
+    headers.insert("x-cache", HeaderValue::from_static(outcome));
+    response
+}
+
+/// Weak comparison, as `If-None-Match` uses (RFC 9110 §13.1.2).
+fn etag_matches(request_headers: &HeaderMap, etag: &HeaderValue) -> bool {
+    let weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let Ok(etag) = etag.to_str() else {
+        return false;
+    };
+    request_headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|tag| tag.trim() == "*" || weak(tag) == weak(etag))
+}
+
+/// Copies the body as it streams to the client, and stores it once it has
+/// ended, if it stayed within `max`.
+struct Tee {
+    inner: Body,
+    // `None` once the body is known not to be storable.
+    buffer: Option<Vec<u8>>,
+    max: usize,
+    store: Option<Box<dyn FnOnce(Bytes) + Send>>,
+}
+
+impl HttpBody for Tee {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let this = &mut *self;
+        let frame = ready!(Pin::new(&mut this.inner).poll_frame(cx));
+        match &frame {
+            Some(Ok(frame)) => match (frame.data_ref(), &mut this.buffer) {
+                (Some(data), Some(buffer)) if buffer.len() + data.len() <= this.max => {
+                    buffer.extend_from_slice(data);
+                }
+                _ => this.buffer = None,
+            },
+            Some(Err(_)) => this.buffer = None,
+            None => {}
+        }
+        // A server stops polling once it has sent `Content-Length` bytes, so
+        // the end is not always seen as `None`.
+        if frame.is_none() || this.inner.is_end_stream() {
+            if let (Some(buffer), Some(store)) = (this.buffer.take(), this.store.take()) {
+                store(Bytes::from(buffer));
+            }
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    fn policy(ttl_secs: Option<u64>, swr_secs: Option<u64>, authenticated: bool) -> CachePolicy {
+        let config = RouteCacheConfig {
+            ttl_secs,
+            stale_while_revalidate_secs: swr_secs,
+        };
+        CachePolicy::new("r".to_string(), &config, authenticated)
+    }
+
+    fn cache(max_bytes: u64) -> Arc<Cache> {
+        Arc::new(Cache::new(&CacheConfig {
+            max_bytes,
+            max_entry_bytes: max_bytes,
+        }))
+    }
+
+    fn upstream_response(body: &'static str, headers: &[(&'static str, &'static str)]) -> Response {
+        let mut response = Response::new(Body::from(body));
+        for (name, value) in headers {
+            response
+                .headers_mut()
+                .append(*name, HeaderValue::from_static(value));
+        }
+        response
+    }
+
+    /// A GET through the cache, with the body read to the end so that a
+    /// miss gets stored.
+    async fn get<U>(
+        cache: &Arc<Cache>,
+        policy: &CachePolicy,
+        headers: &[(&'static str, &'static str)],
+        upstream: U,
+    ) -> (StatusCode, HeaderMap, Bytes)
+    where
+        U: FnOnce(&HeaderMap) -> Response + Send + 'static,
+    {
+        let mut request = Request::new(Body::empty());
+        *request.uri_mut() = "/items?page=1".parse().unwrap();
+        for (name, value) in headers {
+            request
+                .headers_mut()
+                .append(*name, HeaderValue::from_static(value));
+        }
+        let fetch = move |request: Request| async move { upstream(request.headers()) };
+        let (parts, body) = cache.handle(policy, request, fetch).await.into_parts();
+        let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        (parts.status, parts.headers, body)
+    }
+
+    fn counting(
+        calls: &Arc<AtomicUsize>,
+        body: &'static str,
+        headers: &'static [(&'static str, &'static str)],
+    ) -> impl FnOnce(&HeaderMap) -> Response + Send + 'static {
+        let calls = Arc::clone(calls);
+        move |_| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            upstream_response(body, headers)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_age_hit_and_miss() {
+        let cache = cache(1 << 20);
+        let policy = policy(None, None, false);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let fresh = &[("cache-control", "max-age=60")];
+
+        let (_, headers, body) = get(&cache, &policy, &[], counting(&calls, "v1", fresh)).await;
+        assert_eq!(headers["x-cache"], "MISS");
+        assert_eq!(body, "v1");
+        let (status, headers, body) =
+            get(&cache, &policy, &[], counting(&calls, "v2", fresh)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers["x-cache"], "HIT");
+        assert_eq!(headers[header::AGE], "0");
+        assert_eq!(body, "v1");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // The client can ask to skip the stored copy.
+        let (_, headers, body) = get(
+            &cache,
+            &policy,
+            &[("cache-control", "no-cache")],
+            counting(&calls, "v2", fresh),
+        )
+        .await;
+        assert_eq!(headers["x-cache"], "MISS");
+        assert_eq!(body, "v2");
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 2, 1));
+    }
+
+    #[tokio::test]
+    async fn test_not_stored() {
+        let cache = cache(1 << 20);
+        let cases: [(&CachePolicy, &'static [(&str, &str)]); 5] = [
+            (&policy(None, None, false), &[]),
+            (
+                &policy(None, None, false),
+                &[("cache-control", "no-store, max-age=60")],
+            ),
+            (
+                &policy(Some(60), None, false),
+                &[("cache-control", "private")],
+            ),
+            (&policy(Some(60), None, false), &[("set-cookie", "a=b")]),
+            // Authenticated responses must be marked shareable.
+            (
+                &policy(None, None, true),
+                &[("cache-control", "max-age=60")],
+            ),
+        ];
+        for (policy, headers) in cases {
+            let calls = Arc::new(AtomicUsize::new(0));
+            get(&cache, policy, &[], counting(&calls, "a", headers)).await;
+            get(&cache, policy, &[], counting(&calls, "a", headers)).await;
+            assert_eq!(calls.load(Ordering::SeqCst), 2, "{:?}", headers);
+        }
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let public = &[("cache-control", "public, max-age=60")];
+        let policy = policy(None, None, true);
+        get(&cache, &policy, &[], counting(&calls, "a", public)).await;
+        get(&cache, &policy, &[], counting(&calls, "a", public)).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_authorization_on_public_route() {
+        // The route's TTL does not make a response to one caller's
+        // credentials shareable.
+        let policy = policy(Some(60), None, false);
+        let bearer = &[("authorization", "Bearer alice")];
+        for (headers, stored) in [
+            (&[][..], false),
+            (&[("cache-control", "max-age=60")][..], false),
+            (&[("cache-control", "public")][..], true),
+            (&[("cache-control", "s-maxage=60")][..], true),
+            (&[("cache-control", "must-revalidate")][..], true),
+        ] {
+            let cache = cache(1 << 20);
+            let calls = Arc::new(AtomicUsize::new(0));
+            let headers: &'static [(&str, &str)] = headers;
+            get(
+                &cache,
+                &policy,
+                bearer,
+                counting(&calls, "alice's", headers),
+            )
+            .await;
+            let (_, _, body) =
+                get(&cache, &policy, &[], counting(&calls, "anyone's", headers)).await;
+            let expected = if stored { "alice's" } else { "anyone's" };
+            assert_eq!(body, expected, "{:?}", headers);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_etag_revalidation() {
+        let cache = cache(1 << 20);
+        let policy = policy(None, None, false);
+        let headers = &[("cache-control", "no-cache"), ("etag", "\"v1\"")];
+        get(&cache, &policy, &[], |_| upstream_response("body", headers)).await;
+
+        // Stored, but confirmed with the upstream on every use.
+        let (status, response_headers, body) = get(&cache, &policy, &[], |request| {
+            assert_eq!(request[header::IF_NONE_MATCH], "\"v1\"");
+            let mut response = upstream_response("", &[("cache-control", "max-age=60")]);
+            *response.status_mut() = StatusCode::NOT_MODIFIED;
+            response
+        })
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response_headers["x-cache"], "REVALIDATED");
+        assert_eq!(body, "body");
+
+        // Now fresh for 60s, and the client's copy still matches.
+        let (status, response_headers, body) =
+            get(&cache, &policy, &[("if-none-match", "W/\"v1\"")], |_| {
+                panic!("served from the cache")
+            })
+            .await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+        assert_eq!(response_headers["x-cache"], "HIT");
+        assert_eq!(response_headers[header::ETAG], "\"v1\"");
+        assert!(body.is_empty());
+        assert_eq!(cache.stats().revalidations, 1);
+    }
+
+    #[tokio::test]
+    async fn test_vary() {
+        let cache = cache(1 << 20);
+        let policy = policy(Some(60), None, false);
+        let gzip = &[("accept-encoding", "gzip")];
+        let vary = &[("vary", "Accept-Encoding")];
+        get(&cache, &policy, gzip, |_| upstream_response("gzip", vary)).await;
+        get(&cache, &policy, &[], |_| upstream_response("plain", vary)).await;
+
+        let (_, _, body) = get(&cache, &policy, gzip, |_| panic!("cached")).await;
+        assert_eq!(body, "gzip");
+        let (_, _, body) = get(&cache, &policy, &[], |_| panic!("cached")).await;
+        assert_eq!(body, "plain");
+        assert_eq!(cache.stats().entries, 2);
+    }
+
+    #[tokio::test]
+    async fn test_stale_while_revalidate() {
+        let cache = cache(1 << 20);
+        // Stale at once, but usable for a minute while refreshed.
+        let policy = policy(Some(0), Some(60), false);
+        get(&cache, &policy, &[], |_| upstream_response("v1", &[])).await;
+
+        let (_, headers, body) = get(&cache, &policy, &[], |_| upstream_response("v2", &[])).await;
+        assert_eq!(headers["x-cache"], "STALE");
+        assert_eq!(body, "v1");
+        // Refreshed in the background.
+        for _ in 0..100 {
+            let stored = cache.lookup("r|/items?page=1", &HeaderMap::new()).unwrap();
+            if stored.body == "v2" {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let (_, headers, body) = get(&cache, &policy, &[], |_| upstream_response("v3", &[])).await;
+        assert_eq!(headers["x-cache"], "STALE");
+        assert_eq!(body, "v2");
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let cache = cache(250);
+        let policy = policy(Some(60), None, false);
+        let freshness = freshness(
+            &policy,
+            &HeaderMap::new(),
+            StatusCode::OK,
+            &HeaderMap::new(),
+        )
+        .unwrap();
+        let store = |key: &str| {
+            let body = Bytes::from(vec![b'x'; 100]);
+            let stored = Stored::new(StatusCode::OK, HeaderMap::new(), body, freshness, key);
+            cache.insert(key.to_string(), &HeaderMap::new(), stored);
+        };
+        store("a");
+        store("b");
+        assert!(cache.lookup("a", &HeaderMap::new()).is_some());
+        store("c");
+        assert!(cache.lookup("b", &HeaderMap::new()).is_none());
+        assert!(cache.lookup("a", &HeaderMap::new()).is_some());
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.bytes, stats.evictions), (2, 202, 1));
+    }
+
+    #[test]
+    fn test_freshness() {
+        let lifetime = |policy: &CachePolicy, headers: &[(&'static str, &'static str)]| {
+            let response = upstream_response("", headers);
+            freshness(
+                policy,
+                &HeaderMap::new(),
+                StatusCode::OK,
+                response.headers(),
+            )
+            .map(|f| f.lifetime.as_secs())
+        };
+        let shared = policy(None, None, false);
+        assert_eq!(
+            lifetime(&shared, &[("cache-control", "max-age=10")]),
+            Some(10)
+        );
+        assert_eq!(
+            lifetime(&shared, &[("cache-control", "max-age=10, s-maxage=20")]),
+            Some(20)
+        );
+        assert_eq!(
+            lifetime(
+                &shared,
+                &[
+                    ("date", "Sun, 06 Nov 1994 08:49:37 GMT"),
+                    ("expires", "Sun, 06 Nov 1994 08:50:07 GMT"),
+                ],
+            ),
+            Some(30)
+        );
+        assert_eq!(lifetime(&shared, &[("expires", "0")]), Some(0));
+        assert_eq!(
+            lifetime(&shared, &[("vary", "*"), ("cache-control", "max-age=10")]),
+            None
+        );
+        // The route's TTL wins over the upstream's.
+        assert_eq!(
+            lifetime(
+                &policy(Some(5), None, false),
+                &[("cache-control", "max-age=10")]
+            ),
+            Some(5)
+        );
+
+        let swr = freshness(
+            &shared,
+            &HeaderMap::new(),
+            StatusCode::OK,
+            upstream_response(
+                "",
+                &[(
+                    "cache-control",
+                    "max-age=1, stale-while-revalidate=30, must-revalidate",
+                )],
+            )
+            .headers(),
+        )
+        .unwrap();
+        assert_eq!(swr.stale_while_revalidate, Duration::ZERO);
+        assert_eq!(
+            freshness(
+                &shared,
+                &HeaderMap::new(),
+                StatusCode::CREATED,
+                &HeaderMap::new()
+            ),
+            None
+        );
+    }
+}
@@ -0,0 +1,594 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::consumers::{normalize_subject, parse_hash};
+use crate::plugins::Plugin;
+use crate::rate_limit::Algorithm;
+
+/// Name of the upstream given on the command line.
+pub const DEFAULT_UPSTREAM: &str = "default";
+
+/// Route table loaded from a TOML file. Routes are tried in order and the
+/// first whose path and method match handles the request:
+///
+/// ```toml
+/// # Limit for routes without their own, shared by all of them.
+/// [rate_limit]
+/// requests = 100
+/// window_secs = 60
+///
+/// [upstreams]
+/// users = "http://localhost:3001/api"
+///
+/// # Shared by all routes with a `[routes.cache]` table.
+/// [cache]
+/// max_bytes = 67108864
+/// max_entry_bytes = 1048576
+///
+/// [consumers.batch-job]
+/// api_keys = ["sha256:<hex>"]     # from `lightweight-api-gateway hash-key`
+/// scopes = ["users:read"]
+///
+/// [consumers.reporting]
+/// client_certs = ["CN=reporting,O=Example"]
+///
+/// [[routes]]
+/// path = "/public/*"
+/// auth = false
+///
+/// [[routes]]
+/// path = "/users/{id}"
+/// methods = ["GET", "PUT"]
+/// upstream = "users"
+/// scopes = ["users:read"]
+/// consumers = ["batch-job", "reporting"]
+///
+/// [routes.rate_limit]
+/// algorithm = "sliding-log"
+/// requests = 10
+/// window_secs = 1
+/// key = "consumer"
+///
+/// [routes.rate_limit.consumers]
+/// batch-job = { requests = 100, window_secs = 1 }
+///
+/// [routes.cache]
+/// ttl_secs = 30
+/// stale_while_revalidate_secs = 10
+///
+/// [[routes.plugins]]
+/// type = "rewrite"
+/// pattern = "^/users/(\\d+)$"
+/// replace = "/v2/users/$1"
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    /// Named upstream URLs; `default` is the command-line target.
+    #[serde(default)]
+    pub upstreams: BTreeMap<String, String>,
+    /// Callers authenticating with an API key or client certificate.
+    #[serde(default)]
+    pub consumers: BTreeMap<String, ConsumerConfig>,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    pub routes: Vec<RouteConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Bodies and headers of all entries together; the least recently used
+    /// are evicted beyond it.
+    #[serde(default = "default_cache_bytes")]
+    pub max_bytes: u64,
+    /// Larger responses are passed through without being stored.
+    #[serde(default = "default_cache_entry_bytes")]
+    pub max_entry_bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConsumerConfig {
+    /// `sha256:<hex>` hashes of the keys sent in `X-API-Key`.
+    #[serde(default)]
+    pub api_keys: Vec<String>,
+    /// Subjects of client certificates, e.g. `CN=reporting,O=Example`.
+    #[serde(default)]
+    pub client_certs: Vec<String>,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    /// Names the route in logs and keeps its rate-limit counters apart from
+    /// other routes'. Defaults to the path pattern.
+    pub name: Option<String>,
+    /// `/`-separated segments: literals, `{param}` for any one segment, and
+    /// a trailing `*` for the rest of the path.
+    pub path: String,
+    /// Empty allows every method.
+    #[serde(default)]
+    pub methods: Vec<String>,
+    #[serde(default = "default_upstream")]
+    pub upstream: String,
+    /// Whether callers must authenticate, with a token, API key or client
+    /// certificate.
+    #[serde(default = "default_auth")]
+    pub auth: bool,
+    /// Scopes the caller must all have.
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// Consumers allowed to use the route; a token is not admitted by its
+    /// subject. Empty allows everyone who authenticates.
+    #[serde(default)]
+    pub consumers: Vec<String>,
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Caches GET responses; routes without it are never cached.
+    pub cache: Option<RouteCacheConfig>,
+    /// Run in order on the request, and in reverse on the response.
+    #[serde(default)]
+    pub plugins: Vec<PluginConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteCacheConfig {
+    /// Freshness lifetime, overriding the upstream's `max-age`. Without it
+    /// only responses the upstream gives a lifetime are stored.
+    pub ttl_secs: Option<u64>,
+    /// How long a stale entry may still be served while it is refreshed in
+    /// the background, overriding the upstream's `stale-while-revalidate`.
+    pub stale_while_revalidate_secs: Option<u64>,
+}
+
+/// A request/response transformation, selected by `type`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum PluginConfig {
+    Headers(HeadersConfig),
+    Rewrite(RewriteConfig),
+    RequestId(RequestIdConfig),
+    Cors(CorsConfig),
+    BodyLimit(BodyLimitConfig),
+}
+
+/// Headers to set or remove; removals happen first.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeadersConfig {
+    #[serde(default)]
+    pub request_set: BTreeMap<String, String>,
+    #[serde(default)]
+    pub request_remove: Vec<String>,
+    #[serde(default)]
+    pub response_set: BTreeMap<String, String>,
+    #[serde(default)]
+    pub response_remove: Vec<String>,
+}
+
+/// Rewrites the path sent upstream; the query is kept.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RewriteConfig {
+    /// Matched against the path.
+    pub pattern: String,
+    /// Replaces the first match; `$1` and `${name}` insert captures.
+    pub replace: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RequestIdConfig {
+    #[serde(default = "default_request_id_header")]
+    pub header: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Origins such as `https://app.example.com`, or `*` for any.
+    pub allow_origins: Vec<String>,
+    /// Empty allows the method a preflight asks for.
+    #[serde(default)]
+    pub allow_methods: Vec<String>,
+    /// Empty allows the headers a preflight asks for.
+    #[serde(default)]
+    pub allow_headers: Vec<String>,
+    #[serde(default)]
+    pub expose_headers: Vec<String>,
+    #[serde(default)]
+    pub allow_credentials: bool,
+    pub max_age_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BodyLimitConfig {
+    pub max_bytes: u64,
+}
+
+impl PluginConfig {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Headers(_) => "headers",
+            Self::Rewrite(_) => "rewrite",
+            Self::RequestId(_) => "request-id",
+            Self::Cors(_) => "cors",
+            Self::BodyLimit(_) => "body-limit",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// `token-bucket`, `sliding-log` or `fixed-window`.
+    #[serde(default = "default_algorithm")]
+    pub algorithm: String,
+    pub requests: u32,
+    pub window_secs: u64,
+    /// Token-bucket capacity; defaults to `requests`.
+    pub burst: Option<u32>,
+    /// What the limit is counted per: `ip`, or `consumer` (the consumer or
+    /// the token's subject, each its own counter, falling back to the IP
+    /// for anonymous requests).
+    #[serde(default = "default_key")]
+    pub key: String,
+    /// Limits for individual consumers, overriding the one above. They do
+    /// not apply to tokens.
+    #[serde(default)]
+    pub consumers: BTreeMap<String, ConsumerLimit>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConsumerLimit {
+    pub requests: u32,
+    pub window_secs: u64,
+    pub burst: Option<u32>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: default_cache_bytes(),
+            max_entry_bytes: default_cache_entry_bytes(),
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            algorithm: default_algorithm(),
+            requests: 100,
+            window_secs: 60,
+            burst: None,
+            key: default_key(),
+            consumers: BTreeMap::new(),
+        }
+    }
+}
+
+fn default_upstream() -> String {
+    DEFAULT_UPSTREAM.to_string()
+}
+
+fn default_auth() -> bool {
+    true
+}
+
+fn default_algorithm() -> String {
+    Algorithm::TokenBucket.as_str().to_string()
+}
+
+fn default_key() -> String {
+    "ip".to_string()
+}
+
+fn default_cache_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_cache_entry_bytes() -> u64 {
+    1024 * 1024
+}
+
+fn default_request_id_header() -> String {
+    "x-request-id".to_string()
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("reading {}: {}", path.display(), e))?;
+        Self::parse(&text).map_err(|e| format!("loading {}: {}", path.display(), e))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let config: Self = toml::from_str(text).map_err(|e| e.to_string())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// The table used without a config file: `/public/*` is open, and
+    /// everything else needs a token and goes to the command-line target.
+    pub fn fallback() -> Self {
+        let route = |path: &str, auth| RouteConfig {
+            name: None,
+            path: path.to_string(),
+            methods: Vec::new(),
+            upstream: default_upstream(),
+            auth,
+            scopes: Vec::new(),
+            consumers: Vec::new(),
+            rate_limit: None,
+            cache: None,
+            plugins: Vec::new(),
+        };
+        Self {
+            rate_limit: RateLimitConfig::default(),
+            upstreams: BTreeMap::new(),
+            consumers: BTreeMap::new(),
+            cache: CacheConfig::default(),
+            routes: vec![route("/public/*", false), route("/*", true)],
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.upstreams.contains_key(DEFAULT_UPSTREAM) {
+            return Err(format!(
+                "upstream '{}' is the command-line target and cannot be redefined",
+                DEFAULT_UPSTREAM
+            ));
+        }
+        self.rate_limit
+            .validate()
+            .map_err(|e| format!("rate_limit: {}", e))?;
+        if self.cache.max_entry_bytes == 0 || self.cache.max_entry_bytes > self.cache.max_bytes {
+            return Err("cache: max_entry_bytes must be positive and at most max_bytes".to_string());
+        }
+        let mut credentials = BTreeMap::new();
+        for (name, consumer) in &self.consumers {
+            if consumer.api_keys.is_empty() && consumer.client_certs.is_empty() {
+                return Err(format!("consumer '{}' has no api_keys or client_certs", name));
+            }
+            for hash in &consumer.api_keys {
+                if parse_hash(hash).is_none() {
+                    return Err(format!(
+                        "consumer '{}': API key '{}' is not sha256:<64 hex digits>",
+                        name, hash
+                    ));
+                }
+            }
+            let keys = consumer
+                .api_keys
+                .iter()
+                .map(|h| format!("key {}", h.to_lowercase()));
+            let subjects = consumer
+                .client_certs
+                .iter()
+                .map(|s| format!("cert {}", normalize_subject(s)));
+            for credential in keys.chain(subjects) {
+                if let Some(other) = credentials.insert(credential, name).filter(|o| *o != name) {
+                    return Err(format!(
+                        "consumers '{}' and '{}' share a credential",
+                        other, name
+                    ));
+                }
+            }
+        }
+        if self.routes.is_empty() {
+            return Err("no routes".to_string());
+        }
+        for route in &self.routes {
+            route
+                .validate(self)
+                .map_err(|e| format!("route '{}': {}", route.path, e))?;
+        }
+        Ok(())
+    }
+}
+
+impl RouteConfig {
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.path)
+    }
+
+    fn validate(&self, config: &Config) -> Result<(), String> {
+        if !self.path.starts_with('/') {
+            return Err("path must start with '/'".to_string());
+        }
+        let segments: Vec<&str> = self.path[1..].split('/').collect();
+        for (i, segment) in segments.iter().enumerate() {
+            if segment.contains('*') && (*segment != "*" || i + 1 != segments.len()) {
+                return Err("'*' is only allowed as the whole last segment".to_string());
+            }
+            if segment.starts_with('{') != segment.ends_with('}') || *segment == "{}" {
+                return Err(format!("invalid parameter segment '{}'", segment));
+            }
+        }
+        for method in &self.methods {
+            if method.parse::<axum::http::Method>().is_err() || method.to_uppercase() != *method {
+                return Err(format!("invalid method '{}'", method));
+            }
+        }
+        if self.upstream != DEFAULT_UPSTREAM && !config.upstreams.contains_key(&self.upstream) {
+            return Err(format!("undefined upstream '{}'", self.upstream));
+        }
+        if !self.auth && (!self.scopes.is_empty() || !self.consumers.is_empty()) {
+            return Err("scopes and consumers require auth".to_string());
+        }
+        if let Some(limit) = &self.rate_limit {
+            limit.validate().map_err(|e| format!("rate_limit: {}", e))?;
+        }
+        for plugin in &self.plugins {
+            Plugin::build(plugin).map_err(|e| format!("plugin '{}': {}", plugin.kind(), e))?;
+        }
+        Ok(())
+    }
+}
+
+impl RateLimitConfig {
+    fn validate(&self) -> Result<(), String> {
+        let algorithm = Algorithm::parse(&self.algorithm)?;
+        if self.key != "ip" && self.key != "consumer" {
+            return Err(format!(
+                "key must be 'ip' or 'consumer', not '{}'",
+                self.key
+            ));
+        }
+        if !self.consumers.is_empty() && self.key != "consumer" {
+            return Err("consumer limits need key = \"consumer\"".to_string());
+        }
+        check_limit(algorithm, self.requests, self.window_secs, self.burst)?;
+        for (consumer, limit) in &self.consumers {
+            check_limit(algorithm, limit.requests, limit.window_secs, limit.burst)
+                .map_err(|e| format!("consumer '{}': {}", consumer, e))?;
+        }
+        Ok(())
+    }
+}
+
+fn check_limit(
+    algorithm: Algorithm,
+    requests: u32,
+    window_secs: u64,
+    burst: Option<u32>,
+) -> Result<(), String> {
+    if requests == 0 || window_secs == 0 {
+        return Err("requests and window_secs must be positive".to_string());
+    }
+    match burst {
+        Some(_) if algorithm != Algorithm::TokenBucket => {
+            Err("burst only applies to token-bucket".to_string())
+        }
+        Some(0) => Err("burst must be positive".to_string()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_example() {
+        let config = Config::parse(
+            r#"
+            [upstreams]
+            users = "http://localhost:3001/api"
+
+            [[routes]]
+            path = "/public/*"
+            auth = false
+
+            [[routes]]
+            path = "/users/{id}"
+            methods = ["GET", "PUT"]
+            upstream = "users"
+            scopes = ["users:read"]
+
+            [routes.rate_limit]
+            requests = 10
+            window_secs = 1
+            key = "consumer"
+
+            [routes.rate_limit.consumers]
+            batch-job = { requests = 100, window_secs = 1 }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.rate_limit, RateLimitConfig::default());
+        assert_eq!(config.routes.len(), 2);
+        assert_eq!(config.routes[0].upstream, DEFAULT_UPSTREAM);
+        assert!(config.routes[1].auth);
+        let limit = config.routes[1].rate_limit.as_ref().unwrap();
+        assert_eq!(limit.consumers["batch-job"].requests, 100);
+    }
+
+    #[test]
+    fn test_validation_errors() {
+        let cases = [
+            ("[[routes]]\npath = \"users\"", "must start with '/'"),
+            ("[[routes]]\npath = \"/a/*/b\"", "'*' is only allowed"),
+            ("[[routes]]\npath = \"/a*\"", "'*' is only allowed"),
+            ("[[routes]]\npath = \"/{id\"", "invalid parameter"),
+            ("[[routes]]\npath = \"/\"\nmethods = [\"get\"]", "invalid method"),
+            ("[[routes]]\npath = \"/\"\nupstream = \"x\"", "undefined upstream 'x'"),
+            (
+                "[[routes]]\npath = \"/\"\nauth = false\nscopes = [\"a\"]",
+                "scopes and consumers require auth",
+            ),
+            (
+                "[[routes]]\npath = \"/\"\nrate_limit = { requests = 0, window_secs = 1 }",
+                "must be positive",
+            ),
+            (
+                "[[routes]]\npath = \"/\"\nrate_limit = { requests = 1, window_secs = 1, key = \"host\" }",
+                "key must be",
+            ),
+            (
+                "[rate_limit]\nalgorithm = \"leaky\"\nrequests = 1\nwindow_secs = 1\n[[routes]]\npath = \"/\"",
+                "unknown algorithm 'leaky'",
+            ),
+            (
+                "[rate_limit]\nalgorithm = \"sliding-log\"\nrequests = 1\nwindow_secs = 1\nburst = 5\n[[routes]]\npath = \"/\"",
+                "burst only applies",
+            ),
+            (
+                "[[routes]]\npath = \"/\"\n[routes.rate_limit]\nrequests = 1\nwindow_secs = 1\n[routes.rate_limit.consumers]\na = { requests = 1, window_secs = 1 }",
+                "need key = \"consumer\"",
+            ),
+            ("[upstreams]\ndefault = \"http://x\"\n[[routes]]\npath = \"/\"", "cannot be redefined"),
+            ("routes = []", "no routes"),
+            (
+                "[cache]\nmax_bytes = 10\nmax_entry_bytes = 20\n[[routes]]\npath = \"/\"",
+                "max_entry_bytes must be positive and at most max_bytes",
+            ),
+            (
+                "[consumers.a]\nscopes = [\"x\"]\n[[routes]]\npath = \"/\"",
+                "consumer 'a' has no api_keys",
+            ),
+            (
+                "[consumers.a]\napi_keys = [\"plaintext\"]\n[[routes]]\npath = \"/\"",
+                "is not sha256:",
+            ),
+            (
+                "[consumers.a]\nclient_certs = [\"CN=x, O=y\"]\n[consumers.b]\nclient_certs = [\"CN=x,O=y\"]\n[[routes]]\npath = \"/\"",
+                "consumers 'a' and 'b' share a credential",
+            ),
+            ("[[routes]]\npath = \"/\"\nprefix = \"/\"", "unknown field"),
+            (
+                "[[routes]]\npath = \"/\"\n[[routes.plugins]]\ntype = \"rewrite\"\npattern = \"(\"\nreplace = \"/\"",
+                "plugin 'rewrite': regex parse error",
+            ),
+            (
+                "[[routes]]\npath = \"/\"\n[[routes.plugins]]\ntype = \"cors\"\nallow_origins = [\"*\"]\nallow_credentials = true",
+                "allow_credentials cannot be used",
+            ),
+            (
+                "[[routes]]\npath = \"/\"\n[[routes.plugins]]\ntype = \"headers\"\nrequest_remove = [\"bad header\"]",
+                "invalid header name 'bad header'",
+            ),
+            (
+                "[[routes]]\npath = \"/\"\n[[routes.plugins]]\ntype = \"gzip\"",
+                "unknown variant `gzip`",
+            ),
+            (
+                "[[routes]]\npath = \"/\"\n[[routes.plugins]]\ntype = \"body-limit\"\nmax_bytes = 1\nmax = 2",
+                "unknown field `max`",
+            ),
+        ];
+        for (text, expected) in cases {
+            let err = Config::parse(text).unwrap_err();
+            assert!(err.contains(expected), "{:?}: {}", text, err);
+        }
+    }
+}
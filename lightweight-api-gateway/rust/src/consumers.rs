@@ -0,0 +1,219 @@
+use ring::digest::{digest, SHA256};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::Arc;
+
+use crate::config::ConsumerConfig;
+use crate::jwt::Claims;
+
+const HASH_PREFIX: &str = "sha256:";
+
+/// How a caller proved who they are.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthMethod {
+    Jwt,
+    ApiKey,
+    ClientCert,
+    /// The demo token accepted when no JWT key is configured.
+    Demo,
+}
+
+/// Who made a request. Routes' consumer lists, scope checks and per-consumer
+/// rate limits all work on this, whichever way the caller authenticated.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    /// The consumer name, or the token's subject.
+    pub name: Option<String>,
+    pub scopes: Vec<String>,
+    pub method: AuthMethod,
+}
+
+impl Identity {
+    pub fn from_claims(claims: Claims) -> Self {
+        Self {
+            name: claims.sub,
+            scopes: claims.scopes,
+            method: AuthMethod::Jwt,
+        }
+    }
+
+    /// The consumer's name, for callers that authenticated as one. A token
+    /// is never a consumer, even when its subject has a consumer's name.
+    pub fn consumer(&self) -> Option<&str> {
+        match self.method {
+            AuthMethod::ApiKey | AuthMethod::ClientCert => self.name.as_deref(),
+            AuthMethod::Jwt | AuthMethod::Demo => None,
+        }
+    }
+
+    /// Names the caller for per-caller state such as rate-limit counters,
+    /// keeping consumers and token subjects apart.
+    pub fn key(&self) -> Option<String> {
+        let name = self.name.as_deref()?;
+        match self.method {
+            AuthMethod::ApiKey | AuthMethod::ClientCert => Some(format!("consumer:{}", name)),
+            AuthMethod::Jwt => Some(format!("jwt:{}", name)),
+            AuthMethod::Demo => None,
+        }
+    }
+
+    fn of(consumer: &Consumer, method: AuthMethod) -> Self {
+        Self {
+            name: Some(consumer.name.clone()),
+            scopes: consumer.scopes.clone(),
+            method,
+        }
+    }
+}
+
+struct Consumer {
+    name: String,
+    scopes: Vec<String>,
+}
+
+/// Consumers from the config file, indexed by credential.
+#[derive(Default)]
+pub struct Consumers {
+    // SHA-256 of the key. Only hashes are configured, so a leaked config
+    // file does not leak keys.
+    api_keys: HashMap<Vec<u8>, Arc<Consumer>>,
+    subjects: HashMap<String, Arc<Consumer>>,
+}
+
+impl Consumers {
+    /// `config` has been validated: hashes parse and no credential is shared.
+    pub fn build(config: &BTreeMap<String, ConsumerConfig>) -> Self {
+        let mut consumers = Self::default();
+        for (name, c) in config {
+            let consumer = Arc::new(Consumer {
+                name: name.clone(),
+                scopes: c.scopes.clone(),
+            });
+            for hash in &c.api_keys {
+                if let Some(hash) = parse_hash(hash) {
+                    consumers.api_keys.insert(hash, Arc::clone(&consumer));
+                }
+            }
+            for subject in &c.client_certs {
+                consumers
+                    .subjects
+                    .insert(normalize_subject(subject), Arc::clone(&consumer));
+            }
+        }
+        consumers
+    }
+
+    pub fn by_api_key(&self, key: &str) -> Option<Identity> {
+        let hash = digest(&SHA256, key.as_bytes());
+        self.api_keys
+            .get(hash.as_ref())
+            .map(|c| Identity::of(c, AuthMethod::ApiKey))
+    }
+
+    pub fn by_subject(&self, subject: &str) -> Option<Identity> {
+        self.subjects
+            .get(&normalize_subject(subject))
+            .map(|c| Identity::of(c, AuthMethod::ClientCert))
+    }
+}
+
+/// The form API keys are configured in: `sha256:<hex>`.
+pub fn hash_key(key: &str) -> String {
+    let mut out = HASH_PREFIX.to_string();
+    for byte in digest(&SHA256, key.as_bytes()).as_ref() {
+        let _ = write!(out, "{:02x}", byte);
+    }
+    out
+}
+
+pub fn parse_hash(hash: &str) -> Option<Vec<u8>> {
+    let hex = hash.strip_prefix(HASH_PREFIX)?;
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// `CN=a, O=b` and `CN=a,O=b` name the same subject.
+pub fn normalize_subject(subject: &str) -> String {
+    subject
+        .split(',')
+        .map(|part| {
+            let (k, v) = part.split_once('=').unwrap_or((part, ""));
+            format!("{}={}", k.trim(), v.trim())
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn consumers() -> Consumers {
+        let config = BTreeMap::from([
+            (
+                "billing".to_string(),
+                ConsumerConfig {
+                    api_keys: vec![hash_key("k-billing")],
+                    client_certs: Vec::new(),
+                    scopes: vec!["invoices:write".to_string()],
+                },
+            ),
+            (
+                "reporting".to_string(),
+                ConsumerConfig {
+                    api_keys: Vec::new(),
+                    client_certs: vec!["CN=reporting, O=Example".to_string()],
+                    scopes: Vec::new(),
+                },
+            ),
+        ]);
+        Consumers::build(&config)
+    }
+
+    #[test]
+    fn test_hash_key() {
+        assert_eq!(
+            hash_key("test"),
+            "sha256:9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+        );
+        assert_eq!(parse_hash(&hash_key("test")).unwrap().len(), 32);
+        assert_eq!(parse_hash("sha256:abcd"), None);
+        assert_eq!(parse_hash("md5:9f86d081884c7d659a2feaa0c55ad015"), None);
+    }
+
+    #[test]
+    fn test_lookup() {
+        let consumers = consumers();
+        let billing = consumers.by_api_key("k-billing").unwrap();
+        assert_eq!(billing.name.as_deref(), Some("billing"));
+        assert_eq!(billing.scopes, ["invoices:write"]);
+        assert_eq!(billing.method, AuthMethod::ApiKey);
+        assert_eq!(consumers.by_api_key("k-other"), None);
+        // The hash itself is not a key.
+        assert_eq!(consumers.by_api_key(&hash_key("k-billing")), None);
+
+        let reporting = consumers.by_subject("CN=reporting,O=Example").unwrap();
+        assert_eq!(reporting.name.as_deref(), Some("reporting"));
+        assert_eq!(reporting.method, AuthMethod::ClientCert);
+        assert_eq!(consumers.by_subject("CN=reporting"), None);
+    }
+
+    #[test]
+    fn test_token_subject_is_not_a_consumer() {
+        let billing = consumers().by_api_key("k-billing").unwrap();
+        assert_eq!(billing.consumer(), Some("billing"));
+        assert_eq!(billing.key().as_deref(), Some("consumer:billing"));
+
+        let token = Identity::from_claims(Claims {
+            sub: Some("billing".to_string()),
+            scopes: Vec::new(),
+        });
+        assert_eq!(token.consumer(), None);
+        assert_eq!(token.key().as_deref(), Some("jwt:billing"));
+    }
+}
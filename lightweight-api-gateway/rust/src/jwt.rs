@@ -0,0 +1,743 @@
+use axum::body::Body;
+use axum::http::{Request, Uri};
+use http_body_util::BodyExt;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use jsonwebtoken::jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde_json::Value;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+const JWKS_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where and how tokens are verified. Read from the environment:
+///
+/// - `JWT_HS256_SECRET`: shared secret for HS256 tokens.
+/// - `JWT_PUBLIC_KEYS`: comma-separated PEM files with RSA (RS256) or
+///   P-256 (ES256) public keys.
+/// - `JWT_JWKS`: JWKS file path or `http(s)://` URL; keys are picked by
+///   `kid` and re-read every `JWT_JWKS_REFRESH_SECS` (default 300) or when
+///   a token names a `kid` not seen yet.
+/// - `JWT_ISSUER`, `JWT_AUDIENCE`: comma-separated accepted values.
+/// - `JWT_LEEWAY_SECS`: clock skew allowed on `exp` and `nbf` (default 60).
+#[derive(Debug, Clone, Default)]
+pub struct JwtConfig {
+    pub hs256_secret: Option<String>,
+    pub public_keys: Vec<PathBuf>,
+    pub jwks: Option<String>,
+    pub issuers: Vec<String>,
+    pub audiences: Vec<String>,
+    pub leeway: Duration,
+    pub jwks_refresh: Duration,
+    /// Minimum time between JWKS reloads triggered by unknown `kid`s, so
+    /// junk tokens cannot make the gateway hammer the key server.
+    pub jwks_min_refresh: Duration,
+}
+
+impl JwtConfig {
+    pub fn from_env() -> Result<Self, String> {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        let list = |name: &str| -> Vec<String> {
+            var(name)
+                .map(|v| v.split(',').map(|s| s.trim().to_string()).collect())
+                .unwrap_or_default()
+        };
+        let secs = |name: &str, default: u64| -> Result<Duration, String> {
+            match var(name) {
+                Some(v) => v
+                    .parse()
+                    .map(Duration::from_secs)
+                    .map_err(|_| format!("{} must be a number of seconds", name)),
+                None => Ok(Duration::from_secs(default)),
+            }
+        };
+        Ok(Self {
+            hs256_secret: var("JWT_HS256_SECRET"),
+            public_keys: list("JWT_PUBLIC_KEYS")
+                .into_iter()
+                .map(PathBuf::from)
+                .collect(),
+            jwks: var("JWT_JWKS"),
+            issuers: list("JWT_ISSUER"),
+            audiences: list("JWT_AUDIENCE"),
+            leeway: secs("JWT_LEEWAY_SECS", 60)?,
+            jwks_refresh: secs("JWT_JWKS_REFRESH_SECS", 300)?,
+            jwks_min_refresh: Duration::from_secs(10),
+        })
+    }
+
+    /// Whether any key is configured at all.
+    pub fn has_keys(&self) -> bool {
+        self.hs256_secret.is_some() || !self.public_keys.is_empty() || self.jwks.is_some()
+    }
+}
+
+/// What a verified token says about its bearer.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Claims {
+    pub sub: Option<String>,
+    pub scopes: Vec<String>,
+}
+
+impl Claims {
+    /// Scopes come from an OAuth2-style space-separated `scope` string, or a
+    /// `scp`/`scopes` array or string.
+    fn from_json(claims: &Value) -> Self {
+        let scopes = ["scope", "scp", "scopes"]
+            .iter()
+            .find_map(|name| match claims.get(*name)? {
+                Value::String(s) => Some(s.split_whitespace().map(str::to_string).collect()),
+                Value::Array(items) => Some(
+                    items
+                        .iter()
+                        .filter_map(|v| v.as_str().map(str::to_string))
+                        .collect(),
+                ),
+                _ => None,
+            })
+            .unwrap_or_default();
+        Self {
+            sub: claims
+                .get("sub")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+            scopes,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Key {
+    alg: Algorithm,
+    kid: Option<String>,
+    key: DecodingKey,
+}
+
+type JwksClient = Client<HttpsConnector<HttpConnector>, Body>;
+
+enum JwksSource {
+    File(PathBuf),
+    // The client is kept so reloads reuse its connections.
+    Url(Uri, JwksClient),
+}
+
+struct Jwks {
+    source: JwksSource,
+    keys: RwLock<Arc<Vec<Key>>>,
+    // When the last reload for an unknown `kid` finished, failed or not.
+    // Held across the reload, so concurrent tokens wait for one fetch
+    // instead of each starting their own.
+    last_reload: tokio::sync::Mutex<Instant>,
+}
+
+impl Jwks {
+    async fn load(&self) -> Result<(), String> {
+        let text = match &self.source {
+            JwksSource::File(path) => tokio::fs::read(path)
+                .await
+                .map_err(|e| format!("reading JWKS {}: {}", path.display(), e))?,
+            JwksSource::Url(uri, client) => fetch(client, uri).await?,
+        };
+        let set: JwkSet =
+            serde_json::from_slice(&text).map_err(|e| format!("parsing JWKS: {}", e))?;
+        let keys = set.keys.iter().filter_map(jwk_key).collect();
+        *self.keys.write().unwrap() = Arc::new(keys);
+        Ok(())
+    }
+
+    fn keys(&self) -> Arc<Vec<Key>> {
+        Arc::clone(&self.keys.read().unwrap())
+    }
+}
+
+/// Turns a JWKS entry into a verification key. Symmetric keys are skipped:
+/// a key server has no business handing out HMAC secrets.
+fn jwk_key(jwk: &Jwk) -> Option<Key> {
+    let alg = match (&jwk.common.key_algorithm, &jwk.algorithm) {
+        (_, AlgorithmParameters::OctetKey(_)) => return None,
+        (Some(alg), _) => Algorithm::from_str(&alg.to_string()).ok()?,
+        (None, AlgorithmParameters::RSA(_)) => Algorithm::RS256,
+        (None, AlgorithmParameters::EllipticCurve(ec)) => match ec.curve {
+            EllipticCurve::P256 => Algorithm::ES256,
+            EllipticCurve::P384 => Algorithm::ES384,
+            _ => return None,
+        },
+        (None, AlgorithmParameters::OctetKeyPair(_)) => Algorithm::EdDSA,
+    };
+    Some(Key {
+        alg,
+        kid: jwk.common.key_id.clone(),
+        key: DecodingKey::from_jwk(jwk).ok()?,
+    })
+}
+
+fn jwks_client() -> JwksClient {
+    let connector = HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    Client::builder(TokioExecutor::new()).build(connector)
+}
+
+async fn fetch(client: &JwksClient, uri: &Uri) -> Result<Vec<u8>, String> {
+    let request = Request::get(uri.clone())
+        .body(Body::empty())
+        .map_err(|e| e.to_string())?;
+    let response = tokio::time::timeout(JWKS_FETCH_TIMEOUT, client.request(request))
+        .await
+        .map_err(|_| format!("fetching JWKS {}: timed out", uri))?
+        .map_err(|e| format!("fetching JWKS {}: {}", uri, e))?;
+    if !response.status().is_success() {
+        return Err(format!("fetching JWKS {}: {}", uri, response.status()));
+    }
+    let body = response
+        .into_body()
+        .collect()
+        .await
+        .map_err(|e| format!("fetching JWKS {}: {}", uri, e))?;
+    Ok(body.to_bytes().to_vec())
+}
+
+pub struct JwtVerifier {
+    static_keys: Vec<Key>,
+    jwks: Option<Jwks>,
+    issuers: Vec<String>,
+    audiences: Vec<String>,
+    leeway: Duration,
+    jwks_refresh: Duration,
+    jwks_min_refresh: Duration,
+}
+
+impl JwtVerifier {
+    /// Loads every configured key; a JWKS that cannot be loaded now is an
+    /// error rather than a gateway that rejects everything.
+    pub async fn new(config: JwtConfig) -> Result<Self, String> {
+        let mut static_keys = Vec::new();
+        if let Some(secret) = &config.hs256_secret {
+            static_keys.push(Key {
+                alg: Algorithm::HS256,
+                kid: None,
+                key: DecodingKey::from_secret(secret.as_bytes()),
+            });
+        }
+        for path in &config.public_keys {
+            let pem =
+                std::fs::read(path).map_err(|e| format!("reading {}: {}", path.display(), e))?;
+            let key = match DecodingKey::from_rsa_pem(&pem) {
+                Ok(key) => (Algorithm::RS256, key),
+                Err(_) => DecodingKey::from_ec_pem(&pem)
+                    .map(|key| (Algorithm::ES256, key))
+                    .map_err(|_| format!("{} is not an RSA or EC public key", path.display()))?,
+            };
+            static_keys.push(Key {
+                alg: key.0,
+                kid: None,
+                key: key.1,
+            });
+        }
+        let jwks = match &config.jwks {
+            Some(source) => {
+                let source = if source.starts_with("http://") || source.starts_with("https://") {
+                    let uri = source.parse().map_err(|e| format!("JWT_JWKS: {}", e))?;
+                    JwksSource::Url(uri, jwks_client())
+                } else {
+                    JwksSource::File(PathBuf::from(source))
+                };
+                let jwks = Jwks {
+                    source,
+                    keys: RwLock::default(),
+                    last_reload: tokio::sync::Mutex::new(Instant::now()),
+                };
+                jwks.load().await?;
+                Some(jwks)
+            }
+            None => None,
+        };
+        Ok(Self {
+            static_keys,
+            jwks,
+            issuers: config.issuers,
+            audiences: config.audiences,
+            leeway: config.leeway,
+            jwks_refresh: config.jwks_refresh,
+            jwks_min_refresh: config.jwks_min_refresh,
+        })
+    }
+
+    /// Re-reads the JWKS periodically so rotated keys are picked up even
+    /// before a token asks for them.
+    pub fn spawn_refresh(self: &Arc<Self>) {
+        if self.jwks.is_none() || self.jwks_refresh.is_zero() {
+            return;
+        }
+        let verifier = Arc::downgrade(self);
+        let every = self.jwks_refresh;
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(every).await;
+                let Some(verifier) = verifier.upgrade() else {
+                    break;
+                };
+                if let Some(jwks) = &verifier.jwks {
+                    if let Err(e) = jwks.load().await {
+                        eprintln!("JWKS refresh failed, keeping current keys: {}", e);
+                    }
+                }
+            }
+        });
+    }
+
+    /// Checks the signature and the `exp`, `nbf`, `iss` and `aud` claims.
+    /// The error is a short reason, safe to show to the client.
+    pub async fn verify(&self, token: &str) -> Result<Claims, &'static str> {
+        let header = decode_header(token).map_err(|_| "malformed token")?;
+        let (alg, kid) = (header.alg, header.kid.as_deref());
+        let validation = self.validation(alg);
+        let from_jwks = self.jwks_candidates(alg, kid);
+        let unknown_kid = kid.is_some() && from_jwks.is_empty();
+        let result = self.check(token, &self.candidates(alg, kid), &validation);
+        // A `kid` the JWKS does not have may belong to a key rotated in
+        // since it was loaded.
+        if unknown_kid
+            && matches!(result, Err("no key for token" | "invalid signature"))
+            && self.reload_for_unknown_kid().await
+        {
+            let keys = self.jwks_candidates(alg, kid);
+            if !keys.is_empty() {
+                return self.check(token, &keys, &validation);
+            }
+        }
+        result
+    }
+
+    fn validation(&self, alg: Algorithm) -> Validation {
+        let mut validation = Validation::new(alg);
+        validation.leeway = self.leeway.as_secs();
+        validation.validate_nbf = true;
+        // A configured `iss` or `aud` is only checked when the token has
+        // one, unless it is also required.
+        let mut required = vec!["exp"];
+        if !self.issuers.is_empty() {
+            validation.set_issuer(&self.issuers);
+            required.push("iss");
+        }
+        if self.audiences.is_empty() {
+            validation.validate_aud = false;
+        } else {
+            validation.set_audience(&self.audiences);
+            required.push("aud");
+        }
+        validation.set_required_spec_claims(&required);
+        validation
+    }
+
+    /// Tries `keys` in turn until one has signed the token.
+    fn check(
+        &self,
+        token: &str,
+        keys: &[Key],
+        validation: &Validation,
+    ) -> Result<Claims, &'static str> {
+        use jsonwebtoken::errors::ErrorKind;
+        if keys.is_empty() {
+            return Err("no key for token");
+        }
+        let mut result = Err("invalid signature");
+        for key in keys {
+            match decode::<Value>(token, &key.key, validation) {
+                Ok(data) => return Ok(Claims::from_json(&data.claims)),
+                // Another key of the same type may still match.
+                Err(e) if matches!(e.kind(), ErrorKind::InvalidSignature) => continue,
+                Err(e) => {
+                    result = Err(match e.kind() {
+                        ErrorKind::ExpiredSignature => "token expired",
+                        ErrorKind::ImmatureSignature => "token not yet valid",
+                        ErrorKind::InvalidIssuer => "invalid issuer",
+                        ErrorKind::InvalidAudience => "invalid audience",
+                        ErrorKind::MissingRequiredClaim(claim) => match claim.as_str() {
+                            "exp" => "missing exp claim",
+                            "nbf" => "missing nbf claim",
+                            "iss" => "missing iss claim",
+                            "aud" => "missing aud claim",
+                            "sub" => "missing sub claim",
+                            _ => "missing required claim",
+                        },
+                        _ => "invalid token",
+                    });
+                    break;
+                }
+            }
+        }
+        result
+    }
+
+    /// Keys that could have signed a token with this `alg` and `kid`. A
+    /// token with a `kid` matches the JWKS keys with that id; when there are
+    /// none, it is checked against the static keys, which have no id.
+    fn candidates(&self, alg: Algorithm, kid: Option<&str>) -> Vec<Key> {
+        let mut keys = self.jwks_candidates(alg, kid);
+        if kid.is_none() || keys.is_empty() {
+            keys.extend(self.static_keys.iter().filter(|k| k.alg == alg).cloned());
+        }
+        keys
+    }
+
+    fn jwks_candidates(&self, alg: Algorithm, kid: Option<&str>) -> Vec<Key> {
+        let jwks_keys = self.jwks.as_ref().map(Jwks::keys).unwrap_or_default();
+        jwks_keys
+            .iter()
+            .filter(|k| k.alg == alg && (kid.is_none() || k.kid.as_deref() == kid))
+            .cloned()
+            .collect()
+    }
+
+    /// Reloads the JWKS at most once per `jwks_min_refresh`. Returns whether
+    /// the keys may have changed since the caller looked.
+    async fn reload_for_unknown_kid(&self) -> bool {
+        let Some(jwks) = &self.jwks else {
+            return false;
+        };
+        let asked = Instant::now();
+        let mut last_reload = jwks.last_reload.lock().await;
+        if *last_reload >= asked {
+            // Another token's reload finished while this one waited.
+            return true;
+        }
+        if last_reload.elapsed() < self.jwks_min_refresh {
+            return false;
+        }
+        let result = jwks.load().await;
+        // Failures count too, so a key server that is down is not retried
+        // for every junk token.
+        *last_reload = Instant::now();
+        match result {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!("JWKS reload failed: {}", e);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+    use rsa::pkcs1::EncodeRsaPrivateKey;
+    use rsa::pkcs8::{EncodePublicKey, LineEnding};
+    use serde_json::json;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    const SECRET: &str = "test-secret";
+
+    fn now() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("gateway-jwt-{}-{}", std::process::id(), name))
+    }
+
+    fn hs256(claims: Value) -> String {
+        encode(
+            &Header::new(Algorithm::HS256),
+            &claims,
+            &EncodingKey::from_secret(SECRET.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    async fn hs256_verifier(issuers: &[&str], audiences: &[&str]) -> JwtVerifier {
+        JwtVerifier::new(JwtConfig {
+            hs256_secret: Some(SECRET.to_string()),
+            issuers: issuers.iter().map(|s| s.to_string()).collect(),
+            audiences: audiences.iter().map(|s| s.to_string()).collect(),
+            leeway: Duration::from_secs(30),
+            ..JwtConfig::default()
+        })
+        .await
+        .unwrap()
+    }
+
+    /// A fresh P-256 key: the signing key and its public JWK.
+    fn es256_key(kid: &str) -> (EncodingKey, Value) {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+            .unwrap();
+        // Uncompressed point: 0x04, then x and y.
+        let point = pair.public_key().as_ref();
+        let jwk = json!({
+            "kty": "EC",
+            "crv": "P-256",
+            "kid": kid,
+            "alg": "ES256",
+            "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+            "y": URL_SAFE_NO_PAD.encode(&point[33..]),
+        });
+        (EncodingKey::from_ec_der(pkcs8.as_ref()), jwk)
+    }
+
+    fn es256(key: &EncodingKey, kid: &str, claims: Value) -> String {
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some(kid.to_string());
+        encode(&header, &claims, key).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_hs256_claims() {
+        let verifier = hs256_verifier(&[], &[]).await;
+        let token = hs256(json!({"sub": "alice", "scope": "read write", "exp": now() + 60}));
+        let claims = verifier.verify(&token).await.unwrap();
+        assert_eq!(claims.sub.as_deref(), Some("alice"));
+        assert_eq!(claims.scopes, ["read", "write"]);
+
+        let token = hs256(json!({"sub": "bob", "scp": ["admin"], "exp": now() + 60}));
+        let claims = verifier.verify(&token).await.unwrap();
+        assert_eq!(claims.scopes, ["admin"]);
+
+        let forged = encode(
+            &Header::new(Algorithm::HS256),
+            &json!({"sub": "alice", "exp": now() + 60}),
+            &EncodingKey::from_secret(b"other-secret"),
+        )
+        .unwrap();
+        assert_eq!(verifier.verify(&forged).await, Err("invalid signature"));
+        assert_eq!(verifier.verify("garbage").await, Err("malformed token"));
+    }
+
+    #[tokio::test]
+    async fn test_exp_and_nbf_with_leeway() {
+        let verifier = hs256_verifier(&[], &[]).await;
+        let within_skew = hs256(json!({"exp": now() - 10, "nbf": now() + 10}));
+        assert!(verifier.verify(&within_skew).await.is_ok());
+
+        let expired = hs256(json!({"exp": now() - 120}));
+        assert_eq!(verifier.verify(&expired).await, Err("token expired"));
+        let early = hs256(json!({"exp": now() + 600, "nbf": now() + 120}));
+        assert_eq!(verifier.verify(&early).await, Err("token not yet valid"));
+        let no_exp = hs256(json!({"sub": "alice"}));
+        assert_eq!(verifier.verify(&no_exp).await, Err("missing exp claim"));
+    }
+
+    #[tokio::test]
+    async fn test_issuer_and_audience() {
+        let verifier = hs256_verifier(&["https://issuer"], &["api", "admin"]).await;
+        let exp = now() + 60;
+        let good = hs256(json!({"iss": "https://issuer", "aud": "api", "exp": exp}));
+        assert!(verifier.verify(&good).await.is_ok());
+        let any_aud = hs256(json!({"iss": "https://issuer", "aud": ["x", "admin"], "exp": exp}));
+        assert!(verifier.verify(&any_aud).await.is_ok());
+
+        let wrong_iss = hs256(json!({"iss": "https://other", "aud": "api", "exp": exp}));
+        assert_eq!(verifier.verify(&wrong_iss).await, Err("invalid issuer"));
+        let wrong_aud = hs256(json!({"iss": "https://issuer", "aud": "web", "exp": exp}));
+        assert_eq!(verifier.verify(&wrong_aud).await, Err("invalid audience"));
+        let no_iss = hs256(json!({"aud": "api", "exp": exp}));
+        assert_eq!(verifier.verify(&no_iss).await, Err("missing iss claim"));
+        let no_aud = hs256(json!({"iss": "https://issuer", "exp": exp}));
+        assert_eq!(verifier.verify(&no_aud).await, Err("missing aud claim"));
+    }
+
+    #[tokio::test]
+    async fn test_rs256_public_key() {
+        let private = rsa::RsaPrivateKey::new(&mut rsa::rand_core::OsRng, 2048).unwrap();
+        let public_pem = private
+            .to_public_key()
+            .to_public_key_pem(LineEnding::LF)
+            .unwrap();
+        let path = temp_path("rsa.pem");
+        std::fs::write(&path, &public_pem).unwrap();
+        let verifier = JwtVerifier::new(JwtConfig {
+            public_keys: vec![path.clone()],
+            ..JwtConfig::default()
+        })
+        .await
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let signing =
+            EncodingKey::from_rsa_pem(private.to_pkcs1_pem(LineEnding::LF).unwrap().as_bytes())
+                .unwrap();
+        let claims = json!({"sub": "svc", "exp": now() + 60});
+        let token = encode(&Header::new(Algorithm::RS256), &claims, &signing).unwrap();
+        assert_eq!(
+            verifier.verify(&token).await.unwrap().sub.as_deref(),
+            Some("svc")
+        );
+
+        // The public key must not double as an HMAC secret.
+        let confused = encode(
+            &Header::new(Algorithm::HS256),
+            &claims,
+            &EncodingKey::from_secret(public_pem.as_bytes()),
+        )
+        .unwrap();
+        assert_eq!(verifier.verify(&confused).await, Err("no key for token"));
+    }
+
+    #[tokio::test]
+    async fn test_static_keys_accept_tokens_with_kid() {
+        let private = rsa::RsaPrivateKey::new(&mut rsa::rand_core::OsRng, 2048).unwrap();
+        let rsa_path = temp_path("kid-rsa.pem");
+        let public_pem = private
+            .to_public_key()
+            .to_public_key_pem(LineEnding::LF)
+            .unwrap();
+        std::fs::write(&rsa_path, public_pem).unwrap();
+        let ec = rcgen::KeyPair::generate().unwrap();
+        let ec_path = temp_path("kid-ec.pem");
+        std::fs::write(&ec_path, ec.public_key_pem()).unwrap();
+        let verifier = JwtVerifier::new(JwtConfig {
+            public_keys: vec![rsa_path.clone(), ec_path.clone()],
+            ..JwtConfig::default()
+        })
+        .await
+        .unwrap();
+        std::fs::remove_file(&rsa_path).unwrap();
+        std::fs::remove_file(&ec_path).unwrap();
+
+        let claims = json!({"sub": "svc", "exp": now() + 60});
+        let rsa_signing =
+            EncodingKey::from_rsa_pem(private.to_pkcs1_pem(LineEnding::LF).unwrap().as_bytes())
+                .unwrap();
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some("rsa-2024".to_string());
+        let token = encode(&header, &claims, &rsa_signing).unwrap();
+        assert_eq!(
+            verifier.verify(&token).await.unwrap().sub.as_deref(),
+            Some("svc")
+        );
+
+        let ec_signing = EncodingKey::from_ec_der(&ec.serialize_der());
+        let token = encode(&Header::new(Algorithm::ES256), &claims, &ec_signing).unwrap();
+        assert!(verifier.verify(&token).await.is_ok());
+        let token = es256(&ec_signing, "ec-2024", claims.clone());
+        assert!(verifier.verify(&token).await.is_ok());
+
+        let (other, _) = es256_key("ec-2024");
+        let forged = es256(&other, "ec-2024", claims);
+        assert_eq!(verifier.verify(&forged).await, Err("invalid signature"));
+    }
+
+    #[tokio::test]
+    async fn test_jwks_rotation_by_kid() {
+        let (old_key, old_jwk) = es256_key("2024-01");
+        let (new_key, new_jwk) = es256_key("2024-02");
+        let path = temp_path("jwks.json");
+        std::fs::write(&path, json!({"keys": [old_jwk]}).to_string()).unwrap();
+        let verifier = JwtVerifier::new(JwtConfig {
+            jwks: Some(path.display().to_string()),
+            jwks_min_refresh: Duration::ZERO,
+            ..JwtConfig::default()
+        })
+        .await
+        .unwrap();
+
+        let claims = json!({"sub": "alice", "exp": now() + 60});
+        let old_token = es256(&old_key, "2024-01", claims.clone());
+        let new_token = es256(&new_key, "2024-02", claims.clone());
+        assert!(verifier.verify(&old_token).await.is_ok());
+        assert_eq!(verifier.verify(&new_token).await, Err("no key for token"));
+
+        // The unknown kid triggers a reload that picks up the rotated set.
+        std::fs::write(&path, json!({"keys": [new_jwk]}).to_string()).unwrap();
+        assert!(verifier.verify(&new_token).await.is_ok());
+        assert_eq!(verifier.verify(&old_token).await, Err("no key for token"));
+
+        // A token claiming one kid but signed with another key fails.
+        let mislabeled = es256(&old_key, "2024-02", claims);
+        assert_eq!(verifier.verify(&mislabeled).await, Err("invalid signature"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unknown_kid_reload_is_rate_limited() {
+        let (key, jwk) = es256_key("a");
+        let path = temp_path("jwks-limited.json");
+        std::fs::write(&path, json!({"keys": []}).to_string()).unwrap();
+        let verifier = JwtVerifier::new(JwtConfig {
+            jwks: Some(path.display().to_string()),
+            jwks_min_refresh: Duration::from_secs(60),
+            ..JwtConfig::default()
+        })
+        .await
+        .unwrap();
+
+        std::fs::write(&path, json!({"keys": [jwk]}).to_string()).unwrap();
+        let token = es256(&key, "a", json!({"exp": now() + 60}));
+        assert_eq!(verifier.verify(&token).await, Err("no key for token"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unknown_kid_reloads_are_single_flight() {
+        use axum::http::StatusCode;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // Serves an empty key set once, then fails slowly, like a key
+        // server that has gone down.
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let app = axum::Router::new().route(
+            "/jwks",
+            axum::routing::get({
+                let fetches = Arc::clone(&fetches);
+                move || async move {
+                    if fetches.fetch_add(1, Ordering::SeqCst) == 0 {
+                        return (StatusCode::OK, json!({"keys": []}).to_string());
+                    }
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    (StatusCode::SERVICE_UNAVAILABLE, String::new())
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let min_refresh = Duration::from_millis(200);
+        let verifier = Arc::new(
+            JwtVerifier::new(JwtConfig {
+                jwks: Some(format!("http://{}/jwks", addr)),
+                jwks_min_refresh: min_refresh,
+                ..JwtConfig::default()
+            })
+            .await
+            .unwrap(),
+        );
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        tokio::time::sleep(min_refresh).await;
+
+        let (key, _) = es256_key("unknown");
+        let token = es256(&key, "unknown", json!({"exp": now() + 60}));
+        let tasks: Vec<_> = (0..20)
+            .map(|_| {
+                let verifier = Arc::clone(&verifier);
+                let token = token.clone();
+                tokio::spawn(async move { verifier.verify(&token).await })
+            })
+            .collect();
+        for task in tasks {
+            assert_eq!(task.await.unwrap(), Err("no key for token"));
+        }
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+
+        // The failed fetch still counts against the minimum interval.
+        assert_eq!(verifier.verify(&token).await, Err("no key for token"));
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+}
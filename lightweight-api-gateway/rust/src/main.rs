@@ -1,142 +1,349 @@
+mod cache;
+mod config;
+mod consumers;
+mod jwt;
+mod plugins;
+mod proxy;
+mod rate_limit;
+mod redis;
+mod routes;
+mod tls;
+
 use axum::{
-    extract::{Request, State},
-    http::StatusCode,
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode, Uri},
     middleware::{self, Next},
     response::{IntoResponse, Response},
     routing::get,
-    Router,
+    Extension, Json, Router,
 };
-use dashmap::DashMap;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+
+use cache::CacheStats;
+use config::Config;
+use consumers::{AuthMethod, Identity};
+use jwt::{JwtConfig, JwtVerifier};
+use rate_limit::{Decision, RateLimiter};
+use redis::RedisStore;
+use routes::{NoRoute, Route, RouteTable};
+use tls::TlsInfo;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+const RATE_LIMIT_SWEEP: Duration = Duration::from_secs(60);
+// Accepted when no JWT key is configured, so the cross-language benchmark
+// keeps working without key setup.
+const DEMO_TOKEN: &str = "valid-test-token";
 
 #[derive(Clone)]
 struct AppState {
-    target_url: String,
+    // Swapped wholesale on reload; requests keep the `Route` they matched.
+    routes: Arc<RwLock<Arc<RouteTable>>>,
+    // `None` when no key is configured: only the demo token is accepted.
+    jwt: Option<Arc<JwtVerifier>>,
     rate_limiter: Arc<RateLimiter>,
 }
 
-struct RateLimiter {
-    clients: DashMap<String, ClientState>,
+/// Picks the route for the request; the middlewares after it and the
+/// handler act on its settings. The table goes along too, for its consumers.
+async fn route_middleware(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    // Matched and forwarded as the upstream will read it, so `..` cannot
+    // step from a public route onto another.
+    let Some(path) = routes::normalize_path(request.uri().path()) else {
+        return (StatusCode::BAD_REQUEST, "Bad Request").into_response();
+    };
+    if path != request.uri().path() {
+        match with_path(request.uri(), &path) {
+            Some(uri) => *request.uri_mut() = uri,
+            None => return (StatusCode::BAD_REQUEST, "Bad Request").into_response(),
+        }
+    }
+    let table = Arc::clone(&state.routes.read().unwrap());
+    let mut found = table.find(request.method(), request.uri().path());
+    // A CORS preflight is for the method it asks about, which the route may
+    // allow even though it does not allow OPTIONS. Only a route with a CORS
+    // plugin answers it; others would pass the OPTIONS upstream.
+    if matches!(found, Err(NoRoute::MethodNotAllowed(_))) && plugins::is_preflight(&request) {
+        let requested = request
+            .headers()
+            .get(header::ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|m| m.to_str().ok())
+            .and_then(|m| m.parse().ok());
+        let cors_route = requested
+            .and_then(|method| table.find(&method, request.uri().path()).ok())
+            .filter(|route| route.plugins.has_cors());
+        if let Some(route) = cors_route {
+            found = Ok(route);
+        }
+    }
+    match found {
+        Ok(route) => {
+            request.extensions_mut().insert(route);
+            request.extensions_mut().insert(table);
+            next.run(request).await
+        }
+        Err(NoRoute::NotFound) => (StatusCode::NOT_FOUND, "Not Found").into_response(),
+        Err(NoRoute::MethodNotAllowed(methods)) => {
+            let allow: Vec<&str> = methods.iter().map(|m| m.as_str()).collect();
+            let mut response =
+                (StatusCode::METHOD_NOT_ALLOWED, "Method Not Allowed").into_response();
+            if let Ok(value) = HeaderValue::from_str(&allow.join(", ")) {
+                response.headers_mut().insert(header::ALLOW, value);
+            }
+            response
+        }
+    }
 }
 
-struct ClientState {
-    count: u32,
-    last_reset: Instant,
+fn with_path(uri: &Uri, path: &str) -> Option<Uri> {
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse().ok()?);
+    Uri::from_parts(parts).ok()
 }
 
-impl RateLimiter {
-    fn new() -> Self {
-        Self {
-            clients: DashMap::new(),
-        }
+/// Runs the route's plugins around auth, rate limiting and the upstream, so
+/// their responses get CORS and request-ID headers too.
+async fn plugin_middleware(
+    Extension(route): Extension<Arc<Route>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    route.plugins.run(request, |request| next.run(request)).await
+}
+
+async fn auth_middleware(
+    State(state): State<AppState>,
+    Extension(table): Extension<Arc<RouteTable>>,
+    Extension(route): Extension<Arc<Route>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    // Identity headers are only ever set by the gateway, and API keys are
+    // not passed on.
+    request.headers_mut().remove("x-auth-subject");
+    request.headers_mut().remove("x-auth-scopes");
+    let api_key = request.headers_mut().remove("x-api-key");
+
+    if !route.auth {
+        return next.run(request).await;
     }
 
-    fn allow(&self, client_ip: &str, limit: u32, window: Duration) -> bool {
-        let now = Instant::now();
-        
-        let mut entry = self.clients.entry(client_ip.to_string()).or_insert(ClientState {
-            count: 0,
-            last_reset: now,
-        });
+    let tls = request.extensions().get::<TlsInfo>();
+    let identity = match authenticate(&state, &table, request.headers(), tls, api_key).await {
+        Ok(identity) => identity,
+        Err(response) => return response,
+    };
+    if !route.admits(&identity) {
+        return auth_error(
+            StatusCode::FORBIDDEN,
+            "forbidden",
+            "consumer is not allowed on this route",
+            None,
+        );
+    }
+    let missing = route.missing_scopes(&identity);
+    if !missing.is_empty() {
+        return insufficient_scope(&missing);
+    }
 
-        if now.duration_since(entry.last_reset) >= window {
-            entry.count = 0;
-            entry.last_reset = now;
-        }
+    let headers = request.headers_mut();
+    if let Some(name) = identity.name.as_deref().and_then(|s| HeaderValue::from_str(s).ok()) {
+        headers.insert("x-auth-subject", name);
+    }
+    if let Ok(scopes) = HeaderValue::from_str(&identity.scopes.join(" ")) {
+        headers.insert("x-auth-scopes", scopes);
+    }
+    request.extensions_mut().insert(identity);
+    next.run(request).await
+}
 
-        if entry.count >= limit {
-            return false;
-        }
+/// A known client certificate wins, then an API key, then a bearer token.
+/// A credential that is presented but unknown fails rather than falling
+/// through to the next kind.
+async fn authenticate(
+    state: &AppState,
+    table: &RouteTable,
+    headers: &HeaderMap,
+    tls: Option<&TlsInfo>,
+    api_key: Option<HeaderValue>,
+) -> Result<Identity, Response> {
+    let subject = tls.and_then(|tls| tls.client_subject.as_deref());
+    if let Some(identity) = subject.and_then(|s| table.consumers().by_subject(s)) {
+        return Ok(identity);
+    }
+
+    if let Some(key) = api_key {
+        return key
+            .to_str()
+            .ok()
+            .and_then(|key| table.consumers().by_api_key(key))
+            .ok_or_else(|| {
+                auth_error(StatusCode::UNAUTHORIZED, "invalid_api_key", "unknown API key", None)
+            });
+    }
 
-        entry.count += 1;
-        true
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map(str::trim);
+    let Some(token) = token else {
+        return Err(unauthorized(None));
+    };
+    match &state.jwt {
+        Some(verifier) => match verifier.verify(token).await {
+            Ok(claims) => Ok(Identity::from_claims(claims)),
+            Err(reason) => Err(unauthorized(Some(reason))),
+        },
+        None if token == DEMO_TOKEN => Ok(Identity {
+            name: None,
+            scopes: Vec::new(),
+            method: AuthMethod::Demo,
+        }),
+        None => Err(unauthorized(Some("invalid token"))),
     }
 }
 
-async fn jwt_middleware(
-    State(_state): State<AppState>,
-    request: Request,
-    next: Next,
-) -> Result<Response, StatusCode> {
-    // Skip JWT for public endpoints and health
-    if request.uri().path().starts_with("/public/") || request.uri().path() == "/health" {
-        return Ok(next.run(request).await);
-    }
-
-    let auth_header = request
-        .headers()
-        .get("authorization")
-        .and_then(|h| h.to_str().ok());
-
-    if let Some(auth_header) = auth_header {
-        if let Some(token) = auth_header.strip_prefix("Bearer ") {
-            // Simple JWT validation (for demo - in production use proper validation)
-            if token == "valid-test-token" {
-                return Ok(next.run(request).await);
-            }
-        }
+/// Auth failures answer with `{"error": ..., "message": ...}` so clients can
+/// tell them apart without parsing the challenge.
+fn auth_error(
+    status: StatusCode,
+    error: &str,
+    message: &str,
+    challenge: Option<String>,
+) -> Response {
+    let body = serde_json::json!({ "error": error, "message": message }).to_string();
+    let mut response = (status, [(header::CONTENT_TYPE, "application/json")], body).into_response();
+    if let Some(value) = challenge.and_then(|c| HeaderValue::from_str(&c).ok()) {
+        response.headers_mut().insert(header::WWW_AUTHENTICATE, value);
+    }
+    response
+}
+
+/// A 401 with the RFC 6750 challenge; a request without credentials gets no
+/// error code.
+fn unauthorized(reason: Option<&str>) -> Response {
+    match reason {
+        Some(reason) => auth_error(
+            StatusCode::UNAUTHORIZED,
+            "invalid_token",
+            reason,
+            Some(format!(
+                "Bearer error=\"invalid_token\", error_description=\"{}\"",
+                reason
+            )),
+        ),
+        None => auth_error(
+            StatusCode::UNAUTHORIZED,
+            "unauthorized",
+            "credentials required",
+            Some("Bearer".to_string()),
+        ),
     }
+}
 
-    Err(StatusCode::UNAUTHORIZED)
+fn insufficient_scope(missing: &[&str]) -> Response {
+    auth_error(
+        StatusCode::FORBIDDEN,
+        "insufficient_scope",
+        &format!("missing scopes: {}", missing.join(" ")),
+        Some(format!(
+            "Bearer error=\"insufficient_scope\", scope=\"{}\"",
+            missing.join(" ")
+        )),
+    )
 }
 
 async fn rate_limit_middleware(
     State(state): State<AppState>,
+    Extension(route): Extension<Arc<Route>>,
     request: Request,
     next: Next,
-) -> Result<Response, StatusCode> {
+) -> Response {
     let client_ip = request
         .extensions()
-        .get::<SocketAddr>()
-        .map(|addr| addr.ip().to_string())
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string())
         .unwrap_or_else(|| "unknown".to_string());
+    let identity = request.extensions().get::<Identity>();
 
-    if !state.rate_limiter.allow(&client_ip, 100, Duration::from_secs(60)) {
-        return Err(StatusCode::TOO_MANY_REQUESTS);
+    let (key, limit) = route.rate_limit.key_for(&client_ip, identity);
+    let decision = state.rate_limiter.check(&key, &limit).await;
+    if !decision.allowed {
+        let mut response = (StatusCode::TOO_MANY_REQUESTS, "Too Many Requests").into_response();
+        add_rate_limit_headers(response.headers_mut(), &decision);
+        let retry_after = whole_secs(decision.retry_after).max(1);
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        return response;
     }
 
-    Ok(next.run(request).await)
+    let mut response = next.run(request).await;
+    add_rate_limit_headers(response.headers_mut(), &decision);
+    response
+}
+
+/// The `RateLimit-*` headers of the IETF rate-limit headers draft.
+fn add_rate_limit_headers(headers: &mut HeaderMap, decision: &Decision) {
+    headers.insert("ratelimit-limit", HeaderValue::from(decision.limit));
+    headers.insert("ratelimit-remaining", HeaderValue::from(decision.remaining));
+    headers.insert("ratelimit-reset", HeaderValue::from(whole_secs(decision.reset)));
+}
+
+fn whole_secs(d: Duration) -> u64 {
+    d.as_millis().div_ceil(1000) as u64
 }
 
 async fn proxy_handler(
-    State(state): State<AppState>,
+    Extension(table): Extension<Arc<RouteTable>>,
+    Extension(route): Extension<Arc<Route>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    tls: Option<Extension<TlsInfo>>,
     request: Request,
-) -> impl IntoResponse {
-    // For demo purposes, return a simple response instead of actual proxy
-    let path = request.uri().path();
-    let method = request.method().to_string();
-    
-    let response = format!(
-        r#"{{
-        "message": "Gateway received request",
-        "method": "{}",
-        "path": "{}",
-        "target": "{}",
-        "timestamp": {}
-    }}"#,
-        method,
-        path,
-        state.target_url,
-        std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
-    );
-
-    (StatusCode::OK, response)
+) -> Response {
+    let proto = if tls.is_some() { "https" } else { "http" };
+    let Some(policy) = &route.cache else {
+        return route.upstream.forward(request, peer.ip(), proto).await;
+    };
+    let upstream = Arc::clone(&route.upstream);
+    let fetch = move |request| async move { upstream.forward(request, peer.ip(), proto).await };
+    table.cache().handle(policy, request, fetch).await
 }
 
 async fn health_check() -> &'static str {
     "OK"
 }
 
+/// Served without auth on the same listener as traffic, like `/health`. It
+/// only holds counters, no URLs or keys.
+async fn cache_stats(State(state): State<AppState>) -> Json<CacheStats> {
+    let table = Arc::clone(&state.routes.read().unwrap());
+    Json(table.cache().stats())
+}
+
 #[tokio::main]
 async fn main() {
     let args: Vec<String> = std::env::args().collect();
-    if args.len() != 3 {
-        eprintln!("Usage: {} <listen_addr> <target_url>", args[0]);
+    if args.len() == 3 && args[1] == "hash-key" {
+        println!("{}", consumers::hash_key(&args[2]));
+        return;
+    }
+    if args.len() != 3 && args.len() != 4 {
+        eprintln!("Usage: {} <listen_addr> <target_url> [routes.toml]", args[0]);
+        eprintln!("       {} hash-key <api_key>", args[0]);
         eprintln!("Example: {} :8080 http://localhost:3000", args[0]);
         std::process::exit(1);
     }
@@ -148,25 +355,419 @@ async fn main() {
     };
     let listen_addr: SocketAddr = addr_str.parse().expect("Invalid listen address");
     let target_url = args[2].clone();
+    let config_path = args.get(3).map(PathBuf::from);
+    let table = match load_routes(config_path.as_deref(), &target_url) {
+        Ok(table) => table,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let routes = Arc::new(RwLock::new(Arc::new(table)));
+    if let Err(e) = spawn_reloader(config_path, target_url.clone(), Arc::clone(&routes)) {
+        eprintln!("Cannot listen for SIGHUP: {}", e);
+        std::process::exit(1);
+    }
 
-    let rate_limiter = Arc::new(RateLimiter::new());
+    let jwt_config = match JwtConfig::from_env() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let jwt = if jwt_config.has_keys() {
+        match JwtVerifier::new(jwt_config).await {
+            Ok(verifier) => {
+                let verifier = Arc::new(verifier);
+                verifier.spawn_refresh();
+                Some(verifier)
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        eprintln!(
+            "Warning: no JWT key configured, accepting only the demo token \"{}\"",
+            DEMO_TOKEN
+        );
+        None
+    };
 
-    let app_state = AppState {
-        target_url,
-        rate_limiter,
+    let store = match std::env::var("RATE_LIMIT_STORE") {
+        Ok(url) if !url.is_empty() => match RedisStore::from_url(&url) {
+            Ok(store) => Some(store),
+            Err(e) => {
+                eprintln!("RATE_LIMIT_STORE: {}", e);
+                std::process::exit(1);
+            }
+        },
+        _ => None,
+    };
+    let tls = match tls_from_env() {
+        Ok(tls) => tls,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
     };
+    let rate_limiter = Arc::new(RateLimiter::new(store));
+    rate_limiter.spawn_sweeper(RATE_LIMIT_SWEEP);
 
-    let target_url_for_print = app_state.target_url.clone();
-    
-    let app = Router::new()
-        .route("/health", get(health_check))
-        .fallback(proxy_handler)
-        .layer(middleware::from_fn_with_state(app_state.clone(), rate_limit_middleware))
-        .layer(middleware::from_fn_with_state(app_state.clone(), jwt_middleware))
-        .with_state(app_state);
+    let app = app(AppState {
+        routes,
+        jwt,
+        rate_limiter,
+    });
 
-    println!("Starting gateway on {} -> {}", listen_addr, target_url_for_print);
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    println!("Starting gateway on {}://{} -> {}", scheme, listen_addr, target_url);
 
     let listener = tokio::net::TcpListener::bind(listen_addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    match tls {
+        Some(acceptor) => tls::serve(listener, acceptor, app).await,
+        None => axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .unwrap(),
+    }
+}
+
+fn app(state: AppState) -> Router {
+    // Layers run bottom-up; `/health` and `/cache/stats` are added after
+    // them so they are answered by the gateway itself, unrouted and
+    // unlimited.
+    Router::new()
+        .fallback(proxy_handler)
+        .layer(middleware::from_fn_with_state(state.clone(), rate_limit_middleware))
+        .layer(middleware::from_fn_with_state(state.clone(), auth_middleware))
+        .layer(middleware::from_fn(plugin_middleware))
+        .layer(middleware::from_fn_with_state(state.clone(), route_middleware))
+        .route("/health", get(health_check))
+        .route("/cache/stats", get(cache_stats))
+        .with_state(state)
+}
+
+/// HTTPS when TLS_CERT and TLS_KEY are set; TLS_CLIENT_CA additionally
+/// accepts client certificates it signed.
+fn tls_from_env() -> Result<Option<tokio_rustls::TlsAcceptor>, String> {
+    let var = |name| std::env::var(name).ok().filter(|v: &String| !v.is_empty());
+    let client_ca = var("TLS_CLIENT_CA");
+    match (var("TLS_CERT"), var("TLS_KEY")) {
+        (Some(cert), Some(key)) => {
+            tls::acceptor(Path::new(&cert), Path::new(&key), client_ca.as_deref().map(Path::new))
+                .map(Some)
+        }
+        (None, None) if client_ca.is_none() => Ok(None),
+        _ => Err("TLS needs both TLS_CERT and TLS_KEY".to_string()),
+    }
+}
+
+/// Builds the route table from `path`, or the built-in one without a file.
+fn load_routes(path: Option<&Path>, target_url: &str) -> Result<RouteTable, String> {
+    let config = match path {
+        Some(path) => Config::load(path)?,
+        None => Config::fallback(),
+    };
+    let table = RouteTable::build(&config, target_url, CONNECT_TIMEOUT, RESPONSE_TIMEOUT)?;
+    for route in table.routes() {
+        let access = if route.auth { "auth" } else { "public" };
+        println!("Route {} ({})", route.name, access);
+    }
+    Ok(table)
+}
+
+/// Reloads the route file on SIGHUP. Requests already routed finish on their
+/// old route; a file that fails to load leaves the current table active.
+fn spawn_reloader(
+    path: Option<PathBuf>,
+    target_url: String,
+    routes: Arc<RwLock<Arc<RouteTable>>>,
+) -> std::io::Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            let Some(path) = &path else {
+                println!("SIGHUP: no route file to reload");
+                continue;
+            };
+            match load_routes(Some(path), &target_url) {
+                Ok(table) => {
+                    *routes.write().unwrap() = Arc::new(table);
+                    println!("Reloaded {}", path.display());
+                }
+                Err(e) => eprintln!("Reload failed, keeping current routes: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use http_body_util::BodyExt;
+    use hyper_util::client::legacy::{connect::HttpConnector, Client};
+    use hyper_util::rt::TokioExecutor;
+
+    async fn serve(app: Router) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = app.into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        addr
+    }
+
+    /// An upstream that answers with the path and query it was asked for.
+    async fn echo_upstream() -> String {
+        let app = Router::new().fallback(|request: Request| async move {
+            request.uri().path_and_query().unwrap().to_string()
+        });
+        format!("http://{}", serve(app).await)
+    }
+
+    fn state(config: &Config, target: &str, jwt: Option<JwtVerifier>) -> AppState {
+        let table = RouteTable::build(config, target, CONNECT_TIMEOUT, RESPONSE_TIMEOUT).unwrap();
+        AppState {
+            routes: Arc::new(RwLock::new(Arc::new(table))),
+            jwt: jwt.map(Arc::new),
+            rate_limiter: Arc::new(RateLimiter::new(None)),
+        }
+    }
+
+    async fn gateway(config: &Config, target: &str) -> SocketAddr {
+        serve(app(state(config, target, None))).await
+    }
+
+    async fn send(request: Request) -> (StatusCode, HeaderMap, String) {
+        let client: Client<HttpConnector, Body> =
+            Client::builder(TokioExecutor::new()).build_http();
+        let response = client.request(request).await.unwrap();
+        let (parts, body) = response.into_parts();
+        let body = body.collect().await.unwrap().to_bytes();
+        (
+            parts.status,
+            parts.headers,
+            String::from_utf8_lossy(&body).into_owned(),
+        )
+    }
+
+    fn get(gateway: SocketAddr, path: &str) -> Request {
+        Request::get(format!("http://{}{}", gateway, path))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    const SECRET: &str = "test-secret";
+
+    fn token(sub: &str) -> String {
+        let exp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 60;
+        jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &serde_json::json!({ "sub": sub, "exp": exp }),
+            &jsonwebtoken::EncodingKey::from_secret(SECRET.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    /// `batch-job` has an API key and a certificate, `reporting` an API key;
+    /// `/batch/*` is for `batch-job` only and `/reports/*` needs a scope.
+    async fn auth_state(target: &str) -> AppState {
+        let config = Config::parse(&format!(
+            r#"
+            [consumers.batch-job]
+            api_keys = ["{}"]
+            client_certs = ["CN=batch-job, O=Example"]
+            [consumers.reporting]
+            api_keys = ["{}"]
+
+            [[routes]]
+            path = "/batch/*"
+            consumers = ["batch-job"]
+            [[routes]]
+            path = "/reports/*"
+            scopes = ["reports:read"]
+            [[routes]]
+            path = "/*"
+            "#,
+            consumers::hash_key("k-batch"),
+            consumers::hash_key("k-reporting"),
+        ))
+        .unwrap();
+        let jwt = JwtVerifier::new(JwtConfig {
+            hs256_secret: Some(SECRET.to_string()),
+            ..JwtConfig::default()
+        })
+        .await
+        .unwrap();
+        state(&config, target, Some(jwt))
+    }
+
+    #[tokio::test]
+    async fn test_preflight_needs_a_cors_route() {
+        let config = Config::parse(
+            r#"
+            [[routes]]
+            path = "/cors/*"
+            methods = ["PUT"]
+            [[routes.plugins]]
+            type = "cors"
+            allow_origins = ["https://app.example.com"]
+            [[routes]]
+            path = "/plain/*"
+            methods = ["PUT"]
+            "#,
+        )
+        .unwrap();
+        let gw = serve(app(state(&config, &echo_upstream().await, None))).await;
+        let preflight = |path: &str| {
+            Request::options(format!("http://{}{}", gw, path))
+                .header(header::ORIGIN, "https://app.example.com")
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "PUT")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let (status, headers, _) = send(preflight("/cors/x")).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+
+        // Without a CORS plugin the OPTIONS is not passed upstream.
+        let (status, headers, _) = send(preflight("/plain/x")).await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(headers[header::ALLOW], "PUT");
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_order() {
+        let state = auth_state("http://127.0.0.1:9").await;
+        let table = Arc::clone(&state.routes.read().unwrap());
+        let known = TlsInfo {
+            client_subject: Some("CN=batch-job,O=Example".to_string()),
+        };
+        let unknown = TlsInfo {
+            client_subject: Some("CN=stranger".to_string()),
+        };
+        let key = |key: &'static str| Some(HeaderValue::from_static(key));
+        let mut bearer = HeaderMap::new();
+        let value = format!("Bearer {}", token("alice"));
+        bearer.insert(header::AUTHORIZATION, value.parse().unwrap());
+        let none = HeaderMap::new();
+
+        let who = |result: Result<Identity, Response>| {
+            result
+                .map(|i| (i.name.unwrap(), i.method))
+                .map_err(|r| r.status())
+        };
+        let batch = |method| Ok(("batch-job".to_string(), method));
+        let reporting = Ok(("reporting".to_string(), AuthMethod::ApiKey));
+        let alice = Ok(("alice".to_string(), AuthMethod::Jwt));
+
+        // A known certificate wins over whatever else is presented.
+        let result = authenticate(&state, &table, &bearer, Some(&known), key("k-reporting")).await;
+        assert_eq!(who(result), batch(AuthMethod::ClientCert));
+        let result = authenticate(&state, &table, &none, Some(&known), key("wrong")).await;
+        assert_eq!(who(result), batch(AuthMethod::ClientCert));
+        // An unknown certificate falls through; an API key beats a token.
+        let result =
+            authenticate(&state, &table, &bearer, Some(&unknown), key("k-reporting")).await;
+        assert_eq!(who(result), reporting);
+        let result = authenticate(&state, &table, &bearer, Some(&unknown), None).await;
+        assert_eq!(who(result), alice);
+        // An unknown API key fails even alongside a valid token.
+        let result = authenticate(&state, &table, &bearer, None, key("wrong")).await;
+        assert_eq!(who(result), Err(StatusCode::UNAUTHORIZED));
+        let result = authenticate(&state, &table, &none, None, None).await;
+        assert_eq!(who(result), Err(StatusCode::UNAUTHORIZED));
+    }
+
+    #[tokio::test]
+    async fn test_auth_errors() {
+        let gw = serve(app(auth_state(&echo_upstream().await).await)).await;
+        let with = |path: &str, name: &'static str, value: String| {
+            let mut request = get(gw, path);
+            request.headers_mut().insert(name, value.parse().unwrap());
+            request
+        };
+        let bearer = |sub: &str| format!("Bearer {}", token(sub));
+
+        let cases = [
+            (get(gw, "/x"), StatusCode::UNAUTHORIZED, "unauthorized"),
+            (
+                with("/x", "x-api-key", "wrong".into()),
+                StatusCode::UNAUTHORIZED,
+                "invalid_api_key",
+            ),
+            (
+                with("/x", "authorization", "Bearer junk".into()),
+                StatusCode::UNAUTHORIZED,
+                "invalid_token",
+            ),
+            (
+                with("/batch/x", "x-api-key", "k-reporting".into()),
+                StatusCode::FORBIDDEN,
+                "forbidden",
+            ),
+            // A token is not the consumer its subject names.
+            (
+                with("/batch/x", "authorization", bearer("batch-job")),
+                StatusCode::FORBIDDEN,
+                "forbidden",
+            ),
+            (
+                with("/reports/x", "authorization", bearer("alice")),
+                StatusCode::FORBIDDEN,
+                "insufficient_scope",
+            ),
+        ];
+        for (request, status, error) in cases {
+            let path = request.uri().path().to_string();
+            let (got, headers, body) = send(request).await;
+            assert_eq!(got, status, "{path} {error}");
+            assert_eq!(headers[header::CONTENT_TYPE], "application/json");
+            let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(body["error"], error);
+            assert!(body["message"].is_string());
+        }
+
+        let (status, _, body) = send(with("/batch/x", "x-api-key", "k-batch".into())).await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "/batch/x"));
+    }
+
+    #[tokio::test]
+    async fn test_dot_segments_cannot_leave_a_public_route() {
+        // `/public/*` is public and `/*` needs auth.
+        let gw = gateway(&Config::fallback(), &echo_upstream().await).await;
+
+        let (status, _, body) = send(get(gw, "/public/./css/../app.css?v=2")).await;
+        assert_eq!(
+            (status, body.as_str()),
+            (StatusCode::OK, "/public/app.css?v=2")
+        );
+
+        for path in [
+            "/public/../admin",
+            "/public/%2e%2e/admin",
+            "/public/%2E./x/%2e%2E/../admin",
+        ] {
+            let (status, _, _) = send(get(gw, path)).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED, "{path}");
+        }
+        for path in ["/public/..%2fadmin", "/public/..;/admin"] {
+            let (status, _, _) = send(get(gw, path)).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{path}");
+        }
+    }
 }
@@ -0,0 +1,550 @@
+use axum::body::Body;
+use axum::extract::Request;
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use http_body_util::Limited;
+use regex::Regex;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::future::Future;
+
+use crate::config::{CorsConfig, HeadersConfig, PluginConfig};
+
+// Longest request ID taken from a client; longer ones are replaced.
+const MAX_REQUEST_ID: usize = 128;
+
+/// A route's plugins. On the way in each may change the request or answer
+/// it itself; on the way out they see the response in reverse order.
+#[derive(Default)]
+pub struct Plugins {
+    plugins: Vec<Plugin>,
+}
+
+pub enum Plugin {
+    Headers(Headers),
+    Rewrite { pattern: Regex, replace: String },
+    RequestId(HeaderName),
+    Cors(Cors),
+    BodyLimit(u64),
+}
+
+pub struct Headers {
+    request_set: Vec<(HeaderName, HeaderValue)>,
+    request_remove: Vec<HeaderName>,
+    response_set: Vec<(HeaderName, HeaderValue)>,
+    response_remove: Vec<HeaderName>,
+}
+
+pub struct Cors {
+    // Empty with `any_origin`.
+    origins: Vec<String>,
+    any_origin: bool,
+    // `None` reflects what the preflight asks for.
+    allow_methods: Option<HeaderValue>,
+    allow_headers: Option<HeaderValue>,
+    expose_headers: Option<HeaderValue>,
+    allow_credentials: bool,
+    max_age: Option<HeaderValue>,
+}
+
+/// What the response side needs to know about the request.
+struct Exchange {
+    origin: Option<HeaderValue>,
+    request_id: Option<HeaderValue>,
+}
+
+impl Plugins {
+    /// `configs` have been validated with the config.
+    pub fn build(configs: &[PluginConfig]) -> Self {
+        Self {
+            plugins: configs
+                .iter()
+                .filter_map(|c| Plugin::build(c).ok())
+                .collect(),
+        }
+    }
+
+    /// Whether the chain answers CORS preflights.
+    pub fn has_cors(&self) -> bool {
+        self.plugins.iter().any(|p| matches!(p, Plugin::Cors(_)))
+    }
+
+    /// Runs the chain around `next`, which stands for the rest of the
+    /// gateway: auth, rate limiting and the upstream.
+    pub async fn run<F, Fut>(&self, mut request: Request, next: F) -> Response
+    where
+        F: FnOnce(Request) -> Fut,
+        Fut: Future<Output = Response>,
+    {
+        if self.plugins.is_empty() {
+            return next(request).await;
+        }
+        let mut exchange = Exchange {
+            origin: request.headers().get(header::ORIGIN).cloned(),
+            request_id: None,
+        };
+        for (i, plugin) in self.plugins.iter().enumerate() {
+            if let Some(mut response) = plugin.on_request(&mut request, &mut exchange) {
+                for plugin in self.plugins[..i].iter().rev() {
+                    plugin.on_response(&mut response, &exchange);
+                }
+                return response;
+            }
+        }
+        let mut response = next(request).await;
+        for plugin in self.plugins.iter().rev() {
+            plugin.on_response(&mut response, &exchange);
+        }
+        response
+    }
+}
+
+impl Plugin {
+    pub fn build(config: &PluginConfig) -> Result<Self, String> {
+        Ok(match config {
+            PluginConfig::Headers(c) => Self::Headers(Headers::build(c)?),
+            PluginConfig::Rewrite(c) => {
+                let pattern = Regex::new(&c.pattern).map_err(|e| e.to_string())?;
+                Self::Rewrite {
+                    pattern,
+                    replace: c.replace.clone(),
+                }
+            }
+            PluginConfig::RequestId(c) => Self::RequestId(header_name(&c.header)?),
+            PluginConfig::Cors(c) => Self::Cors(Cors::build(c)?),
+            PluginConfig::BodyLimit(c) => Self::BodyLimit(c.max_bytes),
+        })
+    }
+
+    /// A response answers the request instead of passing it on.
+    fn on_request(&self, request: &mut Request, exchange: &mut Exchange) -> Option<Response> {
+        match self {
+            Self::Headers(h) => apply(request.headers_mut(), &h.request_remove, &h.request_set),
+            Self::Rewrite { pattern, replace } => {
+                let Cow::Owned(path) = pattern.replace(request.uri().path(), replace.as_str())
+                else {
+                    return None;
+                };
+                let uri = match request.uri().query() {
+                    Some(query) => format!("{}?{}", path, query),
+                    None => path,
+                };
+                match uri.parse::<Uri>() {
+                    Ok(uri) if uri.path().starts_with('/') => *request.uri_mut() = uri,
+                    _ => return Some((StatusCode::BAD_REQUEST, "Bad Request").into_response()),
+                }
+            }
+            Self::RequestId(name) => {
+                let id = request
+                    .headers()
+                    .get(name)
+                    .filter(|id| valid_request_id(id))
+                    .cloned()
+                    .unwrap_or_else(new_request_id);
+                request.headers_mut().insert(name.clone(), id.clone());
+                exchange.request_id = Some(id);
+            }
+            Self::Cors(cors) if is_preflight(request) => {
+                return Some(cors.preflight(request.headers(), exchange.origin.as_ref()));
+            }
+            Self::Cors(_) => {}
+            Self::BodyLimit(max) => {
+                let declared = request
+                    .headers()
+                    .get(header::CONTENT_LENGTH)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.parse::<u64>().ok());
+                if declared.is_some_and(|len| len > *max) {
+                    return Some(payload_too_large());
+                }
+                // Chunked bodies are counted as they stream, and the proxy
+                // answers 413 once they pass the limit.
+                let body = std::mem::take(request.body_mut());
+                *request.body_mut() = Body::new(Limited::new(body, *max as usize));
+            }
+        }
+        None
+    }
+
+    fn on_response(&self, response: &mut Response, exchange: &Exchange) {
+        match self {
+            Self::Headers(h) => apply(response.headers_mut(), &h.response_remove, &h.response_set),
+            Self::RequestId(name) => {
+                if let Some(id) = &exchange.request_id {
+                    response.headers_mut().insert(name.clone(), id.clone());
+                }
+            }
+            Self::Cors(cors) => {
+                let headers = response.headers_mut();
+                if cors.add_origin(headers, exchange.origin.as_ref()) {
+                    if let Some(expose) = &cors.expose_headers {
+                        headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, expose.clone());
+                    }
+                }
+            }
+            Self::Rewrite { .. } | Self::BodyLimit(_) => {}
+        }
+    }
+}
+
+pub fn payload_too_large() -> Response {
+    (StatusCode::PAYLOAD_TOO_LARGE, "Payload Too Large").into_response()
+}
+
+impl Headers {
+    fn build(config: &HeadersConfig) -> Result<Self, String> {
+        let set = |headers: &BTreeMap<String, String>| {
+            headers
+                .iter()
+                .map(|(name, value)| {
+                    let value = HeaderValue::from_str(value)
+                        .map_err(|_| format!("invalid value for header '{}'", name))?;
+                    Ok((header_name(name)?, value))
+                })
+                .collect::<Result<Vec<_>, String>>()
+        };
+        let remove = |names: &[String]| {
+            names
+                .iter()
+                .map(|n| header_name(n))
+                .collect::<Result<Vec<_>, String>>()
+        };
+        Ok(Self {
+            request_set: set(&config.request_set)?,
+            request_remove: remove(&config.request_remove)?,
+            response_set: set(&config.response_set)?,
+            response_remove: remove(&config.response_remove)?,
+        })
+    }
+}
+
+fn apply(headers: &mut HeaderMap, remove: &[HeaderName], set: &[(HeaderName, HeaderValue)]) {
+    for name in remove {
+        headers.remove(name);
+    }
+    for (name, value) in set {
+        headers.insert(name.clone(), value.clone());
+    }
+}
+
+impl Cors {
+    fn build(config: &CorsConfig) -> Result<Self, String> {
+        if config.allow_origins.is_empty() {
+            return Err("allow_origins is empty".to_string());
+        }
+        let any_origin = config.allow_origins.iter().any(|o| o == "*");
+        if any_origin && config.allow_credentials {
+            return Err("allow_credentials cannot be used with origin '*'".to_string());
+        }
+        for method in &config.allow_methods {
+            if method.parse::<Method>().is_err() || method.to_uppercase() != *method {
+                return Err(format!("invalid method '{}'", method));
+            }
+        }
+        for name in config.allow_headers.iter().chain(&config.expose_headers) {
+            header_name(name)?;
+        }
+        let list = |items: &[String]| {
+            if items.is_empty() {
+                Ok(None)
+            } else {
+                HeaderValue::from_str(&items.join(", "))
+                    .map(Some)
+                    .map_err(|e| e.to_string())
+            }
+        };
+        Ok(Self {
+            origins: if any_origin {
+                Vec::new()
+            } else {
+                config.allow_origins.clone()
+            },
+            any_origin,
+            allow_methods: list(&config.allow_methods)?,
+            allow_headers: list(&config.allow_headers)?,
+            expose_headers: list(&config.expose_headers)?,
+            allow_credentials: config.allow_credentials,
+            max_age: config.max_age_secs.map(HeaderValue::from),
+        })
+    }
+
+    /// Adds `Access-Control-Allow-Origin` if `origin` is allowed, and
+    /// returns whether it was.
+    fn add_origin(&self, headers: &mut HeaderMap, origin: Option<&HeaderValue>) -> bool {
+        if !self.any_origin {
+            headers.append(header::VARY, HeaderValue::from_static("origin"));
+        }
+        let Some(origin) = origin else {
+            return false;
+        };
+        let allowed = self.any_origin
+            || origin
+                .to_str()
+                .is_ok_and(|o| self.origins.iter().any(|a| a.eq_ignore_ascii_case(o)));
+        if !allowed {
+            return false;
+        }
+        let value = if self.any_origin {
+            HeaderValue::from_static("*")
+        } else {
+            origin.clone()
+        };
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, value);
+        if self.allow_credentials {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+        true
+    }
+
+    /// Answers a preflight. A disallowed origin gets no CORS headers, which
+    /// the browser treats as a refusal.
+    fn preflight(&self, request: &HeaderMap, origin: Option<&HeaderValue>) -> Response {
+        let mut response = StatusCode::NO_CONTENT.into_response();
+        let headers = response.headers_mut();
+        if !self.add_origin(headers, origin) {
+            return response;
+        }
+        let requested = |name| request.get(name).cloned();
+        let allow = [
+            (
+                header::ACCESS_CONTROL_ALLOW_METHODS,
+                self.allow_methods
+                    .clone()
+                    .or_else(|| requested(header::ACCESS_CONTROL_REQUEST_METHOD)),
+            ),
+            (
+                header::ACCESS_CONTROL_ALLOW_HEADERS,
+                self.allow_headers
+                    .clone()
+                    .or_else(|| requested(header::ACCESS_CONTROL_REQUEST_HEADERS)),
+            ),
+            (header::ACCESS_CONTROL_MAX_AGE, self.max_age.clone()),
+        ];
+        for (name, value) in allow {
+            if let Some(value) = value {
+                headers.insert(name, value);
+            }
+        }
+        response
+    }
+}
+
+/// An `OPTIONS` request a browser sends before a cross-origin request.
+pub fn is_preflight(request: &Request) -> bool {
+    request.method() == Method::OPTIONS
+        && request.headers().contains_key(header::ORIGIN)
+        && request
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+fn header_name(name: &str) -> Result<HeaderName, String> {
+    HeaderName::from_bytes(name.as_bytes()).map_err(|_| format!("invalid header name '{}'", name))
+}
+
+fn valid_request_id(id: &HeaderValue) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID
+        && id.as_bytes().iter().all(|b| b.is_ascii_graphic())
+}
+
+fn new_request_id() -> HeaderValue {
+    let id = uuid::Uuid::new_v4().hyphenated().to_string();
+    HeaderValue::from_str(&id).expect("a UUID is a valid header value")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn plugins(toml: &str) -> Plugins {
+        let config = Config::parse(&format!("[[routes]]\npath = \"/*\"\n{}", toml)).unwrap();
+        Plugins::build(&config.routes[0].plugins)
+    }
+
+    fn request(method: Method, uri: &str, headers: &[(&str, &str)]) -> Request {
+        let mut builder = Request::builder().method(method).uri(uri);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    /// Stands in for the upstream: answers with the request's headers, its
+    /// URI in `x-uri`, and a `server` header.
+    async fn echo(request: Request) -> Response {
+        let mut response = Response::new(Body::empty());
+        *response.headers_mut() = request.headers().clone();
+        let uri = HeaderValue::from_str(&request.uri().to_string()).unwrap();
+        response.headers_mut().insert("x-uri", uri);
+        response
+            .headers_mut()
+            .insert(header::SERVER, HeaderValue::from_static("upstream"));
+        response
+    }
+
+    #[tokio::test]
+    async fn test_headers_and_rewrite() {
+        let plugins = plugins(
+            r#"
+            [[routes.plugins]]
+            type = "headers"
+            request_set = { x-env = "prod" }
+            request_remove = ["cookie"]
+            response_remove = ["server"]
+
+            [[routes.plugins]]
+            type = "rewrite"
+            pattern = "^/users/(?P<id>\\d+)$"
+            replace = "/v2/users/${id}"
+            "#,
+        );
+        let response = plugins
+            .run(
+                request(Method::GET, "/users/42?full=1", &[("cookie", "a=b")]),
+                echo,
+            )
+            .await;
+        let headers = response.headers();
+        assert_eq!(headers["x-uri"], "/v2/users/42?full=1");
+        assert_eq!(headers["x-env"], "prod");
+        assert!(!headers.contains_key("cookie"));
+        assert!(!headers.contains_key(header::SERVER));
+
+        // No match leaves the path alone.
+        let response = plugins
+            .run(request(Method::GET, "/users/me", &[]), echo)
+            .await;
+        assert_eq!(response.headers()["x-uri"], "/users/me");
+    }
+
+    #[tokio::test]
+    async fn test_request_id() {
+        let plugins = plugins("[[routes.plugins]]\ntype = \"request-id\"");
+        let response = plugins.run(request(Method::GET, "/", &[]), echo).await;
+        let id = response.headers()["x-request-id"].to_str().unwrap();
+        assert!(uuid::Uuid::parse_str(id).is_ok(), "{}", id);
+
+        let response = plugins
+            .run(
+                request(Method::GET, "/", &[("x-request-id", "abc-123")]),
+                echo,
+            )
+            .await;
+        assert_eq!(response.headers()["x-request-id"], "abc-123");
+
+        let response = plugins
+            .run(request(Method::GET, "/", &[("x-request-id", "a b")]), echo)
+            .await;
+        assert_ne!(response.headers()["x-request-id"], "a b");
+    }
+
+    #[tokio::test]
+    async fn test_cors() {
+        let plugins = plugins(
+            r#"
+            [[routes.plugins]]
+            type = "request-id"
+
+            [[routes.plugins]]
+            type = "cors"
+            allow_origins = ["https://app.example.com"]
+            expose_headers = ["x-request-id"]
+            allow_credentials = true
+            max_age_secs = 600
+            "#,
+        );
+        let preflight = |origin| {
+            request(
+                Method::OPTIONS,
+                "/items",
+                &[
+                    ("origin", origin),
+                    ("access-control-request-method", "PUT"),
+                    ("access-control-request-headers", "content-type"),
+                ],
+            )
+        };
+
+        // Answered without reaching the upstream, but with a request ID.
+        let response = plugins
+            .run(preflight("https://app.example.com"), echo)
+            .await;
+        let headers = response.headers();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(!headers.contains_key("x-uri"));
+        assert!(headers.contains_key("x-request-id"));
+        assert_eq!(
+            headers["access-control-allow-origin"],
+            "https://app.example.com"
+        );
+        assert_eq!(headers["access-control-allow-methods"], "PUT");
+        assert_eq!(headers["access-control-allow-headers"], "content-type");
+        assert_eq!(headers["access-control-allow-credentials"], "true");
+        assert_eq!(headers["access-control-max-age"], "600");
+        assert_eq!(headers[header::VARY], "origin");
+
+        let response = plugins
+            .run(preflight("https://evil.example.com"), echo)
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(!response
+            .headers()
+            .contains_key("access-control-allow-origin"));
+
+        let response = plugins
+            .run(
+                request(
+                    Method::GET,
+                    "/items",
+                    &[("origin", "https://app.example.com")],
+                ),
+                echo,
+            )
+            .await;
+        let headers = response.headers();
+        assert!(headers.contains_key("x-uri"));
+        assert_eq!(
+            headers["access-control-allow-origin"],
+            "https://app.example.com"
+        );
+        assert_eq!(headers["access-control-expose-headers"], "x-request-id");
+    }
+
+    #[tokio::test]
+    async fn test_body_limit() {
+        let plugins = plugins("[[routes.plugins]]\ntype = \"body-limit\"\nmax_bytes = 4");
+        let upload = |declared: bool| {
+            let mut request = Request::builder().method(Method::POST).uri("/");
+            if declared {
+                request = request.header(header::CONTENT_LENGTH, "10");
+            }
+            request.body(Body::from("0123456789")).unwrap()
+        };
+        let read_body = |request: Request| async move {
+            match axum::body::to_bytes(request.into_body(), usize::MAX).await {
+                Ok(_) => StatusCode::OK.into_response(),
+                Err(_) => payload_too_large(),
+            }
+        };
+
+        let response = plugins.run(upload(true), echo).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(!response.headers().contains_key("x-uri"));
+
+        // Without a length the body is cut off as it is read.
+        let response = plugins.run(upload(false), read_body).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let small = Request::builder()
+            .method(Method::POST)
+            .uri("/")
+            .body(Body::from("0123"))
+            .unwrap();
+        assert_eq!(plugins.run(small, read_body).await.status(), StatusCode::OK);
+    }
+}
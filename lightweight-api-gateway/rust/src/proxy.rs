@@ -0,0 +1,454 @@
+use axum::body::Body;
+use axum::extract::Request;
+use axum::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use axum::http::{StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use http_body_util::LengthLimitError;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use std::error::Error;
+use std::io;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use crate::plugins::payload_too_large;
+
+// Headers that only describe one hop and must not be forwarded (RFC 9110
+// §7.6.1). This and `strip_hop_by_hop` are copied from
+// high-perf-reverse-proxy's proxy.rs; the two projects share no crate, so a
+// fix to one belongs in the other too.
+const HOP_BY_HOP: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// The service requests are forwarded to, with a pooled HTTP/1.1 client.
+pub struct Upstream {
+    // `http://host:port`, without a trailing slash.
+    origin: String,
+    // Path prefix from the target URL, prepended to every request path.
+    base_path: String,
+    host: HeaderValue,
+    client: Client<HttpConnector, Body>,
+    response_timeout: Duration,
+}
+
+impl Upstream {
+    /// `target` is an `http://host[:port][/base]` URL.
+    pub fn new(
+        target: &str,
+        connect_timeout: Duration,
+        response_timeout: Duration,
+    ) -> Result<Self, String> {
+        let uri: Uri = target
+            .parse()
+            .map_err(|e| format!("invalid target URL {}: {}", target, e))?;
+        if uri.scheme_str() != Some("http") {
+            return Err(format!("target URL {} must start with http://", target));
+        }
+        let authority = uri
+            .authority()
+            .ok_or_else(|| format!("target URL {} has no host", target))?;
+        let mut connector = HttpConnector::new();
+        connector.set_nodelay(true);
+        connector.set_connect_timeout(Some(connect_timeout));
+        Ok(Self {
+            origin: format!("http://{}", authority),
+            base_path: uri.path().trim_end_matches('/').to_string(),
+            host: HeaderValue::from_str(authority.as_str()).map_err(|e| e.to_string())?,
+            client: Client::builder(TokioExecutor::new()).build(connector),
+            response_timeout,
+        })
+    }
+
+    /// Forwards `request` as is, bodies streamed both ways, and returns the
+    /// upstream response. Connect failures become 502 and timeouts 504.
+    /// `proto` is the scheme the client used, for `X-Forwarded-Proto`.
+    pub async fn forward(
+        &self,
+        request: Request,
+        client_ip: IpAddr,
+        proto: &'static str,
+    ) -> Response {
+        let (mut parts, body) = request.into_parts();
+        let path = parts.uri.path_and_query().map_or("/", |pq| pq.as_str());
+        let uri = match format!("{}{}{}", self.origin, self.base_path, path).parse() {
+            Ok(uri) => uri,
+            Err(_) => return (StatusCode::BAD_REQUEST, "Bad Request").into_response(),
+        };
+        let original_host = parts.headers.get(header::HOST).cloned();
+        strip_hop_by_hop(&mut parts.headers);
+        // The server side already answered any `100-continue`.
+        parts.headers.remove(header::EXPECT);
+        add_forwarded(&mut parts.headers, client_ip, original_host, proto);
+        parts.headers.insert(header::HOST, self.host.clone());
+        parts.uri = uri;
+        parts.version = axum::http::Version::HTTP_11;
+
+        let request = Request::from_parts(parts, body);
+        match tokio::time::timeout(self.response_timeout, self.client.request(request)).await {
+            Ok(Ok(response)) => {
+                let (mut parts, body) = response.into_parts();
+                strip_hop_by_hop(&mut parts.headers);
+                Response::from_parts(parts, Body::new(body))
+            }
+            Ok(Err(e)) if e.is_connect() && timed_out(&e) => gateway_timeout(),
+            Ok(Err(e)) if body_too_large(&e) => payload_too_large(),
+            Ok(Err(e)) if e.is_connect() => {
+                (StatusCode::BAD_GATEWAY, "Upstream connect error").into_response()
+            }
+            Ok(Err(_)) => (StatusCode::BAD_GATEWAY, "Bad Gateway").into_response(),
+            Err(_) => gateway_timeout(),
+        }
+    }
+}
+
+fn gateway_timeout() -> Response {
+    (StatusCode::GATEWAY_TIMEOUT, "Gateway Timeout").into_response()
+}
+
+/// Whether a connect error was the connect timeout expiring.
+fn timed_out(e: &(dyn Error + 'static)) -> bool {
+    let mut source = Some(e);
+    while let Some(err) = source {
+        if err
+            .downcast_ref::<io::Error>()
+            .is_some_and(|io| io.kind() == io::ErrorKind::TimedOut)
+        {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// Whether sending failed because the body passed a body-limit plugin's
+/// limit.
+fn body_too_large(e: &(dyn Error + 'static)) -> bool {
+    let mut source = Some(e);
+    while let Some(err) = source {
+        if err.is::<LengthLimitError>() {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// Removes hop-by-hop headers, including any the sender listed in `Connection`.
+/// `TE: trailers` survives: it says the client accepts trailers, which holds
+/// for the next hop too (RFC 9110 §7.6.1).
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let trailers = headers
+        .get_all(header::TE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|t| t.split(';').next().unwrap_or("").trim())
+        .any(|t| t.eq_ignore_ascii_case("trailers"));
+    let listed: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(name);
+    }
+    if trailers {
+        headers.insert(header::TE, HeaderValue::from_static("trailers"));
+    }
+}
+
+/// Appends the client to `X-Forwarded-For` and records the host and scheme
+/// it originally asked for.
+fn add_forwarded(
+    headers: &mut HeaderMap,
+    client_ip: IpAddr,
+    original_host: Option<HeaderValue>,
+    proto: &'static str,
+) {
+    let previous: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect();
+    let forwarded_for = if previous.is_empty() {
+        client_ip.to_string()
+    } else {
+        format!("{}, {}", previous.join(", "), client_ip)
+    };
+    if let Ok(value) = HeaderValue::from_str(&forwarded_for) {
+        headers.insert("x-forwarded-for", value);
+    }
+    if let Some(host) = original_host {
+        headers.insert("x-forwarded-host", host);
+    }
+    headers.insert("x-forwarded-proto", HeaderValue::from_static(proto));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Bytes;
+    use axum::extract::Request;
+    use axum::routing::{any, get};
+    use axum::{Json, Router};
+    use http_body::Frame;
+    use http_body_util::BodyExt;
+    use std::net::SocketAddr;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use tokio::net::TcpListener;
+    use tokio::sync::{mpsc, Notify};
+
+    const CLIENT_IP: &str = "192.0.2.7";
+    const WAIT: Duration = Duration::from_secs(5);
+
+    /// A body the test feeds chunk by chunk.
+    struct ChannelBody(mpsc::Receiver<Bytes>);
+
+    impl http_body::Body for ChannelBody {
+        type Data = Bytes;
+        type Error = std::convert::Infallible;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Bytes>, Self::Error>>> {
+            self.0
+                .poll_recv(cx)
+                .map(|chunk| chunk.map(|chunk| Ok(Frame::data(chunk))))
+        }
+    }
+
+    /// Answers with what it received: the URI, headers and body.
+    async fn echo(request: Request) -> Json<serde_json::Value> {
+        let (parts, body) = request.into_parts();
+        let headers: serde_json::Map<String, serde_json::Value> = parts
+            .headers
+            .keys()
+            .map(|name| {
+                let values: Vec<&str> = parts
+                    .headers
+                    .get_all(name)
+                    .iter()
+                    .map(|v| v.to_str().unwrap())
+                    .collect();
+                (name.to_string(), values.join(", ").into())
+            })
+            .collect();
+        let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        Json(serde_json::json!({
+            "uri": parts.uri.to_string(),
+            "headers": headers,
+            "body": String::from_utf8_lossy(&body),
+        }))
+    }
+
+    async fn serve(app: Router) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        addr
+    }
+
+    fn upstream(target: &str) -> Upstream {
+        Upstream::new(target, WAIT, WAIT).unwrap()
+    }
+
+    async fn forward(upstream: &Upstream, request: Request) -> Response {
+        upstream
+            .forward(request, CLIENT_IP.parse().unwrap(), "http")
+            .await
+    }
+
+    fn request(uri: &str, headers: &[(&'static str, &'static str)]) -> Request {
+        let mut request = Request::new(Body::empty());
+        *request.uri_mut() = uri.parse().unwrap();
+        for (name, value) in headers {
+            request
+                .headers_mut()
+                .append(*name, HeaderValue::from_static(value));
+        }
+        request
+    }
+
+    async fn json(response: Response) -> serde_json::Value {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_connect_error_is_502() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let response = forward(&upstream(&format!("http://{addr}")), request("/", &[])).await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_response_timeout_is_504() {
+        // Accepts connections but never answers.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                held.push(stream);
+            }
+        });
+        let upstream =
+            Upstream::new(&format!("http://{addr}"), WAIT, Duration::from_millis(200)).unwrap();
+        let response = forward(&upstream, request("/", &[])).await;
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_and_hop_by_hop_headers() {
+        let addr = serve(Router::new().route("/", any(echo)).route(
+            "/hop",
+            get(|| async {
+                (
+                    [
+                        ("connection", "x-internal"),
+                        ("x-internal", "1"),
+                        ("keep-alive", "timeout=5"),
+                        ("x-kept", "1"),
+                    ],
+                    "ok",
+                )
+            }),
+        ))
+        .await;
+        let upstream = upstream(&format!("http://{addr}"));
+
+        let req = request(
+            "/",
+            &[
+                ("host", "api.example.com"),
+                ("x-forwarded-for", "10.0.0.1"),
+                ("x-forwarded-for", "10.0.0.2"),
+                ("connection", "keep-alive, x-secret"),
+                ("x-secret", "1"),
+                ("keep-alive", "timeout=5"),
+                ("proxy-authorization", "Basic Zm9vOmJhcg=="),
+                ("upgrade", "websocket"),
+                ("te", "trailers, deflate;q=0.5"),
+                ("x-kept", "1"),
+            ],
+        );
+        let seen = json(forward(&upstream, req).await).await;
+        let headers = &seen["headers"];
+        assert_eq!(headers["x-forwarded-for"], "10.0.0.1, 10.0.0.2, 192.0.2.7");
+        assert_eq!(headers["x-forwarded-host"], "api.example.com");
+        assert_eq!(headers["x-forwarded-proto"], "http");
+        assert_eq!(headers["host"], addr.to_string());
+        assert_eq!(headers["x-kept"], "1");
+        assert_eq!(headers["te"], "trailers");
+        for name in ["x-secret", "keep-alive", "proxy-authorization", "upgrade"] {
+            assert!(headers.get(name).is_none(), "{name} was forwarded");
+        }
+
+        let response = forward(&upstream, request("/hop", &[])).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get("x-internal").is_none());
+        assert!(response.headers().get("keep-alive").is_none());
+        assert_eq!(response.headers()["x-kept"], "1");
+    }
+
+    #[tokio::test]
+    async fn test_base_path_is_joined() {
+        let addr = serve(Router::new().fallback(echo)).await;
+        for (target, expected) in [
+            (format!("http://{addr}"), "/users/1?active=true"),
+            (format!("http://{addr}/api/"), "/api/users/1?active=true"),
+            (
+                format!("http://{addr}/api/v2"),
+                "/api/v2/users/1?active=true",
+            ),
+        ] {
+            let seen =
+                json(forward(&upstream(&target), request("/users/1?active=true", &[])).await).await;
+            assert_eq!(seen["uri"], expected, "{target}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bodies_are_streamed() {
+        // Sends one chunk, and the next only once the test says so.
+        let release = Arc::new(Notify::new());
+        let app = {
+            let release = Arc::clone(&release);
+            Router::new()
+                .route(
+                    "/first",
+                    any(|request: Request| async move {
+                        let mut body = request.into_body();
+                        let frame = body.frame().await.unwrap().unwrap();
+                        frame.into_data().unwrap()
+                    }),
+                )
+                .route(
+                    "/chunks",
+                    get(move || async move {
+                        let (tx, rx) = mpsc::channel(1);
+                        tx.send(Bytes::from("first")).await.unwrap();
+                        tokio::spawn(async move {
+                            release.notified().await;
+                            let _ = tx.send(Bytes::from("second")).await;
+                        });
+                        Body::new(ChannelBody(rx))
+                    }),
+                )
+        };
+        let upstream = upstream(&format!("http://{}", serve(app).await));
+
+        // The request body is still open when the upstream answers.
+        let (tx, rx) = mpsc::channel(1);
+        tx.send(Bytes::from("hello")).await.unwrap();
+        let mut post = Request::new(Body::new(ChannelBody(rx)));
+        *post.method_mut() = axum::http::Method::POST;
+        *post.uri_mut() = "/first".parse().unwrap();
+        let response = tokio::time::timeout(WAIT, forward(&upstream, post))
+            .await
+            .expect("request body was buffered");
+        let body = tokio::time::timeout(WAIT, axum::body::to_bytes(response.into_body(), 64))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(body, "hello");
+        drop(tx);
+
+        // The first response chunk arrives before the upstream finishes.
+        let response = tokio::time::timeout(WAIT, forward(&upstream, request("/chunks", &[])))
+            .await
+            .expect("response was buffered");
+        let mut body = response.into_body();
+        let first = tokio::time::timeout(WAIT, body.frame())
+            .await
+            .expect("response body was buffered")
+            .unwrap()
+            .unwrap();
+        assert_eq!(first.into_data().unwrap(), "first");
+        release.notify_one();
+        let rest = body.collect().await.unwrap().to_bytes();
+        assert_eq!(rest, "second");
+    }
+}
@@ -0,0 +1,365 @@
+use dashmap::DashMap;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::redis::RedisStore;
+
+// How long to limit locally after the shared store fails before trying it
+// again, so an unreachable store does not slow down every request.
+const STORE_RETRY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    /// Refills `requests` tokens per window, holding at most `burst`.
+    TokenBucket,
+    /// Allows `requests` in any window-long span; keeps one timestamp per
+    /// request.
+    SlidingLog,
+    /// Resets the count at the end of each window; allows up to twice the
+    /// limit across a window boundary.
+    FixedWindow,
+}
+
+impl Algorithm {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "token-bucket" => Ok(Self::TokenBucket),
+            "sliding-log" => Ok(Self::SlidingLog),
+            "fixed-window" => Ok(Self::FixedWindow),
+            _ => Err(format!(
+                "unknown algorithm '{}' (token-bucket, sliding-log, fixed-window)",
+                name
+            )),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::TokenBucket => "token-bucket",
+            Self::SlidingLog => "sliding-log",
+            Self::FixedWindow => "fixed-window",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    pub algorithm: Algorithm,
+    pub requests: u32,
+    pub window: Duration,
+    /// Token-bucket capacity; the same as `requests` for other algorithms.
+    pub burst: u32,
+}
+
+impl Limit {
+    /// What `RateLimit-Limit` reports: the most requests allowed at once.
+    pub fn quota(&self) -> u32 {
+        match self.algorithm {
+            Algorithm::TokenBucket => self.burst,
+            _ => self.requests,
+        }
+    }
+}
+
+/// The outcome of one check, with what the `RateLimit-*` headers report.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Until the full quota is available again.
+    pub reset: Duration,
+    /// Until the next request would be allowed; zero when this one was.
+    pub retry_after: Duration,
+}
+
+enum State {
+    Tokens { tokens: f64, updated: Instant },
+    Log(VecDeque<Instant>),
+    Window { count: u32, started: Instant },
+}
+
+struct Entry {
+    algorithm: Algorithm,
+    state: State,
+    // From then on the entry is as good as a fresh one and can be dropped.
+    idle_at: Instant,
+}
+
+/// Counts requests per key, in process or in a shared store. If the store
+/// cannot be reached, limits are enforced per instance until it is back.
+pub struct RateLimiter {
+    entries: DashMap<String, Entry>,
+    store: Option<RedisStore>,
+    // When the store last failed, if it has not answered since.
+    store_down: Mutex<Option<Instant>>,
+}
+
+impl RateLimiter {
+    pub fn new(store: Option<RedisStore>) -> Self {
+        Self {
+            entries: DashMap::new(),
+            store,
+            store_down: Mutex::new(None),
+        }
+    }
+
+    pub async fn check(&self, key: &str, limit: &Limit) -> Decision {
+        if let Some(store) = &self.store {
+            let down_since = *self.store_down.lock().unwrap();
+            if down_since.is_none_or(|t| t.elapsed() >= STORE_RETRY) {
+                match store.check(key, limit).await {
+                    Ok(decision) => {
+                        if self.store_down.lock().unwrap().take().is_some() {
+                            eprintln!("Rate limit store reachable again");
+                        }
+                        return decision;
+                    }
+                    Err(e) => {
+                        let mut down = self.store_down.lock().unwrap();
+                        if down.is_none() {
+                            eprintln!("Rate limit store failed, limiting locally: {}", e);
+                        }
+                        *down = Some(Instant::now());
+                    }
+                }
+            }
+        }
+        self.check_local(key, limit, Instant::now())
+    }
+
+    fn check_local(&self, key: &str, limit: &Limit, now: Instant) -> Decision {
+        let mut entry = self
+            .entries
+            .entry(key.to_string())
+            .or_insert_with(|| Entry::new(limit.algorithm, now));
+        // A reload may have switched the route to another algorithm.
+        if entry.algorithm != limit.algorithm {
+            *entry = Entry::new(limit.algorithm, now);
+        }
+        let decision = entry.check(limit, now);
+        entry.idle_at = now + decision.reset;
+        decision
+    }
+
+    /// Drops entries that have fully recovered; returns how many.
+    pub fn evict_idle(&self, now: Instant) -> usize {
+        let before = self.entries.len();
+        self.entries.retain(|_, entry| entry.idle_at > now);
+        before - self.entries.len()
+    }
+
+    /// Periodically evicts idle entries, so one-off clients do not
+    /// accumulate forever.
+    pub fn spawn_sweeper(self: &Arc<Self>, every: Duration) {
+        let limiter = Arc::downgrade(self);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(every).await;
+                let Some(limiter) = limiter.upgrade() else {
+                    break;
+                };
+                limiter.evict_idle(Instant::now());
+            }
+        });
+    }
+}
+
+impl Entry {
+    fn new(algorithm: Algorithm, now: Instant) -> Self {
+        let state = match algorithm {
+            Algorithm::TokenBucket => State::Tokens {
+                tokens: f64::MAX,
+                updated: now,
+            },
+            Algorithm::SlidingLog => State::Log(VecDeque::new()),
+            Algorithm::FixedWindow => State::Window {
+                count: 0,
+                started: now,
+            },
+        };
+        Self {
+            algorithm,
+            state,
+            idle_at: now,
+        }
+    }
+
+    fn check(&mut self, limit: &Limit, now: Instant) -> Decision {
+        let mut decision = Decision {
+            allowed: false,
+            limit: limit.quota(),
+            remaining: 0,
+            reset: Duration::ZERO,
+            retry_after: Duration::ZERO,
+        };
+        match &mut self.state {
+            State::Tokens { tokens, updated } => {
+                let capacity = limit.burst as f64;
+                let rate = limit.requests as f64 / limit.window.as_secs_f64();
+                *tokens =
+                    (*tokens + now.duration_since(*updated).as_secs_f64() * rate).min(capacity);
+                *updated = now;
+                decision.allowed = *tokens >= 1.0;
+                if decision.allowed {
+                    *tokens -= 1.0;
+                } else {
+                    decision.retry_after = Duration::from_secs_f64((1.0 - *tokens) / rate);
+                }
+                decision.remaining = *tokens as u32;
+                decision.reset = Duration::from_secs_f64((capacity - *tokens) / rate);
+            }
+            State::Log(log) => {
+                while log
+                    .front()
+                    .is_some_and(|t| now.duration_since(*t) >= limit.window)
+                {
+                    log.pop_front();
+                }
+                decision.allowed = log.len() < limit.requests as usize;
+                if decision.allowed {
+                    log.push_back(now);
+                } else if let Some(oldest) = log.front() {
+                    decision.retry_after = *oldest + limit.window - now;
+                }
+                decision.remaining = limit.requests.saturating_sub(log.len() as u32);
+                if let Some(newest) = log.back() {
+                    decision.reset = *newest + limit.window - now;
+                }
+            }
+            State::Window { count, started } => {
+                if now.duration_since(*started) >= limit.window {
+                    *count = 0;
+                    *started = now;
+                }
+                decision.allowed = *count < limit.requests;
+                if decision.allowed {
+                    *count += 1;
+                }
+                // A reload may have lowered the limit below the count.
+                decision.remaining = limit.requests.saturating_sub(*count);
+                decision.reset = *started + limit.window - now;
+                if !decision.allowed {
+                    decision.retry_after = decision.reset;
+                }
+            }
+        }
+        decision
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(algorithm: Algorithm, requests: u32, window_secs: u64, burst: u32) -> Limit {
+        Limit {
+            algorithm,
+            requests,
+            window: Duration::from_secs(window_secs),
+            burst,
+        }
+    }
+
+    fn run(limiter: &RateLimiter, limit: &Limit, at: Instant, n: usize) -> Vec<bool> {
+        (0..n)
+            .map(|_| limiter.check_local("k", limit, at).allowed)
+            .collect()
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::new(None);
+        // 10 per second, bursts of up to 5.
+        let limit = limit(Algorithm::TokenBucket, 10, 1, 5);
+        let t0 = Instant::now();
+        assert_eq!(
+            run(&limiter, &limit, t0, 6),
+            [true, true, true, true, true, false]
+        );
+
+        let denied = limiter.check_local("k", &limit, t0);
+        assert_eq!(denied.remaining, 0);
+        assert_eq!(denied.retry_after, Duration::from_millis(100));
+        assert_eq!(denied.reset, Duration::from_millis(500));
+
+        // 250ms refill two tokens.
+        let later = t0 + Duration::from_millis(250);
+        assert_eq!(run(&limiter, &limit, later, 3), [true, true, false]);
+    }
+
+    #[test]
+    fn test_sliding_log_has_no_boundary_burst() {
+        let limiter = RateLimiter::new(None);
+        let limit = limit(Algorithm::SlidingLog, 3, 10, 3);
+        let t0 = Instant::now();
+        assert_eq!(
+            run(&limiter, &limit, t0 + Duration::from_secs(9), 3),
+            [true; 3]
+        );
+        // A fixed window would reset at t0 + 10s and allow three more.
+        let edge = t0 + Duration::from_secs(11);
+        let denied = limiter.check_local("k", &limit, edge);
+        assert!(!denied.allowed);
+        assert_eq!(denied.retry_after, Duration::from_secs(8));
+        assert_eq!(denied.reset, Duration::from_secs(8));
+        assert_eq!(
+            run(&limiter, &limit, t0 + Duration::from_secs(19), 4),
+            [true, true, true, false]
+        );
+    }
+
+    #[test]
+    fn test_fixed_window() {
+        let limiter = RateLimiter::new(None);
+        let limit = limit(Algorithm::FixedWindow, 2, 10, 2);
+        let t0 = Instant::now();
+        assert_eq!(run(&limiter, &limit, t0, 3), [true, true, false]);
+        let denied = limiter.check_local("k", &limit, t0 + Duration::from_secs(4));
+        assert_eq!(denied.retry_after, Duration::from_secs(6));
+        assert_eq!(
+            run(&limiter, &limit, t0 + Duration::from_secs(10), 3),
+            [true, true, false]
+        );
+    }
+
+    #[test]
+    fn test_reload_lowers_limit_below_count() {
+        let limiter = RateLimiter::new(None);
+        let t0 = Instant::now();
+        let fixed = limit(Algorithm::FixedWindow, 5, 60, 5);
+        assert_eq!(run(&limiter, &fixed, t0, 3), [true; 3]);
+        let lowered = limit(Algorithm::FixedWindow, 2, 60, 2);
+        let denied = limiter.check_local("k", &lowered, t0);
+        assert!(!denied.allowed);
+        assert_eq!(denied.remaining, 0);
+    }
+
+    #[test]
+    fn test_evict_idle() {
+        let limiter = RateLimiter::new(None);
+        let t0 = Instant::now();
+        let bucket = limit(Algorithm::TokenBucket, 10, 1, 10);
+        let log = limit(Algorithm::SlidingLog, 10, 60, 10);
+        limiter.check_local("bucket", &bucket, t0);
+        limiter.check_local("log", &log, t0);
+
+        assert_eq!(limiter.evict_idle(t0 + Duration::from_millis(50)), 0);
+        // The bucket is full again after 100ms; the log entry lasts 60s.
+        assert_eq!(limiter.evict_idle(t0 + Duration::from_millis(100)), 1);
+        assert!(limiter.entries.contains_key("log"));
+        assert_eq!(limiter.evict_idle(t0 + Duration::from_secs(60)), 1);
+        assert!(limiter.entries.is_empty());
+    }
+
+    #[test]
+    fn test_algorithm_change_resets_state() {
+        let limiter = RateLimiter::new(None);
+        let t0 = Instant::now();
+        let fixed = limit(Algorithm::FixedWindow, 1, 60, 1);
+        assert_eq!(run(&limiter, &fixed, t0, 2), [true, false]);
+        let bucket = limit(Algorithm::TokenBucket, 1, 60, 1);
+        assert_eq!(run(&limiter, &bucket, t0, 2), [true, false]);
+    }
+}
@@ -0,0 +1,481 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+
+use crate::rate_limit::{Algorithm, Decision, Limit};
+
+const TIMEOUT: Duration = Duration::from_secs(1);
+// Checks in flight at once; each has a connection to itself.
+const POOL_SIZE: usize = 8;
+const KEY_PREFIX: &str = "gateway:rl:";
+
+// Each script takes the key and the limit, and returns
+// `{allowed, remaining, reset_ms, retry_after_ms}`. Time comes from the
+// server, so instances with skewed clocks still agree.
+const TOKEN_BUCKET: &str = r#"
+local capacity = tonumber(ARGV[1])
+local rate = tonumber(ARGV[2]) / tonumber(ARGV[3])
+local t = redis.call('TIME')
+local now = t[1] * 1000 + math.floor(t[2] / 1000)
+local state = redis.call('HMGET', KEYS[1], 'tokens', 'ts')
+local tokens = tonumber(state[1]) or capacity
+local ts = tonumber(state[2]) or now
+tokens = math.min(capacity, tokens + math.max(0, now - ts) * rate)
+local allowed, retry = 0, 0
+if tokens >= 1 then
+  tokens = tokens - 1
+  allowed = 1
+else
+  retry = math.ceil((1 - tokens) / rate)
+end
+local reset = math.ceil((capacity - tokens) / rate)
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'ts', now)
+redis.call('PEXPIRE', KEYS[1], math.max(reset, 1))
+return {allowed, math.floor(tokens), reset, retry}
+"#;
+
+const SLIDING_LOG: &str = r#"
+local limit = tonumber(ARGV[1])
+local window = tonumber(ARGV[3])
+local t = redis.call('TIME')
+local now = t[1] * 1000 + math.floor(t[2] / 1000)
+redis.call('ZREMRANGEBYSCORE', KEYS[1], '-inf', now - window)
+local count = redis.call('ZCARD', KEYS[1])
+local allowed, retry = 0, 0
+if count < limit then
+  redis.call('ZADD', KEYS[1], now, ARGV[4])
+  count = count + 1
+  allowed = 1
+else
+  local oldest = redis.call('ZRANGE', KEYS[1], 0, 0, 'WITHSCORES')
+  retry = tonumber(oldest[2]) + window - now
+end
+local newest = redis.call('ZRANGE', KEYS[1], -1, -1, 'WITHSCORES')
+local reset = tonumber(newest[2]) + window - now
+redis.call('PEXPIRE', KEYS[1], window)
+return {allowed, limit - count, reset, retry}
+"#;
+
+const FIXED_WINDOW: &str = r#"
+local limit = tonumber(ARGV[1])
+local count = redis.call('INCR', KEYS[1])
+if count == 1 then
+  redis.call('PEXPIRE', KEYS[1], ARGV[3])
+end
+local ttl = redis.call('PTTL', KEYS[1])
+if count > limit then
+  return {0, 0, ttl, ttl}
+end
+return {1, limit - count, ttl, 0}
+"#;
+
+/// A RESP reply.
+#[derive(Debug, PartialEq)]
+enum Value {
+    Simple(String),
+    Error(String),
+    Int(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Value>>),
+}
+
+/// Shared rate-limit counters in a server speaking the Redis protocol
+/// (Redis, Valkey, KeyDB, ...), so several gateways enforce one limit.
+/// Commands go over a pool of up to `POOL_SIZE` connections; one that
+/// fails or times out is dropped and replaced when next needed.
+pub struct RedisStore {
+    addr: String,
+    password: Option<String>,
+    db: Option<u32>,
+    permits: Semaphore,
+    idle: Mutex<Vec<BufStream<TcpStream>>>,
+    // Makes sliding-log entries unique across requests and instances.
+    instance: u64,
+    seq: AtomicU64,
+}
+
+impl RedisStore {
+    /// `redis://[:password@]host[:port][/db]`
+    pub fn from_url(url: &str) -> Result<Self, String> {
+        let rest = url
+            .strip_prefix("redis://")
+            .ok_or_else(|| format!("store URL {} must start with redis://", url))?;
+        let (auth, rest) = match rest.rsplit_once('@') {
+            Some((auth, rest)) => (Some(auth), rest),
+            None => (None, rest),
+        };
+        // Redis 6 ACL users are not supported; only the password is used.
+        let password = auth
+            .map(|a| a.rsplit_once(':').map_or(a, |(_, p)| p).to_string())
+            .filter(|p| !p.is_empty());
+        let (host, db) = match rest.split_once('/') {
+            Some((host, "")) => (host, None),
+            Some((host, db)) => (
+                host,
+                Some(
+                    db.parse()
+                        .map_err(|_| format!("invalid database in store URL {}", url))?,
+                ),
+            ),
+            None => (rest, None),
+        };
+        if host.is_empty() {
+            return Err(format!("store URL {} has no host", url));
+        }
+        let addr = if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{}:6379", host)
+        };
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Ok(Self {
+            addr,
+            password,
+            db,
+            permits: Semaphore::new(POOL_SIZE),
+            idle: Mutex::new(Vec::new()),
+            instance: started.as_nanos() as u64 ^ ((std::process::id() as u64) << 32),
+            seq: AtomicU64::new(0),
+        })
+    }
+
+    pub async fn check(&self, key: &str, limit: &Limit) -> Result<Decision, String> {
+        let script = match limit.algorithm {
+            Algorithm::TokenBucket => TOKEN_BUCKET,
+            Algorithm::SlidingLog => SLIDING_LOG,
+            Algorithm::FixedWindow => FIXED_WINDOW,
+        };
+        // The algorithm is part of the key: each one stores a different type.
+        let key = format!("{}{}:{}", KEY_PREFIX, limit.algorithm.as_str(), key);
+        let member = format!(
+            "{:x}-{}",
+            self.instance,
+            self.seq.fetch_add(1, Ordering::Relaxed)
+        );
+        let quota = limit.quota().to_string();
+        let requests = limit.requests.to_string();
+        let window = limit.window.as_millis().to_string();
+        let args = [
+            "EVAL", script, "1", &key, &quota, &requests, &window, &member,
+        ];
+        let reply = self
+            .command(&args)
+            .await
+            .map_err(|e| format!("{}: {}", self.addr, e))?;
+        let ms = |v: &Value| match v {
+            Value::Int(n) => Ok(Duration::from_millis((*n).max(0) as u64)),
+            other => Err(format!("unexpected reply {:?}", other)),
+        };
+        match reply {
+            Value::Array(Some(items)) if items.len() == 4 => Ok(Decision {
+                allowed: items[0] == Value::Int(1),
+                limit: limit.quota(),
+                remaining: match items[1] {
+                    Value::Int(n) => n.max(0) as u32,
+                    ref other => return Err(format!("unexpected reply {:?}", other)),
+                },
+                reset: ms(&items[2])?,
+                retry_after: ms(&items[3])?,
+            }),
+            Value::Error(e) => Err(e),
+            other => Err(format!("unexpected reply {:?}", other)),
+        }
+    }
+
+    /// Only the round trip counts against the timeout: waiting for a free
+    /// connection says nothing about the server, and gives up without
+    /// dropping one.
+    async fn command(&self, args: &[&str]) -> io::Result<Value> {
+        let _permit = tokio::time::timeout(TIMEOUT, self.permits.acquire())
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "all connections busy"))?
+            .expect("semaphore is never closed");
+        let idle = self.idle.lock().unwrap().pop();
+        let round_trip = async {
+            let mut stream = match idle {
+                Some(stream) => stream,
+                None => self.connect().await?,
+            };
+            let reply = send(&mut stream, args).await?;
+            Ok::<_, io::Error>((stream, reply))
+        };
+        // A connection that errored or timed out is dropped: a late reply
+        // must not be read as the answer to the next command.
+        match tokio::time::timeout(TIMEOUT, round_trip).await {
+            Ok(Ok((stream, reply))) => {
+                self.idle.lock().unwrap().push(stream);
+                Ok(reply)
+            }
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")),
+        }
+    }
+
+    async fn connect(&self) -> io::Result<BufStream<TcpStream>> {
+        let stream = TcpStream::connect(&self.addr).await?;
+        stream.set_nodelay(true)?;
+        let mut stream = BufStream::new(stream);
+        if let Some(password) = &self.password {
+            expect_ok(send(&mut stream, &["AUTH", password]).await?)?;
+        }
+        if let Some(db) = self.db {
+            expect_ok(send(&mut stream, &["SELECT", &db.to_string()]).await?)?;
+        }
+        Ok(stream)
+    }
+}
+
+fn expect_ok(reply: Value) -> io::Result<()> {
+    match reply {
+        Value::Simple(_) => Ok(()),
+        Value::Error(e) => Err(io::Error::other(e)),
+        other => Err(io::Error::other(format!("unexpected reply {:?}", other))),
+    }
+}
+
+async fn send(stream: &mut BufStream<TcpStream>, args: &[&str]) -> io::Result<Value> {
+    stream.write_all(&encode(args)).await?;
+    stream.flush().await?;
+    read_value(stream).await
+}
+
+fn encode(args: &[&str]) -> Vec<u8> {
+    let mut out = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        out.extend_from_slice(arg.as_bytes());
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
+
+fn read_value<'a, R>(r: &'a mut R) -> Pin<Box<dyn Future<Output = io::Result<Value>> + Send + 'a>>
+where
+    R: AsyncBufRead + Unpin + Send,
+{
+    Box::pin(async move {
+        let mut line = Vec::new();
+        r.read_until(b'\n', &mut line).await?;
+        if !line.ends_with(b"\r\n") {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let text = String::from_utf8_lossy(&line[1..line.len() - 2]).into_owned();
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed reply");
+        let len = || text.parse::<i64>().map_err(|_| invalid());
+        match line[0] {
+            b'+' => Ok(Value::Simple(text)),
+            b'-' => Ok(Value::Error(text)),
+            b':' => Ok(Value::Int(len()?)),
+            b'$' => match len()? {
+                -1 => Ok(Value::Bulk(None)),
+                n if n >= 0 => {
+                    let mut data = vec![0; n as usize + 2];
+                    r.read_exact(&mut data).await?;
+                    data.truncate(n as usize);
+                    Ok(Value::Bulk(Some(data)))
+                }
+                _ => Err(invalid()),
+            },
+            b'*' => match len()? {
+                -1 => Ok(Value::Array(None)),
+                n if n >= 0 => {
+                    let mut items = Vec::with_capacity(n as usize);
+                    for _ in 0..n {
+                        items.push(read_value(r).await?);
+                    }
+                    Ok(Value::Array(Some(items)))
+                }
+                _ => Err(invalid()),
+            },
+            _ => Err(invalid()),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_codec() {
+        assert_eq!(encode(&["GET", "k"]), b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n");
+        let mut input: &[u8] = b"*4\r\n:1\r\n$3\r\na\r\n\r\n$-1\r\n*2\r\n+OK\r\n-ERR x\r\n";
+        assert_eq!(
+            read_value(&mut input).await.unwrap(),
+            Value::Array(Some(vec![
+                Value::Int(1),
+                Value::Bulk(Some(b"a\r\n".to_vec())),
+                Value::Bulk(None),
+                Value::Array(Some(vec![
+                    Value::Simple("OK".to_string()),
+                    Value::Error("ERR x".to_string()),
+                ])),
+            ]))
+        );
+        let mut truncated: &[u8] = b":1";
+        assert!(read_value(&mut truncated).await.is_err());
+    }
+
+    #[test]
+    fn test_from_url() {
+        let store = RedisStore::from_url("redis://:secret@cache.internal/2").unwrap();
+        assert_eq!(store.addr, "cache.internal:6379");
+        assert_eq!(store.password.as_deref(), Some("secret"));
+        assert_eq!(store.db, Some(2));
+        let store = RedisStore::from_url("redis://127.0.0.1:7000").unwrap();
+        assert_eq!(store.addr, "127.0.0.1:7000");
+        assert_eq!(store.password, None);
+        assert!(RedisStore::from_url("http://x").is_err());
+        assert!(RedisStore::from_url("redis://x/y").is_err());
+    }
+
+    /// Answers each command from `replies` in turn and returns the commands.
+    async fn fake_server(
+        replies: Vec<&'static [u8]>,
+    ) -> (String, tokio::task::JoinHandle<Vec<Value>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufStream::new(stream);
+            let mut commands = Vec::new();
+            for reply in replies {
+                commands.push(read_value(&mut stream).await.unwrap());
+                stream.write_all(reply).await.unwrap();
+                stream.flush().await.unwrap();
+            }
+            commands
+        });
+        (addr, handle)
+    }
+
+    fn args(command: &Value) -> Vec<String> {
+        match command {
+            Value::Array(Some(items)) => items
+                .iter()
+                .map(|v| match v {
+                    Value::Bulk(Some(b)) => String::from_utf8_lossy(b).into_owned(),
+                    other => panic!("{:?}", other),
+                })
+                .collect(),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_against_fake_server() {
+        let (addr, server) = fake_server(vec![
+            b"+OK\r\n",
+            b"*4\r\n:1\r\n:4\r\n:200\r\n:0\r\n",
+            b"*4\r\n:0\r\n:0\r\n:1000\r\n:100\r\n",
+            b"-NOSCRIPT busy\r\n",
+        ])
+        .await;
+        let store = RedisStore::from_url(&format!("redis://:pw@{}", addr)).unwrap();
+        let limit = Limit {
+            algorithm: Algorithm::TokenBucket,
+            requests: 10,
+            window: Duration::from_secs(1),
+            burst: 5,
+        };
+
+        let allowed = store.check("ip:1.2.3.4", &limit).await.unwrap();
+        assert!(allowed.allowed);
+        assert_eq!(allowed.limit, 5);
+        assert_eq!(allowed.remaining, 4);
+        assert_eq!(allowed.reset, Duration::from_millis(200));
+
+        let denied = store.check("ip:1.2.3.4", &limit).await.unwrap();
+        assert!(!denied.allowed);
+        assert_eq!(denied.retry_after, Duration::from_millis(100));
+
+        assert_eq!(
+            store.check("ip:1.2.3.4", &limit).await,
+            Err("NOSCRIPT busy".to_string())
+        );
+
+        let commands = server.await.unwrap();
+        assert_eq!(args(&commands[0]), ["AUTH", "pw"]);
+        let eval = args(&commands[1]);
+        assert_eq!(eval[0], "EVAL");
+        assert_eq!(eval[1], TOKEN_BUCKET);
+        assert_eq!(
+            eval[2..7],
+            ["1", "gateway:rl:token-bucket:ip:1.2.3.4", "5", "10", "1000"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_checks_use_separate_connections() {
+        // Each reply takes 300ms: one connection at a time would push the
+        // later checks past the timeout.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicU64::new(0));
+        let accepted = Arc::clone(&connections);
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                accepted.fetch_add(1, Ordering::Relaxed);
+                tokio::spawn(async move {
+                    let mut stream = BufStream::new(stream);
+                    while read_value(&mut stream).await.is_ok() {
+                        tokio::time::sleep(Duration::from_millis(300)).await;
+                        stream
+                            .write_all(b"*4\r\n:1\r\n:9\r\n:100\r\n:0\r\n")
+                            .await
+                            .unwrap();
+                        stream.flush().await.unwrap();
+                    }
+                });
+            }
+        });
+        let store = Arc::new(RedisStore::from_url(&format!("redis://{}", addr)).unwrap());
+        let limit = Limit {
+            algorithm: Algorithm::FixedWindow,
+            requests: 10,
+            window: Duration::from_secs(1),
+            burst: 10,
+        };
+
+        let checks: Vec<_> = (0..POOL_SIZE)
+            .map(|_| {
+                let store = Arc::clone(&store);
+                tokio::spawn(async move { store.check("k", &limit).await })
+            })
+            .collect();
+        for check in checks {
+            assert!(check.await.unwrap().unwrap().allowed);
+        }
+        assert_eq!(connections.load(Ordering::Relaxed), POOL_SIZE as u64);
+
+        // The connections are reused.
+        store.check("k", &limit).await.unwrap();
+        assert_eq!(connections.load(Ordering::Relaxed), POOL_SIZE as u64);
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_store() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let store = RedisStore::from_url(&format!("redis://{}", addr)).unwrap();
+        let limit = Limit {
+            algorithm: Algorithm::SlidingLog,
+            requests: 1,
+            window: Duration::from_secs(1),
+            burst: 1,
+        };
+        assert!(store.check("k", &limit).await.is_err());
+    }
+}
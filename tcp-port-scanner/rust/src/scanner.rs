@@ -0,0 +1,194 @@
+use std::io::{self, ErrorKind, Read};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+// Most services that greet first fit their greeting in this.
+const BANNER_BYTES: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortState {
+    Open,
+    /// The host answered with a reset.
+    Closed,
+    /// No answer within the timeout, or the host was unreachable.
+    Filtered,
+    /// The probe failed locally, e.g. out of ephemeral ports, so the port's
+    /// state is unknown.
+    Error,
+}
+
+impl PortState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Open => "open",
+            Self::Closed => "closed",
+            Self::Filtered => "filtered",
+            Self::Error => "error",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortResult {
+    pub addr: SocketAddr,
+    pub state: PortState,
+    /// What an open port sent first, with control characters escaped.
+    pub banner: Option<String>,
+    /// Why the probe failed, for [`PortState::Error`].
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ScanOptions {
+    /// Connections in flight at once.
+    pub concurrency: usize,
+    pub connect_timeout: Duration,
+}
+
+/// Probes `ports` on each of `hosts` with up to `concurrency` connections
+/// at once across all of them. Results are grouped by host, in the order
+/// of `hosts` and then `ports`. Banners are left to [`grab_banners`].
+pub fn scan(hosts: &[IpAddr], ports: &[u16], options: &ScanOptions) -> Vec<PortResult> {
+    parallel(hosts.len() * ports.len(), options.concurrency, |i| probe(SocketAddr::new(hosts[i / ports.len()], ports[i % ports.len()]), options.connect_timeout))
+}
+
+/// Reconnects to the open ports in `results` and waits up to `timeout` for
+/// each to send something. Kept out of [`scan`] so that repeated scans
+/// measure connects, not read timeouts on silent services.
+pub fn grab_banners(results: &mut [PortResult], timeout: Duration, options: &ScanOptions) {
+    let open: Vec<usize> = (0..results.len()).filter(|i| results[*i].state == PortState::Open).collect();
+    let banners = parallel(open.len(), options.concurrency, |i| {
+        TcpStream::connect_timeout(&results[open[i]].addr, options.connect_timeout)
+            .ok()
+            .and_then(|stream| read_banner(stream, timeout))
+    });
+    for (i, banner) in open.into_iter().zip(banners) { results[i].banner = banner; }
+}
+
+/// Runs `job` for `0..total` on up to `concurrency` threads, returning the
+/// results in index order.
+fn parallel<T: Send>(total: usize, concurrency: usize, job: impl Fn(usize) -> T + Sync) -> Vec<T> {
+    let next = AtomicUsize::new(0);
+    let workers = concurrency.clamp(1, total.max(1));
+    let mut results: Vec<(usize, T)> = thread::scope(|s| {
+        let handles: Vec<_> = (0..workers).map(|_| s.spawn(|| {
+            let mut done = Vec::new();
+            loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if i >= total { break; }
+                done.push((i, job(i)));
+            }
+            done
+        })).collect();
+        handles.into_iter().flat_map(|h| h.join().expect("scan worker panicked")).collect()
+    });
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+fn probe(addr: SocketAddr, timeout: Duration) -> PortResult {
+    let (state, error) = match TcpStream::connect_timeout(&addr, timeout) {
+        Ok(_) => (PortState::Open, None),
+        Err(e) => classify(&e),
+    };
+    PortResult { addr, state, banner: None, error }
+}
+
+/// Only errors that come from the network say anything about the port.
+/// Anything else, such as running out of ephemeral ports at high
+/// concurrency, is reported rather than passed off as filtered.
+fn classify(e: &io::Error) -> (PortState, Option<String>) {
+    match e.kind() {
+        ErrorKind::ConnectionRefused => (PortState::Closed, None),
+        ErrorKind::TimedOut | ErrorKind::HostUnreachable | ErrorKind::NetworkUnreachable => (PortState::Filtered, None),
+        _ => (PortState::Error, Some(e.to_string())),
+    }
+}
+
+fn read_banner(mut stream: TcpStream, timeout: Duration) -> Option<String> {
+    stream.set_read_timeout(Some(timeout)).ok()?;
+    let mut buf = [0u8; BANNER_BYTES];
+    let n = stream.read(&mut buf).ok().filter(|n| *n > 0)?;
+    let text = String::from_utf8_lossy(&buf[..n]);
+    Some(text.trim_end().chars().map(|c| if c.is_control() { c.escape_default().to_string() } else { c.to_string() }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::{Ipv4Addr, TcpListener};
+
+    const BANNER_TIMEOUT: Duration = Duration::from_millis(200);
+
+    fn options() -> ScanOptions {
+        ScanOptions { concurrency: 4, connect_timeout: Duration::from_millis(500) }
+    }
+
+    /// A listener that greets each connection with `banner`, if any.
+    fn listener(banner: Option<&'static [u8]>) -> u16 {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for mut conn in listener.incoming().flatten() {
+                thread::spawn(move || {
+                    if let Some(banner) = banner { let _ = conn.write_all(banner); }
+                    // Held open until the scanner gives up reading.
+                    thread::sleep(Duration::from_millis(300));
+                });
+            }
+        });
+        port
+    }
+
+    fn closed_port() -> u16 {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    #[test]
+    fn test_scan_states_and_banners() {
+        let ssh = listener(Some(b"SSH-2.0-OpenSSH_9.6\r\n"));
+        let silent = listener(None);
+        let closed = closed_port();
+        let ftp = listener(Some(b"220-Welcome\r\n220 Ready\r\n"));
+
+        let ports = [ssh, silent, closed, ftp];
+        let mut results = scan(&[IpAddr::V4(Ipv4Addr::LOCALHOST)], &ports, &options());
+        assert!(results.iter().all(|r| r.banner.is_none()));
+        grab_banners(&mut results, BANNER_TIMEOUT, &options());
+        let summary: Vec<_> = results.iter().map(|r| (r.addr.port(), r.state, r.banner.as_deref())).collect();
+        assert_eq!(summary, [
+            (ssh, PortState::Open, Some("SSH-2.0-OpenSSH_9.6")),
+            (silent, PortState::Open, None),
+            (closed, PortState::Closed, None),
+            (ftp, PortState::Open, Some("220-Welcome\\r\\n220 Ready")),
+        ]);
+    }
+
+    #[test]
+    fn test_classify() {
+        let state = |kind| classify(&io::Error::from(kind)).0;
+        assert_eq!(state(ErrorKind::ConnectionRefused), PortState::Closed);
+        assert_eq!(state(ErrorKind::TimedOut), PortState::Filtered);
+        assert_eq!(state(ErrorKind::HostUnreachable), PortState::Filtered);
+        assert_eq!(state(ErrorKind::NetworkUnreachable), PortState::Filtered);
+        assert_eq!(state(ErrorKind::AddrNotAvailable), PortState::Error);
+        assert_eq!(state(ErrorKind::PermissionDenied), PortState::Error);
+        assert!(classify(&io::Error::from(ErrorKind::AddrNotAvailable)).1.is_some());
+    }
+
+    #[test]
+    fn test_scan_groups_results_by_host() {
+        let open = listener(None);
+        let closed = closed_port();
+        let hosts = [IpAddr::V4(Ipv4Addr::LOCALHOST), IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2))];
+        let results = scan(&hosts, &[open, closed], &options());
+        let addrs: Vec<_> = results.iter().map(|r| r.addr).collect();
+        assert_eq!(addrs, [SocketAddr::new(hosts[0], open), SocketAddr::new(hosts[0], closed), SocketAddr::new(hosts[1], open), SocketAddr::new(hosts[1], closed)]);
+        assert_eq!(results[0].state, PortState::Open);
+        assert_eq!(results[1].state, PortState::Closed);
+    }
+}
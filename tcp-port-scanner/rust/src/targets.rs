@@ -0,0 +1,247 @@
+use std::collections::{BTreeSet, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs};
+use std::path::Path;
+
+/// nmap's most common TCP ports.
+const TOP_20: &str = "21-23,25,53,80,110-111,135,139,143,443,445,993,995,1723,3306,3389,5900,8080";
+const TOP_100: &str = "7,9,13,21-23,25-26,37,53,79-81,88,106,110-111,113,119,135,139,143-144,179,199,389,427,443-445,465,513-515,543-544,548,554,587,631,646,873,990,993,995,1025-1029,1110,1433,1720,1723,1755,1900,2000-2001,2049,2121,2717,3000,3128,3306,3389,3986,4899,5000,5009,5051,5060,5101,5190,5357,5432,5631,5666,5800,5900,6000-6001,6646,7070,8000,8008-8009,8080-8081,8443,8888,9100,9999-10000,32768,49152-49157";
+
+// Keeps a typo like /8 from queueing millions of hosts.
+const MAX_BLOCK_BITS: u32 = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostSpec {
+    /// An address, or a CIDR block when the prefix is shorter than the
+    /// address.
+    Net(IpAddr, u8),
+    Name(String),
+}
+
+impl HostSpec {
+    fn contains(&self, addr: IpAddr) -> bool {
+        match (self, addr) {
+            (Self::Net(IpAddr::V4(net), prefix), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - *prefix as u32).unwrap_or(0);
+                u32::from(*net) & mask == u32::from(addr) & mask
+            }
+            (Self::Net(IpAddr::V6(net), prefix), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - *prefix as u32).unwrap_or(0);
+                u128::from(*net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    /// The hostname the address was resolved from.
+    pub name: Option<String>,
+    pub addr: IpAddr,
+}
+
+#[derive(Debug)]
+pub struct Resolved {
+    pub targets: Vec<Target>,
+    /// Hostnames that did not resolve; they are skipped.
+    pub unresolved: Vec<String>,
+}
+
+/// Parses targets separated by commas or whitespace: addresses
+/// (`10.0.0.1`), CIDR blocks (`10.0.0.0/24`) and hostnames.
+pub fn parse_hosts(text: &str) -> Result<Vec<HostSpec>, String> {
+    text.split(|c: char| c == ',' || c.is_whitespace()).filter(|item| !item.is_empty()).map(parse_host).collect()
+}
+
+/// Reads a host list, one or more targets per line. `#` starts a comment.
+pub fn read_hosts(path: &Path) -> Result<Vec<HostSpec>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("reading {}: {}", path.display(), e))?;
+    let mut specs = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("");
+        specs.extend(parse_hosts(line).map_err(|e| format!("{}:{}: {}", path.display(), i + 1, e))?);
+    }
+    Ok(specs)
+}
+
+fn parse_host(item: &str) -> Result<HostSpec, String> {
+    let Some((addr, prefix)) = item.split_once('/') else {
+        return Ok(match item.parse::<IpAddr>() {
+            Ok(addr) => HostSpec::Net(addr, max_prefix(addr)),
+            Err(_) => HostSpec::Name(item.to_string()),
+        });
+    };
+    let addr: IpAddr = addr.parse().map_err(|_| format!("invalid CIDR block {item}"))?;
+    let prefix: u8 = prefix.parse().ok().filter(|p| *p <= max_prefix(addr)).ok_or_else(|| format!("invalid prefix length in {item}"))?;
+    if (max_prefix(addr) - prefix) as u32 > MAX_BLOCK_BITS {
+        return Err(format!("{item} is too large; split it into blocks of at most 2^{MAX_BLOCK_BITS} addresses"));
+    }
+    Ok(HostSpec::Net(addr, prefix))
+}
+
+fn max_prefix(addr: IpAddr) -> u8 {
+    if addr.is_ipv4() { 32 } else { 128 }
+}
+
+/// Expands blocks and resolves hostnames, dropping anything covered by
+/// `exclude` and addresses already seen.
+pub fn resolve(specs: &[HostSpec], exclude: &[HostSpec]) -> Resolved {
+    resolve_with(specs, exclude, lookup)
+}
+
+/// [`resolve`] with the name lookup passed in; an empty result means the
+/// name did not resolve.
+fn resolve_with(specs: &[HostSpec], exclude: &[HostSpec], lookup: impl Fn(&str) -> Vec<IpAddr>) -> Resolved {
+    let mut unresolved = Vec::new();
+    let mut excluded = Vec::new();
+    for spec in exclude {
+        match spec {
+            HostSpec::Net(..) => excluded.push(spec.clone()),
+            // Every address of an excluded name is excluded.
+            HostSpec::Name(name) => match lookup(name).as_slice() {
+                [] => unresolved.push(name.clone()),
+                addrs => excluded.extend(addrs.iter().map(|a| HostSpec::Net(*a, max_prefix(*a)))),
+            },
+        }
+    }
+
+    let mut targets = Vec::new();
+    let mut seen = HashSet::new();
+    let mut add = |name: Option<&str>, addr: IpAddr| {
+        if !excluded.iter().any(|spec| spec.contains(addr)) && seen.insert(addr) {
+            targets.push(Target { name: name.map(str::to_string), addr });
+        }
+    };
+    for spec in specs {
+        match spec {
+            HostSpec::Net(IpAddr::V4(net), prefix) => {
+                let size = 1u32 << (32 - *prefix as u32);
+                let first = u32::from(*net) & !(size - 1);
+                for i in 0..size { add(None, IpAddr::V4(Ipv4Addr::from(first + i))); }
+            }
+            HostSpec::Net(IpAddr::V6(net), prefix) => {
+                let size = 1u128 << (128 - *prefix as u32);
+                let first = u128::from(*net) & !(size - 1);
+                for i in 0..size { add(None, IpAddr::V6(Ipv6Addr::from(first + i))); }
+            }
+            // Like nmap, a name is scanned at its first address only.
+            HostSpec::Name(name) => match lookup(name).first() {
+                Some(addr) => add(Some(name), *addr),
+                None => unresolved.push(name.clone()),
+            },
+        }
+    }
+    Resolved { targets, unresolved }
+}
+
+fn lookup(name: &str) -> Vec<IpAddr> {
+    match (name, 0).to_socket_addrs() {
+        Ok(addrs) => addrs.map(|addr| addr.ip()).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Parses a comma-separated port list: single ports (`22`), ranges
+/// (`8000-8100`, open-ended `-1024` and `60000-`) and the named sets
+/// `top-20` and `top-100`. The result is sorted and free of duplicates.
+pub fn parse_ports(spec: &str) -> Result<Vec<u16>, String> {
+    let mut ports = BTreeSet::new();
+    for item in spec.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+        match item {
+            "top-20" => ports.extend(parse_ports(TOP_20)?),
+            "top-100" => ports.extend(parse_ports(TOP_100)?),
+            _ => {
+                let bound = |text: &str, open: u16| if text.is_empty() { Ok(open) } else { parse_port(text) };
+                let (first, last) = match item.split_once('-') {
+                    Some((first, last)) => (bound(first, 1)?, bound(last, u16::MAX)?),
+                    None => (parse_port(item)?, parse_port(item)?),
+                };
+                if last < first { return Err(format!("invalid port range {item}")); }
+                ports.extend(first..=last);
+            }
+        }
+    }
+    if ports.is_empty() { return Err("no ports given".to_string()); }
+    Ok(ports.into_iter().collect())
+}
+
+fn parse_port(text: &str) -> Result<u16, String> {
+    text.parse::<u16>().ok().filter(|p| *p != 0).ok_or_else(|| format!("invalid port {text}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(resolved: &Resolved) -> Vec<String> {
+        resolved.targets.iter().map(|t| t.addr.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_ports() {
+        assert_eq!(parse_ports("80,22,8000-8002,22").unwrap(), [22, 80, 8000, 8001, 8002]);
+        assert_eq!(parse_ports("65534-").unwrap(), [65534, 65535]);
+        assert_eq!(parse_ports("-3").unwrap(), [1, 2, 3]);
+        assert_eq!(parse_ports("top-100").unwrap().len(), 100);
+        assert_eq!(parse_ports("top-20").unwrap().len(), 20);
+        assert!(parse_ports("top-20").unwrap().iter().all(|p| parse_ports("top-100").unwrap().contains(p)));
+
+        for bad in ["0", "80-22", "http", "70000", ""] {
+            assert!(parse_ports(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_parse_hosts() {
+        assert_eq!(parse_hosts("10.0.0.0/30, example.com ::1").unwrap(), [
+            HostSpec::Net("10.0.0.0".parse().unwrap(), 30),
+            HostSpec::Name("example.com".to_string()),
+            HostSpec::Net("::1".parse().unwrap(), 128),
+        ]);
+        assert!(parse_hosts("10.0.0.0/33").is_err());
+        assert!(parse_hosts("10.0.0.0/8").is_err());
+        assert!(parse_hosts("example.com/24").is_err());
+    }
+
+    /// Stands in for DNS so the tests don't depend on the network.
+    fn fake_lookup(name: &str) -> Vec<IpAddr> {
+        match name {
+            "web.test" => vec!["192.168.1.10".parse().unwrap(), "::1".parse().unwrap()],
+            "alias.test" => vec!["10.0.0.7".parse().unwrap()],
+            _ => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_resolve_expands_excludes_and_dedups() {
+        // A block is aligned to its prefix, as nmap does.
+        let specs = parse_hosts("10.0.0.5/30,10.0.0.6,192.168.1.1,web.test").unwrap();
+        let exclude = parse_hosts("10.0.0.4/31").unwrap();
+        let resolved = resolve_with(&specs, &exclude, fake_lookup);
+        assert_eq!(addrs(&resolved), ["10.0.0.6", "10.0.0.7", "192.168.1.1", "192.168.1.10"]);
+        assert_eq!(resolved.targets[3].name.as_deref(), Some("web.test"));
+        assert!(resolved.unresolved.is_empty());
+
+        // alias.test resolves to an address the block already covers.
+        let specs = parse_hosts("127.0.0.1,127.0.0.1/32,10.0.0.6/31,alias.test,nope.test").unwrap();
+        let resolved = resolve_with(&specs, &[], fake_lookup);
+        assert_eq!(addrs(&resolved), ["127.0.0.1", "10.0.0.6", "10.0.0.7"]);
+        assert_eq!(resolved.targets[2].name, None);
+        assert_eq!(resolved.unresolved, ["nope.test"]);
+
+        // Excluding a name excludes every address it resolves to.
+        let specs = parse_hosts("192.168.1.10,::1,192.168.1.11").unwrap();
+        let exclude = parse_hosts("web.test").unwrap();
+        let resolved = resolve_with(&specs, &exclude, fake_lookup);
+        assert_eq!(addrs(&resolved), ["192.168.1.11"]);
+    }
+
+    #[test]
+    fn test_read_hosts() {
+        let path = std::env::temp_dir().join(format!("scanner-hosts-{}", std::process::id()));
+        std::fs::write(&path, "# lab\n10.0.0.1 10.0.0.2\n\n10.0.1.0/31  # switches\n").unwrap();
+        let specs = read_hosts(&path);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(addrs(&resolve(&specs.unwrap(), &[])), ["10.0.0.1", "10.0.0.2", "10.0.1.0", "10.0.1.1"]);
+    }
+}